@@ -0,0 +1,180 @@
+//! ビルド時に作るイメージ
+//!
+//! - `initrd/` ディレクトリをUSTARのアーカイブにまとめ、`OUT_DIR/initrd.tar` に置く。
+//!   カーネルは `include_bytes!` でこれを取り込み、起動時に `/initrd` にマウントする
+//! - ext2のテストに使う小さなイメージを `mke2fs` で作り、`OUT_DIR/ext2.img` に置く。
+//!   `mke2fs` がない、またはUnixでないホストでは空のファイルを置き、テストはとばされる
+//!
+//! どちらも `build.rs` と `initrd/` が変わったときだけ作り直す
+
+use std::env;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+const BLOCK_SIZE: usize = 512;
+
+/// ext2のテスト用イメージのブロックの大きさと数。グループを複数にするため1グループを小さくする
+const EXT2_BLOCK_SIZE: u32 = 1024;
+const EXT2_BLOCKS: u32 = 2048;
+const EXT2_BLOCKS_PER_GROUP: u32 = 1024;
+
+fn main() -> io::Result<()> {
+    let out_dir = PathBuf::from(env::var("OUT_DIR").expect("OUT_DIR is set by cargo"));
+    println!("cargo:rerun-if-changed=build.rs");
+    build_initrd(&out_dir)?;
+    build_ext2_image(&out_dir)
+}
+
+fn build_initrd(out_dir: &Path) -> io::Result<()> {
+    let source = Path::new("initrd");
+    println!("cargo:rerun-if-changed={}", source.display());
+
+    let mut archive = Vec::new();
+    if source.is_dir() {
+        append_directory(&mut archive, source, "")?;
+    }
+    // アーカイブの終わりは0の2ブロック
+    archive.resize(archive.len() + 2 * BLOCK_SIZE, 0);
+    fs::write(out_dir.join("initrd.tar"), archive)
+}
+
+#[cfg(not(unix))]
+fn build_ext2_image(out_dir: &Path) -> io::Result<()> {
+    println!("cargo:warning=ext2 fixtures need symlinks; the ext2 tests are skipped");
+    fs::write(out_dir.join("ext2.img"), [])
+}
+
+/// `tests/ext2.rs` が確かめる中身のイメージを作る
+///
+/// 大きなファイルは二重間接ブロックまで、長いシンボリックリンクはブロックに置かれる
+#[cfg(unix)]
+fn build_ext2_image(out_dir: &Path) -> io::Result<()> {
+    use std::io::{Seek, SeekFrom, Write};
+    use std::os::unix::fs::symlink;
+    use std::process::Command;
+
+    let root = out_dir.join("ext2-root");
+    let image = out_dir.join("ext2.img");
+    if root.exists() {
+        fs::remove_dir_all(&root)?;
+    }
+    fs::create_dir_all(root.join("dir/nested"))?;
+    fs::create_dir_all(root.join("many"))?;
+    fs::write(root.join("hello.txt"), "Hello from ext2!\n")?;
+    fs::write(root.join("dir/nested/deep.txt"), "deep\n")?;
+    let big: Vec<u8> = (0..300_000u32)
+        .map(|i| ((i * 7 + i / 1024) % 251) as u8)
+        .collect();
+    fs::write(root.join("big.bin"), big)?;
+    // 途中に穴のあるファイル
+    let mut sparse = fs::File::create(root.join("sparse.bin"))?;
+    sparse.write_all(b"start")?;
+    sparse.seek(SeekFrom::Start(200_000))?;
+    sparse.write_all(b"end")?;
+    for i in 0..100 {
+        fs::write(
+            root.join(format!("many/file{:03}.txt", i)),
+            format!("{}\n", i),
+        )?;
+    }
+    symlink("hello.txt", root.join("link"))?;
+    symlink("dir/nested", root.join("dir_link"))?;
+    symlink(
+        format!("dir/{}nested/deep.txt", "./".repeat(40)),
+        root.join("long_link"),
+    )?;
+
+    let _ = fs::remove_file(&image);
+    let status = Command::new("mke2fs")
+        .args(["-q", "-F", "-t", "ext2", "-L", "blog_os", "-N", "256"])
+        .args(["-b", &EXT2_BLOCK_SIZE.to_string()])
+        .args(["-g", &EXT2_BLOCKS_PER_GROUP.to_string()])
+        .arg("-d")
+        .arg(&root)
+        .arg(&image)
+        .arg(EXT2_BLOCKS.to_string())
+        .status();
+    match status {
+        Ok(status) if status.success() => Ok(()),
+        result => {
+            println!(
+                "cargo:warning=mke2fs failed ({:?}); the ext2 tests are skipped",
+                result
+            );
+            fs::write(&image, [])
+        }
+    }
+}
+
+fn append_directory(archive: &mut Vec<u8>, directory: &Path, prefix: &str) -> io::Result<()> {
+    let mut entries = fs::read_dir(directory)?.collect::<io::Result<Vec<_>>>()?;
+    // ビルドごとに同じアーカイブになるよう名前の順に並べる
+    entries.sort_by_key(|entry| entry.file_name());
+    for entry in entries {
+        let path = entry.path();
+        println!("cargo:rerun-if-changed={}", path.display());
+        let name = format!("{}{}", prefix, entry.file_name().to_string_lossy());
+        let file_type = entry.file_type()?;
+        if file_type.is_symlink() {
+            let target = fs::read_link(&path)?;
+            append_header(archive, &name, 0o777, 0, b'2', &target.to_string_lossy());
+        } else if file_type.is_dir() {
+            append_header(archive, &format!("{}/", name), 0o755, 0, b'5', "");
+            append_directory(archive, &path, &format!("{}/", name))?;
+        } else {
+            let data = fs::read(&path)?;
+            append_header(archive, &name, 0o644, data.len(), b'0', "");
+            archive.extend_from_slice(&data);
+            archive.resize(archive.len().next_multiple_of(BLOCK_SIZE), 0);
+        }
+    }
+    Ok(())
+}
+
+fn append_header(
+    archive: &mut Vec<u8>,
+    name: &str,
+    mode: u32,
+    size: usize,
+    type_flag: u8,
+    link_name: &str,
+) {
+    assert!(name.len() <= 100, "initrd: path is too long: {}", name);
+    assert!(
+        link_name.len() <= 100,
+        "initrd: link target is too long: {}",
+        link_name
+    );
+
+    let mut header = [0u8; BLOCK_SIZE];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    write_octal(&mut header[100..108], mode as u64);
+    write_octal(&mut header[108..116], 0);
+    write_octal(&mut header[116..124], 0);
+    write_octal(&mut header[124..136], size as u64);
+    write_octal(&mut header[136..148], 0);
+    header[156] = type_flag;
+    header[157..157 + link_name.len()].copy_from_slice(link_name.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+
+    // チェックサムはチェックサム欄を空白にして数える
+    header[148..156].fill(b' ');
+    let checksum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    write_octal(&mut header[148..155], u64::from(checksum));
+    archive.extend_from_slice(&header);
+}
+
+/// 欄の長さ - 1 桁の8進数とNULを書く
+fn write_octal(field: &mut [u8], value: u64) {
+    let digits = field.len() - 1;
+    let text = format!("{:0width$o}", value, width = digits);
+    assert!(
+        text.len() == digits,
+        "initrd: value does not fit in the header: {}",
+        value
+    );
+    field[..digits].copy_from_slice(text.as_bytes());
+    field[digits] = 0;
+}
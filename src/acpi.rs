@@ -0,0 +1,754 @@
+//! ACPIのテーブル
+//!
+//! BIOSの領域からRSDPを探し、RSDT/XSDTをたどってシグネチャでテーブルを引けるようにする。
+//! テーブルは物理メモリのオフセットを使ったマッピング越しに読み、使う前にチェックサムを確かめる。
+//! MADT, FADT, HPET, MCFG は型付きの構造として取り出せる
+
+use crate::memory;
+use core::fmt;
+use core::mem::{MaybeUninit, size_of};
+use core::ptr::{self, read_unaligned};
+use core::slice;
+use spin::Mutex;
+use x86_64::PhysAddr;
+
+/// EBDAのセグメントが書かれている場所
+const EBDA_POINTER: u64 = 0x40e;
+/// EBDAのうちRSDPを探す範囲
+const EBDA_SEARCH_SIZE: u64 = 1024;
+/// BIOSの読み出し専用領域
+const BIOS_AREA: core::ops::Range<u64> = 0xe_0000..0x10_0000;
+
+const RSDP_SIGNATURE: &[u8; 8] = b"RSD PTR ";
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum AcpiError {
+    /// BIOSの領域にRSDPがない
+    RsdpNotFound,
+    /// `init` がまだ成功していない
+    NotInitialized,
+    TableNotFound([u8; 4]),
+    InvalidChecksum([u8; 4]),
+    /// ヘッダに書かれた長さが短すぎる
+    InvalidLength([u8; 4]),
+    /// FADTのレジスタがI/Oポートの番号として使えない場所を指している
+    InvalidPort(&'static str),
+}
+
+impl fmt::Display for AcpiError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            AcpiError::RsdpNotFound => write!(f, "acpi: RSDP not found"),
+            AcpiError::NotInitialized => write!(f, "acpi: not initialized"),
+            AcpiError::TableNotFound(signature) => {
+                write!(f, "acpi: no {} table", signature_str(signature))
+            }
+            AcpiError::InvalidChecksum(signature) => {
+                write!(
+                    f,
+                    "acpi: {} table has a bad checksum",
+                    signature_str(signature)
+                )
+            }
+            AcpiError::InvalidLength(signature) => {
+                write!(f, "acpi: {} table is too short", signature_str(signature))
+            }
+            AcpiError::InvalidPort(register) => {
+                write!(f, "acpi: {} is not an I/O port", register)
+            }
+        }
+    }
+}
+
+/// シグネチャを表示用の文字列にする
+pub fn signature_str(signature: &[u8; 4]) -> &str {
+    core::str::from_utf8(signature).unwrap_or("????")
+}
+
+/// RSDP (ACPI 1.0の部分)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp {
+    signature: [u8; 8],
+    checksum: u8,
+    oem_id: [u8; 6],
+    revision: u8,
+    rsdt_address: u32,
+}
+
+/// RSDP (ACPI 2.0以降で増えた部分を含む)
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct Rsdp2 {
+    rsdp: Rsdp,
+    length: u32,
+    xsdt_address: u64,
+    extended_checksum: u8,
+    reserved: [u8; 3],
+}
+
+/// すべてのシステム記述テーブルに共通のヘッダ
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct SdtHeader {
+    pub signature: [u8; 4],
+    pub length: u32,
+    pub revision: u8,
+    pub checksum: u8,
+    pub oem_id: [u8; 6],
+    pub oem_table_id: [u8; 8],
+    pub oem_revision: u32,
+    pub creator_id: u32,
+    pub creator_revision: u32,
+}
+
+/// レジスタの場所を表すGeneric Address Structure
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct GenericAddress {
+    /// 0ならメモリ空間、1ならI/Oポート空間
+    pub address_space: u8,
+    pub bit_width: u8,
+    pub bit_offset: u8,
+    pub access_size: u8,
+    pub address: u64,
+}
+
+impl GenericAddress {
+    pub const SYSTEM_MEMORY: u8 = 0;
+    pub const SYSTEM_IO: u8 = 1;
+}
+
+/// HPETテーブル
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct HpetTable {
+    pub header: SdtHeader,
+    pub event_timer_block_id: u32,
+    pub base_address: GenericAddress,
+    pub hpet_number: u8,
+    /// 周期モードで割り込みを落とさずに使える最小のカウント数
+    pub minimum_tick: u16,
+    pub page_protection: u8,
+}
+
+/// FADT (シグネチャは `FACP`)
+///
+/// 古い版のテーブルは短いので、ない部分は0として読む
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+pub struct Fadt {
+    pub header: SdtHeader,
+    pub firmware_ctrl: u32,
+    pub dsdt: u32,
+    pub reserved0: u8,
+    pub preferred_pm_profile: u8,
+    /// SCI割り込みのIRQ番号
+    pub sci_interrupt: u16,
+    pub smi_command_port: u32,
+    pub acpi_enable: u8,
+    pub acpi_disable: u8,
+    pub s4bios_req: u8,
+    pub pstate_control: u8,
+    pub pm1a_event_block: u32,
+    pub pm1b_event_block: u32,
+    pub pm1a_control_block: u32,
+    pub pm1b_control_block: u32,
+    pub pm2_control_block: u32,
+    pub pm_timer_block: u32,
+    pub gpe0_block: u32,
+    pub gpe1_block: u32,
+    pub pm1_event_length: u8,
+    pub pm1_control_length: u8,
+    pub pm2_control_length: u8,
+    pub pm_timer_length: u8,
+    pub gpe0_block_length: u8,
+    pub gpe1_block_length: u8,
+    pub gpe1_base: u8,
+    pub cstate_control: u8,
+    pub worst_c2_latency: u16,
+    pub worst_c3_latency: u16,
+    pub flush_size: u16,
+    pub flush_stride: u16,
+    pub duty_offset: u8,
+    pub duty_width: u8,
+    pub day_alarm: u8,
+    pub month_alarm: u8,
+    /// CMOSの世紀のレジスタの番号。0ならない
+    pub century: u8,
+    pub boot_architecture_flags: u16,
+    pub reserved1: u8,
+    pub flags: u32,
+    pub reset_register: GenericAddress,
+    pub reset_value: u8,
+    pub arm_boot_architecture_flags: u16,
+    pub minor_version: u8,
+    pub x_firmware_ctrl: u64,
+    pub x_dsdt: u64,
+    pub x_pm1a_event_block: GenericAddress,
+    pub x_pm1b_event_block: GenericAddress,
+    pub x_pm1a_control_block: GenericAddress,
+    pub x_pm1b_control_block: GenericAddress,
+    pub x_pm2_control_block: GenericAddress,
+    pub x_pm_timer_block: GenericAddress,
+    pub x_gpe0_block: GenericAddress,
+    pub x_gpe1_block: GenericAddress,
+}
+
+impl Fadt {
+    /// フラグ: `reset_register` が使える
+    pub const FLAG_RESET_REGISTER_SUPPORTED: u32 = 1 << 10;
+
+    /// DSDTの物理アドレス。64ビットの方があればそちらを使う
+    pub fn dsdt_address(&self) -> PhysAddr {
+        match self.x_dsdt {
+            0 => PhysAddr::new(self.dsdt.into()),
+            address => PhysAddr::new(address),
+        }
+    }
+
+    pub fn reset_register_supported(&self) -> bool {
+        self.flags & Self::FLAG_RESET_REGISTER_SUPPORTED != 0
+    }
+
+    /// PM1a制御ブロックのI/Oポート。なければ `None`
+    pub fn pm1a_control_port(&self) -> Result<Option<u16>, AcpiError> {
+        io_port(
+            self.x_pm1a_control_block,
+            self.pm1a_control_block,
+            "PM1a_CNT_BLK",
+        )
+    }
+
+    /// PM1b制御ブロックのI/Oポート。なければ `None`
+    pub fn pm1b_control_port(&self) -> Result<Option<u16>, AcpiError> {
+        io_port(
+            self.x_pm1b_control_block,
+            self.pm1b_control_block,
+            "PM1b_CNT_BLK",
+        )
+    }
+
+    /// SMIコマンドポート。なければ `None`
+    pub fn smi_command_port(&self) -> Result<Option<u16>, AcpiError> {
+        match self.smi_command_port {
+            0 => Ok(None),
+            port => u16::try_from(port)
+                .map(Some)
+                .map_err(|_| AcpiError::InvalidPort("SMI_CMD")),
+        }
+    }
+}
+
+/// 64ビットの `extended` が0でなければそちらを、0なら32ビットの `legacy` をポート番号にする
+///
+/// 上位のビットを切り捨てると別のポートに書いてしまうので、収まらなければエラーにする
+fn io_port(
+    extended: GenericAddress,
+    legacy: u32,
+    register: &'static str,
+) -> Result<Option<u16>, AcpiError> {
+    let address = match extended.address {
+        0 => u64::from(legacy),
+        _ if extended.address_space != GenericAddress::SYSTEM_IO => {
+            return Err(AcpiError::InvalidPort(register));
+        }
+        address => address,
+    };
+    match address {
+        0 => Ok(None),
+        address => u16::try_from(address)
+            .map(Some)
+            .map_err(|_| AcpiError::InvalidPort(register)),
+    }
+}
+
+/// MADTの固定部分
+#[derive(Debug, Clone, Copy)]
+#[repr(C, packed)]
+struct MadtHeader {
+    header: SdtHeader,
+    local_apic_address: u32,
+    flags: u32,
+}
+
+/// MADT (シグネチャは `APIC`)。割り込みコントローラとプロセッサの一覧
+#[derive(Debug, Clone, Copy)]
+pub struct Madt {
+    pub header: SdtHeader,
+    pub local_apic_address: u32,
+    pub flags: u32,
+    entries: &'static [u8],
+}
+
+impl Madt {
+    /// フラグ: 8259 PICも載っている
+    pub const FLAG_PCAT_COMPAT: u32 = 1 << 0;
+
+    pub fn entries(&self) -> MadtEntries<'static> {
+        MadtEntries {
+            bytes: self.entries,
+        }
+    }
+
+    pub fn has_8259(&self) -> bool {
+        self.flags & Self::FLAG_PCAT_COMPAT != 0
+    }
+
+    /// Local APICのアドレス。64ビットで上書きする項目があればそちらを使う
+    pub fn local_apic_address(&self) -> PhysAddr {
+        let address = self
+            .entries()
+            .find_map(|entry| match entry {
+                MadtEntry::LocalApicAddressOverride { address } => Some(address),
+                _ => None,
+            })
+            .unwrap_or(self.local_apic_address.into());
+        PhysAddr::new(address)
+    }
+}
+
+/// MADTの項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum MadtEntry {
+    LocalApic {
+        processor_id: u8,
+        apic_id: u8,
+        flags: u32,
+    },
+    IoApic {
+        id: u8,
+        address: u32,
+        /// このI/O APICの最初の入力に対応するグローバルなシステム割り込み番号
+        gsi_base: u32,
+    },
+    /// ISAのIRQがI/O APICの別の入力につながっている
+    InterruptSourceOverride {
+        bus: u8,
+        source: u8,
+        gsi: u32,
+        flags: u16,
+    },
+    NmiSource {
+        flags: u16,
+        gsi: u32,
+    },
+    LocalApicNmi {
+        /// 0xffなら全プロセッサ
+        processor_id: u8,
+        flags: u16,
+        lint: u8,
+    },
+    LocalApicAddressOverride {
+        address: u64,
+    },
+    LocalX2Apic {
+        x2apic_id: u32,
+        flags: u32,
+        processor_uid: u32,
+    },
+    /// このカーネルが知らない種類の項目
+    Unknown {
+        entry_type: u8,
+        length: u8,
+    },
+}
+
+impl MadtEntry {
+    /// `LocalApic` と `LocalX2Apic` のフラグ: 使えるプロセッサ
+    pub const PROCESSOR_ENABLED: u32 = 1 << 0;
+}
+
+/// MADTの項目を順にたどるイテレータ
+#[derive(Debug, Clone)]
+pub struct MadtEntries<'a> {
+    bytes: &'a [u8],
+}
+
+impl Iterator for MadtEntries<'_> {
+    type Item = MadtEntry;
+
+    fn next(&mut self) -> Option<MadtEntry> {
+        let &[entry_type, length, ..] = self.bytes else {
+            return None;
+        };
+        let length = usize::from(length);
+        if length < 2 || length > self.bytes.len() {
+            // 壊れた項目の先は信用できない
+            self.bytes = &[];
+            return None;
+        }
+        let data = &self.bytes[2..length];
+        self.bytes = &self.bytes[length..];
+
+        let entry = match entry_type {
+            0 => MadtEntry::LocalApic {
+                processor_id: u8_at(data, 0),
+                apic_id: u8_at(data, 1),
+                flags: u32_at(data, 2),
+            },
+            1 => MadtEntry::IoApic {
+                id: u8_at(data, 0),
+                address: u32_at(data, 2),
+                gsi_base: u32_at(data, 6),
+            },
+            2 => MadtEntry::InterruptSourceOverride {
+                bus: u8_at(data, 0),
+                source: u8_at(data, 1),
+                gsi: u32_at(data, 2),
+                flags: u16_at(data, 6),
+            },
+            3 => MadtEntry::NmiSource {
+                flags: u16_at(data, 0),
+                gsi: u32_at(data, 2),
+            },
+            4 => MadtEntry::LocalApicNmi {
+                processor_id: u8_at(data, 0),
+                flags: u16_at(data, 1),
+                lint: u8_at(data, 3),
+            },
+            5 => MadtEntry::LocalApicAddressOverride {
+                address: u64_at(data, 2),
+            },
+            9 => MadtEntry::LocalX2Apic {
+                x2apic_id: u32_at(data, 2),
+                flags: u32_at(data, 6),
+                processor_uid: u32_at(data, 10),
+            },
+            _ => MadtEntry::Unknown {
+                entry_type,
+                length: length as u8,
+            },
+        };
+        Some(entry)
+    }
+}
+
+/// MCFG (PCI Expressの拡張コンフィギュレーション空間) の1項目
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct McfgEntry {
+    /// バス0の先頭に当たる物理アドレス
+    pub base_address: u64,
+    pub segment: u16,
+    pub start_bus: u8,
+    pub end_bus: u8,
+}
+
+/// MCFGの項目の大きさ
+const MCFG_ENTRY_SIZE: usize = 16;
+/// MCFGのヘッダの後の予約領域
+const MCFG_RESERVED_SIZE: usize = 8;
+
+/// MCFG
+#[derive(Debug, Clone, Copy)]
+pub struct Mcfg {
+    pub header: SdtHeader,
+    entries: &'static [u8],
+}
+
+impl Mcfg {
+    pub fn entries(&self) -> impl Iterator<Item = McfgEntry> + 'static {
+        self.entries
+            .chunks_exact(MCFG_ENTRY_SIZE)
+            .map(|entry| McfgEntry {
+                base_address: u64_at(entry, 0),
+                segment: u16_at(entry, 8),
+                start_bus: u8_at(entry, 10),
+                end_bus: u8_at(entry, 11),
+            })
+    }
+}
+
+/// 範囲外は0として読む
+fn u8_at(bytes: &[u8], offset: usize) -> u8 {
+    bytes.get(offset).copied().unwrap_or(0)
+}
+
+fn u16_at(bytes: &[u8], offset: usize) -> u16 {
+    u16::from_le_bytes([u8_at(bytes, offset), u8_at(bytes, offset + 1)])
+}
+
+fn u32_at(bytes: &[u8], offset: usize) -> u32 {
+    u32::from(u16_at(bytes, offset)) | u32::from(u16_at(bytes, offset + 2)) << 16
+}
+
+fn u64_at(bytes: &[u8], offset: usize) -> u64 {
+    u64::from(u32_at(bytes, offset)) | u64::from(u32_at(bytes, offset + 4)) << 32
+}
+
+/// ルートのテーブル (RSDTかXSDT)
+#[derive(Debug, Clone, Copy)]
+struct Root {
+    table: &'static [u8],
+    /// XSDTなら項目が64ビット
+    extended: bool,
+}
+
+static ROOT: Mutex<Option<Root>> = Mutex::new(None);
+
+/// RSDPを探してルートのテーブルを確かめる。`memory::init` の後に呼ぶこと
+pub fn init() -> Result<(), AcpiError> {
+    let address = find_rsdp().ok_or(AcpiError::RsdpNotFound)?;
+    let rsdp: Rsdp = unsafe { read_phys(address) };
+
+    // ACPI 2.0以降ならXSDTを使う。拡張部分のチェックサムが合わなければRSDTに戻る
+    let mut root = None;
+    if rsdp.revision >= 2 {
+        let rsdp2: Rsdp2 = unsafe { read_phys(address) };
+        let length = (rsdp2.length as usize).max(size_of::<Rsdp2>());
+        if checksum(unsafe { phys_slice(address, length) }) == 0 && rsdp2.xsdt_address != 0 {
+            root = Some(Root {
+                table: table_bytes(PhysAddr::new(rsdp2.xsdt_address))?,
+                extended: true,
+            });
+        }
+    }
+    let root = match root {
+        Some(root) => root,
+        None => Root {
+            table: table_bytes(PhysAddr::new(rsdp.rsdt_address.into()))?,
+            extended: false,
+        },
+    };
+    *ROOT.lock() = Some(root);
+    Ok(())
+}
+
+fn find_rsdp() -> Option<PhysAddr> {
+    let ebda_segment: u16 = unsafe { read_phys(PhysAddr::new(EBDA_POINTER)) };
+    let ebda = u64::from(ebda_segment) << 4;
+    let ebda_area = ebda..ebda + EBDA_SEARCH_SIZE;
+    let candidates = ebda_area.step_by(16).chain(BIOS_AREA.step_by(16));
+    candidates
+        .map(PhysAddr::new)
+        .find(|&address| is_valid_rsdp(address))
+}
+
+fn is_valid_rsdp(address: PhysAddr) -> bool {
+    let rsdp: Rsdp = unsafe { read_phys(address) };
+    &rsdp.signature == RSDP_SIGNATURE
+        && checksum(unsafe { phys_slice(address, size_of::<Rsdp>()) }) == 0
+}
+
+/// バイトの和。ACPIの構造は全体の和が0になるように作られている
+fn checksum(bytes: &[u8]) -> u8 {
+    bytes.iter().fold(0u8, |sum, &byte| sum.wrapping_add(byte))
+}
+
+/// 物理アドレスから値を読む
+///
+/// # Safety
+/// `address` に `T` として読める値がなければならない
+unsafe fn read_phys<T: Copy>(address: PhysAddr) -> T {
+    let ptr = memory::phys_to_virt(address).as_ptr::<T>();
+    unsafe { read_unaligned(ptr) }
+}
+
+/// 物理アドレスから `length` バイトを切り出す
+///
+/// # Safety
+/// その範囲がファームウェアのテーブルのように書き換えられないメモリでなければならない
+unsafe fn phys_slice(address: PhysAddr, length: usize) -> &'static [u8] {
+    let ptr = memory::phys_to_virt(address).as_ptr::<u8>();
+    unsafe { slice::from_raw_parts(ptr, length) }
+}
+
+/// バイト列の先頭から `T` を読む。足りない部分は0で埋める
+///
+/// # Safety
+/// `T` はすべてのビットパターンが有効な値 (整数だけからなる構造体) でなければならない
+unsafe fn read_struct<T: Copy>(bytes: &[u8]) -> T {
+    let mut value = MaybeUninit::<T>::zeroed();
+    let length = bytes.len().min(size_of::<T>());
+    unsafe {
+        ptr::copy_nonoverlapping(bytes.as_ptr(), value.as_mut_ptr().cast::<u8>(), length);
+        value.assume_init()
+    }
+}
+
+/// 物理アドレスにあるテーブル全体をヘッダの長さで切り出し、チェックサムを確かめる
+pub fn table_bytes(address: PhysAddr) -> Result<&'static [u8], AcpiError> {
+    let header: SdtHeader = unsafe { read_phys(address) };
+    let length = header.length as usize;
+    if length < size_of::<SdtHeader>() {
+        return Err(AcpiError::InvalidLength(header.signature));
+    }
+    let bytes = unsafe { phys_slice(address, length) };
+    if checksum(bytes) != 0 {
+        return Err(AcpiError::InvalidChecksum(header.signature));
+    }
+    Ok(bytes)
+}
+
+/// ルートのテーブルに載っているテーブルの物理アドレス
+fn table_addresses() -> Result<impl Iterator<Item = PhysAddr>, AcpiError> {
+    let root = (*ROOT.lock()).ok_or(AcpiError::NotInitialized)?;
+    let entry_size = if root.extended { 8 } else { 4 };
+    let entries = &root.table[size_of::<SdtHeader>()..];
+    Ok(entries.chunks_exact(entry_size).map(move |entry| {
+        let address = if root.extended {
+            u64_at(entry, 0)
+        } else {
+            u32_at(entry, 0).into()
+        };
+        PhysAddr::new(address)
+    }))
+}
+
+/// ルートのテーブルに載っているテーブルの一覧
+#[derive(Debug, Clone, Copy)]
+pub struct TableInfo {
+    pub address: PhysAddr,
+    pub header: SdtHeader,
+    /// チェックサムが合っている
+    pub valid: bool,
+}
+
+pub fn tables() -> Result<impl Iterator<Item = TableInfo>, AcpiError> {
+    Ok(table_addresses()?.map(|address| TableInfo {
+        address,
+        header: unsafe { read_phys(address) },
+        valid: table_bytes(address).is_ok(),
+    }))
+}
+
+/// シグネチャが `signature` のテーブルを探して、全体をバイト列として返す
+pub fn find_table(signature: &[u8; 4]) -> Result<&'static [u8], AcpiError> {
+    let address = table_addresses()?
+        .find(|&address| {
+            let header: SdtHeader = unsafe { read_phys(address) };
+            &header.signature == signature
+        })
+        .ok_or(AcpiError::TableNotFound(*signature))?;
+    table_bytes(address)
+}
+
+/// `signature` のテーブルを探して固定長の部分を `T` として読む
+///
+/// # Safety
+/// `T` は整数だけからなる `repr(C, packed)` の構造体でなければならない
+unsafe fn find_struct<T: Copy>(signature: &[u8; 4], minimum_length: usize) -> Result<T, AcpiError> {
+    let bytes = find_table(signature)?;
+    if bytes.len() < minimum_length {
+        return Err(AcpiError::InvalidLength(*signature));
+    }
+    Ok(unsafe { read_struct(bytes) })
+}
+
+pub fn hpet() -> Result<HpetTable, AcpiError> {
+    unsafe { find_struct(b"HPET", size_of::<HpetTable>()) }
+}
+
+/// FADTを読む。ACPI 1.0の短いテーブルでもよい
+pub fn fadt() -> Result<Fadt, AcpiError> {
+    // ACPI 1.0のFADTは `flags` まで
+    const FADT_V1_LENGTH: usize = 116;
+    unsafe { find_struct(b"FACP", FADT_V1_LENGTH) }
+}
+
+pub fn madt() -> Result<Madt, AcpiError> {
+    let bytes = find_table(b"APIC")?;
+    if bytes.len() < size_of::<MadtHeader>() {
+        return Err(AcpiError::InvalidLength(*b"APIC"));
+    }
+    let fixed: MadtHeader = unsafe { read_struct(bytes) };
+    Ok(Madt {
+        header: fixed.header,
+        local_apic_address: fixed.local_apic_address,
+        flags: fixed.flags,
+        entries: &bytes[size_of::<MadtHeader>()..],
+    })
+}
+
+pub fn mcfg() -> Result<Mcfg, AcpiError> {
+    let bytes = find_table(b"MCFG")?;
+    let entries_start = size_of::<SdtHeader>() + MCFG_RESERVED_SIZE;
+    if bytes.len() < entries_start {
+        return Err(AcpiError::InvalidLength(*b"MCFG"));
+    }
+    Ok(Mcfg {
+        header: unsafe { read_struct(bytes) },
+        entries: &bytes[entries_start..],
+    })
+}
+
+#[test_case]
+fn test_table_layout() {
+    // 仕様書に書かれている大きさ
+    assert_eq!(size_of::<Rsdp>(), 20);
+    assert_eq!(size_of::<Rsdp2>(), 36);
+    assert_eq!(size_of::<SdtHeader>(), 36);
+    assert_eq!(size_of::<GenericAddress>(), 12);
+    assert_eq!(size_of::<HpetTable>(), 56);
+    assert_eq!(size_of::<Fadt>(), 244);
+    assert_eq!(size_of::<MadtHeader>(), 44);
+}
+
+#[test_case]
+fn test_io_port() {
+    let io = |address| GenericAddress {
+        address_space: GenericAddress::SYSTEM_IO,
+        bit_width: 16,
+        bit_offset: 0,
+        access_size: 2,
+        address,
+    };
+    assert_eq!(io_port(io(0), 0, "PM1a_CNT_BLK"), Ok(None));
+    assert_eq!(io_port(io(0), 0x604, "PM1a_CNT_BLK"), Ok(Some(0x604)));
+    // 64ビットの方を優先する
+    assert_eq!(io_port(io(0xb004), 0x604, "PM1a_CNT_BLK"), Ok(Some(0xb004)));
+    assert_eq!(
+        io_port(io(0x1_0604), 0, "PM1a_CNT_BLK"),
+        Err(AcpiError::InvalidPort("PM1a_CNT_BLK"))
+    );
+    let memory = GenericAddress {
+        address_space: GenericAddress::SYSTEM_MEMORY,
+        ..io(0xfed0_0000)
+    };
+    assert_eq!(
+        io_port(memory, 0x604, "PM1a_CNT_BLK"),
+        Err(AcpiError::InvalidPort("PM1a_CNT_BLK"))
+    );
+}
+
+#[test_case]
+fn test_madt_entries() {
+    #[rustfmt::skip]
+    let bytes = [
+        // Local APIC: processor 0, APIC ID 1, enabled
+        0, 8, 0, 1, 1, 0, 0, 0,
+        // I/O APIC: ID 2, 0xfec00000, GSI 0から
+        1, 12, 2, 0, 0x00, 0x00, 0xc0, 0xfe, 0, 0, 0, 0,
+        // IRQ0がGSI 2につながっている
+        2, 10, 0, 0, 2, 0, 0, 0, 0, 0,
+        // 長さが足りない壊れた項目
+        0, 40, 0,
+    ];
+    let mut entries = MadtEntries { bytes: &bytes };
+    assert_eq!(
+        entries.next(),
+        Some(MadtEntry::LocalApic {
+            processor_id: 0,
+            apic_id: 1,
+            flags: MadtEntry::PROCESSOR_ENABLED,
+        })
+    );
+    assert_eq!(
+        entries.next(),
+        Some(MadtEntry::IoApic {
+            id: 2,
+            address: 0xfec0_0000,
+            gsi_base: 0,
+        })
+    );
+    assert_eq!(
+        entries.next(),
+        Some(MadtEntry::InterruptSourceOverride {
+            bus: 0,
+            source: 0,
+            gsi: 2,
+            flags: 0,
+        })
+    );
+    assert_eq!(entries.next(), None);
+}
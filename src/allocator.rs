@@ -1,7 +1,8 @@
 use linked_list_allocator::LockedHeap;
 
 pub const HEAP_START: usize = 0x_4444_4444_0000;
-pub const HEAP_SIZE: usize = 100 * 1024; // 100 KiB
+/// VGAのスクロールバックの履歴やブロックキャッシュを置けるよう 4 MiB 取る
+pub const HEAP_SIZE: usize = 4 * 1024 * 1024; // 4 MiB
 
 use x86_64::{
     structures::paging::{
@@ -41,3 +42,20 @@ pub fn init_heap(
 
 #[global_allocator]
 static ALLOCATOR: LockedHeap = LockedHeap::empty();
+
+/// ヒープの使用状況
+#[derive(Debug, Clone, Copy)]
+pub struct HeapStats {
+    pub size: usize,
+    pub used: usize,
+    pub free: usize,
+}
+
+pub fn heap_stats() -> HeapStats {
+    let heap = ALLOCATOR.lock();
+    HeapStats {
+        size: heap.size(),
+        used: heap.used(),
+        free: heap.free(),
+    }
+}
@@ -0,0 +1,477 @@
+//! ATA (IDE) ディスクのPIOドライバ
+//!
+//! プライマリとセカンダリのチャンネルのマスター/スレーブをIDENTIFYで見つけ、
+//! LBA28かLBA48でセクタを読み書きする。コマンドの完了はIRQ14/15で待ち、
+//! 割り込みが止まっているときはステータスレジスタをポーリングする
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+use crate::time::{Duration, Instant};
+use crate::{interrupts, log};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::instructions::port::Port;
+
+pub const SECTOR_SIZE: usize = 512;
+
+/// 互換モードのチャンネルの (コマンドブロック, 制御ブロック, IRQ)
+const CHANNELS: [(u16, u16, u8); 2] = [(0x1f0, 0x3f6, 14), (0x170, 0x376, 15)];
+const CASCADE_IRQ: u8 = 2;
+
+// コマンドブロックのレジスタ
+const REGISTER_DATA: u16 = 0;
+const REGISTER_SECTOR_COUNT: u16 = 2;
+const REGISTER_LBA_LOW: u16 = 3;
+const REGISTER_LBA_MID: u16 = 4;
+const REGISTER_LBA_HIGH: u16 = 5;
+const REGISTER_DRIVE: u16 = 6;
+const REGISTER_STATUS: u16 = 7;
+const REGISTER_COMMAND: u16 = 7;
+
+const STATUS_ERROR: u8 = 1 << 0;
+const STATUS_DATA_REQUEST: u8 = 1 << 3;
+const STATUS_DRIVE_FAULT: u8 = 1 << 5;
+const STATUS_BUSY: u8 = 1 << 7;
+
+/// デバイス制御レジスタ: 割り込みを止める
+const CONTROL_INTERRUPT_DISABLE: u8 = 1 << 1;
+/// デバイス制御レジスタ: ソフトウェアリセット
+const CONTROL_RESET: u8 = 1 << 2;
+
+/// ドライブ選択レジスタ: LBAでアドレスを指定する
+const DRIVE_LBA: u8 = 0xe0;
+const DRIVE_SLAVE: u8 = 1 << 4;
+
+const COMMAND_READ_SECTORS: u8 = 0x20;
+const COMMAND_READ_SECTORS_EXT: u8 = 0x24;
+const COMMAND_WRITE_SECTORS: u8 = 0x30;
+const COMMAND_WRITE_SECTORS_EXT: u8 = 0x34;
+const COMMAND_CACHE_FLUSH: u8 = 0xe7;
+const COMMAND_CACHE_FLUSH_EXT: u8 = 0xea;
+const COMMAND_IDENTIFY: u8 = 0xec;
+
+/// LBA28で指定できるセクタの数
+const LBA28_LIMIT: u64 = 1 << 28;
+/// 1回のコマンドで読み書きするセクタ数の上限 (LBA28のセクタ数レジスタは8ビット)
+const MAX_SECTORS_PER_COMMAND: usize = 256;
+
+/// コマンドの完了を待つ時間
+const COMMAND_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// IDE コントローラのプログラミングインターフェース: チャンネルがネイティブモード
+const PROG_IF_PRIMARY_NATIVE: u8 = 1 << 0;
+const PROG_IF_SECONDARY_NATIVE: u8 = 1 << 2;
+/// プログラミングインターフェース: モードを切り替えられる
+const PROG_IF_PRIMARY_SWITCHABLE: u8 = 1 << 1;
+const PROG_IF_SECONDARY_SWITCHABLE: u8 = 1 << 3;
+const REGISTER_PROG_IF: u16 = 0x09;
+
+/// チャンネルの割り込みが来たか
+static IRQ_RECEIVED: [AtomicBool; 2] = [AtomicBool::new(false), AtomicBool::new(false)];
+
+/// 同じチャンネルのマスターとスレーブはレジスタを共有するので、1台ずつ使う
+static CHANNEL_LOCKS: [Mutex<()>; 2] = [Mutex::new(()), Mutex::new(())];
+
+/// IDEコントローラのPCIドライバ。互換モードで動くチャンネルだけを扱う
+pub static DRIVER: PciDriver = PciDriver {
+    name: "ata",
+    matches: &[PciMatch::class(0x01, 0x01)],
+    probe,
+};
+
+fn probe(device: &PciDevice) -> Result<(), PciError> {
+    // ネイティブモードのチャンネルは切り替えられれば互換モードにする
+    let prog_if = device.read_u8(REGISTER_PROG_IF);
+    let mut compatible = prog_if;
+    if prog_if & PROG_IF_PRIMARY_NATIVE != 0 && prog_if & PROG_IF_PRIMARY_SWITCHABLE != 0 {
+        compatible &= !PROG_IF_PRIMARY_NATIVE;
+    }
+    if prog_if & PROG_IF_SECONDARY_NATIVE != 0 && prog_if & PROG_IF_SECONDARY_SWITCHABLE != 0 {
+        compatible &= !PROG_IF_SECONDARY_NATIVE;
+    }
+    if compatible != prog_if {
+        let class = device.read_u32(0x08);
+        device.write_u32(0x08, class & !0xff00 | u32::from(compatible) << 8);
+    }
+    if compatible & (PROG_IF_PRIMARY_NATIVE | PROG_IF_SECONDARY_NATIVE) != 0 {
+        return Err(PciError::Driver(
+            "ata: native-mode channels are not supported",
+        ));
+    }
+    device.enable();
+
+    let found = init();
+    if found == 0 {
+        return Err(PciError::Driver("ata: no drives"));
+    }
+    Ok(())
+}
+
+/// 互換モードの両方のチャンネルからドライブを探して `hda`..`hdd` として登録する
+///
+/// 見つかったドライブの数を返す
+pub fn init() -> usize {
+    let mut found = 0;
+    for (channel, &(_, _, irq)) in CHANNELS.iter().enumerate() {
+        let mut present = false;
+        for slave in [false, true] {
+            let Some(drive) = AtaDrive::identify(channel, slave) else {
+                continue;
+            };
+            let name = ["hda", "hdb", "hdc", "hdd"][channel * 2 + usize::from(slave)];
+            log!(
+                "ata: {} {} ({} MiB{})",
+                name,
+                drive.model(),
+                drive.sectors * SECTOR_SIZE as u64 / (1024 * 1024),
+                if drive.lba48 { ", lba48" } else { "" }
+            );
+            block::DEVICES.register(name, Arc::new(Mutex::new(drive)));
+            present = true;
+            found += 1;
+        }
+        if present {
+            interrupts::unmask_irq(CASCADE_IRQ);
+            interrupts::unmask_irq(irq);
+        }
+    }
+    found
+}
+
+/// IRQ14/15から呼ばれる
+pub(crate) fn handle_interrupt(channel: usize) {
+    // ステータスレジスタを読むとドライブの割り込みが下がる
+    let (command_base, _, _) = CHANNELS[channel];
+    let mut status: Port<u8> = Port::new(command_base + REGISTER_STATUS);
+    unsafe { status.read() };
+    IRQ_RECEIVED[channel].store(true, Ordering::Release);
+}
+
+pub struct AtaDrive {
+    channel: usize,
+    slave: bool,
+    lba48: bool,
+    sectors: u64,
+    /// IDENTIFYで得た型番 (ASCII、後ろは空白)
+    model: [u8; 40],
+}
+
+impl AtaDrive {
+    fn port<T: x86_64::instructions::port::PortRead + x86_64::instructions::port::PortWrite>(
+        &self,
+        register: u16,
+    ) -> Port<T> {
+        Port::new(CHANNELS[self.channel].0 + register)
+    }
+
+    fn read_register(&self, register: u16) -> u8 {
+        unsafe { self.port::<u8>(register).read() }
+    }
+
+    fn write_register(&self, register: u16, value: u8) {
+        unsafe { self.port::<u8>(register).write(value) }
+    }
+
+    /// 副作用のない代替ステータスレジスタ
+    fn alternate_status(&self) -> u8 {
+        let mut control: Port<u8> = Port::new(CHANNELS[self.channel].1);
+        unsafe { control.read() }
+    }
+
+    fn write_control(&self, value: u8) {
+        let mut control: Port<u8> = Port::new(CHANNELS[self.channel].1);
+        unsafe { control.write(value) }
+    }
+
+    /// ドライブを選んだ後などに必要な400nsの待ち (代替ステータスを4回読む)
+    fn delay_400ns(&self) {
+        for _ in 0..4 {
+            self.alternate_status();
+        }
+    }
+
+    /// ドライブにIDENTIFYを送る。ATAのディスクがなければ `None`
+    fn identify(channel: usize, slave: bool) -> Option<AtaDrive> {
+        let mut drive = AtaDrive {
+            channel,
+            slave,
+            lba48: false,
+            sectors: 0,
+            model: [b' '; 40],
+        };
+        let _lock = CHANNEL_LOCKS[channel].lock();
+
+        // 浮いているバスは0xffを返す
+        if drive.alternate_status() == 0xff {
+            return None;
+        }
+        drive.write_control(CONTROL_INTERRUPT_DISABLE);
+        drive.write_register(REGISTER_DRIVE, 0xa0 | if slave { DRIVE_SLAVE } else { 0 });
+        drive.delay_400ns();
+        for register in [
+            REGISTER_SECTOR_COUNT,
+            REGISTER_LBA_LOW,
+            REGISTER_LBA_MID,
+            REGISTER_LBA_HIGH,
+        ] {
+            drive.write_register(register, 0);
+        }
+        drive.write_register(REGISTER_COMMAND, COMMAND_IDENTIFY);
+        if drive.read_register(REGISTER_STATUS) == 0 {
+            return None;
+        }
+        drive.wait_not_busy().ok()?;
+        // ATAPIやSATAのドライブはここにシグネチャを置くので、ATAのディスクではない
+        if drive.read_register(REGISTER_LBA_MID) != 0 || drive.read_register(REGISTER_LBA_HIGH) != 0
+        {
+            return None;
+        }
+        drive.wait_data_request().ok()?;
+
+        let mut words = [0u16; 256];
+        let mut data = drive.port::<u16>(REGISTER_DATA);
+        for word in words.iter_mut() {
+            *word = unsafe { data.read() };
+        }
+        drive.write_control(0);
+
+        drive.lba48 = words[83] & (1 << 10) != 0;
+        drive.sectors = if drive.lba48 {
+            (0..4).fold(0, |sectors, i| {
+                sectors | u64::from(words[100 + i]) << (16 * i)
+            })
+        } else {
+            u64::from(words[60]) | u64::from(words[61]) << 16
+        };
+        // 文字列は1ワードごとに上位バイトが先
+        for (i, word) in words[27..47].iter().enumerate() {
+            drive.model[i * 2] = (word >> 8) as u8;
+            drive.model[i * 2 + 1] = *word as u8;
+        }
+        (drive.sectors > 0).then_some(drive)
+    }
+
+    /// IDENTIFYで得た型番
+    pub fn model(&self) -> &str {
+        core::str::from_utf8(&self.model).unwrap_or("").trim_end()
+    }
+
+    fn wait_not_busy(&self) -> Result<u8, BlockError> {
+        let start = Instant::now();
+        loop {
+            let status = self.alternate_status();
+            if status & STATUS_BUSY == 0 {
+                return Ok(status);
+            }
+            if start.elapsed() > COMMAND_TIMEOUT {
+                return Err(BlockError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// データの転送の準備ができるまで待つ
+    fn wait_data_request(&self) -> Result<(), BlockError> {
+        let status = self.wait_not_busy()?;
+        check_status(status)?;
+        if status & STATUS_DATA_REQUEST == 0 {
+            return Err(BlockError::Device("ata: drive is not ready for data"));
+        }
+        Ok(())
+    }
+
+    /// コマンドの区切りの割り込みを待つ。割り込みが止まっていればポーリングする
+    fn wait_interrupt(&self) -> Result<u8, BlockError> {
+        if cpu_interrupts::are_enabled() {
+            let start = Instant::now();
+            loop {
+                cpu_interrupts::disable();
+                if IRQ_RECEIVED[self.channel].swap(false, Ordering::Acquire) {
+                    cpu_interrupts::enable();
+                    break;
+                }
+                if start.elapsed() > COMMAND_TIMEOUT {
+                    cpu_interrupts::enable();
+                    return Err(BlockError::Timeout);
+                }
+                // タイマー割り込みで少なくとも10msごとに起きる
+                cpu_interrupts::enable_and_hlt();
+            }
+        }
+        let status = self.wait_not_busy()?;
+        // 割り込みを受けてもドライブの割り込みを下げるため、本物のステータスも読む
+        self.read_register(REGISTER_STATUS);
+        check_status(status)?;
+        Ok(status)
+    }
+
+    /// LBAとセクタ数を設定してコマンドを送る
+    fn issue(&self, command: u8, lba: u64, count: usize) {
+        let slave = if self.slave { DRIVE_SLAVE } else { 0 };
+        IRQ_RECEIVED[self.channel].store(false, Ordering::Release);
+        if self.lba48 {
+            self.write_register(REGISTER_DRIVE, DRIVE_LBA | slave);
+            self.delay_400ns();
+            // 上位のバイトを先に書く
+            self.write_register(REGISTER_SECTOR_COUNT, (count >> 8) as u8);
+            self.write_register(REGISTER_LBA_LOW, (lba >> 24) as u8);
+            self.write_register(REGISTER_LBA_MID, (lba >> 32) as u8);
+            self.write_register(REGISTER_LBA_HIGH, (lba >> 40) as u8);
+        } else {
+            self.write_register(
+                REGISTER_DRIVE,
+                DRIVE_LBA | slave | ((lba >> 24) & 0x0f) as u8,
+            );
+            self.delay_400ns();
+        }
+        self.write_register(REGISTER_SECTOR_COUNT, count as u8);
+        self.write_register(REGISTER_LBA_LOW, lba as u8);
+        self.write_register(REGISTER_LBA_MID, (lba >> 8) as u8);
+        self.write_register(REGISTER_LBA_HIGH, (lba >> 16) as u8);
+        self.write_register(REGISTER_COMMAND, command);
+    }
+
+    /// LBA48が必要なアクセスか
+    fn needs_lba48(&self, lba: u64, count: usize) -> Result<bool, BlockError> {
+        let needs = lba + count as u64 > LBA28_LIMIT;
+        if needs && !self.lba48 {
+            return Err(BlockError::OutOfRange);
+        }
+        Ok(needs || self.lba48)
+    }
+
+    fn read_chunk(&self, lba: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = buffer.len() / SECTOR_SIZE;
+        let command = if self.needs_lba48(lba, count)? {
+            COMMAND_READ_SECTORS_EXT
+        } else {
+            COMMAND_READ_SECTORS
+        };
+        self.wait_not_busy()?;
+        self.issue(command, lba, count);
+
+        let mut data = self.port::<u16>(REGISTER_DATA);
+        for sector in buffer.chunks_exact_mut(SECTOR_SIZE) {
+            // セクタ1つ分のデータがそろうたびに割り込みが来る
+            let status = self.wait_interrupt()?;
+            if status & STATUS_DATA_REQUEST == 0 {
+                return Err(BlockError::Device("ata: drive did not send data"));
+            }
+            for word in sector.chunks_exact_mut(2) {
+                word.copy_from_slice(&unsafe { data.read() }.to_le_bytes());
+            }
+        }
+        Ok(())
+    }
+
+    fn write_chunk(&self, lba: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        let count = buffer.len() / SECTOR_SIZE;
+        let command = if self.needs_lba48(lba, count)? {
+            COMMAND_WRITE_SECTORS_EXT
+        } else {
+            COMMAND_WRITE_SECTORS
+        };
+        self.wait_not_busy()?;
+        self.issue(command, lba, count);
+
+        // 最初のセクタは割り込みを待たずに送り、その後はセクタごとに割り込みが来る
+        self.wait_data_request()?;
+        let mut data = self.port::<u16>(REGISTER_DATA);
+        for sector in buffer.chunks_exact(SECTOR_SIZE) {
+            let status = self.alternate_status();
+            if status & STATUS_DATA_REQUEST == 0 {
+                return Err(BlockError::Device("ata: drive did not accept data"));
+            }
+            for word in sector.chunks_exact(2) {
+                unsafe { data.write(u16::from_le_bytes([word[0], word[1]])) };
+            }
+            self.wait_interrupt()?;
+        }
+        Ok(())
+    }
+
+    /// ドライブをリセットしてから再び使えるようにする。エラーの後に呼ぶ
+    fn reset(&self) {
+        self.write_control(CONTROL_RESET);
+        self.delay_400ns();
+        self.write_control(0);
+        let _ = self.wait_not_busy();
+    }
+}
+
+fn check_status(status: u8) -> Result<(), BlockError> {
+    if status & STATUS_DRIVE_FAULT != 0 {
+        Err(BlockError::Device("ata: drive fault"))
+    } else if status & STATUS_ERROR != 0 {
+        Err(BlockError::Device("ata: command aborted"))
+    } else {
+        Ok(())
+    }
+}
+
+impl BlockDevice for AtaDrive {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.sectors
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_range(self, start, buffer.len())?;
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        for (i, chunk) in buffer
+            .chunks_mut(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = start + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            self.read_chunk(lba, chunk).inspect_err(|_| self.reset())?;
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_range(self, start, buffer.len())?;
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        for (i, chunk) in buffer
+            .chunks(MAX_SECTORS_PER_COMMAND * SECTOR_SIZE)
+            .enumerate()
+        {
+            let lba = start + (i * MAX_SECTORS_PER_COMMAND) as u64;
+            self.write_chunk(lba, chunk).inspect_err(|_| self.reset())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        let _lock = CHANNEL_LOCKS[self.channel].lock();
+        let command = if self.lba48 {
+            COMMAND_CACHE_FLUSH_EXT
+        } else {
+            COMMAND_CACHE_FLUSH
+        };
+        self.wait_not_busy()?;
+        self.issue(command, 0, 0);
+        self.wait_interrupt().map(|_| ())
+    }
+
+    fn description(&self) -> &str {
+        self.model()
+    }
+}
+
+#[test_case]
+fn test_check_status() {
+    assert_eq!(check_status(0x50), Ok(()));
+    assert_eq!(
+        check_status(0x50 | STATUS_ERROR),
+        Err(BlockError::Device("ata: command aborted"))
+    );
+    assert_eq!(
+        check_status(STATUS_DRIVE_FAULT),
+        Err(BlockError::Device("ata: drive fault"))
+    );
+}
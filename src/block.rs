@@ -0,0 +1,81 @@
+//! ブロックデバイス
+//!
+//! ディスクのドライバはすべて `BlockDevice` を実装し、名前を付けて `DEVICES` に登録する。
+//! ファイルシステムは名前でデバイスを引いて使う
+
+use crate::registry::Registry;
+use alloc::sync::Arc;
+use core::fmt;
+use spin::Mutex;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum BlockError {
+    /// デバイスの終わりを越えている
+    OutOfRange,
+    /// バッファの長さがブロックの大きさの倍数ではない
+    InvalidBuffer,
+    /// 書き込めないデバイス
+    ReadOnly,
+    /// デバイスが応答しなくなった
+    Timeout,
+    /// デバイスがエラーを返した
+    Device(&'static str),
+}
+
+impl fmt::Display for BlockError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BlockError::OutOfRange => write!(f, "block: access beyond the end of the device"),
+            BlockError::InvalidBuffer => {
+                write!(f, "block: buffer is not a multiple of the block size")
+            }
+            BlockError::ReadOnly => write!(f, "block: device is read-only"),
+            BlockError::Timeout => write!(f, "block: device timed out"),
+            BlockError::Device(reason) => write!(f, "block: {}", reason),
+        }
+    }
+}
+
+/// ブロック単位で読み書きするデバイス
+pub trait BlockDevice: Send {
+    /// 1ブロックのバイト数
+    fn block_size(&self) -> usize;
+
+    /// ブロックの総数
+    fn block_count(&self) -> u64;
+
+    /// `start` 番目のブロックから `buffer` の長さ分を読む
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError>;
+
+    /// `start` 番目のブロックから `buffer` の長さ分を書く
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError>;
+
+    /// デバイスのキャッシュに残っている書き込みを媒体に書き出す
+    fn flush(&mut self) -> Result<(), BlockError> {
+        Ok(())
+    }
+
+    /// 一覧に表示する説明 (型番など)
+    fn description(&self) -> &str {
+        ""
+    }
+}
+
+/// `start` から `length` バイトを読み書きしてよいか確かめ、ブロック数を返す
+pub fn check_range(device: &dyn BlockDevice, start: u64, length: usize) -> Result<u64, BlockError> {
+    let block_size = device.block_size();
+    if !length.is_multiple_of(block_size) {
+        return Err(BlockError::InvalidBuffer);
+    }
+    let blocks = (length / block_size) as u64;
+    match start.checked_add(blocks) {
+        Some(end) if end <= device.block_count() => Ok(blocks),
+        _ => Err(BlockError::OutOfRange),
+    }
+}
+
+/// カーネルのあちこちから共有されるブロックデバイス
+pub type SharedBlockDevice = Arc<Mutex<dyn BlockDevice>>;
+
+/// 名前で登録されたブロックデバイス
+pub static DEVICES: Registry<SharedBlockDevice> = Registry::new();
@@ -0,0 +1,388 @@
+//! ブロックデバイスのバッファキャッシュ
+//!
+//! ファイルシステムはデバイスを直接ではなく `open` で得たキャッシュ越しに使う。
+//! 書き込みはキャッシュにためて `sync` か一定時間後にまとめて書き出し、
+//! 順番に読まれているときは先のブロックまで読んでおく
+//!
+//! 時間での書き出しはタイマー割り込みからは行わない (割り込みハンドラでディスクを待てない)。
+//! 次に同じキャッシュへ書いたときと、シェルのアイドルループが `sync_expired` を呼んだときだけ
+//! 行われるので、シェルが動いていない間に書いたまま放っておくと `sync` まで残る
+
+use crate::allocator;
+use crate::block::{self, BlockDevice, BlockError, SharedBlockDevice};
+use crate::time::{Duration, Instant};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// `open` で開いたキャッシュ全部で使ってよいヒープの量。開いているキャッシュで等分する
+pub const TOTAL_BUDGET: usize = allocator::HEAP_SIZE / 4;
+
+// tmpfsの上限と合わせても、カーネルのほかの部分が使う分が残るようにする
+const _: () = assert!(TOTAL_BUDGET + crate::tmpfs::DEFAULT_LIMIT < allocator::HEAP_SIZE);
+
+/// 汚れたブロックを書き出さずにおく時間
+pub const SYNC_INTERVAL: Duration = Duration::from_secs(5);
+
+/// 先読みするブロック数の最初の値と上限。順番に読まれ続けると倍々に増やす
+const MIN_READ_AHEAD: u64 = 4;
+const MAX_READ_AHEAD: u64 = 64;
+
+struct Entry {
+    data: Box<[u8]>,
+    dirty: bool,
+    /// 最後に使った順番。`lru` のキー
+    stamp: u64,
+}
+
+/// キャッシュの統計
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    /// 先読みで読んだブロックの数
+    pub read_ahead: u64,
+    /// 書き出したブロックの数
+    pub written_back: u64,
+    pub evicted: u64,
+    /// キャッシュしているブロックの数と、そのうち汚れているもの
+    pub cached: usize,
+    pub dirty: usize,
+}
+
+/// 1つのブロックデバイスの前に置くキャッシュ
+///
+/// 自身も `BlockDevice` なので、デバイスの代わりにそのまま渡せる
+pub struct BlockCache {
+    device: SharedBlockDevice,
+    block_size: usize,
+    block_count: u64,
+    entries: BTreeMap<u64, Entry>,
+    /// 使った順番からブロック番号を引く。先頭が一番古い
+    lru: BTreeMap<u64, u64>,
+    next_stamp: u64,
+    budget: usize,
+    /// 最初に汚れたブロックができた時刻
+    dirty_since: Option<Instant>,
+    /// 直前に読んだ範囲の終わりと、今の先読みの量
+    last_read_end: u64,
+    read_ahead: u64,
+    stats: CacheStats,
+}
+
+impl BlockCache {
+    /// `budget` バイトまでブロックをためるキャッシュを作る
+    pub fn new(device: SharedBlockDevice, budget: usize) -> BlockCache {
+        let (block_size, block_count) = {
+            let device = device.lock();
+            (device.block_size(), device.block_count())
+        };
+        BlockCache {
+            device,
+            block_size,
+            block_count,
+            entries: BTreeMap::new(),
+            lru: BTreeMap::new(),
+            next_stamp: 0,
+            budget,
+            dirty_since: None,
+            last_read_end: u64::MAX,
+            read_ahead: 0,
+            stats: CacheStats::default(),
+        }
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        CacheStats {
+            cached: self.entries.len(),
+            dirty: self.entries.values().filter(|entry| entry.dirty).count(),
+            ..self.stats
+        }
+    }
+
+    /// 使ってよいヒープの量を変え、超えていれば追い出す
+    pub fn set_budget(&mut self, budget: usize) -> Result<(), BlockError> {
+        self.budget = budget;
+        self.evict(0)
+    }
+
+    /// 汚れたブロックをすべて書き出し、デバイスのキャッシュも書き出させる
+    pub fn sync(&mut self) -> Result<(), BlockError> {
+        let dirty: Vec<u64> = self
+            .entries
+            .iter()
+            .filter(|(_, entry)| entry.dirty)
+            .map(|(&number, _)| number)
+            .collect();
+        // 連続したブロックは1回で書く
+        let mut start = 0;
+        while start < dirty.len() {
+            let mut end = start + 1;
+            while end < dirty.len() && dirty[end] == dirty[end - 1] + 1 {
+                end += 1;
+            }
+            self.write_back(&dirty[start..end])?;
+            start = end;
+        }
+        self.dirty_since = None;
+        self.device.lock().flush()
+    }
+
+    /// 汚れてから `SYNC_INTERVAL` が過ぎていれば書き出す
+    pub fn sync_if_expired(&mut self) -> Result<(), BlockError> {
+        match self.dirty_since {
+            Some(since) if since.elapsed() >= SYNC_INTERVAL => self.sync(),
+            _ => Ok(()),
+        }
+    }
+
+    /// 汚れているかどうかに関係なく、キャッシュを空にする。汚れたブロックは書き出す
+    pub fn invalidate(&mut self) -> Result<(), BlockError> {
+        self.sync()?;
+        self.entries.clear();
+        self.lru.clear();
+        Ok(())
+    }
+
+    /// 連続した `blocks` を1回でデバイスに書き、きれいにする
+    fn write_back(&mut self, blocks: &[u64]) -> Result<(), BlockError> {
+        let mut buffer = Vec::with_capacity(blocks.len() * self.block_size);
+        for number in blocks {
+            buffer.extend_from_slice(&self.entries[number].data);
+        }
+        self.device.lock().write_blocks(blocks[0], &buffer)?;
+        for number in blocks {
+            if let Some(entry) = self.entries.get_mut(number) {
+                entry.dirty = false;
+            }
+        }
+        self.stats.written_back += blocks.len() as u64;
+        Ok(())
+    }
+
+    fn touch(&mut self, number: u64) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(entry) = self.entries.get_mut(&number) {
+            self.lru.remove(&entry.stamp);
+            entry.stamp = stamp;
+            self.lru.insert(stamp, number);
+        }
+    }
+
+    fn insert(&mut self, number: u64, data: Box<[u8]>, dirty: bool) {
+        let stamp = self.next_stamp;
+        self.next_stamp += 1;
+        if let Some(old) = self.entries.insert(number, Entry { data, dirty, stamp }) {
+            self.lru.remove(&old.stamp);
+        }
+        self.lru.insert(stamp, number);
+    }
+
+    /// キャッシュに置けるブロックの数
+    fn capacity(&self) -> usize {
+        (self.budget / self.block_size).max(1)
+    }
+
+    /// `incoming` ブロック分の空きができるまで古いブロックから追い出す
+    fn evict(&mut self, incoming: usize) -> Result<(), BlockError> {
+        let capacity = self.capacity();
+        while self.entries.len() + incoming > capacity {
+            let Some((&stamp, &number)) = self.lru.iter().next() else {
+                break;
+            };
+            if self.entries[&number].dirty {
+                self.write_back(&[number])?;
+            }
+            self.lru.remove(&stamp);
+            self.entries.remove(&number);
+            self.stats.evicted += 1;
+        }
+        Ok(())
+    }
+
+    /// デバイスから読んだ `start` からのブロック `data` をキャッシュに入れる
+    ///
+    /// キャッシュより大きければ後ろの `capacity` ブロックだけを入れ、残りはキャッシュしない。
+    /// 順番に読まれていれば、次に使われるのは後ろの方
+    fn insert_clean(&mut self, start: u64, data: &[u8]) -> Result<(), BlockError> {
+        let count = data.len() / self.block_size;
+        let skip = count.saturating_sub(self.capacity());
+        self.evict(count - skip)?;
+        for (i, block) in data.chunks_exact(self.block_size).enumerate().skip(skip) {
+            self.insert(start + i as u64, block.into(), false);
+        }
+        Ok(())
+    }
+
+    /// キャッシュにない `start` からの `count` ブロックをデバイスから読んで入れる
+    fn fill(&mut self, start: u64, count: u64) -> Result<(), BlockError> {
+        let mut buffer = vec![0u8; count as usize * self.block_size];
+        self.device.lock().read_blocks(start, &mut buffer)?;
+        self.insert_clean(start, &buffer)
+    }
+
+    /// 順番に読まれていれば、`end` から先のまだないブロックを読んでおく
+    fn prefetch(&mut self, start: u64, end: u64) -> Result<(), BlockError> {
+        self.read_ahead = if start == self.last_read_end {
+            (self.read_ahead * 2).clamp(MIN_READ_AHEAD, MAX_READ_AHEAD)
+        } else {
+            0
+        };
+        self.last_read_end = end;
+        // キャッシュの半分より多くは先読みしない
+        let limit = (self.budget / self.block_size / 2) as u64;
+        let window_end = (end + self.read_ahead.min(limit)).min(self.block_count);
+        let mut first = end;
+        while first < window_end && self.entries.contains_key(&first) {
+            first += 1;
+        }
+        let mut last = first;
+        while last < window_end && !self.entries.contains_key(&last) {
+            last += 1;
+        }
+        if first < last {
+            self.fill(first, last - first)?;
+            self.stats.read_ahead += last - first;
+        }
+        Ok(())
+    }
+}
+
+impl BlockDevice for BlockCache {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        let count = block::check_range(self, start, buffer.len())?;
+        let end = start + count;
+        let block_size = self.block_size;
+        let offset = |number: u64| (number - start) as usize * block_size;
+
+        let mut number = start;
+        while number < end {
+            if let Some(entry) = self.entries.get(&number) {
+                buffer[offset(number)..offset(number + 1)].copy_from_slice(&entry.data);
+                self.touch(number);
+                self.stats.hits += 1;
+                number += 1;
+                continue;
+            }
+            // ないブロックは連続した範囲ごとにまとめて、呼び出し側のバッファに直接読む
+            let mut run_end = number + 1;
+            while run_end < end && !self.entries.contains_key(&run_end) {
+                run_end += 1;
+            }
+            self.stats.misses += run_end - number;
+            let run = &mut buffer[offset(number)..offset(run_end)];
+            self.device.lock().read_blocks(number, run)?;
+            self.insert_clean(number, run)?;
+            number = run_end;
+        }
+        self.prefetch(start, end)
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_range(self, start, buffer.len())?;
+        for (i, chunk) in buffer.chunks_exact(self.block_size).enumerate() {
+            let number = start + i as u64;
+            match self.entries.get_mut(&number) {
+                Some(entry) => {
+                    entry.data.copy_from_slice(chunk);
+                    entry.dirty = true;
+                    self.touch(number);
+                }
+                None => {
+                    self.evict(1)?;
+                    self.insert(number, chunk.into(), true);
+                }
+            }
+        }
+        self.dirty_since.get_or_insert_with(Instant::now);
+        // アイドルループが回っていなくても、書き続けていれば古い書き込みは出ていく
+        self.sync_if_expired()
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        self.sync()
+    }
+
+    fn description(&self) -> &str {
+        "cached"
+    }
+}
+
+/// デバイスの名前ごとのキャッシュ
+static CACHES: Mutex<Vec<(String, Arc<Mutex<BlockCache>>)>> = Mutex::new(Vec::new());
+
+/// 登録されたデバイス `name` のキャッシュを返す。同じデバイスには同じキャッシュを返す
+///
+/// 新しく開いたときは、すでに開いているキャッシュの分を減らして `TOTAL_BUDGET` を分け直す
+pub fn open(name: &str) -> Option<SharedBlockDevice> {
+    let mut caches = CACHES.lock();
+    if let Some((_, cache)) = caches.iter().find(|(existing, _)| existing == name) {
+        return Some(cache.clone());
+    }
+    let device = block::DEVICES.get(name)?;
+    let share = TOTAL_BUDGET / (caches.len() + 1);
+    for (name, cache) in caches.iter() {
+        // 追い出すブロックを書き出せなくても、汚れたブロックは残るので失うものはない
+        if let Err(err) = cache.lock().set_budget(share) {
+            crate::log!("{}: {}", name, err);
+        }
+    }
+    let cache = Arc::new(Mutex::new(BlockCache::new(device, share)));
+    caches.push((name.to_string(), cache.clone()));
+    Some(cache)
+}
+
+/// 開いているキャッシュの名前と統計
+pub fn stats() -> Vec<(String, CacheStats)> {
+    CACHES
+        .lock()
+        .iter()
+        .map(|(name, cache)| (name.clone(), cache.lock().stats()))
+        .collect()
+}
+
+/// すべてのキャッシュを書き出す。失敗しても残りは書き出し、最初のエラーを返す
+pub fn sync_all() -> Result<(), BlockError> {
+    let caches: Vec<_> = CACHES
+        .lock()
+        .iter()
+        .map(|(_, cache)| cache.clone())
+        .collect();
+    let mut result = Ok(());
+    for cache in caches {
+        let synced = cache.lock().sync();
+        result = result.and(synced);
+    }
+    result
+}
+
+/// 汚れてから時間が経ったキャッシュを書き出す
+///
+/// 今はシェルのアイドルループだけが呼ぶ。シェル以外で待つときは自分で呼ぶか `sync_all` を使う
+pub fn sync_expired() {
+    // 割り込みハンドラではなくアイドルループから呼ぶので、使用中ならとばして次の機会に回す
+    let Some(caches) = CACHES.try_lock() else {
+        return;
+    };
+    let caches: Vec<_> = caches.iter().map(|(_, cache)| cache.clone()).collect();
+    for cache in caches {
+        if let Some(mut cache) = cache.try_lock()
+            && let Err(err) = cache.sync_if_expired()
+        {
+            crate::log!("{}", err);
+        }
+    }
+}
@@ -0,0 +1,456 @@
+//! キャラクタデバイス
+//!
+//! バイト列を順に読み書きするデバイスは `CharDevice` を実装し、名前を付けて `DEVICES` に登録する。
+//! 登録したデバイスは devfs が `/dev` の下のファイルとして見せる
+
+use crate::keyboard::{self, DecodedKey, KeyEvent, KeyState, Modifiers};
+use crate::registry::Registry;
+use crate::vfs::FsError;
+use crate::{serial, time, vga_buffer};
+use alloc::collections::VecDeque;
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicBool, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::{self, without_interrupts};
+use x86_64::instructions::random::RdRand;
+
+/// 読むものがないとき、待たずに `FsError::WouldBlock` を返すようにする (引数が0以外のとき)
+pub const SET_NONBLOCKING: u32 = 1;
+/// 待たずに読めるものがあれば1、なければ0を返す
+pub const INPUT_READY: u32 = 2;
+/// コンソールの画面を消す
+pub const CONSOLE_CLEAR: u32 = 0x100;
+/// 表示する仮想コンソールを引数の番号に切り替える
+pub const CONSOLE_SWITCH: u32 = 0x101;
+/// シリアルポートのボーレートを引数の値にする
+pub const TTY_SET_BAUD_RATE: u32 = 0x200;
+/// キーボードのLEDを設定する。ビット0がScroll Lock、1がNum Lock、2がCaps Lock
+pub const KBD_SET_LEDS: u32 = 0x300;
+/// 修飾キーの状態を `/dev/kbd` の記録と同じビットで返す
+pub const KBD_GET_MODIFIERS: u32 = 0x301;
+
+/// `/dev/kbd` から読める記録1つの大きさ
+///
+/// キーコード、状態 (0が解放、1が押下、2がその他)、修飾キーのビット (リトルエンディアンの16ビット)
+pub const KEY_RECORD_SIZE: usize = 4;
+
+/// バイト単位で読み書きするデバイス
+pub trait CharDevice: Send + Sync {
+    /// 読めるだけ読み、読んだバイト数を返す。読むものがなければ届くまで待つ
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// 書いたバイト数を返す
+    fn write(&self, data: &[u8]) -> Result<usize, FsError>;
+
+    /// デバイス固有の操作。`request` はこのモジュールの定数
+    fn ioctl(&self, _request: u32, _arg: u64) -> Result<u64, FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// カーネルのあちこちから共有されるキャラクタデバイス
+pub type SharedCharDevice = Arc<dyn CharDevice>;
+
+/// 名前で登録されたキャラクタデバイス
+pub static DEVICES: Registry<SharedCharDevice> = Registry::new();
+
+/// コンソール、シリアルポート、キーボードと `null` / `zero` / `random` を登録する
+pub fn init() {
+    DEVICES.register("console", Arc::new(Console::default()));
+    for index in 0..serial::PORTS.len() {
+        DEVICES.register(&format!("ttyS{}", index), Arc::new(Serial::new(index)));
+    }
+    DEVICES.register("kbd", Arc::new(Keyboard::default()));
+    DEVICES.register("null", Arc::new(Null));
+    DEVICES.register("zero", Arc::new(Zero));
+    DEVICES.register("random", Arc::new(Random::default()));
+}
+
+/// `poll` が何か返すまで待つ。`nonblocking` なら待たずに `FsError::WouldBlock` を返す
+fn wait_for<T>(
+    nonblocking: &AtomicBool,
+    mut poll: impl FnMut() -> Option<T>,
+) -> Result<T, FsError> {
+    // 確かめてから止まるまでの間に来た割り込みを取りこぼさないよう、割り込みを止めて確かめる
+    let sleep = interrupts::are_enabled();
+    loop {
+        if sleep {
+            interrupts::disable();
+        }
+        let value = poll();
+        if value.is_some() || nonblocking.load(Ordering::Relaxed) {
+            if sleep {
+                interrupts::enable();
+            }
+            return value.ok_or(FsError::WouldBlock);
+        }
+        if sleep {
+            // 入力の割り込みか、次のタイマー割り込みまで止まる
+            interrupts::enable_and_hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// 読み込みを待たない設定と、入力があるかの問い合わせ。どのデバイスにも共通する
+fn common_ioctl(
+    nonblocking: &AtomicBool,
+    ready: impl FnOnce() -> bool,
+    request: u32,
+    arg: u64,
+) -> Result<u64, FsError> {
+    match request {
+        SET_NONBLOCKING => {
+            nonblocking.store(arg != 0, Ordering::Relaxed);
+            Ok(0)
+        }
+        INPUT_READY => Ok(u64::from(ready())),
+        _ => Err(FsError::NotSupported),
+    }
+}
+
+/// 表示中の仮想コンソールとキーボード
+///
+/// 読み込みは行単位で、打った文字を画面に返す。Backspaceで1文字消し、
+/// 行の先頭でのCtrl+Dはファイルの終わりになる
+#[derive(Default)]
+pub struct Console {
+    nonblocking: AtomicBool,
+    input: Mutex<LineInput>,
+}
+
+#[derive(Default)]
+struct LineInput {
+    /// 編集中の行
+    line: String,
+    /// Enterで確定し、まだ読まれていないバイト
+    ready: VecDeque<u8>,
+    /// Ctrl+Dが押され、次の読み込みで0を返す
+    end_of_file: bool,
+}
+
+impl LineInput {
+    /// 届いているキー入力を行に反映する
+    fn poll(&mut self) {
+        while let Some(event) = keyboard::read_event() {
+            if event.state != KeyState::Down {
+                continue;
+            }
+            let Some(DecodedKey::Unicode(c)) = event.key else {
+                continue;
+            };
+            if event.modifiers.ctrl() && c.eq_ignore_ascii_case(&'d') {
+                if self.line.is_empty() {
+                    self.end_of_file = true;
+                } else {
+                    self.ready.extend(self.line.bytes());
+                    self.line.clear();
+                }
+                continue;
+            }
+            match c {
+                '\n' => {
+                    self.ready.extend(self.line.bytes());
+                    self.ready.push_back(b'\n');
+                    self.line.clear();
+                    write_console(b"\n");
+                }
+                '\x08' => {
+                    if self.line.pop().is_some() {
+                        write_console(b"\x08");
+                    }
+                }
+                c if !c.is_control() => {
+                    self.line.push(c);
+                    write_console(c.encode_utf8(&mut [0; 4]).as_bytes());
+                }
+                _ => {}
+            }
+        }
+    }
+}
+
+/// 表示中の仮想コンソールに書く
+fn write_console(bytes: &[u8]) {
+    without_interrupts(|| {
+        vga_buffer::console(vga_buffer::active_console())
+            .lock()
+            .write_bytes(bytes)
+    });
+}
+
+impl CharDevice for Console {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        wait_for(&self.nonblocking, || {
+            let mut input = self.input.lock();
+            input.poll();
+            if input.end_of_file && input.ready.is_empty() {
+                input.end_of_file = false;
+                return Some(0);
+            }
+            if input.ready.is_empty() {
+                return None;
+            }
+            let count = buffer.len().min(input.ready.len());
+            for (slot, byte) in buffer.iter_mut().zip(input.ready.drain(..count)) {
+                *slot = byte;
+            }
+            Some(count)
+        })
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        write_console(data);
+        Ok(data.len())
+    }
+
+    fn ioctl(&self, request: u32, arg: u64) -> Result<u64, FsError> {
+        match request {
+            CONSOLE_CLEAR => {
+                without_interrupts(|| {
+                    vga_buffer::console(vga_buffer::active_console())
+                        .lock()
+                        .clear_screen()
+                });
+                Ok(0)
+            }
+            CONSOLE_SWITCH => {
+                let index = usize::try_from(arg).map_err(|_| FsError::InvalidArgument)?;
+                if index >= vga_buffer::NUM_CONSOLES {
+                    return Err(FsError::InvalidArgument);
+                }
+                vga_buffer::switch_console(index);
+                Ok(0)
+            }
+            _ => common_ioctl(
+                &self.nonblocking,
+                || {
+                    let mut input = self.input.lock();
+                    input.poll();
+                    input.end_of_file || !input.ready.is_empty()
+                },
+                request,
+                arg,
+            ),
+        }
+    }
+}
+
+/// シリアルポート (`ttyS0` がCOM1)
+///
+/// 書くときは改行の前に復帰を入れる。つながっていないポートを読むと0バイトで終わる
+pub struct Serial {
+    index: usize,
+    nonblocking: AtomicBool,
+}
+
+impl Serial {
+    pub fn new(index: usize) -> Serial {
+        Serial {
+            index,
+            nonblocking: AtomicBool::new(false),
+        }
+    }
+}
+
+impl CharDevice for Serial {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if buffer.is_empty() || !serial::present(self.index) {
+            return Ok(0);
+        }
+        buffer[0] = wait_for(&self.nonblocking, || serial::receive(self.index))?;
+        let mut count = 1;
+        while count < buffer.len() {
+            let Some(byte) = serial::receive(self.index) else {
+                break;
+            };
+            buffer[count] = byte;
+            count += 1;
+        }
+        Ok(count)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        without_interrupts(|| {
+            let mut port = serial::port(self.index).lock();
+            for &byte in data {
+                if byte == b'\n' {
+                    port.send_raw(b'\r');
+                }
+                port.send_raw(byte);
+            }
+        });
+        Ok(data.len())
+    }
+
+    fn ioctl(&self, request: u32, arg: u64) -> Result<u64, FsError> {
+        match request {
+            TTY_SET_BAUD_RATE => {
+                let baud = u32::try_from(arg).map_err(|_| FsError::InvalidArgument)?;
+                if !serial::set_baud_rate(self.index, baud) {
+                    return Err(FsError::InvalidArgument);
+                }
+                Ok(0)
+            }
+            _ => common_ioctl(
+                &self.nonblocking,
+                || serial::receive_pending(self.index),
+                request,
+                arg,
+            ),
+        }
+    }
+}
+
+/// 生のキーイベント。`KEY_RECORD_SIZE` バイトの記録の並びとして読む
+#[derive(Default)]
+pub struct Keyboard {
+    nonblocking: AtomicBool,
+}
+
+/// 修飾キーとロックの状態を、左右のShift、Ctrl、Alt、Caps、Num、Scroll Lockの順のビットにする
+fn modifier_bits(modifiers: &Modifiers) -> u16 {
+    [
+        modifiers.lshift,
+        modifiers.rshift,
+        modifiers.lctrl,
+        modifiers.rctrl,
+        modifiers.lalt,
+        modifiers.ralt,
+        modifiers.caps_lock,
+        modifiers.num_lock,
+        modifiers.scroll_lock,
+    ]
+    .iter()
+    .enumerate()
+    .fold(0, |bits, (bit, &set)| bits | u16::from(set) << bit)
+}
+
+fn key_record(event: &KeyEvent) -> [u8; KEY_RECORD_SIZE] {
+    let state = match event.state {
+        KeyState::Up => 0,
+        KeyState::Down => 1,
+        KeyState::SingleShot => 2,
+    };
+    let [low, high] = modifier_bits(&event.modifiers).to_le_bytes();
+    [event.code as u8, state, low, high]
+}
+
+impl CharDevice for Keyboard {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut records = buffer.chunks_exact_mut(KEY_RECORD_SIZE);
+        let Some(first) = records.next() else {
+            return Err(FsError::InvalidArgument);
+        };
+        first.copy_from_slice(&key_record(&wait_for(
+            &self.nonblocking,
+            keyboard::read_event,
+        )?));
+        let mut count = KEY_RECORD_SIZE;
+        for record in records {
+            let Some(event) = keyboard::read_event() else {
+                break;
+            };
+            record.copy_from_slice(&key_record(&event));
+            count += KEY_RECORD_SIZE;
+        }
+        Ok(count)
+    }
+
+    fn write(&self, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn ioctl(&self, request: u32, arg: u64) -> Result<u64, FsError> {
+        match request {
+            KBD_SET_LEDS => {
+                keyboard::set_locks(arg & 4 != 0, arg & 2 != 0, arg & 1 != 0);
+                Ok(0)
+            }
+            KBD_GET_MODIFIERS => Ok(u64::from(modifier_bits(&keyboard::modifiers()))),
+            _ => common_ioctl(&self.nonblocking, keyboard::events_pending, request, arg),
+        }
+    }
+}
+
+/// 読むとすぐに終わり、書いたものは捨てる
+pub struct Null;
+
+impl CharDevice for Null {
+    fn read(&self, _buffer: &mut [u8]) -> Result<usize, FsError> {
+        Ok(0)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// 読むと0が続き、書いたものは捨てる
+pub struct Zero;
+
+impl CharDevice for Zero {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        buffer.fill(0);
+        Ok(buffer.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        Ok(data.len())
+    }
+}
+
+/// 乱数
+///
+/// TSCを種にしたxorshiftに、CPUが対応していればRDRANDの値を混ぜる。書いたバイトは種に混ぜる
+#[derive(Default)]
+pub struct Random {
+    state: Mutex<u64>,
+}
+
+impl Random {
+    fn next(&self) -> u64 {
+        let mut state = self.state.lock();
+        if *state == 0 {
+            *state = time::rdtsc() | 1;
+        }
+        *state ^= *state >> 12;
+        *state ^= *state << 25;
+        *state ^= *state >> 27;
+        let value = state.wrapping_mul(0x2545_f491_4f6c_dd1d);
+        match RdRand::new().and_then(RdRand::get_u64) {
+            Some(hardware) => value ^ hardware,
+            None => value,
+        }
+    }
+}
+
+impl CharDevice for Random {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        for chunk in buffer.chunks_mut(8) {
+            chunk.copy_from_slice(&self.next().to_le_bytes()[..chunk.len()]);
+        }
+        Ok(buffer.len())
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        let mut state = self.state.lock();
+        for &byte in data {
+            *state = state.rotate_left(8) ^ u64::from(byte);
+        }
+        Ok(data.len())
+    }
+}
+
+#[test_case]
+fn test_modifier_bits() {
+    let mut modifiers = Modifiers::default();
+    assert_eq!(modifier_bits(&modifiers), 0);
+    modifiers.lshift = true;
+    modifiers.rctrl = true;
+    modifiers.scroll_lock = true;
+    assert_eq!(modifier_bits(&modifiers), 0b1_0000_1001);
+}
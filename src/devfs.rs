@@ -0,0 +1,129 @@
+//! デバイスファイル
+//!
+//! `chardev` に登録されたデバイスを `/dev` の下のファイルとして見せる。
+//! 読み書きはオフセットを無視してデバイスにそのまま渡し、`vfs::ioctl` はデバイスの `ioctl` になる
+
+use crate::chardev::{self, SharedCharDevice};
+use crate::rtc;
+use crate::vfs::{self, DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// マウントする場所
+pub const MOUNT_POINT: &str = "/dev";
+
+const ROOT: u64 = 1;
+
+#[derive(Default)]
+pub struct DevFs {
+    /// 一度見えたデバイスの名前ごとのinode番号。登録し直されても変えない
+    inodes: Mutex<BTreeMap<String, u64>>,
+}
+
+impl DevFs {
+    fn inode_for(&self, name: &str) -> u64 {
+        let mut inodes = self.inodes.lock();
+        if let Some(&inode) = inodes.get(name) {
+            return inode;
+        }
+        let inode = inodes.len() as u64 + 2;
+        inodes.insert(name.to_string(), inode);
+        inode
+    }
+
+    fn device(&self, inode: u64) -> Result<SharedCharDevice, FsError> {
+        if inode == ROOT {
+            return Err(FsError::IsADirectory);
+        }
+        let inodes = self.inodes.lock();
+        let name = inodes
+            .iter()
+            .find(|&(_, &number)| number == inode)
+            .map(|(name, _)| name)
+            .ok_or(FsError::NotFound)?;
+        chardev::DEVICES.get(name).ok_or(FsError::NotFound)
+    }
+}
+
+impl FileSystem for DevFs {
+    fn name(&self) -> &'static str {
+        "devfs"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        if dir != ROOT {
+            self.device(dir)?;
+            return Err(FsError::NotADirectory);
+        }
+        chardev::DEVICES.get(name).ok_or(FsError::NotFound)?;
+        Ok(self.inode_for(name))
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError> {
+        let modified = rtc::now().unix_timestamp();
+        if inode == ROOT {
+            return Ok(Metadata {
+                inode,
+                file_type: FileType::Directory,
+                size: chardev::DEVICES.entries().len() as u64,
+                mode: 0o755,
+                links: 2,
+                modified,
+            });
+        }
+        self.device(inode)?;
+        Ok(Metadata {
+            inode,
+            file_type: FileType::CharDevice,
+            size: 0,
+            mode: 0o666,
+            links: 1,
+            modified,
+        })
+    }
+
+    fn read(&self, inode: u64, _offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        self.device(inode)?.read(buffer)
+    }
+
+    fn write(&self, inode: u64, _offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        self.device(inode)?.write(data)
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        if dir != ROOT {
+            self.device(dir)?;
+            return Err(FsError::NotADirectory);
+        }
+        Ok(chardev::DEVICES
+            .entries()
+            .into_iter()
+            .map(|(name, _)| DirEntry {
+                inode: self.inode_for(&name),
+                name,
+                file_type: FileType::CharDevice,
+            })
+            .collect())
+    }
+
+    /// デバイスには大きさがないので、`TRUNCATE` 付きで開いても何もしない
+    fn truncate(&self, inode: u64, _size: u64) -> Result<(), FsError> {
+        self.device(inode).map(|_| ())
+    }
+
+    fn ioctl(&self, inode: u64, request: u32, arg: u64) -> Result<u64, FsError> {
+        self.device(inode)?.ioctl(request, arg)
+    }
+}
+
+/// `MOUNT_POINT` に作ってマウントする。ルートをマウントした後に呼ぶ
+pub fn init() -> Result<(), FsError> {
+    vfs::mount_at(MOUNT_POINT, Arc::new(DevFs::default()))
+}
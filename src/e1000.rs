@@ -0,0 +1,495 @@
+//! Intel 8254x (e1000) のドライバ
+//!
+//! 送受信のディスクリプタリングとパケットのバッファは物理的に連続したDMA用のフレームに置く。
+//! 受信と送信完了は割り込みで知らされ、待つ側はhltで眠る。
+//! 割り込みハンドラはICRを読んで割り込みを下げるだけで、リングはドライバの側で見る
+
+use crate::interrupts;
+use crate::log;
+use crate::memory::{self, DmaRegion};
+use crate::net::{self, MacAddress, NetDevice, NetError, NetStats};
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+use crate::time::{Duration, Instant};
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+use spin::Mutex;
+use x86_64::VirtAddr;
+use x86_64::instructions::interrupts as cpu_interrupts;
+
+const VENDOR_INTEL: u16 = 0x8086;
+
+// レジスタのオフセット
+const REG_CTRL: u64 = 0x0000;
+const REG_STATUS: u64 = 0x0008;
+const REG_EERD: u64 = 0x0014;
+const REG_ICR: u64 = 0x00c0;
+const REG_IMS: u64 = 0x00d0;
+const REG_IMC: u64 = 0x00d8;
+const REG_RCTL: u64 = 0x0100;
+const REG_TCTL: u64 = 0x0400;
+const REG_TIPG: u64 = 0x0410;
+const REG_RDBAL: u64 = 0x2800;
+const REG_RDBAH: u64 = 0x2804;
+const REG_RDLEN: u64 = 0x2808;
+const REG_RDH: u64 = 0x2810;
+const REG_RDT: u64 = 0x2818;
+const REG_TDBAL: u64 = 0x3800;
+const REG_TDBAH: u64 = 0x3804;
+const REG_TDLEN: u64 = 0x3808;
+const REG_TDH: u64 = 0x3810;
+const REG_TDT: u64 = 0x3818;
+/// マルチキャストのハッシュ表 (128個の `u32`)
+const REG_MTA: u64 = 0x5200;
+const MTA_ENTRIES: u64 = 128;
+const REG_RAL0: u64 = 0x5400;
+const REG_RAH0: u64 = 0x5404;
+
+const CTRL_LRST: u32 = 1 << 3;
+const CTRL_ASDE: u32 = 1 << 5;
+const CTRL_SLU: u32 = 1 << 6;
+const CTRL_RST: u32 = 1 << 26;
+const CTRL_PHY_RST: u32 = 1 << 31;
+
+const STATUS_LU: u32 = 1 << 1;
+const STATUS_SPEED_SHIFT: u32 = 6;
+
+const EERD_START: u32 = 1 << 0;
+const EERD_DONE: u32 = 1 << 4;
+const EERD_ADDRESS_SHIFT: u32 = 8;
+const EERD_DATA_SHIFT: u32 = 16;
+
+/// 受信アドレスが有効 (Address Valid)
+const RAH_AV: u32 = 1 << 31;
+
+// 割り込みの要因
+const ICR_TXDW: u32 = 1 << 0;
+const ICR_LSC: u32 = 1 << 2;
+const ICR_RXDMT0: u32 = 1 << 4;
+const ICR_RXO: u32 = 1 << 6;
+const ICR_RXT0: u32 = 1 << 7;
+
+const RCTL_EN: u32 = 1 << 1;
+const RCTL_BAM: u32 = 1 << 15;
+/// CRCを取り除いてから受信バッファに書く
+const RCTL_SECRC: u32 = 1 << 26;
+
+const TCTL_EN: u32 = 1 << 1;
+/// 短いパケットを64バイトまで埋める
+const TCTL_PSP: u32 = 1 << 3;
+const TCTL_CT_SHIFT: u32 = 4;
+const TCTL_COLD_SHIFT: u32 = 12;
+/// 再送の回数と衝突の距離 (全二重) の推奨値
+const TCTL_CT: u32 = 0x0f;
+const TCTL_COLD: u32 = 0x40;
+/// IPGT 10、IPGR1 8、IPGR2 6 (IEEE 802.3の推奨値)
+const TIPG_DEFAULT: u32 = 10 | 8 << 10 | 6 << 20;
+
+const RX_STATUS_DD: u8 = 1 << 0;
+const RX_STATUS_EOP: u8 = 1 << 1;
+
+const TX_CMD_EOP: u8 = 1 << 0;
+const TX_CMD_IFCS: u8 = 1 << 1;
+const TX_CMD_RS: u8 = 1 << 3;
+const TX_STATUS_DD: u8 = 1 << 0;
+
+/// リングのディスクリプタの数。リングの大きさは128バイトの倍数でなければならない
+const RING_SIZE: usize = 32;
+/// パケット1つ分のバッファの大きさ (RCTL.BSIZE の既定値)
+const BUFFER_SIZE: usize = 2048;
+const PAGE_SIZE: usize = 4096;
+
+/// リセットとEEPROMの読み出しを待つ時間
+const RESET_TIMEOUT: Duration = Duration::from_millis(100);
+/// 送信ディスクリプタが空くのを待つ時間
+const TRANSMIT_TIMEOUT: Duration = Duration::from_secs(1);
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "e1000",
+    // QEMUの `e1000` (82540EM) と、VMwareなどの82545EM
+    matches: &[
+        PciMatch::device(VENDOR_INTEL, 0x100e),
+        PciMatch::device(VENDOR_INTEL, 0x100f),
+    ],
+    probe,
+};
+
+fn probe(device: &PciDevice) -> Result<(), PciError> {
+    let nic = E1000::new(device)?;
+    let name = net::next_name();
+    match nic.link_speed() {
+        Some(speed) => log!("e1000: {} {} link up {} Mb/s", name, nic.mac, speed),
+        None => log!("e1000: {} {} link down", name, nic.mac),
+    }
+    net::DEVICES.register(&name, Arc::new(Mutex::new(nic)));
+    Ok(())
+}
+
+/// 割り込みを受けるNICのレジスタの仮想アドレス (0は空き)
+static REGISTERS: [AtomicU64; 4] = [const { AtomicU64::new(0) }; 4];
+
+/// PCIの割り込みハンドラ。ICRを読むと要因が消え、割り込みが下がる
+fn handle_interrupt() {
+    for registers in &REGISTERS {
+        let address = registers.load(Ordering::Relaxed);
+        if address != 0 {
+            unsafe { ((address + REG_ICR) as *const u32).read_volatile() };
+        }
+    }
+}
+
+/// 受信ディスクリプタ (legacy)
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct RxDescriptor {
+    address: u64,
+    length: u16,
+    checksum: u16,
+    status: u8,
+    errors: u8,
+    special: u16,
+}
+
+/// 送信ディスクリプタ (legacy)
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct TxDescriptor {
+    address: u64,
+    length: u16,
+    cso: u8,
+    command: u8,
+    status: u8,
+    css: u8,
+    special: u16,
+}
+
+/// 受信アドレスのレジスタの組からMACアドレスを取り出す。有効でなければ `None`
+fn mac_from_receive_address(low: u32, high: u32) -> Option<MacAddress> {
+    if high & RAH_AV == 0 {
+        return None;
+    }
+    let [a, b, c, d] = low.to_le_bytes();
+    let [e, f, _, _] = high.to_le_bytes();
+    Some(MacAddress([a, b, c, d, e, f]))
+}
+
+pub struct E1000 {
+    registers: VirtAddr,
+    mac: MacAddress,
+    rx_ring: DmaRegion,
+    rx_buffers: DmaRegion,
+    /// 次にデバイスが書き終えるはずの受信ディスクリプタ
+    rx_next: usize,
+    tx_ring: DmaRegion,
+    tx_buffers: DmaRegion,
+    /// 次に使う送信ディスクリプタ
+    tx_next: usize,
+    stats: NetStats,
+}
+
+impl E1000 {
+    pub fn new(device: &PciDevice) -> Result<E1000, PciError> {
+        device.enable();
+        let registers = device.map_bar(0)?;
+        let out_of_memory = PciError::Driver("e1000: out of DMA memory");
+        let buffer_pages = RING_SIZE * BUFFER_SIZE / PAGE_SIZE;
+        let mut nic = E1000 {
+            registers,
+            mac: MacAddress([0; 6]),
+            rx_ring: memory::allocate_dma(1).ok_or(out_of_memory)?,
+            rx_buffers: memory::allocate_dma(buffer_pages).ok_or(out_of_memory)?,
+            rx_next: 0,
+            tx_ring: memory::allocate_dma(1).ok_or(out_of_memory)?,
+            tx_buffers: memory::allocate_dma(buffer_pages).ok_or(out_of_memory)?,
+            tx_next: 0,
+            stats: NetStats::default(),
+        };
+        nic.reset()?;
+        nic.mac = nic.read_mac_address()?;
+        nic.setup_rx();
+        nic.setup_tx();
+
+        let irq = device.interrupt_line;
+        let slot = REGISTERS
+            .iter()
+            .find(|slot| {
+                slot.compare_exchange(0, registers.as_u64(), Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .ok_or(PciError::Driver("e1000: too many devices"))?;
+        if !interrupts::register_pci_handler(irq, handle_interrupt) {
+            slot.store(0, Ordering::Relaxed);
+            return Err(PciError::Driver("e1000: unsupported IRQ line"));
+        }
+        nic.write(
+            REG_IMS,
+            ICR_TXDW | ICR_LSC | ICR_RXDMT0 | ICR_RXO | ICR_RXT0,
+        );
+        Ok(nic)
+    }
+
+    fn read(&self, register: u64) -> u32 {
+        unsafe { (self.registers + register).as_ptr::<u32>().read_volatile() }
+    }
+
+    fn write(&self, register: u64, value: u32) {
+        unsafe {
+            (self.registers + register)
+                .as_mut_ptr::<u32>()
+                .write_volatile(value)
+        }
+    }
+
+    /// デバイスをリセットし、割り込みを止めてリンクを上げる
+    fn reset(&self) -> Result<(), PciError> {
+        self.write(REG_IMC, u32::MAX);
+        self.write(REG_CTRL, self.read(REG_CTRL) | CTRL_RST);
+        let start = Instant::now();
+        while self.read(REG_CTRL) & CTRL_RST != 0 {
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(PciError::Driver("e1000: reset timed out"));
+            }
+            core::hint::spin_loop();
+        }
+        // リセットで割り込みのマスクが戻るので、もう一度止めて溜まった要因を捨てる
+        self.write(REG_IMC, u32::MAX);
+        self.read(REG_ICR);
+        let ctrl = self.read(REG_CTRL);
+        self.write(
+            REG_CTRL,
+            (ctrl | CTRL_SLU | CTRL_ASDE) & !(CTRL_LRST | CTRL_PHY_RST),
+        );
+        Ok(())
+    }
+
+    /// EEPROMから1ワード読む
+    fn read_eeprom(&self, address: u8) -> Result<u16, PciError> {
+        self.write(
+            REG_EERD,
+            EERD_START | u32::from(address) << EERD_ADDRESS_SHIFT,
+        );
+        let start = Instant::now();
+        loop {
+            let value = self.read(REG_EERD);
+            if value & EERD_DONE != 0 {
+                return Ok((value >> EERD_DATA_SHIFT) as u16);
+            }
+            if start.elapsed() > RESET_TIMEOUT {
+                return Err(PciError::Driver("e1000: EEPROM read timed out"));
+            }
+            core::hint::spin_loop();
+        }
+    }
+
+    /// 受信アドレスのレジスタにMACアドレスがなければEEPROMから読んで設定する
+    fn read_mac_address(&self) -> Result<MacAddress, PciError> {
+        if let Some(mac) = mac_from_receive_address(self.read(REG_RAL0), self.read(REG_RAH0)) {
+            return Ok(mac);
+        }
+        let mut mac = [0u8; 6];
+        for (word, bytes) in mac.chunks_exact_mut(2).enumerate() {
+            bytes.copy_from_slice(&self.read_eeprom(word as u8)?.to_le_bytes());
+        }
+        let [a, b, c, d, e, f] = mac;
+        self.write(REG_RAL0, u32::from_le_bytes([a, b, c, d]));
+        self.write(REG_RAH0, u32::from_le_bytes([e, f, 0, 0]) | RAH_AV);
+        Ok(MacAddress(mac))
+    }
+
+    fn rx_descriptor(&self, index: usize) -> *mut RxDescriptor {
+        unsafe { self.rx_ring.as_mut_ptr::<RxDescriptor>().add(index) }
+    }
+
+    fn tx_descriptor(&self, index: usize) -> *mut TxDescriptor {
+        unsafe { self.tx_ring.as_mut_ptr::<TxDescriptor>().add(index) }
+    }
+
+    fn setup_rx(&self) {
+        for index in 0..RING_SIZE {
+            let descriptor = RxDescriptor {
+                address: self.rx_buffers.phys.as_u64() + (index * BUFFER_SIZE) as u64,
+                ..RxDescriptor::default()
+            };
+            unsafe { self.rx_descriptor(index).write_volatile(descriptor) };
+        }
+        for index in 0..MTA_ENTRIES {
+            self.write(REG_MTA + 4 * index, 0);
+        }
+        let ring = self.rx_ring.phys.as_u64();
+        self.write(REG_RDBAL, ring as u32);
+        self.write(REG_RDBAH, (ring >> 32) as u32);
+        self.write(REG_RDLEN, (RING_SIZE * size_of::<RxDescriptor>()) as u32);
+        // 最後の1つを残して全部デバイスに渡す
+        self.write(REG_RDH, 0);
+        self.write(REG_RDT, (RING_SIZE - 1) as u32);
+        self.write(REG_RCTL, RCTL_EN | RCTL_BAM | RCTL_SECRC);
+    }
+
+    fn setup_tx(&self) {
+        // 使い終わった (DDが立った) 状態から始める
+        for index in 0..RING_SIZE {
+            let descriptor = TxDescriptor {
+                status: TX_STATUS_DD,
+                ..TxDescriptor::default()
+            };
+            unsafe { self.tx_descriptor(index).write_volatile(descriptor) };
+        }
+        let ring = self.tx_ring.phys.as_u64();
+        self.write(REG_TDBAL, ring as u32);
+        self.write(REG_TDBAH, (ring >> 32) as u32);
+        self.write(REG_TDLEN, (RING_SIZE * size_of::<TxDescriptor>()) as u32);
+        self.write(REG_TDH, 0);
+        self.write(REG_TDT, 0);
+        self.write(REG_TIPG, TIPG_DEFAULT);
+        self.write(
+            REG_TCTL,
+            TCTL_EN | TCTL_PSP | TCTL_CT << TCTL_CT_SHIFT | TCTL_COLD << TCTL_COLD_SHIFT,
+        );
+    }
+
+    /// リンクの速さ (Mb/s)。リンクが切れていれば `None`
+    pub fn link_speed(&self) -> Option<u32> {
+        let status = self.read(REG_STATUS);
+        if status & STATUS_LU == 0 {
+            return None;
+        }
+        Some(match (status >> STATUS_SPEED_SHIFT) & 0b11 {
+            0 => 10,
+            1 => 100,
+            _ => 1000,
+        })
+    }
+
+    /// 送信ディスクリプタ `index` が空くのを待つ
+    ///
+    /// 割り込みが有効なら送信完了の割り込みが来るまでhltで眠る
+    fn wait_for_tx(&self, index: usize) -> Result<(), NetError> {
+        let sleep = cpu_interrupts::are_enabled();
+        let start = Instant::now();
+        loop {
+            if sleep {
+                cpu_interrupts::disable();
+            }
+            let status = unsafe { self.tx_descriptor(index).read_volatile() }.status;
+            if status & TX_STATUS_DD != 0 || start.elapsed() > TRANSMIT_TIMEOUT {
+                if sleep {
+                    cpu_interrupts::enable();
+                }
+                return match status & TX_STATUS_DD {
+                    0 => Err(NetError::Timeout),
+                    _ => Ok(()),
+                };
+            }
+            if sleep {
+                cpu_interrupts::enable_and_hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+impl NetDevice for E1000 {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        self.read(REG_STATUS) & STATUS_LU != 0
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        net::check_frame(frame)?;
+        if !self.link_up() {
+            return Err(NetError::LinkDown);
+        }
+        let index = self.tx_next;
+        self.wait_for_tx(index)?;
+
+        let offset = index * BUFFER_SIZE;
+        unsafe {
+            let buffer = self.tx_buffers.as_mut_ptr::<u8>().add(offset);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer, frame.len());
+        }
+        let descriptor = TxDescriptor {
+            address: self.tx_buffers.phys.as_u64() + offset as u64,
+            length: frame.len() as u16,
+            command: TX_CMD_EOP | TX_CMD_IFCS | TX_CMD_RS,
+            ..TxDescriptor::default()
+        };
+        unsafe { self.tx_descriptor(index).write_volatile(descriptor) };
+        fence(Ordering::SeqCst);
+        self.tx_next = (index + 1) % RING_SIZE;
+        self.write(REG_TDT, self.tx_next as u32);
+
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += frame.len() as u64;
+        Ok(())
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, NetError> {
+        loop {
+            let index = self.rx_next;
+            let descriptor = unsafe { self.rx_descriptor(index).read_volatile() };
+            if descriptor.status & RX_STATUS_DD == 0 {
+                return Ok(None);
+            }
+            fence(Ordering::SeqCst);
+            let length = usize::from(descriptor.length);
+            // バッファより大きなフレームは受け取らない設定なので、EOPのないものは壊れている
+            let result = if descriptor.status & RX_STATUS_EOP == 0 || descriptor.errors != 0 {
+                Ok(None)
+            } else if length > buffer.len() {
+                Err(NetError::BufferTooSmall)
+            } else {
+                unsafe {
+                    let data = self.rx_buffers.as_mut_ptr::<u8>().add(index * BUFFER_SIZE);
+                    core::ptr::copy_nonoverlapping(data, buffer.as_mut_ptr(), length);
+                }
+                Ok(Some(length))
+            };
+
+            // ディスクリプタをデバイスに返す
+            let cleared = RxDescriptor {
+                address: descriptor.address,
+                ..RxDescriptor::default()
+            };
+            unsafe { self.rx_descriptor(index).write_volatile(cleared) };
+            fence(Ordering::SeqCst);
+            self.rx_next = (index + 1) % RING_SIZE;
+            self.write(REG_RDT, index as u32);
+
+            match result {
+                Ok(Some(length)) => {
+                    self.stats.rx_packets += 1;
+                    self.stats.rx_bytes += length as u64;
+                    return Ok(Some(length));
+                }
+                // 壊れたフレームは捨てて次を見る
+                Ok(None) => self.stats.rx_errors += 1,
+                Err(err) => {
+                    self.stats.rx_errors += 1;
+                    return Err(err);
+                }
+            }
+        }
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    fn description(&self) -> &str {
+        "e1000"
+    }
+}
+
+#[test_case]
+fn test_mac_from_receive_address() {
+    assert_eq!(
+        mac_from_receive_address(0x1200_5452, 0x8000_5634),
+        Some(MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]))
+    );
+    assert_eq!(mac_from_receive_address(0x1200_5452, 0x0000_5634), None);
+    assert_eq!(size_of::<RxDescriptor>(), 16);
+    assert_eq!(size_of::<TxDescriptor>(), 16);
+}
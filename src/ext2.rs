@@ -0,0 +1,473 @@
+//! ext2 のファイルシステム (読み出し専用)
+//!
+//! Linuxの `mke2fs` で作ったイメージを読む。書き込みはすべて `ReadOnly` になる。
+//! inode番号はext2のinode番号をそのまま使う。
+//! ジャーナル付きのext3でも、きれいにアンマウントされていればext2として読める
+
+use crate::block::SharedBlockDevice;
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+
+/// スーパーブロックはボリュームの1024バイト目から1024バイト
+const SUPERBLOCK_OFFSET: u64 = 1024;
+const SUPERBLOCK_SIZE: usize = 1024;
+const MAGIC: u16 = 0xef53;
+
+/// ルートディレクトリのinode番号
+const ROOT: u64 = 2;
+
+/// 改訂0のinodeの大きさ
+const GOOD_OLD_INODE_SIZE: u64 = 128;
+/// グループディスクリプタ1つのバイト数
+const GROUP_DESCRIPTOR_SIZE: u64 = 32;
+
+/// ディレクトリエントリがファイルの種類を持つ
+const INCOMPAT_FILETYPE: u32 = 0x0002;
+/// ジャーナルの再生が要る
+const INCOMPAT_RECOVER: u32 = 0x0004;
+/// 読めるincompatの機能
+const INCOMPAT_SUPPORTED: u32 = INCOMPAT_FILETYPE;
+
+/// inodeの中のブロック番号の数。12個の直接ブロックと、間接、二重間接、三重間接
+const DIRECT_BLOCKS: usize = 12;
+const BLOCK_POINTERS: usize = 15;
+
+/// シンボリックリンクの先が `i_block` に収まる長さ
+const FAST_SYMLINK_MAX: u64 = 60;
+
+const MODE_TYPE_MASK: u16 = 0o170000;
+const MODE_DIRECTORY: u16 = 0o040000;
+const MODE_REGULAR: u16 = 0o100000;
+const MODE_SYMLINK: u16 = 0o120000;
+const MODE_CHAR_DEVICE: u16 = 0o020000;
+const MODE_BLOCK_DEVICE: u16 = 0o060000;
+
+/// ディスク上のinodeの、使う部分
+#[derive(Debug, Clone, Copy)]
+struct Inode {
+    mode: u16,
+    size: u64,
+    modified: u32,
+    links: u16,
+    /// 512バイト単位で数えた、使っているブロック
+    sectors: u32,
+    /// 拡張属性のブロック
+    file_acl: u32,
+    block: [u32; BLOCK_POINTERS],
+}
+
+impl Inode {
+    fn parse(bytes: &[u8]) -> Inode {
+        let u16_at = |offset: usize| u16::from_le_bytes([bytes[offset], bytes[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(bytes[offset..offset + 4].try_into().unwrap());
+        let mode = u16_at(0);
+        let mut block = [0u32; BLOCK_POINTERS];
+        for (i, pointer) in block.iter_mut().enumerate() {
+            *pointer = u32_at(40 + i * 4);
+        }
+        // 通常のファイルでは `i_dir_acl` が大きさの上位32ビット (large_file)
+        let size_high = if mode & MODE_TYPE_MASK == MODE_REGULAR {
+            u64::from(u32_at(108))
+        } else {
+            0
+        };
+        Inode {
+            mode,
+            size: u64::from(u32_at(4)) | (size_high << 32),
+            modified: u32_at(16),
+            links: u16_at(26),
+            sectors: u32_at(28),
+            file_acl: u32_at(104),
+            block,
+        }
+    }
+
+    fn file_type(&self) -> FileType {
+        match self.mode & MODE_TYPE_MASK {
+            MODE_DIRECTORY => FileType::Directory,
+            MODE_SYMLINK => FileType::Symlink,
+            MODE_CHAR_DEVICE => FileType::CharDevice,
+            MODE_BLOCK_DEVICE => FileType::BlockDevice,
+            // FIFOとソケットは中身のないファイルとして見せる
+            _ => FileType::Regular,
+        }
+    }
+}
+
+/// ファイルの `logical` 番目のブロックへの道筋。間接の段数と、各段での添字
+///
+/// `pointers` は1ブロックに入るブロック番号の数。三重間接でも届かなければ `None`
+fn block_path(logical: u64, pointers: u64) -> Option<(usize, [usize; 4])> {
+    let direct = DIRECT_BLOCKS as u64;
+    if logical < direct {
+        return Some((0, [logical as usize, 0, 0, 0]));
+    }
+    let mut rest = logical - direct;
+    let mut span = pointers;
+    for level in 1..=3 {
+        if rest < span {
+            let mut path = [0usize; 4];
+            path[0] = DIRECT_BLOCKS + level - 1;
+            for depth in (1..=level).rev() {
+                path[depth] = (rest % pointers) as usize;
+                rest /= pointers;
+            }
+            return Some((level, path));
+        }
+        rest -= span;
+        span *= pointers;
+    }
+    None
+}
+
+pub struct Ext2Fs {
+    device: SharedBlockDevice,
+    device_block_size: u64,
+    block_size: u64,
+    inodes_per_group: u32,
+    inode_size: u64,
+    inodes_count: u32,
+    /// グループごとのinodeテーブルの最初のブロック
+    inode_tables: Vec<u32>,
+    filetype: bool,
+    label: String,
+}
+
+/// `device` にext2のスーパーブロックがあれば真
+pub fn detect(device: &SharedBlockDevice) -> bool {
+    let mut magic = [0u8; 2];
+    read_device(device, SUPERBLOCK_OFFSET + 56, &mut magic).is_ok()
+        && u16::from_le_bytes(magic) == MAGIC
+}
+
+/// デバイスの `position` バイト目から読む。ブロックの境目にそろっていなくてもよい
+fn read_device(
+    device: &SharedBlockDevice,
+    position: u64,
+    buffer: &mut [u8],
+) -> Result<(), FsError> {
+    let mut device = device.lock();
+    let block_size = device.block_size() as u64;
+    let first = position / block_size;
+    let last = (position + buffer.len() as u64).div_ceil(block_size);
+    let skip = (position - first * block_size) as usize;
+    if skip == 0 && buffer.len() as u64 == (last - first) * block_size {
+        return Ok(device.read_blocks(first, buffer)?);
+    }
+    let mut scratch = vec![0u8; ((last - first) * block_size) as usize];
+    device.read_blocks(first, &mut scratch)?;
+    buffer.copy_from_slice(&scratch[skip..skip + buffer.len()]);
+    Ok(())
+}
+
+impl Ext2Fs {
+    /// `device` のスーパーブロックとグループディスクリプタを読む
+    pub fn new(device: SharedBlockDevice) -> Result<Ext2Fs, FsError> {
+        let mut superblock = [0u8; SUPERBLOCK_SIZE];
+        read_device(&device, SUPERBLOCK_OFFSET, &mut superblock)?;
+        let u16_at =
+            |offset: usize| u16::from_le_bytes([superblock[offset], superblock[offset + 1]]);
+        let u32_at =
+            |offset: usize| u32::from_le_bytes(superblock[offset..offset + 4].try_into().unwrap());
+        if u16_at(56) != MAGIC {
+            return Err(FsError::Corrupted("ext2: bad magic"));
+        }
+        let incompat = u32_at(96);
+        if incompat & INCOMPAT_RECOVER != 0 {
+            return Err(FsError::Corrupted("ext2: journal needs recovery"));
+        }
+        if incompat & !(INCOMPAT_SUPPORTED | INCOMPAT_RECOVER) != 0 {
+            return Err(FsError::NotSupported);
+        }
+
+        let inodes_count = u32_at(0);
+        let blocks_count = u32_at(4);
+        let first_data_block = u32_at(20);
+        let log_block_size = u32_at(24);
+        let blocks_per_group = u32_at(32);
+        let inodes_per_group = u32_at(40);
+        let inode_size = if u32_at(76) == 0 {
+            GOOD_OLD_INODE_SIZE
+        } else {
+            u64::from(u16_at(88))
+        };
+        let device_block_size = device.lock().block_size() as u64;
+        if log_block_size > 6
+            || blocks_per_group == 0
+            || inodes_per_group == 0
+            || inode_size < GOOD_OLD_INODE_SIZE
+            || !inode_size.is_power_of_two()
+        {
+            return Err(FsError::Corrupted("ext2: invalid superblock"));
+        }
+        let block_size = 1024u64 << log_block_size;
+        if !block_size.is_multiple_of(device_block_size) {
+            return Err(FsError::NotSupported);
+        }
+        let label_bytes = &superblock[120..136];
+        let label_end = label_bytes.iter().position(|&byte| byte == 0).unwrap_or(16);
+        let label = String::from_utf8_lossy(&label_bytes[..label_end]).into_owned();
+
+        // グループディスクリプタはスーパーブロックの次のブロックから並ぶ
+        let groups = blocks_count
+            .checked_sub(first_data_block)
+            .ok_or(FsError::Corrupted("ext2: invalid superblock"))?
+            .div_ceil(blocks_per_group);
+        let mut descriptors = vec![0u8; (u64::from(groups) * GROUP_DESCRIPTOR_SIZE) as usize];
+        let table = (u64::from(first_data_block) + 1) * block_size;
+        read_device(&device, table, &mut descriptors)?;
+        let inode_tables = descriptors
+            .chunks_exact(GROUP_DESCRIPTOR_SIZE as usize)
+            .map(|descriptor| u32::from_le_bytes(descriptor[8..12].try_into().unwrap()))
+            .collect();
+
+        Ok(Ext2Fs {
+            device,
+            device_block_size,
+            block_size,
+            inodes_per_group,
+            inode_size,
+            inodes_count,
+            inode_tables,
+            filetype: incompat & INCOMPAT_FILETYPE != 0,
+            label,
+        })
+    }
+
+    /// ボリュームの名前 (`mke2fs -L`)
+    pub fn label(&self) -> &str {
+        &self.label
+    }
+
+    pub fn block_size(&self) -> u64 {
+        self.block_size
+    }
+
+    fn read_block(&self, block: u32, buffer: &mut [u8]) -> Result<(), FsError> {
+        let blocks = self.block_size / self.device_block_size;
+        let start = u64::from(block) * blocks;
+        Ok(self.device.lock().read_blocks(start, buffer)?)
+    }
+
+    fn inode(&self, number: u64) -> Result<Inode, FsError> {
+        if number == 0 || number > u64::from(self.inodes_count) {
+            return Err(FsError::NotFound);
+        }
+        let index = number - 1;
+        let group = (index / u64::from(self.inodes_per_group)) as usize;
+        let table = *self
+            .inode_tables
+            .get(group)
+            .ok_or(FsError::Corrupted("ext2: inode outside the groups"))?;
+        let position = u64::from(table) * self.block_size
+            + index % u64::from(self.inodes_per_group) * self.inode_size;
+        let mut bytes = [0u8; GOOD_OLD_INODE_SIZE as usize];
+        read_device(&self.device, position, &mut bytes)?;
+        Ok(Inode::parse(&bytes))
+    }
+
+    /// 間接ブロック `block` の `index` 番目のブロック番号
+    fn pointer(&self, block: u32, index: usize) -> Result<u32, FsError> {
+        let mut bytes = [0u8; 4];
+        read_device(
+            &self.device,
+            u64::from(block) * self.block_size + index as u64 * 4,
+            &mut bytes,
+        )?;
+        Ok(u32::from_le_bytes(bytes))
+    }
+
+    /// ファイルの `logical` 番目のブロックのボリューム上の番号。穴なら0
+    fn map_block(&self, inode: &Inode, logical: u64) -> Result<u32, FsError> {
+        let (level, path) = block_path(logical, self.block_size / 4)
+            .ok_or(FsError::Corrupted("ext2: block beyond triple indirect"))?;
+        let mut block = inode.block[path[0]];
+        for &index in &path[1..=level] {
+            if block == 0 {
+                break;
+            }
+            block = self.pointer(block, index)?;
+        }
+        Ok(block)
+    }
+
+    /// ファイルの `offset` から読む。大きさの先は読まない
+    fn read_data(&self, inode: &Inode, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        if offset >= inode.size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((inode.size - offset) as usize);
+        let mut scratch = vec![0u8; self.block_size as usize];
+        let mut done = 0;
+        while done < count {
+            let current = offset + done as u64;
+            let within = (current % self.block_size) as usize;
+            let length = (count - done).min(self.block_size as usize - within);
+            let target = &mut buffer[done..done + length];
+            match self.map_block(inode, current / self.block_size)? {
+                0 => target.fill(0),
+                block if length == self.block_size as usize => self.read_block(block, target)?,
+                block => {
+                    self.read_block(block, &mut scratch)?;
+                    target.copy_from_slice(&scratch[within..within + length]);
+                }
+            }
+            done += length;
+        }
+        Ok(count)
+    }
+
+    /// ディレクトリのエントリを (名前, inode番号, 種類) で並べる。`.` と `..` は除く
+    fn entries(&self, dir: u64) -> Result<Vec<(String, u64, Option<FileType>)>, FsError> {
+        let inode = self.inode(dir)?;
+        if inode.file_type() != FileType::Directory {
+            return Err(FsError::NotADirectory);
+        }
+        let corrupted = FsError::Corrupted("ext2: bad directory entry");
+        let mut entries = Vec::new();
+        let mut block = vec![0u8; self.block_size as usize];
+        for offset in (0..inode.size).step_by(self.block_size as usize) {
+            let read = self.read_data(&inode, offset, &mut block)?;
+            let mut position = 0;
+            while position + 8 <= read {
+                let entry = &block[position..];
+                let number = u32::from_le_bytes(entry[..4].try_into().unwrap());
+                let record_length = usize::from(u16::from_le_bytes([entry[4], entry[5]]));
+                // filetypeの機能がなければ名前の長さは16ビット
+                let (name_length, file_type) = if self.filetype {
+                    (usize::from(entry[6]), entry[7])
+                } else {
+                    (usize::from(u16::from_le_bytes([entry[6], entry[7]])), 0)
+                };
+                if record_length < 8
+                    || position + record_length > read
+                    || 8 + name_length > record_length
+                {
+                    return Err(corrupted);
+                }
+                let name = &entry[8..8 + name_length];
+                if number != 0 && name != b"." && name != b".." {
+                    let file_type = match file_type {
+                        1 => Some(FileType::Regular),
+                        2 => Some(FileType::Directory),
+                        3 => Some(FileType::CharDevice),
+                        4 => Some(FileType::BlockDevice),
+                        7 => Some(FileType::Symlink),
+                        5 | 6 => Some(FileType::Regular),
+                        _ => None,
+                    };
+                    entries.push((
+                        String::from_utf8_lossy(name).into_owned(),
+                        u64::from(number),
+                        file_type,
+                    ));
+                }
+                position += record_length;
+            }
+        }
+        Ok(entries)
+    }
+}
+
+impl FileSystem for Ext2Fs {
+    fn name(&self) -> &'static str {
+        "ext2"
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        self.entries(dir)?
+            .into_iter()
+            .find(|(entry, _, _)| entry == name)
+            .map(|(_, inode, _)| inode)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn metadata(&self, number: u64) -> Result<Metadata, FsError> {
+        let inode = self.inode(number)?;
+        Ok(Metadata {
+            inode: number,
+            file_type: inode.file_type(),
+            size: inode.size,
+            mode: inode.mode & 0o7777,
+            links: u32::from(inode.links),
+            modified: u64::from(inode.modified),
+        })
+    }
+
+    fn read(&self, number: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inode = self.inode(number)?;
+        if inode.file_type() == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        self.read_data(&inode, offset, buffer)
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        self.entries(dir)?
+            .into_iter()
+            .map(|(name, inode, file_type)| {
+                let file_type = match file_type {
+                    Some(file_type) => file_type,
+                    None => self.inode(inode)?.file_type(),
+                };
+                Ok(DirEntry {
+                    name,
+                    inode,
+                    file_type,
+                })
+            })
+            .collect()
+    }
+
+    fn readlink(&self, number: u64) -> Result<String, FsError> {
+        let inode = self.inode(number)?;
+        if inode.file_type() != FileType::Symlink {
+            return Err(FsError::InvalidArgument);
+        }
+        // 短いリンクの先はブロックを使わず `i_block` にそのまま入っている
+        let acl_sectors = if inode.file_acl != 0 {
+            (self.block_size / 512) as u32
+        } else {
+            0
+        };
+        if inode.size > self.block_size {
+            return Err(FsError::Corrupted("ext2: symlink is too long"));
+        }
+        let mut target = vec![0u8; inode.size as usize];
+        if inode.size <= FAST_SYMLINK_MAX && inode.sectors == acl_sectors {
+            let bytes: Vec<u8> = inode
+                .block
+                .iter()
+                .flat_map(|pointer| pointer.to_le_bytes())
+                .collect();
+            target.copy_from_slice(&bytes[..inode.size as usize]);
+        } else {
+            self.read_data(&inode, 0, &mut target)?;
+        }
+        String::from_utf8(target).map_err(|_| FsError::Corrupted("ext2: symlink is not UTF-8"))
+    }
+}
+
+#[test_case]
+fn test_block_path() {
+    // 1KiBのブロックには256個のブロック番号が入る
+    assert_eq!(block_path(0, 256), Some((0, [0, 0, 0, 0])));
+    assert_eq!(block_path(11, 256), Some((0, [11, 0, 0, 0])));
+    assert_eq!(block_path(12, 256), Some((1, [12, 0, 0, 0])));
+    assert_eq!(block_path(267, 256), Some((1, [12, 255, 0, 0])));
+    assert_eq!(block_path(268, 256), Some((2, [13, 0, 0, 0])));
+    assert_eq!(block_path(268 + 256 + 3, 256), Some((2, [13, 1, 3, 0])));
+    assert_eq!(block_path(268 + 65536, 256), Some((3, [14, 0, 0, 0])));
+    assert_eq!(block_path(268 + 65536 + 16_777_216, 256), None);
+}
@@ -0,0 +1,1642 @@
+//! FAT12/16/32 のファイルシステム
+//!
+//! 長い名前 (VFAT) を読み書きできる。どの `BlockDevice` の上にも置けるが、
+//! FATやディレクトリエントリは小さな単位で何度も読み書きするので、実際のディスクは
+//! `block_cache::open` で得たキャッシュ越しに使う。
+//! FATにはinode番号がないので、マウントしている間だけディレクトリエントリの位置ごとに振る
+
+use crate::block::SharedBlockDevice;
+use crate::rtc::{self, DateTime};
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::String;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+const ROOT: u64 = 1;
+
+/// ディレクトリエントリ1つのバイト数
+const ENTRY_SIZE: usize = 32;
+
+const ATTR_READ_ONLY: u8 = 0x01;
+const ATTR_VOLUME_ID: u8 = 0x08;
+const ATTR_DIRECTORY: u8 = 0x10;
+const ATTR_ARCHIVE: u8 = 0x20;
+/// 長い名前のエントリは READ_ONLY | HIDDEN | SYSTEM | VOLUME_ID
+const ATTR_LONG_NAME: u8 = 0x0f;
+
+/// 名前の最初のバイトが消されたエントリとディレクトリの終わりを表す
+const ENTRY_FREE: u8 = 0xe5;
+const ENTRY_END: u8 = 0x00;
+
+/// 長い名前のエントリ1つに入るUTF-16の数と、その位置
+const LFN_CHARS: usize = 13;
+const LFN_OFFSETS: [usize; LFN_CHARS] = [1, 3, 5, 7, 9, 14, 16, 18, 20, 22, 24, 28, 30];
+/// 長い名前の最後 (ディスク上では最初) のエントリの印
+const LFN_LAST: u8 = 0x40;
+/// 名前の長さの上限 (UTF-16で)
+const MAX_NAME_LENGTH: usize = 255;
+
+/// 短い名前の本体と拡張子が小文字であることを示す (Windows NTの拡張)
+const CASE_LOWER_BASE: u8 = 0x08;
+const CASE_LOWER_EXT: u8 = 0x10;
+
+/// FAT32のFSInfoセクタの印
+const FS_INFO_LEAD: u32 = 0x4161_5252;
+const FS_INFO_STRUCT: u32 = 0x6141_7272;
+const FS_INFO_TRAIL: u32 = 0xaa55_0000;
+/// FSInfoの空きクラスタ数がわからない
+const FS_INFO_UNKNOWN: u32 = 0xffff_ffff;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FatType {
+    Fat12,
+    Fat16,
+    Fat32,
+}
+
+impl FatType {
+    /// 種類はクラスタの数だけで決まる
+    fn from_clusters(count: u32) -> FatType {
+        if count < 4085 {
+            FatType::Fat12
+        } else if count < 65525 {
+            FatType::Fat16
+        } else {
+            FatType::Fat32
+        }
+    }
+
+    /// チェーンの終わりに書く値。これより少し小さい値から終わりとみなす
+    fn end_of_chain(self) -> u32 {
+        match self {
+            FatType::Fat12 => 0xfff,
+            FatType::Fat16 => 0xffff,
+            FatType::Fat32 => 0x0fff_ffff,
+        }
+    }
+
+    fn is_end_of_chain(self, value: u32) -> bool {
+        value >= self.end_of_chain() - 7
+    }
+
+    /// FATの中の `cluster` の項目のバイト位置
+    fn entry_offset(self, cluster: u32) -> u64 {
+        let cluster = u64::from(cluster);
+        match self {
+            FatType::Fat12 => cluster + cluster / 2,
+            FatType::Fat16 => cluster * 2,
+            FatType::Fat32 => cluster * 4,
+        }
+    }
+
+    /// `clusters` 個の項目 (先頭の予約2つを含めない) に要るFATのバイト数
+    fn table_size(self, clusters: u32) -> u64 {
+        self.entry_offset(clusters + 2) + 1
+    }
+}
+
+/// ボリュームの配置。位置はすべてセクタ番号
+#[derive(Debug, Clone, Copy)]
+struct Geometry {
+    fat_type: FatType,
+    sector_size: u64,
+    /// 1セクタがデバイスの何ブロックか
+    blocks_per_sector: u64,
+    sectors_per_cluster: u64,
+    fat_start: u64,
+    fat_sectors: u64,
+    fat_count: u64,
+    /// FAT12/16の大きさが決まったルートディレクトリ
+    root_start: u64,
+    root_sectors: u64,
+    data_start: u64,
+    /// クラスタ番号は2から `cluster_count + 1` まで
+    cluster_count: u32,
+    /// FAT32のルートディレクトリの最初のクラスタ。FAT12/16では0
+    root_cluster: u32,
+    fs_info: Option<u64>,
+}
+
+impl Geometry {
+    /// ブートセクタのBPBを読む
+    fn parse(boot: &[u8], block_size: usize, block_count: u64) -> Result<Geometry, FsError> {
+        let invalid = FsError::Corrupted("fat: invalid boot sector");
+        let u16_at =
+            |offset: usize| u64::from(u16::from_le_bytes([boot[offset], boot[offset + 1]]));
+        let u32_at = |offset: usize| {
+            u64::from(u32::from_le_bytes(
+                boot[offset..offset + 4].try_into().unwrap(),
+            ))
+        };
+        if boot[510..512] != [0x55, 0xaa] {
+            return Err(invalid);
+        }
+        let sector_size = u16_at(11);
+        let sectors_per_cluster = u64::from(boot[13]);
+        let reserved = u16_at(14);
+        let fat_count = u64::from(boot[16]);
+        let root_entries = u16_at(17);
+        let total_sectors = match u16_at(19) {
+            0 => u32_at(32),
+            count => count,
+        };
+        let fat_sectors = match u16_at(22) {
+            0 => u32_at(36),
+            count => count,
+        };
+        if !(512..=4096).contains(&sector_size)
+            || !sector_size.is_power_of_two()
+            || !sector_size.is_multiple_of(block_size as u64)
+            || !sectors_per_cluster.is_power_of_two()
+            || reserved == 0
+            || fat_count == 0
+            || fat_sectors == 0
+        {
+            return Err(invalid);
+        }
+
+        let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+        let root_start = reserved + fat_count * fat_sectors;
+        let data_start = root_start + root_sectors;
+        let blocks_per_sector = sector_size / block_size as u64;
+        if total_sectors <= data_start || total_sectors * blocks_per_sector > block_count {
+            return Err(invalid);
+        }
+        let cluster_count = u32::try_from((total_sectors - data_start) / sectors_per_cluster)
+            .map_err(|_| invalid)?;
+        let fat_type = FatType::from_clusters(cluster_count);
+        if fat_type.table_size(cluster_count) > fat_sectors * sector_size {
+            return Err(FsError::Corrupted("fat: allocation table is too small"));
+        }
+        let (root_cluster, fs_info) = if fat_type == FatType::Fat32 {
+            if root_entries != 0 {
+                return Err(invalid);
+            }
+            let fs_info = match u16_at(48) {
+                0 | 0xffff => None,
+                sector => Some(sector),
+            };
+            (u32_at(44) as u32, fs_info)
+        } else {
+            (0, None)
+        };
+        Ok(Geometry {
+            fat_type,
+            sector_size,
+            blocks_per_sector,
+            sectors_per_cluster,
+            fat_start: reserved,
+            fat_sectors,
+            fat_count,
+            root_start,
+            root_sectors,
+            data_start,
+            cluster_count,
+            root_cluster,
+            fs_info,
+        })
+    }
+
+    fn cluster_size(&self) -> u64 {
+        self.sector_size * self.sectors_per_cluster
+    }
+
+    /// クラスタの先頭のボリューム上のバイト位置
+    fn cluster_position(&self, cluster: u32) -> u64 {
+        (self.data_start + u64::from(cluster - 2) * self.sectors_per_cluster) * self.sector_size
+    }
+
+    fn is_valid_cluster(&self, cluster: u32) -> bool {
+        (2..self.cluster_count + 2).contains(&cluster)
+    }
+}
+
+/// ディレクトリエントリのうち、短い名前のエントリの中身
+trait ShortEntry {
+    fn attributes(&self) -> u8;
+    fn first_cluster(&self) -> u32;
+    fn size(&self) -> u32;
+    fn modified(&self) -> u64;
+}
+
+impl ShortEntry for [u8; ENTRY_SIZE] {
+    fn attributes(&self) -> u8 {
+        self[11]
+    }
+
+    fn first_cluster(&self) -> u32 {
+        let high = u16::from_le_bytes([self[20], self[21]]);
+        let low = u16::from_le_bytes([self[26], self[27]]);
+        (u32::from(high) << 16) | u32::from(low)
+    }
+
+    fn size(&self) -> u32 {
+        u32::from_le_bytes(self[28..32].try_into().unwrap())
+    }
+
+    fn modified(&self) -> u64 {
+        let time = u16::from_le_bytes([self[22], self[23]]);
+        let date = u16::from_le_bytes([self[24], self[25]]);
+        from_fat_time(time, date)
+    }
+}
+
+/// 名前以外を埋めた短い名前のエントリ
+fn short_entry(attributes: u8, cluster: u32, size: u32, modified: u64) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[11] = attributes;
+    set_entry_cluster(&mut entry, cluster);
+    let (time, date) = to_fat_time(modified);
+    for offset in [14, 22] {
+        entry[offset..offset + 2].copy_from_slice(&time.to_le_bytes());
+    }
+    for offset in [16, 18, 24] {
+        entry[offset..offset + 2].copy_from_slice(&date.to_le_bytes());
+    }
+    entry[28..32].copy_from_slice(&size.to_le_bytes());
+    entry
+}
+
+fn set_entry_cluster(entry: &mut [u8; ENTRY_SIZE], cluster: u32) {
+    entry[20..22].copy_from_slice(&((cluster >> 16) as u16).to_le_bytes());
+    entry[26..28].copy_from_slice(&(cluster as u16).to_le_bytes());
+}
+
+/// ディレクトリから読んだ名前1つ
+struct RawEntry {
+    name: String,
+    short_name: [u8; 11],
+    /// 長い名前のエントリを含めて使っている位置。最後が短い名前のエントリ
+    slots: Vec<u64>,
+    data: [u8; ENTRY_SIZE],
+}
+
+impl RawEntry {
+    fn position(&self) -> u64 {
+        *self
+            .slots
+            .last()
+            .expect("an entry has at least its short name")
+    }
+
+    fn is_directory(&self) -> bool {
+        self.data.attributes() & ATTR_DIRECTORY != 0
+    }
+
+    fn matches(&self, name: &str) -> bool {
+        names_equal(&self.name, name)
+            || names_equal(&short_name_string(&self.short_name, self.data[12]), name)
+    }
+}
+
+/// 組み立て中の長い名前
+struct LongName {
+    units: Vec<u16>,
+    checksum: u8,
+    /// 次に来るはずの順番
+    next: u8,
+    slots: Vec<u64>,
+}
+
+/// ディレクトリのエントリを並べ、長い名前を組み立てる。`.` と `..` は除く
+fn parse_directory(slots: &[(u64, [u8; ENTRY_SIZE])]) -> Vec<RawEntry> {
+    let mut entries = Vec::new();
+    let mut long: Option<LongName> = None;
+    for &(position, data) in slots {
+        match data[0] {
+            ENTRY_END => break,
+            ENTRY_FREE => {
+                long = None;
+                continue;
+            }
+            _ => {}
+        }
+        if data[11] & 0x3f == ATTR_LONG_NAME {
+            let order = data[0] & 0x1f;
+            if data[0] & LFN_LAST != 0 {
+                long = Some(LongName {
+                    units: vec![0xffff; usize::from(order) * LFN_CHARS],
+                    checksum: data[13],
+                    next: order,
+                    slots: Vec::new(),
+                });
+            }
+            long =
+                long.filter(|long| order != 0 && order == long.next && data[13] == long.checksum);
+            if let Some(long) = &mut long {
+                let start = usize::from(order - 1) * LFN_CHARS;
+                for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+                    long.units[start + i] = u16::from_le_bytes([data[offset], data[offset + 1]]);
+                }
+                long.next -= 1;
+                long.slots.push(position);
+            }
+            continue;
+        }
+        let long_name = long.take();
+        if data[11] & ATTR_VOLUME_ID != 0 {
+            continue;
+        }
+        let short_name: [u8; 11] = data[..11].try_into().unwrap();
+        if short_name[0] == b'.' {
+            continue;
+        }
+        let (name, mut slots) = match long_name {
+            Some(long) if long.next == 0 && long.checksum == checksum(&short_name) => {
+                let end = long
+                    .units
+                    .iter()
+                    .position(|&unit| unit == 0)
+                    .unwrap_or(long.units.len());
+                let name = char::decode_utf16(long.units[..end].iter().copied())
+                    .map(|c| c.unwrap_or(char::REPLACEMENT_CHARACTER))
+                    .collect();
+                (name, long.slots)
+            }
+            _ => (short_name_string(&short_name, data[12]), Vec::new()),
+        };
+        slots.push(position);
+        entries.push(RawEntry {
+            name,
+            short_name,
+            slots,
+            data,
+        });
+    }
+    entries
+}
+
+/// 長い名前のエントリ1つ。`units` はこのエントリに入る分 (13以下)
+fn long_entry(order: u8, last: bool, checksum: u8, units: &[u16]) -> [u8; ENTRY_SIZE] {
+    let mut entry = [0u8; ENTRY_SIZE];
+    entry[0] = order | if last { LFN_LAST } else { 0 };
+    entry[11] = ATTR_LONG_NAME;
+    entry[13] = checksum;
+    for (i, &offset) in LFN_OFFSETS.iter().enumerate() {
+        // 名前の後は0を1つ置き、残りは0xffffで埋める
+        let unit = match i.cmp(&units.len()) {
+            core::cmp::Ordering::Less => units[i],
+            core::cmp::Ordering::Equal => 0,
+            core::cmp::Ordering::Greater => 0xffff,
+        };
+        entry[offset..offset + 2].copy_from_slice(&unit.to_le_bytes());
+    }
+    entry
+}
+
+/// 長い名前のエントリが持つ、短い名前のチェックサム
+fn checksum(short_name: &[u8; 11]) -> u8 {
+    short_name
+        .iter()
+        .fold(0u8, |sum, &byte| sum.rotate_right(1).wrapping_add(byte))
+}
+
+/// 短い名前に使える文字 (大文字にした後)
+fn is_short_char(byte: u8) -> bool {
+    byte.is_ascii_uppercase() || byte.is_ascii_digit() || b"$%'-_@~`!(){}^#&".contains(&byte)
+}
+
+/// `name` から作る短い名前と、それがそのまま使えるときは大文字小文字のフラグ
+///
+/// そのまま使えなければ `with_tail` で `~1` などを付けて長い名前と一緒に書く
+fn short_name(name: &str) -> ([u8; 11], Option<u8>) {
+    let (base, extension) = match name.rfind('.') {
+        Some(dot) if dot > 0 => (&name[..dot], &name[dot + 1..]),
+        _ => (name, ""),
+    };
+    let exact_part = |part: &str, max: usize, lower_flag: u8| {
+        if part.len() > max
+            || !part
+                .bytes()
+                .all(|byte| is_short_char(byte.to_ascii_uppercase()))
+        {
+            return None;
+        }
+        let lower = part.bytes().any(|byte| byte.is_ascii_lowercase());
+        let upper = part.bytes().any(|byte| byte.is_ascii_uppercase());
+        match (lower, upper) {
+            (true, true) => None,
+            (true, false) => Some(lower_flag),
+            _ => Some(0),
+        }
+    };
+    let exact = match (
+        exact_part(base, 8, CASE_LOWER_BASE),
+        exact_part(extension, 3, CASE_LOWER_EXT),
+    ) {
+        (Some(base_case), Some(extension_case)) if !base.is_empty() => {
+            Some(base_case | extension_case)
+        }
+        _ => None,
+    };
+
+    let mut raw = [b' '; 11];
+    let fill = |target: &mut [u8], part: &str| {
+        let characters = part.chars().filter(|&c| c != '.' && c != ' ');
+        for (slot, c) in target.iter_mut().zip(characters) {
+            let upper = c.to_ascii_uppercase();
+            *slot = if upper.is_ascii() && is_short_char(upper as u8) {
+                upper as u8
+            } else {
+                b'_'
+            };
+        }
+    };
+    fill(&mut raw[..8], base);
+    fill(&mut raw[8..], extension);
+    (raw, exact)
+}
+
+/// 短い名前の本体の終わりに `~number` を付ける
+fn with_tail(basis: &[u8; 11], number: u32) -> [u8; 11] {
+    let mut digits = [0u8; 10];
+    let mut length = 0;
+    let mut rest = number;
+    loop {
+        digits[length] = b'0' + (rest % 10) as u8;
+        rest /= 10;
+        length += 1;
+        if rest == 0 {
+            break;
+        }
+    }
+    let base_length = basis[..8]
+        .iter()
+        .position(|&byte| byte == b' ')
+        .unwrap_or(8)
+        .min(8 - length - 1);
+    let mut raw = *basis;
+    raw[base_length] = b'~';
+    for i in 0..length {
+        raw[base_length + 1 + i] = digits[length - 1 - i];
+    }
+    raw[base_length + 1 + length..8].fill(b' ');
+    raw
+}
+
+/// 短い名前を `NAME.EXT` の形にする
+fn short_name_string(raw: &[u8; 11], case: u8) -> String {
+    let part = |bytes: &[u8], lower: bool| -> String {
+        let end = bytes
+            .iter()
+            .rposition(|&byte| byte != b' ')
+            .map_or(0, |i| i + 1);
+        bytes[..end]
+            .iter()
+            .enumerate()
+            .map(|(i, &byte)| {
+                // 最初のバイトが0xe5の名前は消されたエントリと区別するため0x05で書かれる
+                let byte = if i == 0 && byte == 0x05 { 0xe5 } else { byte };
+                let c = char::from(byte);
+                if lower { c.to_ascii_lowercase() } else { c }
+            })
+            .collect()
+    };
+    let mut name = part(&raw[..8], case & CASE_LOWER_BASE != 0);
+    let extension = part(&raw[8..], case & CASE_LOWER_EXT != 0);
+    if !extension.is_empty() {
+        name.push('.');
+        name.push_str(&extension);
+    }
+    name
+}
+
+/// FATの名前は大文字小文字を区別しない
+fn names_equal(a: &str, b: &str) -> bool {
+    a.chars()
+        .flat_map(char::to_uppercase)
+        .eq(b.chars().flat_map(char::to_uppercase))
+}
+
+/// 長い名前として書けるか確かめる
+fn check_name(name: &str) -> Result<(), FsError> {
+    if name.is_empty()
+        || name.encode_utf16().count() > MAX_NAME_LENGTH
+        || name.ends_with(['.', ' '])
+        || name.chars().any(|c| c < ' ' || "\"*/:<>?\\|".contains(c))
+    {
+        return Err(FsError::InvalidPath);
+    }
+    Ok(())
+}
+
+/// FATの日付と時刻をUNIX時間にする。日付が0なら0
+fn from_fat_time(time: u16, date: u16) -> u64 {
+    let month = ((date >> 5) & 0xf) as u8;
+    let day = (date & 0x1f) as u8;
+    if !(1..=12).contains(&month) || day == 0 {
+        return 0;
+    }
+    DateTime {
+        year: 1980 + (date >> 9),
+        month,
+        day,
+        hour: (time >> 11) as u8,
+        minute: ((time >> 5) & 0x3f) as u8,
+        second: ((time & 0x1f) * 2) as u8,
+    }
+    .unix_timestamp()
+}
+
+/// UNIX時間をFATの (時刻, 日付) にする。表せない過去は1980-01-01にする
+fn to_fat_time(timestamp: u64) -> (u16, u16) {
+    let time = DateTime::from_unix_timestamp(timestamp);
+    if time.year < 1980 {
+        return (0, (1 << 5) | 1);
+    }
+    let date =
+        ((time.year - 1980).min(127) << 9) | (u16::from(time.month) << 5) | u16::from(time.day);
+    let clock =
+        (u16::from(time.hour) << 11) | (u16::from(time.minute) << 5) | u16::from(time.second / 2);
+    (clock, date)
+}
+
+fn now() -> u64 {
+    rtc::now().unix_timestamp()
+}
+
+/// ファイルかディレクトリ1つ
+struct Node {
+    /// 短い名前のエントリのボリューム上のバイト位置。ルートは `None`
+    entry: Option<u64>,
+    parent: u64,
+    attributes: u8,
+    first_cluster: u32,
+    size: u32,
+    modified: u64,
+    /// 最後にたどったクラスタ (チェーンの何番目か, クラスタ番号)
+    cursor: Option<(u32, u32)>,
+}
+
+impl Node {
+    fn is_directory(&self) -> bool {
+        self.attributes & ATTR_DIRECTORY != 0
+    }
+}
+
+struct Inner {
+    device: SharedBlockDevice,
+    geometry: Geometry,
+    nodes: BTreeMap<u64, Node>,
+    /// 短い名前のエントリの位置からinode番号を引く
+    by_entry: BTreeMap<u64, u64>,
+    next_inode: u64,
+    /// 次に空きを探し始めるクラスタ
+    next_free: u32,
+    /// 空きクラスタの数。わからなければ `None`
+    free_clusters: Option<u32>,
+    /// FSInfoに書き出していない変更がある
+    fs_info_dirty: bool,
+}
+
+impl Inner {
+    fn read_sectors(&self, sector: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let block = sector * self.geometry.blocks_per_sector;
+        Ok(self.device.lock().read_blocks(block, buffer)?)
+    }
+
+    fn write_sectors(&self, sector: u64, buffer: &[u8]) -> Result<(), FsError> {
+        let block = sector * self.geometry.blocks_per_sector;
+        Ok(self.device.lock().write_blocks(block, buffer)?)
+    }
+
+    /// ボリュームの `position` バイト目から読む。セクタの途中でもよい
+    fn read_bytes(&self, position: u64, buffer: &mut [u8]) -> Result<(), FsError> {
+        let sector_size = self.geometry.sector_size;
+        let mut scratch = Vec::new();
+        let mut done = 0;
+        while done < buffer.len() {
+            let current = position + done as u64;
+            let sector = current / sector_size;
+            let within = (current % sector_size) as usize;
+            let remaining = buffer.len() - done;
+            if within == 0 && remaining >= sector_size as usize {
+                let whole = remaining - remaining % sector_size as usize;
+                self.read_sectors(sector, &mut buffer[done..done + whole])?;
+                done += whole;
+            } else {
+                scratch.resize(sector_size as usize, 0);
+                self.read_sectors(sector, &mut scratch)?;
+                let count = remaining.min(sector_size as usize - within);
+                buffer[done..done + count].copy_from_slice(&scratch[within..within + count]);
+                done += count;
+            }
+        }
+        Ok(())
+    }
+
+    /// ボリュームの `position` バイト目に書く。セクタの途中なら読んでから書き戻す
+    fn write_bytes(&self, position: u64, data: &[u8]) -> Result<(), FsError> {
+        let sector_size = self.geometry.sector_size;
+        let mut scratch = Vec::new();
+        let mut done = 0;
+        while done < data.len() {
+            let current = position + done as u64;
+            let sector = current / sector_size;
+            let within = (current % sector_size) as usize;
+            let remaining = data.len() - done;
+            if within == 0 && remaining >= sector_size as usize {
+                let whole = remaining - remaining % sector_size as usize;
+                self.write_sectors(sector, &data[done..done + whole])?;
+                done += whole;
+            } else {
+                scratch.resize(sector_size as usize, 0);
+                self.read_sectors(sector, &mut scratch)?;
+                let count = remaining.min(sector_size as usize - within);
+                scratch[within..within + count].copy_from_slice(&data[done..done + count]);
+                self.write_sectors(sector, &scratch)?;
+                done += count;
+            }
+        }
+        Ok(())
+    }
+
+    fn read_entry(&self, position: u64) -> Result<[u8; ENTRY_SIZE], FsError> {
+        let mut entry = [0u8; ENTRY_SIZE];
+        self.read_bytes(position, &mut entry)?;
+        Ok(entry)
+    }
+
+    /// FATの `cluster` の項目
+    fn fat_entry(&self, cluster: u32) -> Result<u32, FsError> {
+        let geometry = &self.geometry;
+        let position =
+            geometry.fat_start * geometry.sector_size + geometry.fat_type.entry_offset(cluster);
+        Ok(match geometry.fat_type {
+            FatType::Fat12 => {
+                let mut bytes = [0u8; 2];
+                self.read_bytes(position, &mut bytes)?;
+                let value = u16::from_le_bytes(bytes);
+                u32::from(if cluster & 1 == 1 {
+                    value >> 4
+                } else {
+                    value & 0xfff
+                })
+            }
+            FatType::Fat16 => {
+                let mut bytes = [0u8; 2];
+                self.read_bytes(position, &mut bytes)?;
+                u32::from(u16::from_le_bytes(bytes))
+            }
+            FatType::Fat32 => {
+                let mut bytes = [0u8; 4];
+                self.read_bytes(position, &mut bytes)?;
+                u32::from_le_bytes(bytes) & 0x0fff_ffff
+            }
+        })
+    }
+
+    /// FATの `cluster` の項目を書く。FATの写しすべてに書く
+    fn set_fat_entry(&self, cluster: u32, value: u32) -> Result<(), FsError> {
+        let geometry = &self.geometry;
+        for copy in 0..geometry.fat_count {
+            let position = (geometry.fat_start + copy * geometry.fat_sectors)
+                * geometry.sector_size
+                + geometry.fat_type.entry_offset(cluster);
+            match geometry.fat_type {
+                FatType::Fat12 => {
+                    // 12ビットの項目は隣の項目とバイトを分け合う
+                    let mut bytes = [0u8; 2];
+                    self.read_bytes(position, &mut bytes)?;
+                    let old = u16::from_le_bytes(bytes);
+                    let value = value as u16 & 0xfff;
+                    let new = if cluster & 1 == 1 {
+                        (old & 0x000f) | (value << 4)
+                    } else {
+                        (old & 0xf000) | value
+                    };
+                    self.write_bytes(position, &new.to_le_bytes())?;
+                }
+                FatType::Fat16 => self.write_bytes(position, &(value as u16).to_le_bytes())?,
+                FatType::Fat32 => {
+                    // 上の4ビットは予約なので残す
+                    let mut bytes = [0u8; 4];
+                    self.read_bytes(position, &mut bytes)?;
+                    let old = u32::from_le_bytes(bytes);
+                    let new = (old & 0xf000_0000) | (value & 0x0fff_ffff);
+                    self.write_bytes(position, &new.to_le_bytes())?;
+                }
+            }
+        }
+        Ok(())
+    }
+
+    /// チェーンの次のクラスタ。終わりなら `None`
+    fn next_cluster(&self, cluster: u32) -> Result<Option<u32>, FsError> {
+        let value = self.fat_entry(cluster)?;
+        if self.geometry.fat_type.is_end_of_chain(value) {
+            Ok(None)
+        } else if self.geometry.is_valid_cluster(value) {
+            Ok(Some(value))
+        } else {
+            Err(FsError::Corrupted("fat: broken cluster chain"))
+        }
+    }
+
+    /// 空きクラスタを1つ取り、`previous` があればその後ろにつなぐ
+    fn allocate_cluster(&mut self, previous: Option<u32>, zero: bool) -> Result<u32, FsError> {
+        let count = self.geometry.cluster_count;
+        let start = if self.geometry.is_valid_cluster(self.next_free) {
+            self.next_free
+        } else {
+            2
+        };
+        let mut cluster = start;
+        loop {
+            if self.fat_entry(cluster)? == 0 {
+                break;
+            }
+            cluster = if cluster == count + 1 { 2 } else { cluster + 1 };
+            if cluster == start {
+                return Err(FsError::NoSpace);
+            }
+        }
+        self.set_fat_entry(cluster, self.geometry.fat_type.end_of_chain())?;
+        if zero {
+            let zeros = vec![0u8; self.geometry.cluster_size() as usize];
+            self.write_bytes(self.geometry.cluster_position(cluster), &zeros)?;
+        }
+        if let Some(previous) = previous {
+            self.set_fat_entry(previous, cluster)?;
+        }
+        self.next_free = cluster + 1;
+        self.free_clusters = self.free_clusters.map(|free| free.saturating_sub(1));
+        self.fs_info_dirty = true;
+        Ok(cluster)
+    }
+
+    /// `first` から始まるチェーンをすべて空きにする
+    fn free_chain(&mut self, first: u32) -> Result<(), FsError> {
+        let mut cluster = Some(first).filter(|&cluster| self.geometry.is_valid_cluster(cluster));
+        let mut freed = 0;
+        while let Some(current) = cluster {
+            freed += 1;
+            if freed > self.geometry.cluster_count {
+                return Err(FsError::Corrupted("fat: cluster chain loops"));
+            }
+            cluster = self.next_cluster(current)?;
+            self.set_fat_entry(current, 0)?;
+            self.free_clusters = self.free_clusters.map(|free| free + 1);
+            self.next_free = self.next_free.min(current);
+        }
+        self.fs_info_dirty = true;
+        Ok(())
+    }
+
+    fn node(&self, inode: u64) -> Result<&Node, FsError> {
+        self.nodes.get(&inode).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, inode: u64) -> Result<&mut Node, FsError> {
+        self.nodes.get_mut(&inode).ok_or(FsError::NotFound)
+    }
+
+    /// ファイルの `index` 番目のクラスタ。`allocate` なら足りない分をつないで伸ばす
+    fn cluster_at(
+        &mut self,
+        inode: u64,
+        index: u32,
+        allocate: bool,
+    ) -> Result<Option<u32>, FsError> {
+        let node = self.node(inode)?;
+        let (mut current, mut cluster) = match (node.cursor, node.first_cluster) {
+            (Some((at, cluster)), _) if at <= index => (at, cluster),
+            (_, 0) if !allocate => return Ok(None),
+            (_, 0) => {
+                let cluster = self.allocate_cluster(None, false)?;
+                self.node_mut(inode)?.first_cluster = cluster;
+                (0, cluster)
+            }
+            (_, first) => (0, first),
+        };
+        while current < index {
+            cluster = match self.next_cluster(cluster)? {
+                Some(next) => next,
+                None if allocate => self.allocate_cluster(Some(cluster), false)?,
+                None => return Ok(None),
+            };
+            current += 1;
+        }
+        self.node_mut(inode)?.cursor = Some((index, cluster));
+        Ok(Some(cluster))
+    }
+
+    /// ノードの属性、最初のクラスタ、大きさ、時刻をディレクトリエントリに書き戻す
+    fn store(&self, inode: u64) -> Result<(), FsError> {
+        let node = self.node(inode)?;
+        let Some(position) = node.entry else {
+            return Ok(());
+        };
+        let mut entry = self.read_entry(position)?;
+        entry[11] = node.attributes;
+        set_entry_cluster(&mut entry, node.first_cluster);
+        let (time, date) = to_fat_time(node.modified);
+        entry[22..24].copy_from_slice(&time.to_le_bytes());
+        entry[24..26].copy_from_slice(&date.to_le_bytes());
+        entry[18..20].copy_from_slice(&date.to_le_bytes());
+        entry[28..32].copy_from_slice(&node.size.to_le_bytes());
+        self.write_bytes(position, &entry)
+    }
+
+    /// ディレクトリのエントリの位置と中身をすべて読む
+    fn directory_slots(&self, dir: u64) -> Result<Vec<(u64, [u8; ENTRY_SIZE])>, FsError> {
+        let node = self.node(dir)?;
+        if !node.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let geometry = self.geometry;
+        let mut slots = Vec::new();
+        let mut push_region = |position: u64, bytes: &[u8]| {
+            for (i, chunk) in bytes.chunks_exact(ENTRY_SIZE).enumerate() {
+                slots.push((
+                    position + (i * ENTRY_SIZE) as u64,
+                    chunk.try_into().unwrap(),
+                ));
+            }
+        };
+        if node.first_cluster == 0 {
+            if dir != ROOT || geometry.fat_type == FatType::Fat32 {
+                return Err(FsError::Corrupted("fat: directory without clusters"));
+            }
+            let mut region = vec![0u8; (geometry.root_sectors * geometry.sector_size) as usize];
+            self.read_sectors(geometry.root_start, &mut region)?;
+            push_region(geometry.root_start * geometry.sector_size, &region);
+            return Ok(slots);
+        }
+        let mut buffer = vec![0u8; geometry.cluster_size() as usize];
+        let mut cluster = Some(node.first_cluster);
+        let mut visited = 0;
+        while let Some(current) = cluster {
+            // 輪になったチェーンで止まらないようにする
+            visited += 1;
+            if visited > geometry.cluster_count {
+                return Err(FsError::Corrupted("fat: cluster chain loops"));
+            }
+            let position = geometry.cluster_position(current);
+            self.read_bytes(position, &mut buffer)?;
+            push_region(position, &buffer);
+            cluster = self.next_cluster(current)?;
+        }
+        Ok(slots)
+    }
+
+    fn entries(&self, dir: u64) -> Result<Vec<RawEntry>, FsError> {
+        Ok(parse_directory(&self.directory_slots(dir)?))
+    }
+
+    fn find(&self, dir: u64, name: &str) -> Result<Option<RawEntry>, FsError> {
+        Ok(self
+            .entries(dir)?
+            .into_iter()
+            .find(|entry| entry.matches(name)))
+    }
+
+    /// エントリのinode番号。初めて見るエントリなら振る
+    ///
+    /// ディスクから読んだ最初のクラスタはここで確かめ、範囲外なら使う前に壊れているとする
+    fn inode_for(&mut self, dir: u64, entry: &RawEntry) -> Result<u64, FsError> {
+        let position = entry.position();
+        if let Some(&inode) = self.by_entry.get(&position) {
+            return Ok(inode);
+        }
+        let first_cluster = entry.data.first_cluster();
+        if first_cluster != 0 && !self.geometry.is_valid_cluster(first_cluster) {
+            return Err(FsError::Corrupted("fat: invalid first cluster"));
+        }
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.nodes.insert(
+            inode,
+            Node {
+                entry: Some(position),
+                parent: dir,
+                attributes: entry.data.attributes(),
+                first_cluster,
+                size: entry.data.size(),
+                modified: entry.data.modified(),
+                cursor: None,
+            },
+        );
+        self.by_entry.insert(position, inode);
+        Ok(inode)
+    }
+
+    /// `dir` に `name` のエントリを書き、短い名前のエントリの位置を返す
+    ///
+    /// `template` は名前以外の中身。`replacing` の位置のエントリとは名前が重なってもよい
+    fn add_entry(
+        &mut self,
+        dir: u64,
+        name: &str,
+        template: [u8; ENTRY_SIZE],
+        replacing: Option<u64>,
+    ) -> Result<u64, FsError> {
+        check_name(name)?;
+        let mut slots = self.directory_slots(dir)?;
+        let entries = parse_directory(&slots);
+        if entries
+            .iter()
+            .any(|entry| Some(entry.position()) != replacing && entry.matches(name))
+        {
+            return Err(FsError::AlreadyExists);
+        }
+
+        let (basis, exact) = short_name(name);
+        let (short, case, units) = match exact {
+            Some(case) => (basis, case, Vec::new()),
+            None => {
+                let short = (1..1_000_000)
+                    .map(|number| with_tail(&basis, number))
+                    .find(|candidate| entries.iter().all(|entry| entry.short_name != *candidate))
+                    .ok_or(FsError::NoSpace)?;
+                (short, 0, name.encode_utf16().collect())
+            }
+        };
+        let long_count = units.len().div_ceil(LFN_CHARS);
+        let needed = long_count + 1;
+
+        // 連続した空きを探し、なければディレクトリを1クラスタずつ伸ばす
+        let start = loop {
+            let end_marker = slots.iter().position(|(_, data)| data[0] == ENTRY_END);
+            let is_free =
+                |i: usize| end_marker.is_some_and(|end| i >= end) || slots[i].1[0] == ENTRY_FREE;
+            let mut run = 0;
+            let found = (0..slots.len()).find(|&i| {
+                run = if is_free(i) { run + 1 } else { 0 };
+                run == needed
+            });
+            if let Some(last) = found {
+                let start = last + 1 - needed;
+                // 終わりの印を使ったら、すぐ後ろに印を置き直す
+                if end_marker.is_some_and(|end| last >= end) && last + 1 < slots.len() {
+                    self.write_bytes(slots[last + 1].0, &[ENTRY_END])?;
+                }
+                break start;
+            }
+            let first = self.node(dir)?.first_cluster;
+            if first == 0 {
+                return Err(FsError::NoSpace);
+            }
+            let mut last = first;
+            while let Some(next) = self.next_cluster(last)? {
+                last = next;
+            }
+            let cluster = self.allocate_cluster(Some(last), true)?;
+            let position = self.geometry.cluster_position(cluster);
+            let count = self.geometry.cluster_size() as usize / ENTRY_SIZE;
+            slots.extend(
+                (0..count).map(|i| (position + (i * ENTRY_SIZE) as u64, [0u8; ENTRY_SIZE])),
+            );
+        };
+
+        let sum = checksum(&short);
+        for i in 0..long_count {
+            let order = long_count - i;
+            let chunk = &units[(order - 1) * LFN_CHARS..units.len().min(order * LFN_CHARS)];
+            let entry = long_entry(order as u8, i == 0, sum, chunk);
+            self.write_bytes(slots[start + i].0, &entry)?;
+        }
+        let mut entry = template;
+        entry[..11].copy_from_slice(&short);
+        if entry[0] == ENTRY_FREE {
+            entry[0] = 0x05;
+        }
+        entry[12] = case;
+        let position = slots[start + long_count].0;
+        self.write_bytes(position, &entry)?;
+        Ok(position)
+    }
+
+    /// エントリを消す。クラスタはそのまま
+    fn remove_entry(&mut self, entry: &RawEntry) -> Result<(), FsError> {
+        for &slot in &entry.slots {
+            self.write_bytes(slot, &[ENTRY_FREE])?;
+        }
+        if let Some(inode) = self.by_entry.remove(&entry.position()) {
+            self.nodes.remove(&inode);
+        }
+        Ok(())
+    }
+
+    /// エントリを消し、中身のクラスタも空きにする
+    fn delete(&mut self, entry: &RawEntry) -> Result<(), FsError> {
+        self.remove_entry(entry)?;
+        self.free_chain(entry.data.first_cluster())
+    }
+
+    /// ディレクトリに `.` と `..` 以外がなければ真
+    fn is_empty_directory(&self, dir: u64) -> Result<bool, FsError> {
+        Ok(self.entries(dir)?.is_empty())
+    }
+
+    /// サブディレクトリの `..` に書く、親のクラスタ。ルートは0と書く決まり
+    fn parent_cluster(&self, dir: u64) -> Result<u32, FsError> {
+        if dir == ROOT {
+            Ok(0)
+        } else {
+            Ok(self.node(dir)?.first_cluster)
+        }
+    }
+
+    /// ファイルの `offset` から書く。途中で空きがなくなれば書けた分を返す
+    fn write_data(&mut self, inode: u64, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let cluster_size = self.geometry.cluster_size();
+        let mut done = 0;
+        while done < data.len() {
+            let current = offset + done as u64;
+            let index = (current / cluster_size) as u32;
+            let within = current % cluster_size;
+            let cluster = match self.cluster_at(inode, index, true) {
+                Ok(cluster) => cluster.expect("allocating never runs out of chain"),
+                Err(FsError::NoSpace) if done > 0 => break,
+                Err(err) => return Err(err),
+            };
+            let count = (data.len() - done).min((cluster_size - within) as usize);
+            let position = self.geometry.cluster_position(cluster) + within;
+            self.write_bytes(position, &data[done..done + count])?;
+            done += count;
+        }
+        Ok(done)
+    }
+
+    /// ファイルの `from` から `to` までを0で埋める
+    fn zero_fill(&mut self, inode: u64, from: u64, to: u64) -> Result<(), FsError> {
+        let zeros = vec![0u8; (to - from).min(self.geometry.cluster_size()) as usize];
+        let mut current = from;
+        while current < to {
+            let count = (to - current).min(zeros.len() as u64) as usize;
+            let written = self.write_data(inode, current, &zeros[..count])?;
+            self.node_mut(inode)?.size = (current + written as u64) as u32;
+            if written < count {
+                return Err(FsError::NoSpace);
+            }
+            current += count as u64;
+        }
+        Ok(())
+    }
+
+    /// FAT32ならFSInfoに空きクラスタの数と次の空きを書く
+    fn write_fs_info(&mut self) -> Result<(), FsError> {
+        let Some(sector) = self.geometry.fs_info else {
+            return Ok(());
+        };
+        if !self.fs_info_dirty {
+            return Ok(());
+        }
+        let position = sector * self.geometry.sector_size;
+        let mut values = [0u8; 8];
+        values[..4].copy_from_slice(&self.free_clusters.unwrap_or(FS_INFO_UNKNOWN).to_le_bytes());
+        values[4..].copy_from_slice(&self.next_free.to_le_bytes());
+        self.write_bytes(position + 488, &values)?;
+        self.fs_info_dirty = false;
+        Ok(())
+    }
+}
+
+/// FATのボリューム
+pub struct FatFs {
+    inner: Mutex<Inner>,
+}
+
+impl FatFs {
+    /// `device` のFATボリュームを読む
+    pub fn new(device: SharedBlockDevice) -> Result<FatFs, FsError> {
+        let (block_size, block_count) = {
+            let device = device.lock();
+            (device.block_size(), device.block_count())
+        };
+        let mut boot = vec![0u8; 512usize.div_ceil(block_size) * block_size];
+        device.lock().read_blocks(0, &mut boot)?;
+        let geometry = Geometry::parse(&boot, block_size, block_count)?;
+
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            ROOT,
+            Node {
+                entry: None,
+                parent: ROOT,
+                attributes: ATTR_DIRECTORY,
+                first_cluster: geometry.root_cluster,
+                size: 0,
+                modified: 0,
+                cursor: None,
+            },
+        );
+        let mut inner = Inner {
+            device,
+            geometry,
+            nodes,
+            by_entry: BTreeMap::new(),
+            next_inode: ROOT + 1,
+            next_free: 2,
+            free_clusters: None,
+            fs_info_dirty: false,
+        };
+        if let Some(sector) = geometry.fs_info {
+            let mut info = vec![0u8; geometry.sector_size as usize];
+            inner.read_sectors(sector, &mut info)?;
+            let u32_at =
+                |offset: usize| u32::from_le_bytes(info[offset..offset + 4].try_into().unwrap());
+            if u32_at(0) == FS_INFO_LEAD
+                && u32_at(484) == FS_INFO_STRUCT
+                && u32_at(508) == FS_INFO_TRAIL
+            {
+                let free = u32_at(488);
+                inner.free_clusters = Some(free).filter(|&free| free <= geometry.cluster_count);
+                inner.next_free = u32_at(492);
+            }
+        }
+        Ok(FatFs {
+            inner: Mutex::new(inner),
+        })
+    }
+
+    pub fn fat_type(&self) -> FatType {
+        self.inner.lock().geometry.fat_type
+    }
+
+    /// クラスタの数と大きさ (バイト)
+    pub fn clusters(&self) -> (u32, u64) {
+        let inner = self.inner.lock();
+        (inner.geometry.cluster_count, inner.geometry.cluster_size())
+    }
+
+    /// 空きクラスタの数。FATを端から数える
+    pub fn free_clusters(&self) -> Result<u32, FsError> {
+        let mut inner = self.inner.lock();
+        let mut free = 0;
+        for cluster in 2..inner.geometry.cluster_count + 2 {
+            if inner.fat_entry(cluster)? == 0 {
+                free += 1;
+            }
+        }
+        if inner.free_clusters != Some(free) {
+            inner.free_clusters = Some(free);
+            inner.fs_info_dirty = true;
+        }
+        Ok(free)
+    }
+}
+
+impl FileSystem for FatFs {
+    fn name(&self) -> &'static str {
+        "vfat"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        let mut inner = self.inner.lock();
+        let entry = inner.find(dir, name)?.ok_or(FsError::NotFound)?;
+        inner.inode_for(dir, &entry)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError> {
+        let inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        let (file_type, mode, links) = if node.is_directory() {
+            (FileType::Directory, 0o755, 2)
+        } else {
+            (FileType::Regular, 0o644, 1)
+        };
+        let read_only = node.attributes & ATTR_READ_ONLY != 0;
+        Ok(Metadata {
+            inode,
+            file_type,
+            size: u64::from(node.size),
+            mode: if read_only { mode & !0o222 } else { mode },
+            links,
+            modified: node.modified,
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        if node.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = u64::from(node.size);
+        if offset >= size {
+            return Ok(0);
+        }
+        let count = buffer.len().min((size - offset) as usize);
+        let cluster_size = inner.geometry.cluster_size();
+        let mut done = 0;
+        while done < count {
+            let current = offset + done as u64;
+            let cluster = inner
+                .cluster_at(inode, (current / cluster_size) as u32, false)?
+                .ok_or(FsError::Corrupted("fat: file is shorter than its size"))?;
+            let within = current % cluster_size;
+            let length = (count - done).min((cluster_size - within) as usize);
+            let position = inner.geometry.cluster_position(cluster) + within;
+            inner.read_bytes(position, &mut buffer[done..done + length])?;
+            done += length;
+        }
+        Ok(count)
+    }
+
+    fn write(&self, inode: u64, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        if node.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let size = u64::from(node.size);
+        // FATのファイルは4GiB未満
+        if offset
+            .checked_add(data.len() as u64)
+            .is_none_or(|end| end > u64::from(u32::MAX))
+        {
+            return Err(FsError::NoSpace);
+        }
+        let result = if offset > size {
+            inner.zero_fill(inode, size, offset)
+        } else {
+            Ok(())
+        }
+        .and_then(|()| inner.write_data(inode, offset, data));
+        let node = inner.node_mut(inode)?;
+        if let Ok(written) = result {
+            node.size = node.size.max((offset + written as u64) as u32);
+        }
+        node.modified = now();
+        inner.store(inode)?;
+        result
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        let mut inner = self.inner.lock();
+        let entries = inner.entries(dir)?;
+        entries
+            .iter()
+            .map(|entry| {
+                Ok(DirEntry {
+                    name: entry.name.clone(),
+                    inode: inner.inode_for(dir, entry)?,
+                    file_type: if entry.is_directory() {
+                        FileType::Directory
+                    } else {
+                        FileType::Regular
+                    },
+                })
+            })
+            .collect()
+    }
+
+    fn create(&self, dir: u64, name: &str, file_type: FileType) -> Result<u64, FsError> {
+        let mut inner = self.inner.lock();
+        let modified = now();
+        let (template, cluster) = match file_type {
+            FileType::Regular => (short_entry(ATTR_ARCHIVE, 0, 0, modified), 0),
+            FileType::Directory => {
+                check_name(name)?;
+                // 新しいディレクトリには `.` と `..` を置く
+                let cluster = inner.allocate_cluster(None, true)?;
+                let mut dot = short_entry(ATTR_DIRECTORY, cluster, 0, modified);
+                dot[..11].copy_from_slice(b".          ");
+                let mut dot_dot =
+                    short_entry(ATTR_DIRECTORY, inner.parent_cluster(dir)?, 0, modified);
+                dot_dot[..11].copy_from_slice(b"..         ");
+                let position = inner.geometry.cluster_position(cluster);
+                let written = inner
+                    .write_bytes(position, &dot)
+                    .and_then(|()| inner.write_bytes(position + ENTRY_SIZE as u64, &dot_dot));
+                if let Err(err) = written {
+                    inner.free_chain(cluster)?;
+                    return Err(err);
+                }
+                (short_entry(ATTR_DIRECTORY, cluster, 0, modified), cluster)
+            }
+            _ => return Err(FsError::NotSupported),
+        };
+        let position = match inner.add_entry(dir, name, template, None) {
+            Ok(position) => position,
+            Err(err) => {
+                inner.free_chain(cluster)?;
+                return Err(err);
+            }
+        };
+        let entry = inner.read_entry(position)?;
+        let inode = inner.next_inode;
+        inner.next_inode += 1;
+        inner.nodes.insert(
+            inode,
+            Node {
+                entry: Some(position),
+                parent: dir,
+                attributes: entry.attributes(),
+                first_cluster: cluster,
+                size: 0,
+                modified,
+                cursor: None,
+            },
+        );
+        inner.by_entry.insert(position, inode);
+        Ok(inode)
+    }
+
+    fn symlink(&self, _dir: u64, _name: &str, _target: &str) -> Result<u64, FsError> {
+        Err(FsError::NotSupported)
+    }
+
+    fn unlink(&self, dir: u64, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let entry = inner.find(dir, name)?.ok_or(FsError::NotFound)?;
+        if entry.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        inner.delete(&entry)
+    }
+
+    fn rmdir(&self, dir: u64, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let entry = inner.find(dir, name)?.ok_or(FsError::NotFound)?;
+        if !entry.is_directory() {
+            return Err(FsError::NotADirectory);
+        }
+        let inode = inner.inode_for(dir, &entry)?;
+        if !inner.is_empty_directory(inode)? {
+            return Err(FsError::NotEmpty);
+        }
+        inner.delete(&entry)
+    }
+
+    fn rename(
+        &self,
+        old_dir: u64,
+        old_name: &str,
+        new_dir: u64,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let entry = inner.find(old_dir, old_name)?.ok_or(FsError::NotFound)?;
+        let inode = inner.inode_for(old_dir, &entry)?;
+        let is_directory = entry.is_directory();
+        if let Some(existing) = inner.find(new_dir, new_name)? {
+            if existing.position() == entry.position() {
+                // 大文字小文字だけを変えるときは同じエントリが見つかる
+                if existing.name == new_name {
+                    return Ok(());
+                }
+            } else {
+                match (is_directory, existing.is_directory()) {
+                    (true, true) => {
+                        let existing_inode = inner.inode_for(new_dir, &existing)?;
+                        if !inner.is_empty_directory(existing_inode)? {
+                            return Err(FsError::NotEmpty);
+                        }
+                    }
+                    (true, false) => return Err(FsError::NotADirectory),
+                    (false, true) => return Err(FsError::IsADirectory),
+                    (false, false) => {}
+                }
+                inner.delete(&existing)?;
+            }
+        }
+
+        // 新しいエントリを書いてから古いエントリを消す
+        let position = inner.add_entry(new_dir, new_name, entry.data, Some(entry.position()))?;
+        for &slot in &entry.slots {
+            inner.write_bytes(slot, &[ENTRY_FREE])?;
+        }
+        inner.by_entry.remove(&entry.position());
+        inner.by_entry.insert(position, inode);
+        let node = inner.node_mut(inode)?;
+        node.entry = Some(position);
+        node.parent = new_dir;
+        if is_directory && old_dir != new_dir {
+            let parent = inner.parent_cluster(new_dir)?;
+            let dot_dot =
+                inner.geometry.cluster_position(entry.data.first_cluster()) + ENTRY_SIZE as u64;
+            let mut data = inner.read_entry(dot_dot)?;
+            if &data[..11] == b"..         " {
+                set_entry_cluster(&mut data, parent);
+                inner.write_bytes(dot_dot, &data)?;
+            }
+        }
+        Ok(())
+    }
+
+    fn truncate(&self, inode: u64, size: u64) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        if node.is_directory() {
+            return Err(FsError::IsADirectory);
+        }
+        let old_size = u64::from(node.size);
+        let first = node.first_cluster;
+        if size > u64::from(u32::MAX) {
+            return Err(FsError::NoSpace);
+        }
+        if size > old_size {
+            let result = inner.zero_fill(inode, old_size, size);
+            inner.node_mut(inode)?.modified = now();
+            inner.store(inode)?;
+            return result;
+        }
+        let keep = size.div_ceil(inner.geometry.cluster_size()) as u32;
+        if keep == 0 {
+            inner.free_chain(first)?;
+            inner.node_mut(inode)?.first_cluster = 0;
+        } else if let Some(last) = inner.cluster_at(inode, keep - 1, false)? {
+            let rest = inner.next_cluster(last)?;
+            inner.set_fat_entry(last, inner.geometry.fat_type.end_of_chain())?;
+            if let Some(rest) = rest {
+                inner.free_chain(rest)?;
+            }
+        }
+        let node = inner.node_mut(inode)?;
+        node.size = size as u32;
+        node.cursor = None;
+        node.modified = now();
+        inner.store(inode)
+    }
+
+    fn sync(&self) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        inner.write_fs_info()?;
+        Ok(inner.device.lock().flush()?)
+    }
+}
+
+/// `device` 全体をFATでフォーマットする。`fat_type` が `None` なら大きさで選ぶ
+///
+/// 中身はすべて失われる
+pub fn format(device: &SharedBlockDevice, fat_type: Option<FatType>) -> Result<FatType, FsError> {
+    let (block_size, block_count) = {
+        let device = device.lock();
+        (device.block_size(), device.block_count())
+    };
+    let sector_size = block_size.max(512) as u64;
+    if !sector_size.is_power_of_two() || sector_size > 4096 {
+        return Err(FsError::InvalidArgument);
+    }
+    let total_sectors = block_count * block_size as u64 / sector_size;
+    let bytes = total_sectors * sector_size;
+    let fat_type = fat_type.unwrap_or(if bytes < 16 * 1024 * 1024 {
+        FatType::Fat12
+    } else if bytes < 512 * 1024 * 1024 {
+        FatType::Fat16
+    } else {
+        FatType::Fat32
+    });
+    let (reserved, root_entries) = match fat_type {
+        FatType::Fat32 => (32, 0),
+        _ => (1, 512),
+    };
+    let fat_count = 2;
+    let root_sectors = (root_entries * ENTRY_SIZE as u64).div_ceil(sector_size);
+
+    // 欲しい種類になる最小のクラスタの大きさを探す
+    let (sectors_per_cluster, fat_sectors, _) = (0..8)
+        .map(|shift| 1u64 << shift)
+        .find_map(|sectors_per_cluster| {
+            let available = total_sectors.checked_sub(reserved + root_sectors)?;
+            // FATの分を引く前のクラスタ数で見積もれば、FATが足りなくなることはない
+            let estimate = u32::try_from(available / sectors_per_cluster).ok()?;
+            let fat_sectors = fat_type.table_size(estimate).div_ceil(sector_size);
+            let data = available.checked_sub(fat_count * fat_sectors)?;
+            let clusters = u32::try_from(data / sectors_per_cluster).ok()?;
+            (clusters > 0 && FatType::from_clusters(clusters) == fat_type).then_some((
+                sectors_per_cluster,
+                fat_sectors,
+                clusters,
+            ))
+        })
+        .ok_or(FsError::InvalidArgument)?;
+
+    let blocks_per_sector = sector_size / block_size as u64;
+    let write = |sector: u64, data: &[u8]| -> Result<(), FsError> {
+        Ok(device
+            .lock()
+            .write_blocks(sector * blocks_per_sector, data)?)
+    };
+    // 予約領域、FAT、ルートディレクトリ (FAT32ではクラスタ2) を0で埋める
+    let metadata_sectors = reserved
+        + fat_count * fat_sectors
+        + if fat_type == FatType::Fat32 {
+            sectors_per_cluster
+        } else {
+            root_sectors
+        };
+    let zeros = vec![0u8; (64 * sector_size) as usize];
+    let mut sector = 0;
+    while sector < metadata_sectors {
+        let count = (metadata_sectors - sector).min(64);
+        write(sector, &zeros[..(count * sector_size) as usize])?;
+        sector += count;
+    }
+
+    let mut boot = vec![0u8; sector_size as usize];
+    let put16 = |boot: &mut [u8], offset: usize, value: u64| {
+        boot[offset..offset + 2].copy_from_slice(&(value as u16).to_le_bytes())
+    };
+    let put32 = |boot: &mut [u8], offset: usize, value: u64| {
+        boot[offset..offset + 4].copy_from_slice(&(value as u32).to_le_bytes())
+    };
+    boot[..3].copy_from_slice(if fat_type == FatType::Fat32 {
+        &[0xeb, 0x58, 0x90]
+    } else {
+        &[0xeb, 0x3c, 0x90]
+    });
+    boot[3..11].copy_from_slice(b"BLOG_OS ");
+    put16(&mut boot, 11, sector_size);
+    boot[13] = sectors_per_cluster as u8;
+    put16(&mut boot, 14, reserved);
+    boot[16] = fat_count as u8;
+    put16(&mut boot, 17, root_entries);
+    if total_sectors < 0x10000 && fat_type != FatType::Fat32 {
+        put16(&mut boot, 19, total_sectors);
+    } else {
+        put32(&mut boot, 32, total_sectors);
+    }
+    boot[21] = 0xf8;
+    put16(&mut boot, 24, 63);
+    put16(&mut boot, 26, 255);
+    let serial = now();
+    // 拡張BPBの位置はFAT32だけずれる
+    let extended = if fat_type == FatType::Fat32 {
+        put32(&mut boot, 36, fat_sectors);
+        put32(&mut boot, 44, 2);
+        put16(&mut boot, 48, 1);
+        put16(&mut boot, 50, 6);
+        64
+    } else {
+        put16(&mut boot, 22, fat_sectors);
+        36
+    };
+    boot[extended] = 0x80;
+    boot[extended + 2] = 0x29;
+    put32(&mut boot, extended + 3, serial);
+    boot[extended + 7..extended + 18].copy_from_slice(b"NO NAME    ");
+    boot[extended + 18..extended + 26].copy_from_slice(match fat_type {
+        FatType::Fat12 => b"FAT12   ",
+        FatType::Fat16 => b"FAT16   ",
+        FatType::Fat32 => b"FAT32   ",
+    });
+    boot[510] = 0x55;
+    boot[511] = 0xaa;
+    write(0, &boot)?;
+
+    if fat_type == FatType::Fat32 {
+        let mut info = vec![0u8; sector_size as usize];
+        put32(&mut info, 0, u64::from(FS_INFO_LEAD));
+        put32(&mut info, 484, u64::from(FS_INFO_STRUCT));
+        put32(&mut info, 488, u64::from(FS_INFO_UNKNOWN));
+        put32(&mut info, 492, 3);
+        put32(&mut info, 508, u64::from(FS_INFO_TRAIL));
+        write(1, &info)?;
+        // 予備のブートセクタ
+        write(6, &boot)?;
+        write(7, &info)?;
+    }
+
+    // 先頭の2項目は予約。FAT32ではクラスタ2がルートディレクトリ
+    let mut head = vec![0u8; sector_size as usize];
+    let reserved_entries: &[u8] = match fat_type {
+        FatType::Fat12 => &[0xf8, 0xff, 0xff],
+        FatType::Fat16 => &[0xf8, 0xff, 0xff, 0xff],
+        FatType::Fat32 => &[
+            0xf8, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f, 0xff, 0xff, 0xff, 0x0f,
+        ],
+    };
+    head[..reserved_entries.len()].copy_from_slice(reserved_entries);
+    for copy in 0..fat_count {
+        write(reserved + copy * fat_sectors, &head)?;
+    }
+    device.lock().flush()?;
+    Ok(fat_type)
+}
+
+#[test_case]
+fn test_short_names() {
+    assert_eq!(short_name("README.TXT"), (*b"README  TXT", Some(0)));
+    assert_eq!(
+        short_name("readme.txt"),
+        (*b"README  TXT", Some(CASE_LOWER_BASE | CASE_LOWER_EXT))
+    );
+    assert_eq!(short_name("Readme.txt").1, None);
+    assert_eq!(short_name("long file name.text"), (*b"LONGFILETEX", None));
+    assert_eq!(short_name(".profile"), (*b"PROFILE    ", None));
+    assert_eq!(with_tail(b"LONGFILETXT", 1), *b"LONGFI~1TXT");
+    assert_eq!(with_tail(b"AB      TXT", 12), *b"AB~12   TXT");
+    assert_eq!(checksum(b"LONGFI~1TXT"), 212);
+    assert_eq!(checksum(b"README  TXT"), 115);
+}
+
+#[test_case]
+fn test_fat_time() {
+    // 2024-02-29 13:45:58
+    let (time, date) = to_fat_time(1_709_214_358);
+    assert_eq!(date, (44 << 9) | (2 << 5) | 29);
+    assert_eq!(time, (13 << 11) | (45 << 5) | 29);
+    assert_eq!(from_fat_time(time, date), 1_709_214_358);
+    assert_eq!(from_fat_time(0, 0), 0);
+    assert_eq!(to_fat_time(0), (0, (1 << 5) | 1));
+}
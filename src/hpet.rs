@@ -0,0 +1,382 @@
+//! HPET (High Precision Event Timer) のドライバ
+//!
+//! ACPIのHPETテーブルからレジスタの場所を見つけてマップする。
+//! メインカウンタを時計として使えるほか、各タイマーのコンパレータで
+//! 一回限りまたは周期的な割り込みを出せる。
+//!
+//! PICにつなぐ場合はレガシー置き換えモードを使い、タイマー0がIRQ0 (PITの代わり)、
+//! タイマー1がIRQ8 (RTCの代わり) になる。このモードは `use_as_system_timer` でだけ入り、
+//! RTCの周期割り込みを使っている間は入らない。入った後はRTCの周期割り込みを断る
+
+use crate::acpi::{self, AcpiError};
+use crate::{memory, pit, rtc};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, Ordering};
+use core::time::Duration;
+use spin::Mutex;
+use x86_64::{PhysAddr, VirtAddr};
+
+const REGISTER_CAPABILITIES: u64 = 0x000;
+const REGISTER_CONFIG: u64 = 0x010;
+const REGISTER_INTERRUPT_STATUS: u64 = 0x020;
+const REGISTER_MAIN_COUNTER: u64 = 0x0f0;
+/// タイマーごとのレジスタの先頭と間隔
+const REGISTER_TIMER_BASE: u64 = 0x100;
+const REGISTER_TIMER_STRIDE: u64 = 0x20;
+const TIMER_CONFIG: u64 = 0x00;
+const TIMER_COMPARATOR: u64 = 0x08;
+
+/// レジスタ領域の大きさ
+const REGISTER_SIZE: u64 = 0x400;
+
+/// 全体の設定: メインカウンタを動かす
+const CONFIG_ENABLE: u64 = 1 << 0;
+/// 全体の設定: タイマー0と1をIRQ0とIRQ8につなぐ
+const CONFIG_LEGACY_REPLACEMENT: u64 = 1 << 1;
+
+/// 能力: メインカウンタが64ビット
+const CAPABILITY_64BIT: u64 = 1 << 13;
+/// 能力: レガシー置き換えモードが使える
+const CAPABILITY_LEGACY_REPLACEMENT: u64 = 1 << 15;
+
+/// タイマーの設定: レベルトリガ (PICにつなぐときはエッジトリガ)
+const TIMER_LEVEL_TRIGGERED: u64 = 1 << 1;
+/// タイマーの設定: 割り込みを出す
+const TIMER_INTERRUPT_ENABLE: u64 = 1 << 2;
+/// タイマーの設定: 周期モード
+const TIMER_PERIODIC: u64 = 1 << 3;
+/// タイマーの設定 (読み出し専用): 周期モードが使える
+const TIMER_PERIODIC_CAPABLE: u64 = 1 << 4;
+/// タイマーの設定: 次のコンパレータへの書き込みで周期モードのアキュムレータを直接設定する
+const TIMER_VALUE_SET: u64 = 1 << 6;
+/// タイマーの設定: 割り込みの行き先 (I/O APICの入力番号)
+const TIMER_ROUTE_SHIFT: u64 = 9;
+const TIMER_ROUTE_MASK: u64 = 0x1f << TIMER_ROUTE_SHIFT;
+
+/// 仕様で許されるカウンタの周期の最大値 (100ns)
+const MAX_PERIOD_FS: u64 = 100_000_000;
+const FEMTOS_PER_NANO: u64 = 1_000_000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum HpetError {
+    /// ACPIのHPETテーブルが読めない
+    Acpi(AcpiError),
+    /// レジスタがメモリ空間にない
+    UnsupportedAddressSpace(u8),
+    /// カウンタの周期がおかしい
+    InvalidPeriod(u64),
+    /// レジスタをマップできなかった
+    MapFailed,
+    /// `init` がまだ成功していない
+    NotInitialized,
+    /// その番号のタイマーはない
+    NoSuchTimer(usize),
+    /// そのタイマーは周期モードが使えない
+    PeriodicUnsupported(usize),
+    /// レガシー置き換えモードが使えない
+    LegacyReplacementUnsupported,
+    /// RTCの周期割り込みがIRQ8を使っている
+    RtcInUse,
+}
+
+impl fmt::Display for HpetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            HpetError::Acpi(err) => write!(f, "hpet: {}", err),
+            HpetError::UnsupportedAddressSpace(space) => {
+                write!(
+                    f,
+                    "hpet: registers are in unsupported address space {}",
+                    space
+                )
+            }
+            HpetError::InvalidPeriod(period) => {
+                write!(f, "hpet: invalid counter period {} fs", period)
+            }
+            HpetError::MapFailed => write!(f, "hpet: failed to map registers"),
+            HpetError::NotInitialized => write!(f, "hpet: not initialized"),
+            HpetError::NoSuchTimer(index) => write!(f, "hpet: no timer {}", index),
+            HpetError::PeriodicUnsupported(index) => {
+                write!(f, "hpet: timer {} does not support periodic mode", index)
+            }
+            HpetError::LegacyReplacementUnsupported => {
+                write!(f, "hpet: legacy replacement routing is not supported")
+            }
+            HpetError::RtcInUse => {
+                write!(f, "hpet: the RTC periodic interrupt is using IRQ8")
+            }
+        }
+    }
+}
+
+/// コンパレータの動かし方
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum TimerMode {
+    /// 指定した時間の後に1回だけ割り込む
+    OneShot,
+    /// 指定した間隔で割り込み続ける
+    Periodic,
+}
+
+/// `init` で分かったHPETの構成
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct HpetInfo {
+    /// メインカウンタが1増える時間 (フェムト秒)
+    pub period_fs: u64,
+    pub timers: usize,
+    pub counter_64bit: bool,
+    pub legacy_replacement: bool,
+    /// 周期モードで使える最小のカウント数
+    pub minimum_tick: u16,
+}
+
+impl HpetInfo {
+    /// メインカウンタの周波数 (Hz)
+    pub fn frequency(&self) -> u64 {
+        1_000_000_000_000_000 / self.period_fs
+    }
+}
+
+struct Hpet {
+    base: VirtAddr,
+    info: HpetInfo,
+}
+
+impl Hpet {
+    fn read(&self, offset: u64) -> u64 {
+        unsafe { (self.base + offset).as_ptr::<u64>().read_volatile() }
+    }
+
+    fn write(&mut self, offset: u64, value: u64) {
+        unsafe {
+            (self.base + offset)
+                .as_mut_ptr::<u64>()
+                .write_volatile(value)
+        }
+    }
+
+    fn timer_register(index: usize, offset: u64) -> u64 {
+        REGISTER_TIMER_BASE + index as u64 * REGISTER_TIMER_STRIDE + offset
+    }
+
+    /// 32ビットのカウンタでは上位が0のまま回るので、差は下位32ビットで取る
+    fn counter_mask(&self) -> u64 {
+        if self.info.counter_64bit {
+            u64::MAX
+        } else {
+            u64::from(u32::MAX)
+        }
+    }
+
+    fn counts(&self, duration: Duration) -> u64 {
+        let counts =
+            duration.as_nanos() * u128::from(FEMTOS_PER_NANO) / u128::from(self.info.period_fs);
+        (counts as u64).max(1)
+    }
+
+    fn set_enabled(&mut self, enabled: bool) {
+        let config = self.read(REGISTER_CONFIG);
+        let config = if enabled {
+            config | CONFIG_ENABLE
+        } else {
+            config & !CONFIG_ENABLE
+        };
+        self.write(REGISTER_CONFIG, config);
+    }
+}
+
+static HPET: Mutex<Option<Hpet>> = Mutex::new(None);
+/// レガシー置き換えモードに入っているか。IRQ0とIRQ8がHPETにつながっている
+static LEGACY_REPLACEMENT: AtomicBool = AtomicBool::new(false);
+
+/// HPETを探してメインカウンタを動かす。`acpi::init` と `memory::MAPPER` の準備の後に呼ぶこと
+///
+/// タイマーの割り込みはすべて止めた状態で始める
+pub fn init() -> Result<HpetInfo, HpetError> {
+    let table = acpi::hpet().map_err(HpetError::Acpi)?;
+    let base_address = table.base_address;
+    if base_address.address_space != acpi::GenericAddress::SYSTEM_MEMORY {
+        return Err(HpetError::UnsupportedAddressSpace(
+            base_address.address_space,
+        ));
+    }
+    let base = memory::map_mmio(PhysAddr::new(base_address.address), REGISTER_SIZE)
+        .map_err(|_| HpetError::MapFailed)?;
+
+    let mut hpet = Hpet {
+        base,
+        info: HpetInfo {
+            period_fs: 0,
+            timers: 0,
+            counter_64bit: false,
+            legacy_replacement: false,
+            minimum_tick: table.minimum_tick,
+        },
+    };
+    let capabilities = hpet.read(REGISTER_CAPABILITIES);
+    let period_fs = capabilities >> 32;
+    if period_fs == 0 || period_fs > MAX_PERIOD_FS {
+        return Err(HpetError::InvalidPeriod(period_fs));
+    }
+    hpet.info.period_fs = period_fs;
+    hpet.info.timers = ((capabilities >> 8) & 0x1f) as usize + 1;
+    hpet.info.counter_64bit = capabilities & CAPABILITY_64BIT != 0;
+    hpet.info.legacy_replacement = capabilities & CAPABILITY_LEGACY_REPLACEMENT != 0;
+
+    // 止めてからカウンタを0に戻し、全部のタイマーの割り込みを切る
+    hpet.write(REGISTER_CONFIG, 0);
+    hpet.write(REGISTER_MAIN_COUNTER, 0);
+    for index in 0..hpet.info.timers {
+        let register = Hpet::timer_register(index, TIMER_CONFIG);
+        let config = hpet.read(register);
+        hpet.write(
+            register,
+            config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+        );
+    }
+    hpet.write(REGISTER_INTERRUPT_STATUS, u64::MAX);
+    hpet.set_enabled(true);
+
+    let info = hpet.info;
+    *HPET.lock() = Some(hpet);
+    Ok(info)
+}
+
+/// レガシー置き換えモードに入り、IRQ0とIRQ8がHPETから来ているか
+pub fn legacy_replacement_enabled() -> bool {
+    LEGACY_REPLACEMENT.load(Ordering::Relaxed)
+}
+
+/// `init` で分かった構成。初期化前や失敗したときは `None`
+pub fn info() -> Option<HpetInfo> {
+    HPET.lock().as_ref().map(|hpet| hpet.info)
+}
+
+/// メインカウンタの値
+pub fn counter() -> Option<u64> {
+    HPET.lock()
+        .as_ref()
+        .map(|hpet| hpet.read(REGISTER_MAIN_COUNTER))
+}
+
+/// メインカウンタを時間に直したもの (`init` からのナノ秒)
+pub fn nanos() -> Option<u64> {
+    let hpet = HPET.lock();
+    let hpet = hpet.as_ref()?;
+    let counter = hpet.read(REGISTER_MAIN_COUNTER);
+    Some(
+        (u128::from(counter) * u128::from(hpet.info.period_fs) / u128::from(FEMTOS_PER_NANO))
+            as u64,
+    )
+}
+
+/// メインカウンタを見ながら `duration` だけビジーウェイトする
+///
+/// 32ビットのカウンタが一周するより長く待てるよう、読むたびに進んだ分を足していく
+pub fn busy_wait(duration: Duration) -> Result<(), HpetError> {
+    let (mut last, counts, mask) = {
+        let hpet = HPET.lock();
+        let hpet = hpet.as_ref().ok_or(HpetError::NotInitialized)?;
+        (
+            hpet.read(REGISTER_MAIN_COUNTER),
+            hpet.counts(duration),
+            hpet.counter_mask(),
+        )
+    };
+    let mut elapsed = 0u64;
+    while elapsed < counts {
+        core::hint::spin_loop();
+        let now = counter().ok_or(HpetError::NotInitialized)?;
+        elapsed = elapsed.saturating_add(now.wrapping_sub(last) & mask);
+        last = now;
+    }
+    Ok(())
+}
+
+/// タイマー `index` のコンパレータを設定して割り込みを出させる
+///
+/// 割り込みの行き先はレガシー置き換えモードならタイマー0がIRQ0、タイマー1がIRQ8、
+/// そうでなければ `route` (I/O APICの入力番号) になる
+pub fn start_timer(
+    index: usize,
+    mode: TimerMode,
+    interval: Duration,
+    route: u8,
+) -> Result<(), HpetError> {
+    let mut hpet = HPET.lock();
+    let hpet = hpet.as_mut().ok_or(HpetError::NotInitialized)?;
+    if index >= hpet.info.timers {
+        return Err(HpetError::NoSuchTimer(index));
+    }
+    let config_register = Hpet::timer_register(index, TIMER_CONFIG);
+    let comparator_register = Hpet::timer_register(index, TIMER_COMPARATOR);
+    let config = hpet.read(config_register);
+    if mode == TimerMode::Periodic && config & TIMER_PERIODIC_CAPABLE == 0 {
+        return Err(HpetError::PeriodicUnsupported(index));
+    }
+    let counts = hpet.counts(interval);
+
+    let mut config = config
+        & !(TIMER_LEVEL_TRIGGERED | TIMER_PERIODIC | TIMER_VALUE_SET | TIMER_ROUTE_MASK)
+        | TIMER_INTERRUPT_ENABLE
+        | (u64::from(route) << TIMER_ROUTE_SHIFT) & TIMER_ROUTE_MASK;
+
+    // 設定している間にコンパレータを追い越さないようカウンタを止める
+    hpet.set_enabled(false);
+    let now = hpet.read(REGISTER_MAIN_COUNTER);
+    match mode {
+        TimerMode::OneShot => {
+            hpet.write(config_register, config);
+            hpet.write(comparator_register, now.wrapping_add(counts));
+        }
+        TimerMode::Periodic => {
+            config |= TIMER_PERIODIC | TIMER_VALUE_SET;
+            hpet.write(config_register, config);
+            // 1回目はコンパレータ、2回目は周期のアキュムレータに入る
+            hpet.write(comparator_register, now.wrapping_add(counts));
+            hpet.write(comparator_register, counts);
+        }
+    }
+    hpet.set_enabled(true);
+    Ok(())
+}
+
+/// タイマー `index` の割り込みを止める
+pub fn stop_timer(index: usize) -> Result<(), HpetError> {
+    let mut hpet = HPET.lock();
+    let hpet = hpet.as_mut().ok_or(HpetError::NotInitialized)?;
+    if index >= hpet.info.timers {
+        return Err(HpetError::NoSuchTimer(index));
+    }
+    let register = Hpet::timer_register(index, TIMER_CONFIG);
+    let config = hpet.read(register);
+    hpet.write(
+        register,
+        config & !(TIMER_INTERRUPT_ENABLE | TIMER_PERIODIC),
+    );
+    Ok(())
+}
+
+/// レガシー置き換えモードに切り替え、タイマー0で `pit::TIMER_FREQUENCY` の割り込みを出す
+///
+/// IRQ0がPITからHPETに置き換わり、`timer_interrupt_handler` はHPETで動くようになる。
+/// 周波数は同じなので `interrupts::ticks` を使う側 (`rtc::now` など) はそのまま使える。
+/// IRQ8もRTCからタイマー1に置き換わるので、RTCの周期割り込みを使っていれば `RtcInUse` を返し、
+/// 切り替えた後は `rtc::enable_periodic_interrupt` が断る
+pub fn use_as_system_timer() -> Result<(), HpetError> {
+    {
+        let mut hpet = HPET.lock();
+        let hpet = hpet.as_mut().ok_or(HpetError::NotInitialized)?;
+        if !hpet.info.legacy_replacement {
+            return Err(HpetError::LegacyReplacementUnsupported);
+        }
+        if rtc::periodic_interrupt_enabled() {
+            return Err(HpetError::RtcInUse);
+        }
+        let config = hpet.read(REGISTER_CONFIG);
+        hpet.write(REGISTER_CONFIG, config | CONFIG_LEGACY_REPLACEMENT);
+        LEGACY_REPLACEMENT.store(true, Ordering::Relaxed);
+    }
+    let interval = Duration::from_nanos(1_000_000_000 / u64::from(pit::TIMER_FREQUENCY));
+    start_timer(0, TimerMode::Periodic, interval, 0)
+}
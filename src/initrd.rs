@@ -0,0 +1,384 @@
+//! 初期RAMディスク
+//!
+//! カーネルに埋め込んだUSTARかcpio (newc) のアーカイブを読み出し専用のファイルシステムとして見せる。
+//! 中身はアーカイブを指したまま使い、コピーしない。
+//! bootloader 0.9 はブートモジュールを渡せないので、アーカイブは `include_bytes!` で埋め込む。
+//! 別の方法でメモリに置いたアーカイブも `InitrdFs::parse` にそのまま渡せる
+
+use crate::vfs::{self, DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+
+/// `build.rs` が `initrd/` ディレクトリから作ったアーカイブ
+pub static ARCHIVE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/initrd.tar"));
+
+/// 埋め込んだアーカイブをマウントする場所
+pub const MOUNT_POINT: &str = "/initrd";
+
+const TAR_BLOCK_SIZE: usize = 512;
+const CPIO_HEADER_SIZE: usize = 110;
+const CPIO_MAGIC: &[u8] = b"070701";
+const CPIO_TRAILER: &str = "TRAILER!!!";
+
+/// cpioの `mode` の種類のビット
+const MODE_TYPE_MASK: u32 = 0o170000;
+const MODE_DIRECTORY: u32 = 0o040000;
+const MODE_REGULAR: u32 = 0o100000;
+const MODE_SYMLINK: u32 = 0o120000;
+
+const ROOT: u64 = 1;
+
+enum NodeKind {
+    File(&'static [u8]),
+    Directory(BTreeMap<String, u64>),
+    Symlink(String),
+}
+
+struct Node {
+    kind: NodeKind,
+    mode: u16,
+    modified: u64,
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self.kind {
+            NodeKind::File(_) => FileType::Regular,
+            NodeKind::Directory(_) => FileType::Directory,
+            NodeKind::Symlink(_) => FileType::Symlink,
+        }
+    }
+}
+
+/// アーカイブの中身の木。inode番号は `nodes` の添字 + 1
+pub struct InitrdFs {
+    nodes: Vec<Node>,
+}
+
+impl InitrdFs {
+    /// USTARかcpio (newc) のアーカイブを読む。形式は先頭のマジックで見分ける
+    pub fn parse(archive: &'static [u8]) -> Result<InitrdFs, FsError> {
+        let mut fs = InitrdFs {
+            nodes: alloc::vec![Node {
+                kind: NodeKind::Directory(BTreeMap::new()),
+                mode: 0o755,
+                modified: 0,
+            }],
+        };
+        if archive.starts_with(CPIO_MAGIC) {
+            fs.parse_cpio(archive)?;
+        } else if archive.get(257..262) == Some(b"ustar") {
+            fs.parse_tar(archive)?;
+        } else if archive.iter().all(|&byte| byte == 0) {
+            // 空のtar
+        } else {
+            return Err(FsError::Corrupted("initrd: unknown archive format"));
+        }
+        Ok(fs)
+    }
+
+    /// 埋め込まれたアーカイブを読む
+    pub fn embedded() -> Result<InitrdFs, FsError> {
+        InitrdFs::parse(ARCHIVE)
+    }
+
+    fn parse_tar(&mut self, archive: &'static [u8]) -> Result<(), FsError> {
+        let mut offset = 0;
+        while let Some(header) = archive.get(offset..offset + TAR_BLOCK_SIZE) {
+            if header.iter().all(|&byte| byte == 0) {
+                break;
+            }
+            let checksum = parse_octal(&header[148..156])
+                .ok_or(FsError::Corrupted("initrd: bad tar checksum"))?;
+            let sum: u64 = header
+                .iter()
+                .enumerate()
+                .map(|(i, &byte)| if (148..156).contains(&i) { b' ' } else { byte })
+                .map(u64::from)
+                .sum();
+            if sum != checksum {
+                return Err(FsError::Corrupted("initrd: bad tar checksum"));
+            }
+            let size = parse_octal(&header[124..136])
+                .ok_or(FsError::Corrupted("initrd: bad tar header"))?
+                as usize;
+            let mode = parse_octal(&header[100..108]).unwrap_or(0o644) as u16;
+            let modified = parse_octal(&header[136..148]).unwrap_or(0);
+            let data_start = offset + TAR_BLOCK_SIZE;
+            let data = archive
+                .get(data_start..data_start + size)
+                .ok_or(FsError::Corrupted("initrd: truncated tar archive"))?;
+
+            // 100バイトを超える名前は prefix 欄に前半が入る
+            let name = field_str(&header[0..100]);
+            let prefix = field_str(&header[345..500]);
+            let path = if prefix.is_empty() {
+                name.to_string()
+            } else {
+                alloc::format!("{}/{}", prefix, name)
+            };
+            let kind = match header[156] {
+                b'0' | 0 => Some(NodeKind::File(data)),
+                b'5' => Some(NodeKind::Directory(BTreeMap::new())),
+                b'2' => Some(NodeKind::Symlink(field_str(&header[157..257]).to_string())),
+                // デバイスファイルや拡張ヘッダは無視する
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                self.add(&path, kind, mode & 0o7777, modified)?;
+            }
+            offset = data_start + size.next_multiple_of(TAR_BLOCK_SIZE);
+        }
+        Ok(())
+    }
+
+    fn parse_cpio(&mut self, archive: &'static [u8]) -> Result<(), FsError> {
+        let truncated = FsError::Corrupted("initrd: truncated cpio archive");
+        let mut offset = 0;
+        loop {
+            let header = archive
+                .get(offset..offset + CPIO_HEADER_SIZE)
+                .ok_or(truncated)?;
+            if !header.starts_with(CPIO_MAGIC) {
+                return Err(FsError::Corrupted("initrd: bad cpio header"));
+            }
+            let field = |index: usize| {
+                parse_hex(&header[6 + index * 8..14 + index * 8])
+                    .ok_or(FsError::Corrupted("initrd: bad cpio header"))
+            };
+            let mode = field(1)?;
+            let modified = u64::from(field(5)?);
+            let size = field(6)? as usize;
+            let name_size = field(11)? as usize;
+
+            let name_start = offset + CPIO_HEADER_SIZE;
+            let name = archive
+                .get(name_start..name_start + name_size)
+                .ok_or(truncated)?;
+            let name = field_str(name);
+            // 名前とデータはそれぞれ4バイト境界にそろえて置かれる
+            let data_start = (name_start + name_size).next_multiple_of(4);
+            let data = archive
+                .get(data_start..data_start + size)
+                .ok_or(truncated)?;
+            if name == CPIO_TRAILER {
+                break;
+            }
+
+            let kind = match mode & MODE_TYPE_MASK {
+                MODE_REGULAR => Some(NodeKind::File(data)),
+                MODE_DIRECTORY => Some(NodeKind::Directory(BTreeMap::new())),
+                MODE_SYMLINK => Some(NodeKind::Symlink(
+                    String::from_utf8_lossy(data).into_owned(),
+                )),
+                _ => None,
+            };
+            if let Some(kind) = kind {
+                self.add(name, kind, (mode & 0o7777) as u16, modified)?;
+            }
+            offset = (data_start + size).next_multiple_of(4);
+        }
+        Ok(())
+    }
+
+    /// `path` にノードを置く。途中のディレクトリがアーカイブになければ作る
+    fn add(&mut self, path: &str, kind: NodeKind, mode: u16, modified: u64) -> Result<(), FsError> {
+        let mut names = path
+            .split('/')
+            .filter(|name| !name.is_empty() && *name != ".")
+            .peekable();
+        let mut dir = ROOT;
+        while let Some(name) = names.next() {
+            if name == ".." {
+                return Err(FsError::Corrupted("initrd: path escapes the archive"));
+            }
+            let existing = self.entries(dir)?.get(name).copied();
+            if names.peek().is_some() {
+                dir = match existing {
+                    Some(inode) => inode,
+                    None => {
+                        self.insert(dir, name, NodeKind::Directory(BTreeMap::new()), 0o755, 0)?
+                    }
+                };
+                continue;
+            }
+            match existing {
+                // 先に中のファイルが出てきて作ったディレクトリには、属性だけ入れる
+                Some(inode) if matches!(kind, NodeKind::Directory(_)) => {
+                    let node = self.node_mut(inode)?;
+                    if node.file_type() != FileType::Directory {
+                        return Err(FsError::Corrupted("initrd: duplicate entry"));
+                    }
+                    node.mode = mode;
+                    node.modified = modified;
+                }
+                Some(_) => return Err(FsError::Corrupted("initrd: duplicate entry")),
+                None => {
+                    self.insert(dir, name, kind, mode, modified)?;
+                }
+            }
+            return Ok(());
+        }
+        Ok(())
+    }
+
+    fn insert(
+        &mut self,
+        dir: u64,
+        name: &str,
+        kind: NodeKind,
+        mode: u16,
+        modified: u64,
+    ) -> Result<u64, FsError> {
+        self.nodes.push(Node {
+            kind,
+            mode,
+            modified,
+        });
+        let inode = self.nodes.len() as u64;
+        match &mut self.node_mut(dir)?.kind {
+            NodeKind::Directory(entries) => {
+                entries.insert(name.to_string(), inode);
+                Ok(inode)
+            }
+            _ => Err(FsError::Corrupted("initrd: file used as a directory")),
+        }
+    }
+
+    fn node(&self, inode: u64) -> Result<&Node, FsError> {
+        let index = inode.checked_sub(1).ok_or(FsError::NotFound)?;
+        self.nodes.get(index as usize).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, inode: u64) -> Result<&mut Node, FsError> {
+        let index = inode.checked_sub(1).ok_or(FsError::NotFound)?;
+        self.nodes.get_mut(index as usize).ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self, dir: u64) -> Result<&BTreeMap<String, u64>, FsError> {
+        match &self.node(dir)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+}
+
+impl FileSystem for InitrdFs {
+    fn name(&self) -> &'static str {
+        "initrd"
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        self.entries(dir)?
+            .get(name)
+            .copied()
+            .ok_or(FsError::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError> {
+        let node = self.node(inode)?;
+        let (size, links) = match &node.kind {
+            NodeKind::File(data) => (data.len(), 1),
+            NodeKind::Directory(entries) => {
+                let subdirectories = entries
+                    .values()
+                    .filter(|&&child| {
+                        self.node(child)
+                            .is_ok_and(|child| child.file_type() == FileType::Directory)
+                    })
+                    .count();
+                (entries.len(), 2 + subdirectories as u32)
+            }
+            NodeKind::Symlink(target) => (target.len(), 1),
+        };
+        Ok(Metadata {
+            inode,
+            file_type: node.file_type(),
+            size: size as u64,
+            mode: node.mode,
+            links,
+            modified: node.modified,
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let NodeKind::File(data) = self.node(inode)?.kind else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let count = buffer.len().min(data.len() - start);
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        self.entries(dir)?
+            .iter()
+            .map(|(name, &inode)| {
+                Ok(DirEntry {
+                    name: name.clone(),
+                    inode,
+                    file_type: self.node(inode)?.file_type(),
+                })
+            })
+            .collect()
+    }
+
+    fn readlink(&self, inode: u64) -> Result<String, FsError> {
+        match &self.node(inode)?.kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+/// 埋め込んだアーカイブを読み、`MOUNT_POINT` にマウントする。ルートをマウントした後に呼ぶ
+pub fn init() -> Result<(), FsError> {
+    let fs = InitrdFs::embedded()?;
+    vfs::mount_at(MOUNT_POINT, Arc::new(fs))
+}
+
+/// NULか欄の終わりまでの文字列
+fn field_str(field: &[u8]) -> &str {
+    let end = field
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(field.len());
+    core::str::from_utf8(&field[..end]).unwrap_or("")
+}
+
+/// tarの数値の欄 (空白かNULで終わる8進数)
+fn parse_octal(field: &[u8]) -> Option<u64> {
+    let digits = field_str(field).trim_matches(' ');
+    if digits.is_empty() {
+        return Some(0);
+    }
+    u64::from_str_radix(digits, 8).ok()
+}
+
+/// cpio (newc) の数値の欄 (8桁の16進数)
+fn parse_hex(field: &[u8]) -> Option<u32> {
+    u32::from_str_radix(core::str::from_utf8(field).ok()?, 16).ok()
+}
+
+#[test_case]
+fn test_parse_numbers() {
+    assert_eq!(parse_octal(b"0000644\0"), Some(0o644));
+    assert_eq!(parse_octal(b" 17 \0"), Some(0o17));
+    assert_eq!(parse_octal(b"\0\0\0"), Some(0));
+    assert_eq!(parse_octal(b"0009\0"), None);
+    assert_eq!(parse_hex(b"000041ed"), Some(0o40755));
+    assert_eq!(parse_hex(b"0000zz00"), None);
+}
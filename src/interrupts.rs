@@ -1,4 +1,4 @@
-use crate::{print, println};
+use crate::println;
 use lazy_static::lazy_static;
 use x86_64::structures::idt::{InterruptDescriptorTable, InterruptStackFrame};
 
@@ -14,6 +14,15 @@ lazy_static! {
         }
         idt[InterruptIndex::Timer.as_usize()].set_handler_fn(timer_interrupt_handler);
         idt[InterruptIndex::Keyboard.as_usize()].set_handler_fn(keyboard_interrupt_handler);
+        idt[InterruptIndex::Serial1.as_usize()].set_handler_fn(serial1_interrupt_handler);
+        idt[InterruptIndex::Rtc.as_usize()].set_handler_fn(rtc_interrupt_handler);
+        idt[InterruptIndex::Mouse.as_usize()].set_handler_fn(mouse_interrupt_handler);
+        idt[InterruptIndex::PrimaryAta.as_usize()].set_handler_fn(primary_ata_interrupt_handler);
+        idt[InterruptIndex::SecondaryAta.as_usize()]
+            .set_handler_fn(secondary_ata_interrupt_handler);
+        idt[InterruptIndex::Pci9.as_usize()].set_handler_fn(pci9_interrupt_handler);
+        idt[InterruptIndex::Pci10.as_usize()].set_handler_fn(pci10_interrupt_handler);
+        idt[InterruptIndex::Pci11.as_usize()].set_handler_fn(pci11_interrupt_handler);
         idt.page_fault.set_handler_fn(page_fault_handler);
         idt
     };
@@ -34,6 +43,15 @@ pub const PIC_2_OFFSET: u8 = PIC_1_OFFSET + 8;
 pub enum InterruptIndex {
     Timer = PIC_1_OFFSET,
     Keyboard, // 33
+    Serial1 = PIC_1_OFFSET + 4,
+    Rtc = PIC_2_OFFSET,
+    // PCIのINTxが配線されるIRQ線 (QEMUのファームウェアは9〜11を使う)
+    Pci9,
+    Pci10,
+    Pci11,
+    Mouse = PIC_2_OFFSET + 4,
+    PrimaryAta = PIC_2_OFFSET + 6,
+    SecondaryAta,
 }
 
 impl InterruptIndex {
@@ -44,11 +62,135 @@ impl InterruptIndex {
     fn as_usize(self) -> usize {
         usize::from(self.as_u8())
     }
+
+    /// PIC上のIRQ番号
+    fn irq(self) -> usize {
+        usize::from(self.as_u8() - PIC_1_OFFSET)
+    }
+}
+
+use core::sync::atomic::{AtomicU64, Ordering};
+
+/// PICのIRQ線の数
+pub const IRQ_LINES: usize = 16;
+
+/// IRQ線につながっているデバイスの名前
+pub const IRQ_NAMES: [&str; IRQ_LINES] = [
+    "timer",
+    "keyboard",
+    "cascade",
+    "com2",
+    "com1",
+    "lpt2",
+    "floppy",
+    "lpt1",
+    "rtc",
+    "acpi",
+    "",
+    "",
+    "mouse",
+    "fpu",
+    "ata primary",
+    "ata secondary",
+];
+
+/// IRQ線ごとの割り込み回数
+static IRQ_COUNTS: [AtomicU64; IRQ_LINES] = [const { AtomicU64::new(0) }; IRQ_LINES];
+
+/// 起動してからのタイマー割り込みの回数
+static TICKS: AtomicU64 = AtomicU64::new(0);
+
+fn count_irq(index: InterruptIndex) {
+    IRQ_COUNTS[index.irq()].fetch_add(1, Ordering::Relaxed);
+}
+
+/// IRQ線 `irq` で起きた割り込みの回数
+pub fn irq_count(irq: usize) -> u64 {
+    IRQ_COUNTS[irq].load(Ordering::Relaxed)
+}
+
+/// 起動してからのタイマー割り込みの回数。周波数は `pit::TIMER_FREQUENCY`
+pub fn ticks() -> u64 {
+    TICKS.load(Ordering::Relaxed)
+}
+
+/// 1本のIRQ線を共有できるPCIデバイスの数
+const SHARED_HANDLERS: usize = 4;
+
+/// 1本のIRQ線に登録されたハンドラ
+type SharedHandlers = [Option<fn()>; SHARED_HANDLERS];
+
+/// PCIのIRQ線ごとの割り込みハンドラ
+static PCI_HANDLERS: spin::Mutex<[SharedHandlers; IRQ_LINES]> =
+    spin::Mutex::new([[None; SHARED_HANDLERS]; IRQ_LINES]);
+
+/// PCIデバイスのINTxが配線されたIRQ線 `irq` に `handler` を登録し、マスクを外す
+///
+/// 同じ線は複数のデバイスで共有されるので、ハンドラは自分のデバイスが割り込みを
+/// 出したかを確かめること。割り込みを受けられない線なら `false` を返す
+pub fn register_pci_handler(irq: u8, handler: fn()) -> bool {
+    if ![9, 10, 11].contains(&irq) {
+        return false;
+    }
+    let registered = x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut handlers = PCI_HANDLERS.lock();
+        let slots = &mut handlers[usize::from(irq)];
+        if slots.contains(&Some(handler)) {
+            return true;
+        }
+        match slots.iter_mut().find(|slot| slot.is_none()) {
+            Some(slot) => {
+                *slot = Some(handler);
+                true
+            }
+            None => false,
+        }
+    });
+    if registered {
+        unmask_irq(2);
+        unmask_irq(irq);
+    }
+    registered
+}
+
+/// IRQ線 `irq` に登録されたPCIデバイスのハンドラの数
+pub fn pci_handler_count(irq: usize) -> usize {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        PCI_HANDLERS.lock()[irq].iter().flatten().count()
+    })
+}
+
+fn dispatch_pci_interrupt(index: InterruptIndex) {
+    count_irq(index);
+    let handlers = PCI_HANDLERS.lock()[index.irq()];
+    for handler in handlers.iter().flatten() {
+        handler();
+    }
+
+    unsafe {
+        PICS.lock().notify_end_of_interrupt(index.as_u8());
+    }
 }
 
 pub static PICS: spin::Mutex<ChainedPics> =
     spin::Mutex::new(unsafe { ChainedPics::new(PIC_1_OFFSET, PIC_2_OFFSET) });
 
+/// PICでIRQ線 `irq` のマスクを外す
+pub fn unmask_irq(irq: u8) {
+    x86_64::instructions::interrupts::without_interrupts(|| {
+        let mut pics = PICS.lock();
+        unsafe {
+            let [mut primary, mut secondary] = pics.read_masks();
+            if irq < 8 {
+                primary &= !(1 << irq);
+            } else {
+                secondary &= !(1 << (irq - 8));
+            }
+            pics.write_masks(primary, secondary);
+        }
+    });
+}
+
 use crate::hlt_loop;
 use x86_64::structures::idt::PageFaultErrorCode;
 
@@ -78,7 +220,8 @@ extern "x86-interrupt" fn double_fault_handler(
 }
 
 extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    crate::print!(".");
+    count_irq(InterruptIndex::Timer);
+    TICKS.fetch_add(1, Ordering::Relaxed);
 
     // notify EOI
     unsafe {
@@ -88,32 +231,9 @@ extern "x86-interrupt" fn timer_interrupt_handler(_stack_frame: InterruptStackFr
 }
 
 extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStackFrame) {
-    use pc_keyboard::{DecodedKey, HandleControl, Keyboard, ScancodeSet1, layouts};
-    use spin::Mutex;
-    use x86_64::instructions::port::Port;
-
-    // JIS配列を使用
-    lazy_static! {
-        static ref KEYBOARD: Mutex<Keyboard<layouts::Jis109Key, ScancodeSet1>> =
-            Mutex::new(Keyboard::new(
-                ScancodeSet1::new(),
-                layouts::Jis109Key,
-                HandleControl::Ignore
-            ));
-    }
-
-    let mut keyboard = KEYBOARD.lock();
-    let mut port = Port::new(0x60);
-
-    let scancode: u8 = unsafe { port.read() };
-    if let Ok(Some(key_event)) = keyboard.add_byte(scancode) {
-        // キーイベントが起こったうえで文字として処理できるか
-        if let Some(key) = keyboard.process_keyevent(key_event) {
-            match key {
-                DecodedKey::Unicode(character) => print!("{}", character),
-                DecodedKey::RawKey(key) => print!("{:?}", key),
-            }
-        }
+    count_irq(InterruptIndex::Keyboard);
+    if let Some(scancode) = crate::ps2::read_pending() {
+        crate::keyboard::handle_scancode(scancode);
     }
 
     unsafe {
@@ -122,6 +242,70 @@ extern "x86-interrupt" fn keyboard_interrupt_handler(_stack_frame: InterruptStac
     }
 }
 
+extern "x86-interrupt" fn serial1_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_irq(InterruptIndex::Serial1);
+    crate::serial::receive_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Serial1.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn rtc_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_irq(InterruptIndex::Rtc);
+    crate::rtc::handle_interrupt();
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Rtc.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn pci9_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_pci_interrupt(InterruptIndex::Pci9);
+}
+
+extern "x86-interrupt" fn pci10_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_pci_interrupt(InterruptIndex::Pci10);
+}
+
+extern "x86-interrupt" fn pci11_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    dispatch_pci_interrupt(InterruptIndex::Pci11);
+}
+
+extern "x86-interrupt" fn mouse_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_irq(InterruptIndex::Mouse);
+    if let Some(byte) = crate::ps2::read_pending() {
+        crate::mouse::handle_byte(byte);
+    }
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::Mouse.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn primary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_irq(InterruptIndex::PrimaryAta);
+    crate::ata::handle_interrupt(0);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::PrimaryAta.as_u8());
+    }
+}
+
+extern "x86-interrupt" fn secondary_ata_interrupt_handler(_stack_frame: InterruptStackFrame) {
+    count_irq(InterruptIndex::SecondaryAta);
+    crate::ata::handle_interrupt(1);
+
+    unsafe {
+        PICS.lock()
+            .notify_end_of_interrupt(InterruptIndex::SecondaryAta.as_u8());
+    }
+}
+
 #[test_case]
 fn test_breakpoint_exception() {
     // invoke a breakpoint exception
@@ -0,0 +1,456 @@
+use crate::queue::Queue;
+use crate::ps2::{self, Ps2Error, Ps2Port};
+use crate::vga_buffer;
+use pc_keyboard::{HandleControl, KeyboardLayout, ScancodeSet1, ScancodeSet2, layouts::AnyLayout};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+pub use pc_keyboard::{DecodedKey, KeyCode, KeyState};
+
+/// 実行時に選べるキーボード配列
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Layout {
+    Us104,
+    Uk105,
+    Jis109,
+    De105,
+    Azerty,
+    Dvorak104,
+}
+
+impl Layout {
+    pub const ALL: [Layout; 6] = [
+        Layout::Us104,
+        Layout::Uk105,
+        Layout::Jis109,
+        Layout::De105,
+        Layout::Azerty,
+        Layout::Dvorak104,
+    ];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            Layout::Us104 => "us",
+            Layout::Uk105 => "uk",
+            Layout::Jis109 => "jis",
+            Layout::De105 => "de",
+            Layout::Azerty => "azerty",
+            Layout::Dvorak104 => "dvorak",
+        }
+    }
+
+    pub fn from_name(name: &str) -> Option<Layout> {
+        Layout::ALL.into_iter().find(|layout| layout.name() == name)
+    }
+
+    const fn to_any(self) -> AnyLayout {
+        use pc_keyboard::layouts;
+
+        match self {
+            Layout::Us104 => AnyLayout::Us104Key(layouts::Us104Key),
+            Layout::Uk105 => AnyLayout::Uk105Key(layouts::Uk105Key),
+            Layout::Jis109 => AnyLayout::Jis109Key(layouts::Jis109Key),
+            Layout::De105 => AnyLayout::De105Key(layouts::De105Key),
+            Layout::Azerty => AnyLayout::Azerty(layouts::Azerty),
+            Layout::Dvorak104 => AnyLayout::Dvorak104Key(layouts::Dvorak104Key),
+        }
+    }
+}
+
+/// キーボードが送ってくるスキャンコードの種類
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ScancodeSet {
+    /// 8042の変換を通した後のコード。起動直後はこちら
+    Set1,
+    Set2,
+}
+
+/// 実行時にスキャンコードセットを切り替えるためのラッパー
+enum AnyScancodeSet {
+    Set1(ScancodeSet1),
+    Set2(ScancodeSet2),
+}
+
+impl pc_keyboard::ScancodeSet for AnyScancodeSet {
+    fn advance_state(
+        &mut self,
+        code: u8,
+    ) -> Result<Option<pc_keyboard::KeyEvent>, pc_keyboard::Error> {
+        match self {
+            AnyScancodeSet::Set1(set) => set.advance_state(code),
+            AnyScancodeSet::Set2(set) => set.advance_state(code),
+        }
+    }
+}
+
+/// 押されている修飾キーとロックの状態
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Modifiers {
+    pub lshift: bool,
+    pub rshift: bool,
+    pub lctrl: bool,
+    pub rctrl: bool,
+    pub lalt: bool,
+    pub ralt: bool,
+    pub caps_lock: bool,
+    pub num_lock: bool,
+    pub scroll_lock: bool,
+}
+
+impl Modifiers {
+    pub fn shift(&self) -> bool {
+        self.lshift || self.rshift
+    }
+
+    pub fn ctrl(&self) -> bool {
+        self.lctrl || self.rctrl
+    }
+
+    pub fn alt(&self) -> bool {
+        self.lalt || self.ralt
+    }
+
+    /// 配列に渡す形にする。`pause_prefix` はPauseキーの前置きが押されているか
+    fn to_pc_keyboard(self, pause_prefix: bool) -> pc_keyboard::Modifiers {
+        pc_keyboard::Modifiers {
+            lshift: self.lshift,
+            rshift: self.rshift,
+            lctrl: self.lctrl,
+            rctrl: self.rctrl,
+            numlock: self.num_lock,
+            capslock: self.caps_lock,
+            alt_gr: self.ralt,
+            rctrl2: pause_prefix,
+        }
+    }
+
+    /// キーボードのLEDに送るビット列
+    fn leds(&self) -> u8 {
+        u8::from(self.scroll_lock) | u8::from(self.num_lock) << 1 | u8::from(self.caps_lock) << 2
+    }
+
+    fn update(&mut self, code: KeyCode, state: KeyState) {
+        let down = state == KeyState::Down;
+        match code {
+            KeyCode::LShift => self.lshift = down,
+            KeyCode::RShift => self.rshift = down,
+            KeyCode::LControl => self.lctrl = down,
+            KeyCode::RControl => self.rctrl = down,
+            KeyCode::LAlt => self.lalt = down,
+            KeyCode::RAltGr => self.ralt = down,
+            KeyCode::CapsLock if down => self.caps_lock = !self.caps_lock,
+            KeyCode::NumpadLock if down => self.num_lock = !self.num_lock,
+            KeyCode::ScrollLock if down => self.scroll_lock = !self.scroll_lock,
+            _ => {}
+        }
+    }
+}
+
+/// 押下・解放を含むキーの入力
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct KeyEvent {
+    pub code: KeyCode,
+    pub state: KeyState,
+    /// このイベントを反映した後の修飾キーの状態
+    pub modifiers: Modifiers,
+    /// 配列に従って文字に直したもの。押下時にしか得られない
+    pub key: Option<DecodedKey>,
+}
+
+struct KeyboardState {
+    /// スキャンコードをキーの押下・解放に直す。修飾キーとロックの状態は持たない
+    scancodes: AnyScancodeSet,
+    layout: Layout,
+    scancode_set: ScancodeSet,
+    /// 修飾キーとロックの状態。文字への変換もLEDもこれだけを見る
+    modifiers: Modifiers,
+    /// Pauseキーの前置き (隠れた右Ctrl) が押されている
+    pause_prefix: bool,
+    /// LED設定コマンドへのACKを待っている間、送るべきLEDの値を持っておく
+    pending_leds: Option<u8>,
+}
+
+const fn new_scancodes(set: ScancodeSet) -> AnyScancodeSet {
+    match set {
+        ScancodeSet::Set1 => AnyScancodeSet::Set1(ScancodeSet1::new()),
+        ScancodeSet::Set2 => AnyScancodeSet::Set2(ScancodeSet2::new()),
+    }
+}
+
+/// 押されたキーを `layout` に従って文字に直す。解放では `None`
+fn decode(
+    layout: Layout,
+    modifiers: Modifiers,
+    pause_prefix: bool,
+    code: KeyCode,
+    state: KeyState,
+) -> Option<DecodedKey> {
+    if state != KeyState::Down {
+        return None;
+    }
+    match code {
+        // 修飾キーとロックのキーは文字にならない
+        KeyCode::LShift
+        | KeyCode::RShift
+        | KeyCode::LControl
+        | KeyCode::RControl
+        | KeyCode::RControl2
+        | KeyCode::RAltGr
+        | KeyCode::CapsLock
+        | KeyCode::NumpadLock
+        | KeyCode::PauseBreak => Some(DecodedKey::RawKey(code)),
+        _ => Some(layout.to_any().map_keycode(
+            code,
+            &modifiers.to_pc_keyboard(pause_prefix),
+            HandleControl::Ignore,
+        )),
+    }
+}
+
+// JIS配列を既定にする
+static KEYBOARD: Mutex<KeyboardState> = Mutex::new(KeyboardState {
+    scancodes: new_scancodes(ScancodeSet::Set1),
+    layout: Layout::Jis109,
+    scancode_set: ScancodeSet::Set1,
+    modifiers: Modifiers {
+        lshift: false,
+        rshift: false,
+        lctrl: false,
+        rctrl: false,
+        lalt: false,
+        ralt: false,
+        caps_lock: false,
+        // BIOSと同じくNumLockがかかった状態から始める
+        num_lock: true,
+        scroll_lock: false,
+    },
+    pause_prefix: false,
+    pending_leds: None,
+});
+
+static EVENTS: Mutex<Queue<KeyEvent, 64>> = Mutex::new(Queue::new());
+
+const COMMAND_SET_LEDS: u8 = 0xed;
+const COMMAND_SCANCODE_SET: u8 = 0xf0;
+const COMMAND_ENABLE_SCANNING: u8 = 0xf4;
+
+/// キーボード割り込みで読んだバイトを処理する
+pub(crate) fn handle_scancode(scancode: u8) {
+    let mut keyboard = KEYBOARD.lock();
+    match scancode {
+        ps2::RESPONSE_ACK => {
+            // LED設定コマンドの2バイト目はACKが来てから送る
+            if let Some(leds) = keyboard.pending_leds.take() {
+                let _ = ps2::write_data(leds);
+            }
+            return;
+        }
+        ps2::RESPONSE_RESEND => return,
+        _ => {}
+    }
+
+    let Ok(Some(event)) =
+        pc_keyboard::ScancodeSet::advance_state(&mut keyboard.scancodes, scancode)
+    else {
+        return;
+    };
+    let state = event.state;
+    let code = match event.code {
+        KeyCode::RControl2 => {
+            keyboard.pause_prefix = state == KeyState::Down;
+            KeyCode::RControl2
+        }
+        // Pauseキーは前置きの後にNumLockと同じコードを送ってくる
+        KeyCode::NumpadLock if keyboard.pause_prefix => KeyCode::PauseBreak,
+        code => code,
+    };
+    let leds = keyboard.modifiers.leds();
+    keyboard.modifiers.update(code, state);
+    if keyboard.modifiers.leds() != leds {
+        keyboard.send_leds();
+    }
+
+    let event = KeyEvent {
+        code,
+        state,
+        modifiers: keyboard.modifiers,
+        key: decode(
+            keyboard.layout,
+            keyboard.modifiers,
+            keyboard.pause_prefix,
+            code,
+            state,
+        ),
+    };
+    drop(keyboard);
+
+    if state == KeyState::Down && handle_hotkey(&event) {
+        return;
+    }
+    // 溢れた分は捨てる
+    let _ = EVENTS.lock().push(event);
+}
+
+/// Shift+PageUp/PageDown で一度にスクロールする行数
+const SCROLL_STEP: usize = 12;
+
+/// コンソールの操作に割り当てたキーを処理する。処理したら `true`
+fn handle_hotkey(event: &KeyEvent) -> bool {
+    let modifiers = event.modifiers;
+    // Alt+F1..F6 で仮想コンソールを切り替える
+    if modifiers.alt()
+        && let Some(index) = console_for_key(event.code)
+    {
+        vga_buffer::switch_console(index);
+        return true;
+    }
+    // Shift+PageUp/PageDown でスクロールバックを半画面ずつ移動する
+    if modifiers.shift() {
+        let console = vga_buffer::console(vga_buffer::active_console());
+        match event.code {
+            KeyCode::PageUp => console.lock().scroll_view_up(SCROLL_STEP),
+            KeyCode::PageDown => console.lock().scroll_view_down(SCROLL_STEP),
+            _ => return false,
+        }
+        return true;
+    }
+    false
+}
+
+/// ファンクションキーに対応する仮想コンソールの番号
+fn console_for_key(key: KeyCode) -> Option<usize> {
+    let index = match key {
+        KeyCode::F1 => 0,
+        KeyCode::F2 => 1,
+        KeyCode::F3 => 2,
+        KeyCode::F4 => 3,
+        KeyCode::F5 => 4,
+        KeyCode::F6 => 5,
+        _ => return None,
+    };
+    (index < vga_buffer::NUM_CONSOLES).then_some(index)
+}
+
+impl KeyboardState {
+    /// LED設定コマンドを送る。値はACKが返ってきてから `handle_scancode` が送る
+    fn send_leds(&mut self) {
+        self.pending_leds = Some(self.modifiers.leds());
+        if ps2::write_data(COMMAND_SET_LEDS).is_err() {
+            self.pending_leds = None;
+        }
+    }
+}
+
+/// 入力されたキーイベントを1つ取り出す
+pub fn read_event() -> Option<KeyEvent> {
+    without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// キーイベントが残っているか
+pub fn events_pending() -> bool {
+    without_interrupts(|| !EVENTS.lock().is_empty())
+}
+
+/// 現在の修飾キーとロックの状態
+pub fn modifiers() -> Modifiers {
+    without_interrupts(|| KEYBOARD.lock().modifiers)
+}
+
+pub fn layout() -> Layout {
+    without_interrupts(|| KEYBOARD.lock().layout)
+}
+
+/// キーボード配列を切り替える。ロックの状態はそのまま残る
+pub fn set_layout(layout: Layout) {
+    without_interrupts(|| KEYBOARD.lock().layout = layout);
+}
+
+/// Caps/Num/Scroll Lock の状態を設定し、LEDに反映する。以後の文字への変換もこの状態に従う
+pub fn set_locks(caps_lock: bool, num_lock: bool, scroll_lock: bool) {
+    without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+        let modifiers = &mut keyboard.modifiers;
+        modifiers.caps_lock = caps_lock;
+        modifiers.num_lock = num_lock;
+        modifiers.scroll_lock = scroll_lock;
+        keyboard.send_leds();
+    });
+}
+
+pub fn scancode_set() -> ScancodeSet {
+    without_interrupts(|| KEYBOARD.lock().scancode_set)
+}
+
+/// キーボードが送るスキャンコードセットを切り替える
+///
+/// セット2を使うときは8042による変換を止める必要があるので、コントローラの設定も書き換える
+pub fn set_scancode_set(set: ScancodeSet) -> Result<(), Ps2Error> {
+    ps2::ensure_port(Ps2Port::First)?;
+    without_interrupts(|| {
+        let mut keyboard = KEYBOARD.lock();
+
+        ps2::device_command(Ps2Port::First, COMMAND_SCANCODE_SET)?;
+        // セット1をそのまま送れないキーボードもあるので、キーボードは常にセット2にして
+        // セット1が欲しいときは8042に変換させる
+        ps2::device_command(Ps2Port::First, 2)?;
+        ps2::set_translation(set == ScancodeSet::Set1)?;
+
+        keyboard.scancodes = new_scancodes(set);
+        keyboard.scancode_set = set;
+        keyboard.pause_prefix = false;
+        Ok(())
+    })
+}
+
+/// キーボードのスキャンを有効にし、LEDを今のロック状態に合わせる。`ps2::init` の後に呼ぶこと
+pub fn init() -> Result<(), Ps2Error> {
+    ps2::ensure_port(Ps2Port::First)?;
+    without_interrupts(|| {
+        let leds = KEYBOARD.lock().modifiers.leds();
+        ps2::device_command(Ps2Port::First, COMMAND_SET_LEDS)?;
+        ps2::device_command(Ps2Port::First, leds)?;
+        ps2::device_command(Ps2Port::First, COMMAND_ENABLE_SCANNING)
+    })
+}
+
+#[test_case]
+fn test_layout_names_round_trip() {
+    for layout in Layout::ALL {
+        assert_eq!(Layout::from_name(layout.name()), Some(layout));
+    }
+    assert_eq!(Layout::from_name("qwertz"), None);
+}
+
+#[test_case]
+fn test_modifier_leds() {
+    let mut modifiers = Modifiers::default();
+    modifiers.update(KeyCode::CapsLock, KeyState::Down);
+    modifiers.update(KeyCode::CapsLock, KeyState::Up);
+    modifiers.update(KeyCode::LShift, KeyState::Down);
+    assert!(modifiers.caps_lock && modifiers.shift());
+    assert_eq!(modifiers.leds(), 0b100);
+}
+
+#[test_case]
+fn test_decode_follows_locks() {
+    let mut modifiers = Modifiers::default();
+    let decode_us = |modifiers, code| decode(Layout::Us104, modifiers, false, code, KeyState::Down);
+    assert_eq!(
+        decode_us(modifiers, KeyCode::Numpad1),
+        Some(DecodedKey::RawKey(KeyCode::End))
+    );
+    modifiers.caps_lock = true;
+    modifiers.num_lock = true;
+    assert_eq!(
+        decode_us(modifiers, KeyCode::A),
+        Some(DecodedKey::Unicode('A'))
+    );
+    assert_eq!(
+        decode_us(modifiers, KeyCode::Numpad1),
+        Some(DecodedKey::Unicode('1'))
+    );
+    assert_eq!(
+        decode(Layout::Us104, modifiers, false, KeyCode::A, KeyState::Up),
+        None
+    );
+}
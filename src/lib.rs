@@ -8,17 +8,59 @@
 extern crate alloc;
 use core::panic::PanicInfo;
 
+pub mod acpi;
+pub mod ata;
 pub mod gdt;
 pub mod interrupts;
 pub mod memory;
 pub mod serial;
 pub mod vga_buffer;
 pub mod allocator;
+pub mod block;
+pub mod block_cache;
+pub mod chardev;
+pub mod devfs;
+pub mod e1000;
+pub mod ext2;
+pub mod fat;
+pub mod hpet;
+pub mod initrd;
+pub mod keyboard;
+pub mod mouse;
+pub mod net;
+pub mod pci;
+pub mod pit;
+pub mod power;
+pub mod procfs;
+pub mod ps2;
+pub mod queue;
+pub mod ramdisk;
+pub mod registry;
+pub mod rtc;
+pub mod shell;
+pub mod time;
+pub mod tmpfs;
+pub mod vfs;
+pub mod virtio;
+pub mod virtio_blk;
+pub mod virtio_net;
 
 pub fn init() {
     gdt::init();
     interrupts::init_idt();
     unsafe { interrupts::PICS.lock().initialize() };
+    pit::init();
+    time::init();
+    rtc::init();
+    match ps2::init() {
+        Ok(controller) if controller.port1 => {
+            if let Err(err) = keyboard::init() {
+                log!("keyboard initialization failed: {}", err);
+            }
+        }
+        Ok(_) => log!("ps2: no keyboard port"),
+        Err(err) => log!("{}", err),
+    }
     x86_64::instructions::interrupts::enable(); // CPU listens to the interrupt
 }
 
@@ -42,11 +84,28 @@ where
 {
     fn run(&self) {
         serial_print!("{}...\t", core::any::type_name::<T>());
-        self();
-        serial_println!("[ok]");
+        let ((), elapsed) = time::measure(self);
+        serial_println!("[ok] ({} us)", elapsed.as_micros());
     }
 }
 
+/// 統合テストの `main` から呼ぶ初期化
+///
+/// `init` に加えてページテーブルとヒープを用意し、`memory::MAPPER` と
+/// `memory::FRAME_ALLOCATOR` に置く
+pub fn test_init(boot_info: &'static bootloader::BootInfo) {
+    use memory::BootInfoFrameAllocator;
+    use x86_64::VirtAddr;
+
+    init();
+    let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
+    let mut mapper = unsafe { memory::init(phys_mem_offset) };
+    let mut frame_allocator = unsafe { BootInfoFrameAllocator::init(&boot_info.memory_map) };
+    allocator::init_heap(&mut mapper, &mut frame_allocator).expect("heap initialization failed");
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+}
+
 pub fn test_runner(tests: &[&dyn Testable]) {
     serial_println!("Running {} tests", tests.len());
     for test in tests {
@@ -7,11 +7,8 @@
 
 extern crate alloc;
 
-mod serial;
-mod vga_buffer;
-
-use alloc::{boxed::Box, vec, vec::Vec, rc::Rc};
-use blog_os::memory;
+use alloc::{boxed::Box, vec, vec::Vec, rc::Rc, sync::Arc};
+use blog_os::{log, memory, println, vga_buffer};
 use bootloader::{BootInfo, entry_point};
 use core::panic::PanicInfo;
 use x86_64::{
@@ -30,6 +27,9 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     // initialize IDT, GDT, PICS, interrputs
     blog_os::init();
+    if let Err(err) = blog_os::mouse::init() {
+        log!("mouse initialization failed: {}", err);
+    }
 
     let phys_mem_offset = VirtAddr::new(boot_info.physical_memory_offset);
     println!("phys_mem_offset: {:#?}", phys_mem_offset);
@@ -58,6 +58,50 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
 
     allocator::init_heap(&mut mapper, &mut frame_allocator)
         .expect("heap initialization failed");
+    vga_buffer::enable_scrollback();
+    *memory::MAPPER.lock() = Some(mapper);
+    *memory::FRAME_ALLOCATOR.lock() = Some(frame_allocator);
+
+    // ルートはディスクがなくても使えるtmpfsにする
+    blog_os::vfs::mount("/", Arc::new(blog_os::tmpfs::TmpFs::default()))
+        .expect("mounting the root file system failed");
+    blog_os::vfs::mkdir("/tmp").expect("creating /tmp failed");
+    if let Err(err) = blog_os::initrd::init() {
+        log!("{}", err);
+    }
+    if let Err(err) = blog_os::procfs::init() {
+        log!("{}", err);
+    }
+    blog_os::chardev::init();
+    if let Err(err) = blog_os::devfs::init() {
+        log!("{}", err);
+    }
+
+    if let Err(err) = blog_os::acpi::init().and_then(|()| blog_os::power::init()) {
+        log!("{}", err);
+    }
+    // FADTに世紀のレジスタがあれば、それを使って起動時の日時を読み直す
+    blog_os::rtc::init();
+    // HPETがあればTSCを較正し直し、PITの代わりにタイマー割り込みを出させる。
+    // レガシー置き換えでIRQ8もHPETに移るが、RTCの周期割り込みはまだ誰も使っていない
+    let hpet = blog_os::hpet::init();
+    if hpet.is_ok()
+        && let Err(err) = blog_os::time::calibrate_with_hpet()
+    {
+        log!("{}", err);
+    }
+    match hpet.and_then(|_| blog_os::hpet::use_as_system_timer()) {
+        Ok(()) => log!("hpet: driving the system timer"),
+        Err(err) => log!("{}", err),
+    }
+
+    let pci_devices = blog_os::pci::init();
+    log!("pci: {} functions", pci_devices);
+    blog_os::pci::register_driver(&blog_os::ata::DRIVER);
+    blog_os::pci::register_driver(&blog_os::virtio_blk::DRIVER);
+    blog_os::pci::register_driver(&blog_os::e1000::DRIVER);
+    blog_os::pci::register_driver(&blog_os::virtio_net::DRIVER);
+    blog_os::pci::probe_drivers();
 
     // allocate a number on the heap
     let heap_value = Box::new(41);
@@ -81,15 +125,37 @@ fn kernel_main(boot_info: &'static BootInfo) -> ! {
     test_main();
 
     println!("It did not crash!");
-    blog_os::hlt_loop();
+    blog_os::shell::run();
 }
 
 #[panic_handler]
 fn panic(info: &PanicInfo) -> ! {
-    use crate::vga_buffer::Color;
+    use blog_os::serial::SERIAL1;
+    use blog_os::vga_buffer::Color;
     use core::fmt::Write;
-    let mut writer = vga_buffer::WRITER.lock();
+
+    // ロックを外して取り直すので、そのあいだに割り込みハンドラが書き込まないようにする
+    x86_64::instructions::interrupts::disable();
+
+    // シェルが動いていれば表示中なのはコンソール0ではないので、表示中のコンソールに書く
+    let mut writer = vga_buffer::lock_active_for_panic();
+    writer.scroll_to_bottom();
     writer.set_color(vga_buffer::ColorCode::new(Color::Red, Color::Black));
     write!(writer, "{}", info).unwrap();
-    blog_os::hlt_loop();
+    write!(writer, "\nrebooting in {} seconds", PANIC_REBOOT_DELAY.as_secs()).unwrap();
+    drop(writer);
+
+    // 画面のないときのためにシリアルにも出す
+    if SERIAL1.try_lock().is_none() {
+        unsafe { SERIAL1.force_unlock() };
+    }
+    blog_os::serial_println!("{}", info);
+
+    // 画面を読む時間をおいてから再起動する。割り込みは止まっているかもしれず、
+    // TSCが較正されていなければ `Instant` は進まないので、PITで待つ
+    blog_os::pit::busy_wait(PANIC_REBOOT_DELAY);
+    blog_os::power::reboot();
 }
+
+/// パニックしてから再起動するまでの時間
+const PANIC_REBOOT_DELAY: blog_os::time::Duration = blog_os::time::Duration::from_secs(10);
@@ -1,14 +1,35 @@
 use x86_64::{
     PhysAddr, VirtAddr,
-    structures::paging::{FrameAllocator, OffsetPageTable, PageTable, PhysFrame, Size4KiB},
+    structures::paging::{
+        FrameAllocator, Mapper, OffsetPageTable, Page, PageTable, PageTableFlags, PhysFrame,
+        Size4KiB, mapper::MapToError,
+    },
 };
 
 use bootloader::bootinfo::MemoryMap;
+use core::sync::atomic::{AtomicU64, Ordering};
+use spin::Mutex;
+
+/// `kernel_main` の外からページテーブルを触るためのマッパー
+///
+/// ヒープの初期化が終わったら `kernel_main` がここに移す
+pub static MAPPER: Mutex<Option<OffsetPageTable<'static>>> = Mutex::new(None);
+
+/// `MAPPER` と同じタイミングで置かれるフレームアロケータ
+pub static FRAME_ALLOCATOR: Mutex<Option<BootInfoFrameAllocator>> = Mutex::new(None);
 
 /// boot時のメモリマップから利用可能なフレームを取得する
 pub struct BootInfoFrameAllocator {
     memory_map: &'static MemoryMap,
+    /// 次に調べる位置: メモリマップの領域の番号と、その中のアドレス
+    region: usize,
+    address: u64,
+    /// カーソルより前にある利用可能なフレームの数 (割り当てたものと空きリストにあるもの)
     next: usize,
+    /// `allocate_contiguous` が飛ばしたフレームの空きリスト。各フレームの先頭に次のフレームの
+    /// 物理アドレスを書いておく。フレーム0は利用可能にならないので、0でリストの終わりを表す
+    free: Option<PhysFrame>,
+    free_count: usize,
 }
 
 use bootloader::bootinfo::MemoryRegionType;
@@ -17,7 +38,11 @@ impl BootInfoFrameAllocator {
     pub unsafe fn init(memory_map: &'static MemoryMap) -> Self {
         BootInfoFrameAllocator {
             memory_map,
+            region: 0,
+            address: 0,
             next: 0,
+            free: None,
+            free_count: 0,
         }
     }
 
@@ -25,20 +50,114 @@ impl BootInfoFrameAllocator {
     ///
     /// 特定の型ではなくItemにPhysFrameをもつイテレータの実装が返されることを期待している
     fn usable_frames(&self) -> impl Iterator<Item = PhysFrame> {
-        let regions = self.memory_map.iter();
+        Self::usable_frames_from(self.memory_map, 0, 0).map(|(_, frame)| frame)
+    }
+
+    /// 領域 `region` のアドレス `address` から先の利用可能なフレームを、領域の番号と一緒に返す
+    fn usable_frames_from(
+        memory_map: &'static MemoryMap,
+        region: usize,
+        address: u64,
+    ) -> impl Iterator<Item = (usize, PhysFrame)> {
+        let regions = memory_map.iter().enumerate().skip(region);
         // ここで使用可能なフレームをフィルタリングする
-        let usable_regions = regions.filter(|r| r.region_type == MemoryRegionType::Usable);
-        let addr_ranges = usable_regions.map(|r| r.range.start_addr()..r.range.end_addr());
-        let frame_address = addr_ranges.flat_map(|r| r.step_by(4096));
-        frame_address.map(|addr| PhysFrame::containing_address(PhysAddr::new(addr)))
+        let usable_regions = regions.filter(|(_, r)| r.region_type == MemoryRegionType::Usable);
+        usable_regions.flat_map(move |(index, r)| {
+            let start = if index == region {
+                r.range.start_addr().max(address)
+            } else {
+                r.range.start_addr()
+            };
+            (start..r.range.end_addr())
+                .step_by(4096)
+                .map(move |addr| (index, PhysFrame::containing_address(PhysAddr::new(addr))))
+        })
+    }
+
+    /// カーソルを `frame` の次に進める。`skipped` は `frame` までに飛ばした利用可能なフレームの数
+    fn advance(&mut self, region: usize, frame: PhysFrame, skipped: usize) {
+        self.region = region;
+        self.address = frame.start_address().as_u64() + frame.size();
+        self.next += skipped + 1;
+    }
+
+    /// 飛ばしたフレームを空きリストに積む
+    fn push_free(&mut self, frame: PhysFrame) {
+        let next = self.free.map_or(0, |free| free.start_address().as_u64());
+        unsafe {
+            phys_to_virt(frame.start_address())
+                .as_mut_ptr::<u64>()
+                .write(next)
+        };
+        self.free = Some(frame);
+        self.free_count += 1;
+    }
+
+    fn pop_free(&mut self) -> Option<PhysFrame> {
+        let frame = self.free?;
+        let next = unsafe { phys_to_virt(frame.start_address()).as_ptr::<u64>().read() };
+        self.free = (next != 0).then(|| PhysFrame::containing_address(PhysAddr::new(next)));
+        self.free_count -= 1;
+        Some(frame)
+    }
+
+    /// bootloaderから渡された物理メモリのマップ
+    pub fn memory_map(&self) -> &'static MemoryMap {
+        self.memory_map
+    }
+
+    /// 利用可能な物理フレームの総数
+    pub fn usable_frame_count(&self) -> usize {
+        self.usable_frames().count()
+    }
+
+    /// これまでに割り当てたフレームの数
+    pub fn allocated_frame_count(&self) -> usize {
+        self.next - self.free_count
+    }
+
+    /// 物理アドレスが連続した `count` 個のフレームを割り当て、先頭のフレームを返す
+    ///
+    /// 連続していないために飛ばしたフレームは空きリストに入り、`allocate_frame` が先に使う
+    pub fn allocate_contiguous(&mut self, count: usize) -> Option<PhysFrame> {
+        let mut run_start = None;
+        let mut run_length = 0;
+        let mut previous: Option<PhysFrame> = None;
+        let mut found = None;
+        let frames = Self::usable_frames_from(self.memory_map, self.region, self.address);
+        for (skipped, (region, frame)) in frames.enumerate() {
+            if previous.is_some_and(|previous| previous + 1 == frame) {
+                run_length += 1;
+            } else {
+                run_start = Some(frame);
+                run_length = 1;
+            }
+            previous = Some(frame);
+            if run_length == count {
+                found = Some((region, frame, skipped));
+                break;
+            }
+        }
+        let (region, frame, skipped) = found?;
+        let passed = Self::usable_frames_from(self.memory_map, self.region, self.address);
+        for (_, skipped_frame) in passed.take(skipped + 1 - count) {
+            self.push_free(skipped_frame);
+        }
+        self.advance(region, frame, skipped);
+        run_start
     }
 }
 
 unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
+    /// 空きリストを先に使い、次にカーソルから探す。割り当てのたびに先頭から数え直さない
     fn allocate_frame(&mut self) -> Option<PhysFrame<Size4KiB>> {
-        let frame = self.usable_frames().nth(self.next);
-        self.next += 1;
-        frame
+        if let Some(frame) = self.pop_free() {
+            return Some(frame);
+        }
+        let mut frames = Self::usable_frames_from(self.memory_map, self.region, self.address);
+        let (region, frame) = frames.next()?;
+        self.advance(region, frame, 0);
+        Some(frame)
     }
 }
 
@@ -46,6 +165,7 @@ unsafe impl FrameAllocator<Size4KiB> for BootInfoFrameAllocator {
 /// エイリアシングを防ぐため呼び出しは一度にする
 ///
 pub unsafe fn init(physical_memory_offset: VirtAddr) -> OffsetPageTable<'static> {
+    PHYSICAL_MEMORY_OFFSET.store(physical_memory_offset.as_u64(), Ordering::Relaxed);
     unsafe {
         let level_4_table = active_level_4_table(physical_memory_offset);
         OffsetPageTable::new(level_4_table, physical_memory_offset)
@@ -67,3 +187,83 @@ unsafe fn active_level_4_table(physical_memory_offset: VirtAddr) -> &'static mut
 
     unsafe { &mut *page_table_ptr }
 }
+
+/// bootloaderが物理メモリ全体をマップした仮想アドレス。`init` で設定される
+static PHYSICAL_MEMORY_OFFSET: AtomicU64 = AtomicU64::new(0);
+
+/// 物理メモリのオフセットを使って物理アドレスを仮想アドレスに変換する
+///
+/// キャッシュが効くマッピングなので、ACPIテーブルのような普通のメモリを読むのに使う
+pub fn phys_to_virt(addr: PhysAddr) -> VirtAddr {
+    let offset = PHYSICAL_MEMORY_OFFSET.load(Ordering::Relaxed);
+    assert!(offset != 0, "memory::init has not been called");
+    VirtAddr::new(offset + addr.as_u64())
+}
+
+/// デバイスのレジスタをマップする仮想アドレスの領域
+pub const MMIO_START: u64 = 0x_5555_5555_0000;
+
+/// 次にMMIOをマップする仮想アドレス
+static MMIO_NEXT: AtomicU64 = AtomicU64::new(MMIO_START);
+
+/// 物理アドレス `addr` から `size` バイトのデバイスのレジスタを、キャッシュを無効にしてマップする
+///
+/// `MAPPER` と `FRAME_ALLOCATOR` が置かれた後に呼ぶこと。マップした領域は解放しない
+pub fn map_mmio(addr: PhysAddr, size: u64) -> Result<VirtAddr, MapToError<Size4KiB>> {
+    let first_frame = PhysFrame::<Size4KiB>::containing_address(addr);
+    let last_frame = PhysFrame::<Size4KiB>::containing_address(addr + size.max(1) - 1u64);
+    let frames = PhysFrame::range_inclusive(first_frame, last_frame);
+    let pages = frames.count() as u64;
+
+    let start = VirtAddr::new(MMIO_NEXT.fetch_add(pages * 4096, Ordering::Relaxed));
+    let flags = PageTableFlags::PRESENT
+        | PageTableFlags::WRITABLE
+        | PageTableFlags::WRITE_THROUGH
+        | PageTableFlags::NO_CACHE;
+
+    let mut mapper = MAPPER.lock();
+    let mut frame_allocator = FRAME_ALLOCATOR.lock();
+    let mapper = mapper.as_mut().expect("memory::MAPPER is not initialized");
+    let frame_allocator = frame_allocator
+        .as_mut()
+        .expect("memory::FRAME_ALLOCATOR is not initialized");
+    for (i, frame) in frames.enumerate() {
+        let page = Page::<Size4KiB>::containing_address(start + i as u64 * 4096);
+        unsafe { mapper.map_to(page, frame, flags, frame_allocator)?.flush() };
+    }
+    Ok(start + (addr.as_u64() - first_frame.start_address().as_u64()))
+}
+
+/// デバイスとのDMAに使う、物理アドレスが連続したメモリ
+#[derive(Debug, Clone, Copy)]
+pub struct DmaRegion {
+    pub phys: PhysAddr,
+    pub virt: VirtAddr,
+    pub size: usize,
+}
+
+impl DmaRegion {
+    pub fn as_mut_ptr<T>(&self) -> *mut T {
+        self.virt.as_mut_ptr()
+    }
+}
+
+/// DMAのために物理的に連続した `pages` ページを割り当て、ゼロで埋める
+///
+/// 物理メモリ全体のマッピングを通して触る。x86ではDMAはキャッシュと一貫しているので、
+/// キャッシュを無効にする必要はない。割り当てたメモリは解放しない
+pub fn allocate_dma(pages: usize) -> Option<DmaRegion> {
+    let frame = FRAME_ALLOCATOR
+        .lock()
+        .as_mut()
+        .expect("memory::FRAME_ALLOCATOR is not initialized")
+        .allocate_contiguous(pages)?;
+    let phys = frame.start_address();
+    let region = DmaRegion {
+        phys,
+        virt: phys_to_virt(phys),
+        size: pages * 4096,
+    };
+    unsafe { core::ptr::write_bytes(region.as_mut_ptr::<u8>(), 0, region.size) };
+    Some(region)
+}
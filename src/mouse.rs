@@ -0,0 +1,211 @@
+//! PS/2マウスのドライバ
+//!
+//! 8042の2番目のポートにつながったマウスからIRQ12でパケットを受け取り、イベントのキューに積む
+
+use crate::interrupts;
+use crate::ps2::{self, Ps2Error, Ps2Port};
+use crate::queue::Queue;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct MouseButtons {
+    pub left: bool,
+    pub right: bool,
+    pub middle: bool,
+    /// 5ボタンマウスの4番目 (戻る)
+    pub fourth: bool,
+    /// 5ボタンマウスの5番目 (進む)
+    pub fifth: bool,
+}
+
+/// 1パケット分のマウスの動き
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct MouseEvent {
+    /// 右向きが正
+    pub dx: i16,
+    /// 上向きが正
+    pub dy: i16,
+    /// 手前に回すと正
+    pub wheel: i8,
+    pub buttons: MouseButtons,
+}
+
+struct MouseState {
+    packet: [u8; 4],
+    /// 受け取ったパケットのバイト数
+    received: usize,
+    /// 1パケットのバイト数。IntelliMouseなら4
+    packet_size: usize,
+    device_id: u8,
+}
+
+static MOUSE: Mutex<MouseState> = Mutex::new(MouseState {
+    packet: [0; 4],
+    received: 0,
+    packet_size: 3,
+    device_id: 0,
+});
+
+static EVENTS: Mutex<Queue<MouseEvent, 128>> = Mutex::new(Queue::new());
+
+const COMMAND_GET_DEVICE_ID: u8 = 0xf2;
+const COMMAND_SET_SAMPLE_RATE: u8 = 0xf3;
+const COMMAND_ENABLE_REPORTING: u8 = 0xf4;
+const COMMAND_SET_DEFAULTS: u8 = 0xf6;
+
+/// ホイール付き (IntelliMouse)
+const DEVICE_ID_WHEEL: u8 = 3;
+/// ホイールと5ボタン付き (IntelliMouse Explorer)
+const DEVICE_ID_FIVE_BUTTONS: u8 = 4;
+
+const MOUSE_IRQ: u8 = 12;
+const CASCADE_IRQ: u8 = 2;
+
+/// パケットの1バイト目のフラグ
+const LEFT_BUTTON: u8 = 1 << 0;
+const RIGHT_BUTTON: u8 = 1 << 1;
+const MIDDLE_BUTTON: u8 = 1 << 2;
+/// 1バイト目で常に立っているビット。パケットの区切りを見失ったときに使う
+const ALWAYS_ONE: u8 = 1 << 3;
+const X_SIGN: u8 = 1 << 4;
+const Y_SIGN: u8 = 1 << 5;
+const X_OVERFLOW: u8 = 1 << 6;
+const Y_OVERFLOW: u8 = 1 << 7;
+
+/// マウスを初期化してIRQ12を有効にする。`ps2::init` の後に呼ぶこと
+///
+/// ホイールと5ボタンの拡張は、決まった順にサンプルレートを設定してデバイスIDが変わるかで判定する
+pub fn init() -> Result<(), Ps2Error> {
+    ps2::ensure_port(Ps2Port::Second)?;
+    without_interrupts(|| {
+        command(COMMAND_SET_DEFAULTS)?;
+        for rate in [200, 100, 80] {
+            set_sample_rate(rate)?;
+        }
+        let mut device_id = query_device_id()?;
+        if device_id == DEVICE_ID_WHEEL {
+            for rate in [200, 200, 80] {
+                set_sample_rate(rate)?;
+            }
+            device_id = query_device_id()?;
+        }
+        set_sample_rate(100)?;
+        command(COMMAND_ENABLE_REPORTING)?;
+
+        let mut mouse = MOUSE.lock();
+        mouse.device_id = device_id;
+        mouse.packet_size = match device_id {
+            DEVICE_ID_WHEEL | DEVICE_ID_FIVE_BUTTONS => 4,
+            _ => 3,
+        };
+        mouse.received = 0;
+
+        interrupts::unmask_irq(CASCADE_IRQ);
+        interrupts::unmask_irq(MOUSE_IRQ);
+        Ok(())
+    })
+}
+
+fn command(byte: u8) -> Result<(), Ps2Error> {
+    ps2::device_command(Ps2Port::Second, byte)
+}
+
+fn set_sample_rate(rate: u8) -> Result<(), Ps2Error> {
+    command(COMMAND_SET_SAMPLE_RATE)?;
+    command(rate)
+}
+
+fn query_device_id() -> Result<u8, Ps2Error> {
+    command(COMMAND_GET_DEVICE_ID)?;
+    ps2::read_data()
+}
+
+/// マウスが名乗ったデバイスID。0なら普通の3ボタンマウス
+pub fn device_id() -> u8 {
+    without_interrupts(|| MOUSE.lock().device_id)
+}
+
+/// マウス割り込みで読んだバイトを処理する
+pub(crate) fn handle_byte(byte: u8) {
+    let mut mouse = MOUSE.lock();
+    if mouse.received == 0 && byte & ALWAYS_ONE == 0 {
+        // パケットの先頭ではないので読み捨てて区切りを合わせ直す
+        return;
+    }
+    let index = mouse.received;
+    mouse.packet[index] = byte;
+    mouse.received += 1;
+    if mouse.received < mouse.packet_size {
+        return;
+    }
+    mouse.received = 0;
+
+    let size = mouse.packet_size;
+    if let Some(event) = decode_packet(&mouse.packet[..size], mouse.device_id) {
+        // 溢れた分は捨てる
+        let _ = EVENTS.lock().push(event);
+    }
+}
+
+/// 3バイトまたは4バイトのパケットを読む。オーバーフローしたパケットは捨てる
+fn decode_packet(packet: &[u8], device_id: u8) -> Option<MouseEvent> {
+    let flags = packet[0];
+    if flags & (X_OVERFLOW | Y_OVERFLOW) != 0 {
+        return None;
+    }
+    let delta = |value: u8, negative: bool| i16::from(value) - if negative { 256 } else { 0 };
+
+    let mut event = MouseEvent {
+        dx: delta(packet[1], flags & X_SIGN != 0),
+        dy: delta(packet[2], flags & Y_SIGN != 0),
+        wheel: 0,
+        buttons: MouseButtons {
+            left: flags & LEFT_BUTTON != 0,
+            right: flags & RIGHT_BUTTON != 0,
+            middle: flags & MIDDLE_BUTTON != 0,
+            fourth: false,
+            fifth: false,
+        },
+    };
+    match (device_id, packet.get(3)) {
+        (DEVICE_ID_WHEEL, Some(&extra)) => event.wheel = extra as i8,
+        (DEVICE_ID_FIVE_BUTTONS, Some(&extra)) => {
+            // 下位4ビットが符号付きのホイール量
+            event.wheel = ((extra << 4) as i8) >> 4;
+            event.buttons.fourth = extra & (1 << 4) != 0;
+            event.buttons.fifth = extra & (1 << 5) != 0;
+        }
+        _ => {}
+    }
+    Some(event)
+}
+
+/// マウスイベントを1つ取り出す
+pub fn read_event() -> Option<MouseEvent> {
+    without_interrupts(|| EVENTS.lock().pop())
+}
+
+/// マウスイベントが残っているか
+pub fn events_pending() -> bool {
+    without_interrupts(|| !EVENTS.lock().is_empty())
+}
+
+#[test_case]
+fn test_decode_standard_packet() {
+    let event = decode_packet(&[ALWAYS_ONE | LEFT_BUTTON | X_SIGN, 0xfe, 0x05], 0).unwrap();
+    assert_eq!((event.dx, event.dy, event.wheel), (-2, 5, 0));
+    assert!(event.buttons.left && !event.buttons.right);
+}
+
+#[test_case]
+fn test_decode_wheel_packets() {
+    let event = decode_packet(&[ALWAYS_ONE, 0, 0, 0xff], DEVICE_ID_WHEEL).unwrap();
+    assert_eq!(event.wheel, -1);
+
+    let event = decode_packet(&[ALWAYS_ONE, 0, 0, 0x21], DEVICE_ID_FIVE_BUTTONS).unwrap();
+    assert_eq!(event.wheel, 1);
+    assert!(event.buttons.fifth && !event.buttons.fourth);
+
+    assert_eq!(decode_packet(&[ALWAYS_ONE | X_OVERFLOW, 0, 0], 0), None);
+}
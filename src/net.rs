@@ -0,0 +1,237 @@
+//! ネットワークインターフェース
+//!
+//! NICのドライバはすべて `NetDevice` を実装し、名前を付けて `DEVICES` に登録する。
+//! やり取りするのはFCSを除いたEthernetフレームで、上に載るプロトコルはドライバに依らない
+
+use crate::registry::Registry;
+use crate::time::{Duration, Instant};
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use core::fmt;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts;
+
+/// Ethernetヘッダ (宛先、送信元、タイプ) の大きさ
+pub const ETHERNET_HEADER_SIZE: usize = 14;
+/// VLANタグのない、FCSを除いたフレームの最大の大きさ
+pub const MAX_FRAME_SIZE: usize = ETHERNET_HEADER_SIZE + 1500;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum NetError {
+    /// ヘッダより短いか `MAX_FRAME_SIZE` より長いフレーム
+    InvalidFrame,
+    /// 受信したフレームが渡されたバッファに収まらない
+    BufferTooSmall,
+    /// リンクが切れている
+    LinkDown,
+    /// 送信キューが空かなかった
+    Timeout,
+    /// デバイスがエラーを返した
+    Device(&'static str),
+}
+
+impl fmt::Display for NetError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            NetError::InvalidFrame => write!(f, "net: invalid frame length"),
+            NetError::BufferTooSmall => write!(f, "net: buffer too small for the frame"),
+            NetError::LinkDown => write!(f, "net: link is down"),
+            NetError::Timeout => write!(f, "net: device timed out"),
+            NetError::Device(reason) => write!(f, "net: {}", reason),
+        }
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct MacAddress(pub [u8; 6]);
+
+impl MacAddress {
+    pub const BROADCAST: MacAddress = MacAddress([0xff; 6]);
+
+    pub fn is_multicast(&self) -> bool {
+        self.0[0] & 1 != 0
+    }
+}
+
+impl fmt::Display for MacAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let [a, b, c, d, e, g] = self.0;
+        write!(
+            f,
+            "{:02x}:{:02x}:{:02x}:{:02x}:{:02x}:{:02x}",
+            a, b, c, d, e, g
+        )
+    }
+}
+
+/// デバイスに計算を任せるチェックサムの位置
+///
+/// `start` からフレームの終わりまでのインターネットチェックサムを `start + offset` に書く。
+/// 書く位置には、TCPやUDPなら疑似ヘッダの和を、ICMPなら0を入れておく
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ChecksumOffload {
+    pub start: usize,
+    pub offset: usize,
+}
+
+/// 送受信したフレームの数
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct NetStats {
+    pub rx_packets: u64,
+    pub rx_bytes: u64,
+    /// 壊れていたか、バッファに収まらずに捨てたフレーム
+    pub rx_errors: u64,
+    pub tx_packets: u64,
+    pub tx_bytes: u64,
+}
+
+/// Ethernetのフレームを送受信するデバイス
+pub trait NetDevice: Send {
+    fn mac_address(&self) -> MacAddress;
+
+    fn link_up(&self) -> bool;
+
+    /// フレームを1つ送る。送信キューに積んだら戻る
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError>;
+
+    /// 受信したフレームを1つ `buffer` に取り出して長さを返す。なければ `None`
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, NetError>;
+
+    /// `checksum` の位置にチェックサムを入れてフレームを送る
+    ///
+    /// 既定ではここで計算してから `transmit` する。計算できるデバイスはデバイスに任せる
+    fn transmit_with_checksum(
+        &mut self,
+        frame: &mut [u8],
+        checksum: ChecksumOffload,
+    ) -> Result<(), NetError> {
+        complete_checksum(frame, checksum)?;
+        self.transmit(frame)
+    }
+
+    fn stats(&self) -> NetStats;
+
+    /// 一覧に表示する説明 (ドライバ名など)
+    fn description(&self) -> &str {
+        ""
+    }
+}
+
+/// フレームの長さが送れる範囲か確かめる
+pub fn check_frame(frame: &[u8]) -> Result<(), NetError> {
+    if (ETHERNET_HEADER_SIZE..=MAX_FRAME_SIZE).contains(&frame.len()) {
+        Ok(())
+    } else {
+        Err(NetError::InvalidFrame)
+    }
+}
+
+/// `data` の1の補数和を16ビットに畳んで反転したもの (RFC 1071)
+///
+/// チェックサムの欄を含めて計算して0になれば、チェックサムは正しい
+pub fn internet_checksum(data: &[u8]) -> u16 {
+    let mut sum: u32 = 0;
+    let mut words = data.chunks_exact(2);
+    for word in &mut words {
+        sum += u32::from(u16::from_be_bytes([word[0], word[1]]));
+    }
+    if let [last] = words.remainder() {
+        sum += u32::from(*last) << 8;
+    }
+    while sum > 0xffff {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// `checksum` の位置にチェックサムを計算して書く
+pub fn complete_checksum(frame: &mut [u8], checksum: ChecksumOffload) -> Result<(), NetError> {
+    let field = checksum.start + checksum.offset;
+    if field + 2 > frame.len() {
+        return Err(NetError::InvalidFrame);
+    }
+    let value = internet_checksum(&frame[checksum.start..]);
+    frame[field..field + 2].copy_from_slice(&value.to_be_bytes());
+    Ok(())
+}
+
+/// フレームが届くまで待って受け取る。届かなければ `None`
+///
+/// 割り込みが有効なら受信割り込みが来るまでhltで眠る。無効ならポーリングする
+pub fn receive_timeout(
+    device: &SharedNetDevice,
+    buffer: &mut [u8],
+    timeout: Duration,
+) -> Result<Option<usize>, NetError> {
+    let sleep = interrupts::are_enabled();
+    let start = Instant::now();
+    loop {
+        if sleep {
+            interrupts::disable();
+        }
+        let received = device.lock().receive(buffer);
+        if !matches!(received, Ok(None)) || start.elapsed() > timeout {
+            if sleep {
+                interrupts::enable();
+            }
+            return received;
+        }
+        if sleep {
+            // 受信割り込みが来なくてもタイマー割り込みで起きる
+            interrupts::enable_and_hlt();
+        } else {
+            core::hint::spin_loop();
+        }
+    }
+}
+
+/// カーネルのあちこちから共有されるネットワークデバイス
+pub type SharedNetDevice = Arc<Mutex<dyn NetDevice>>;
+
+/// 名前で登録されたネットワークデバイス
+pub static DEVICES: Registry<SharedNetDevice> = Registry::new();
+
+/// 次に付ける名前の番号 (`eth0`, `eth1`, ...)
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+/// まだ使っていない `eth<n>` の名前
+pub fn next_name() -> String {
+    format!("eth{}", NEXT_INDEX.fetch_add(1, Ordering::Relaxed))
+}
+
+#[test_case]
+fn test_check_frame() {
+    assert_eq!(check_frame(&[0; 13]), Err(NetError::InvalidFrame));
+    assert_eq!(check_frame(&[0; 60]), Ok(()));
+    assert_eq!(check_frame(&[0; MAX_FRAME_SIZE]), Ok(()));
+    assert_eq!(
+        check_frame(&[0; MAX_FRAME_SIZE + 1]),
+        Err(NetError::InvalidFrame)
+    );
+    assert!(MacAddress::BROADCAST.is_multicast());
+    assert!(!MacAddress([0x52, 0x54, 0, 0x12, 0x34, 0x56]).is_multicast());
+}
+
+#[test_case]
+fn test_complete_checksum() {
+    // IPv4ヘッダ (チェックサムは 0xb861)
+    let mut header = [
+        0x45, 0x00, 0x00, 0x73, 0x00, 0x00, 0x40, 0x00, 0x40, 0x11, 0x00, 0x00, 0xc0, 0xa8, 0x00,
+        0x01, 0xc0, 0xa8, 0x00, 0xc7,
+    ];
+    let checksum = ChecksumOffload {
+        start: 0,
+        offset: 10,
+    };
+    complete_checksum(&mut header, checksum).unwrap();
+    assert_eq!(header[10..12], [0xb8, 0x61]);
+    assert_eq!(internet_checksum(&header), 0);
+    // 奇数の長さの最後のバイトは上位に置く
+    assert_eq!(internet_checksum(&[0x12]), !0x1200);
+    assert_eq!(
+        complete_checksum(&mut header[..11], checksum),
+        Err(NetError::InvalidFrame)
+    );
+}
@@ -0,0 +1,841 @@
+//! PCIバスのドライバ
+//!
+//! コンフィギュレーション空間はACPIのMCFGがあればPCI ExpressのECAMで、
+//! なければ0xCF8/0xCFCのポートI/Oで読み書きする。起動時に全バスを走査してデバイスを集め、
+//! 登録されたドライバとベンダー/デバイスID・クラスで結びつける
+
+use crate::acpi::{self, McfgEntry};
+use crate::{log, memory};
+use alloc::vec::Vec;
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+use x86_64::{PhysAddr, VirtAddr};
+
+const CONFIG_ADDRESS: u16 = 0xcf8;
+const CONFIG_DATA: u16 = 0xcfc;
+/// CONFIG_ADDRESS: コンフィギュレーション空間へのアクセスを有効にする
+const CONFIG_ENABLE: u32 = 1 << 31;
+
+/// ECAMでバス1本あたりに割り当てられる大きさ (32デバイス x 8ファンクション x 4KiB)
+const ECAM_BUS_SIZE: u64 = 1 << 20;
+
+pub const MAX_DEVICES: u8 = 32;
+pub const MAX_FUNCTIONS: u8 = 8;
+
+// コンフィギュレーション空間のヘッダ
+const REGISTER_VENDOR_ID: u16 = 0x00;
+const REGISTER_DEVICE_ID: u16 = 0x02;
+const REGISTER_COMMAND: u16 = 0x04;
+const REGISTER_STATUS: u16 = 0x06;
+const REGISTER_REVISION: u16 = 0x08;
+const REGISTER_HEADER_TYPE: u16 = 0x0e;
+const REGISTER_BAR0: u16 = 0x10;
+const REGISTER_SUBSYSTEM_VENDOR_ID: u16 = 0x2c;
+const REGISTER_SUBSYSTEM_ID: u16 = 0x2e;
+const REGISTER_CAPABILITIES: u16 = 0x34;
+/// ブリッジの先のバスの番号
+const REGISTER_SECONDARY_BUS: u16 = 0x19;
+const REGISTER_INTERRUPT_LINE: u16 = 0x3c;
+const REGISTER_INTERRUPT_PIN: u16 = 0x3d;
+
+/// ベンダーIDがこの値ならデバイスがない
+const NO_DEVICE: u16 = 0xffff;
+
+/// ヘッダの種類: 複数のファンクションを持つ
+const HEADER_MULTIFUNCTION: u8 = 1 << 7;
+const HEADER_TYPE_MASK: u8 = 0x7f;
+const HEADER_TYPE_GENERAL: u8 = 0x00;
+/// PCI-to-PCIブリッジのBARは2つ
+const HEADER_TYPE_BRIDGE: u8 = 0x01;
+
+/// コマンドレジスタ
+pub const COMMAND_IO_SPACE: u16 = 1 << 0;
+pub const COMMAND_MEMORY_SPACE: u16 = 1 << 1;
+pub const COMMAND_BUS_MASTER: u16 = 1 << 2;
+pub const COMMAND_INTERRUPT_DISABLE: u16 = 1 << 10;
+
+/// ステータスレジスタ: ケーパビリティのリストがある
+const STATUS_CAPABILITIES: u16 = 1 << 4;
+
+/// ケーパビリティのID
+pub const CAPABILITY_MSI: u8 = 0x05;
+pub const CAPABILITY_VENDOR: u8 = 0x09;
+pub const CAPABILITY_PCI_EXPRESS: u8 = 0x10;
+pub const CAPABILITY_MSIX: u8 = 0x11;
+
+/// BAR: I/O空間
+const BAR_IO: u32 = 1 << 0;
+/// BAR: メモリ空間で64ビット
+const BAR_MEMORY_64: u32 = 0b10 << 1;
+const BAR_MEMORY_TYPE_MASK: u32 = 0b11 << 1;
+const BAR_PREFETCHABLE: u32 = 1 << 3;
+
+/// MSIの制御レジスタ
+const MSI_ENABLE: u16 = 1 << 0;
+const MSI_MULTIPLE_MESSAGE_ENABLE_MASK: u16 = 0b111 << 4;
+const MSI_64BIT: u16 = 1 << 7;
+/// MSI-Xの制御レジスタ
+const MSIX_TABLE_SIZE_MASK: u16 = 0x7ff;
+const MSIX_FUNCTION_MASK: u16 = 1 << 14;
+const MSIX_ENABLE: u16 = 1 << 15;
+/// MSI-Xのテーブルの1項目の大きさ
+const MSIX_ENTRY_SIZE: u64 = 16;
+/// MSI-Xのテーブルの項目のベクタ制御: マスクする
+const MSIX_ENTRY_MASKED: u32 = 1 << 0;
+
+/// MSIのメッセージを送るLocal APICのアドレス
+const MSI_ADDRESS_BASE: u32 = 0xfee0_0000;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum PciError {
+    /// `init` がまだ終わっていない
+    NotInitialized,
+    /// そのBARは使われていない
+    NoSuchBar(usize),
+    /// そのBARはメモリ空間ではない
+    NotMemoryBar(usize),
+    /// BARをマップできなかった
+    MapFailed,
+    /// デバイスにそのケーパビリティがない
+    NoCapability(u8),
+    /// MSI-Xのテーブルにその番号の項目がない
+    NoSuchVector(u16),
+    /// ドライバがデバイスを使えなかった理由
+    Driver(&'static str),
+}
+
+impl fmt::Display for PciError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            PciError::NotInitialized => write!(f, "pci: not initialized"),
+            PciError::NoSuchBar(index) => write!(f, "pci: BAR{} is not implemented", index),
+            PciError::NotMemoryBar(index) => write!(f, "pci: BAR{} is not a memory BAR", index),
+            PciError::MapFailed => write!(f, "pci: failed to map a BAR"),
+            PciError::NoCapability(id) => write!(f, "pci: no capability {:#04x}", id),
+            PciError::NoSuchVector(index) => write!(f, "pci: no MSI-X vector {}", index),
+            PciError::Driver(reason) => write!(f, "{}", reason),
+        }
+    }
+}
+
+/// セグメント:バス:デバイス.ファンクション
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct PciAddress {
+    pub segment: u16,
+    pub bus: u8,
+    pub device: u8,
+    pub function: u8,
+}
+
+impl PciAddress {
+    pub const fn new(segment: u16, bus: u8, device: u8, function: u8) -> PciAddress {
+        PciAddress {
+            segment,
+            bus,
+            device,
+            function,
+        }
+    }
+}
+
+impl fmt::Display for PciAddress {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}:{:02x}:{:02x}.{}",
+            self.segment, self.bus, self.device, self.function
+        )
+    }
+}
+
+/// ECAMの1区間。バスごとに最初にアクセスしたときにマップする
+struct EcamRegion {
+    entry: McfgEntry,
+    buses: Vec<Option<VirtAddr>>,
+}
+
+impl EcamRegion {
+    fn contains(&self, address: PciAddress) -> bool {
+        address.segment == self.entry.segment
+            && (self.entry.start_bus..=self.entry.end_bus).contains(&address.bus)
+    }
+
+    fn config_address(&mut self, address: PciAddress, offset: u16) -> Option<VirtAddr> {
+        let index = usize::from(address.bus - self.entry.start_bus);
+        let bus_base = match self.buses[index] {
+            Some(base) => base,
+            None => {
+                let physical = self.entry.base_address + u64::from(address.bus) * ECAM_BUS_SIZE;
+                let base = memory::map_mmio(PhysAddr::new(physical), ECAM_BUS_SIZE).ok()?;
+                self.buses[index] = Some(base);
+                base
+            }
+        };
+        let function = u64::from(address.device) << 15 | u64::from(address.function) << 12;
+        Some(bus_base + function + u64::from(offset & 0xffc))
+    }
+}
+
+/// コンフィギュレーション空間の読み書きの方法
+enum ConfigSpace {
+    PortIo,
+    Ecam(Vec<EcamRegion>),
+}
+
+static CONFIG_SPACE: Mutex<ConfigSpace> = Mutex::new(ConfigSpace::PortIo);
+
+/// コンフィギュレーション空間から4バイト読む。`offset` は4の倍数に切り下げる
+///
+/// デバイスがなければ全ビットが1になる
+pub fn read_config(address: PciAddress, offset: u16) -> u32 {
+    without_interrupts(|| match &mut *CONFIG_SPACE.lock() {
+        ConfigSpace::PortIo => port_io_read(address, offset),
+        ConfigSpace::Ecam(regions) => regions
+            .iter_mut()
+            .find(|region| region.contains(address))
+            .and_then(|region| region.config_address(address, offset))
+            .map_or(u32::MAX, |ptr| unsafe {
+                ptr.as_ptr::<u32>().read_volatile()
+            }),
+    })
+}
+
+/// コンフィギュレーション空間に4バイト書く
+pub fn write_config(address: PciAddress, offset: u16, value: u32) {
+    without_interrupts(|| match &mut *CONFIG_SPACE.lock() {
+        ConfigSpace::PortIo => port_io_write(address, offset, value),
+        ConfigSpace::Ecam(regions) => {
+            let ptr = regions
+                .iter_mut()
+                .find(|region| region.contains(address))
+                .and_then(|region| region.config_address(address, offset));
+            if let Some(ptr) = ptr {
+                unsafe { ptr.as_mut_ptr::<u32>().write_volatile(value) };
+            }
+        }
+    })
+}
+
+fn port_io_address(address: PciAddress, offset: u16) -> Option<u32> {
+    // ポートI/Oで届くのはセグメント0の先頭256バイトだけ
+    (address.segment == 0 && offset < 0x100).then(|| {
+        CONFIG_ENABLE
+            | u32::from(address.bus) << 16
+            | u32::from(address.device) << 11
+            | u32::from(address.function) << 8
+            | u32::from(offset & 0xfc)
+    })
+}
+
+fn port_io_read(address: PciAddress, offset: u16) -> u32 {
+    let Some(config_address) = port_io_address(address, offset) else {
+        return u32::MAX;
+    };
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+    let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+    unsafe {
+        address_port.write(config_address);
+        data_port.read()
+    }
+}
+
+fn port_io_write(address: PciAddress, offset: u16, value: u32) {
+    let Some(config_address) = port_io_address(address, offset) else {
+        return;
+    };
+    let mut address_port: Port<u32> = Port::new(CONFIG_ADDRESS);
+    let mut data_port: Port<u32> = Port::new(CONFIG_DATA);
+    unsafe {
+        address_port.write(config_address);
+        data_port.write(value);
+    }
+}
+
+pub fn read_config_u16(address: PciAddress, offset: u16) -> u16 {
+    (read_config(address, offset) >> ((offset & 2) * 8)) as u16
+}
+
+pub fn read_config_u8(address: PciAddress, offset: u16) -> u8 {
+    (read_config(address, offset) >> ((offset & 3) * 8)) as u8
+}
+
+/// 2バイト書く。同じ4バイトの残りは読んだ値のまま書き戻す
+pub fn write_config_u16(address: PciAddress, offset: u16, value: u16) {
+    let shift = (offset & 2) * 8;
+    let old = read_config(address, offset) & !(0xffff << shift);
+    write_config(address, offset, old | u32::from(value) << shift);
+}
+
+/// BARが指す領域
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Bar {
+    Memory {
+        address: u64,
+        size: u64,
+        prefetchable: bool,
+        /// 次のBARと合わせて64ビット
+        is_64bit: bool,
+    },
+    Io {
+        port: u16,
+        size: u32,
+    },
+}
+
+impl Bar {
+    /// BARに全ビット1を書いて読み戻した値 `mask` から大きさを求める
+    fn size_from_mask(mask: u64) -> u64 {
+        (!mask).wrapping_add(1)
+    }
+}
+
+/// 見つかったファンクション
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciDevice {
+    pub address: PciAddress,
+    pub vendor_id: u16,
+    pub device_id: u16,
+    pub subsystem_vendor_id: u16,
+    pub subsystem_id: u16,
+    pub class: u8,
+    pub subclass: u8,
+    pub prog_if: u8,
+    pub revision: u8,
+    pub header_type: u8,
+    /// レガシーな割り込みのIRQ番号 (ファームウェアが設定したもの)
+    pub interrupt_line: u8,
+    /// INTA#からINTD#なら1から4、使わなければ0
+    pub interrupt_pin: u8,
+    pub bars: [Option<Bar>; 6],
+}
+
+impl PciDevice {
+    /// `address` のファンクションを読む。なければ `None`
+    fn probe(address: PciAddress) -> Option<PciDevice> {
+        let vendor_id = read_config_u16(address, REGISTER_VENDOR_ID);
+        if vendor_id == NO_DEVICE {
+            return None;
+        }
+        let class_register = read_config(address, REGISTER_REVISION);
+        let mut device = PciDevice {
+            address,
+            vendor_id,
+            device_id: read_config_u16(address, REGISTER_DEVICE_ID),
+            subsystem_vendor_id: 0,
+            subsystem_id: 0,
+            class: (class_register >> 24) as u8,
+            subclass: (class_register >> 16) as u8,
+            prog_if: (class_register >> 8) as u8,
+            revision: class_register as u8,
+            header_type: read_config_u8(address, REGISTER_HEADER_TYPE),
+            interrupt_line: read_config_u8(address, REGISTER_INTERRUPT_LINE),
+            interrupt_pin: read_config_u8(address, REGISTER_INTERRUPT_PIN),
+            bars: [None; 6],
+        };
+        let bar_count = match device.header_type & HEADER_TYPE_MASK {
+            HEADER_TYPE_GENERAL => {
+                device.subsystem_vendor_id = read_config_u16(address, REGISTER_SUBSYSTEM_VENDOR_ID);
+                device.subsystem_id = read_config_u16(address, REGISTER_SUBSYSTEM_ID);
+                6
+            }
+            HEADER_TYPE_BRIDGE => 2,
+            _ => 0,
+        };
+        let mut index = 0;
+        while index < bar_count {
+            let bar = device.read_bar(index);
+            device.bars[index] = bar;
+            index += match bar {
+                Some(Bar::Memory { is_64bit: true, .. }) => 2,
+                _ => 1,
+            };
+        }
+        Some(device)
+    }
+
+    pub fn read_u32(&self, offset: u16) -> u32 {
+        read_config(self.address, offset)
+    }
+
+    pub fn read_u16(&self, offset: u16) -> u16 {
+        read_config_u16(self.address, offset)
+    }
+
+    pub fn read_u8(&self, offset: u16) -> u8 {
+        read_config_u8(self.address, offset)
+    }
+
+    pub fn write_u32(&self, offset: u16, value: u32) {
+        write_config(self.address, offset, value)
+    }
+
+    pub fn write_u16(&self, offset: u16, value: u16) {
+        write_config_u16(self.address, offset, value)
+    }
+
+    pub fn command(&self) -> u16 {
+        self.read_u16(REGISTER_COMMAND)
+    }
+
+    pub fn set_command(&self, command: u16) {
+        self.write_u16(REGISTER_COMMAND, command)
+    }
+
+    /// メモリ空間とI/O空間のデコードと、DMAのためのバスマスタを有効にする
+    pub fn enable(&self) {
+        self.set_command(
+            self.command() | COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE | COMMAND_BUS_MASTER,
+        );
+    }
+
+    /// BARの値を読み、全ビット1を書いて大きさを調べる
+    fn read_bar(&self, index: usize) -> Option<Bar> {
+        let offset = REGISTER_BAR0 + index as u16 * 4;
+        let original = self.read_u32(offset);
+
+        // 大きさを調べている間にデバイスが変なアドレスに反応しないようデコードを止める
+        let command = self.command();
+        self.set_command(command & !(COMMAND_IO_SPACE | COMMAND_MEMORY_SPACE));
+
+        let bar = if original & BAR_IO != 0 {
+            self.write_u32(offset, u32::MAX);
+            let mask = self.read_u32(offset) & !0b11;
+            self.write_u32(offset, original);
+            let size = Bar::size_from_mask(u64::from(mask) | 0xffff_ffff_0000_0000) as u32;
+            (mask != 0).then_some(Bar::Io {
+                port: (original & !0b11) as u16,
+                size: size & 0xffff,
+            })
+        } else {
+            let is_64bit = original & BAR_MEMORY_TYPE_MASK == BAR_MEMORY_64;
+            self.write_u32(offset, u32::MAX);
+            let low_mask = self.read_u32(offset) & !0xf;
+            self.write_u32(offset, original);
+            let (high, high_mask) = if is_64bit {
+                let high = self.read_u32(offset + 4);
+                self.write_u32(offset + 4, u32::MAX);
+                let high_mask = self.read_u32(offset + 4);
+                self.write_u32(offset + 4, high);
+                (high, high_mask)
+            } else {
+                (0, u32::MAX)
+            };
+            let mask = u64::from(high_mask) << 32 | u64::from(low_mask);
+            (low_mask != 0).then_some(Bar::Memory {
+                address: u64::from(high) << 32 | u64::from(original & !0xf),
+                size: Bar::size_from_mask(mask),
+                prefetchable: original & BAR_PREFETCHABLE != 0,
+                is_64bit,
+            })
+        };
+
+        self.set_command(command);
+        bar
+    }
+
+    pub fn bar(&self, index: usize) -> Result<Bar, PciError> {
+        self.bars
+            .get(index)
+            .copied()
+            .flatten()
+            .ok_or(PciError::NoSuchBar(index))
+    }
+
+    /// メモリ空間のBARをキャッシュを無効にしてマップする
+    pub fn map_bar(&self, index: usize) -> Result<VirtAddr, PciError> {
+        match self.bar(index)? {
+            Bar::Memory { address, size, .. } => {
+                memory::map_mmio(PhysAddr::new(address), size).map_err(|_| PciError::MapFailed)
+            }
+            Bar::Io { .. } => Err(PciError::NotMemoryBar(index)),
+        }
+    }
+
+    /// ケーパビリティのリストを (ID, オフセット) の組でたどる
+    pub fn capabilities(&self) -> Capabilities {
+        let offset = if self.read_u16(REGISTER_STATUS) & STATUS_CAPABILITIES != 0 {
+            self.read_u8(REGISTER_CAPABILITIES) & !0b11
+        } else {
+            0
+        };
+        Capabilities {
+            address: self.address,
+            offset,
+            remaining: MAX_CAPABILITIES,
+        }
+    }
+
+    pub fn find_capability(&self, id: u8) -> Option<u16> {
+        self.capabilities()
+            .find(|&(capability, _)| capability == id)
+            .map(|(_, offset)| offset)
+    }
+
+    /// MSIを有効にして、割り込みを `apic_id` のLocal APICの `vector` に送らせる
+    ///
+    /// Local APICが有効になっていなければ割り込みは届かない
+    pub fn enable_msi(&self, vector: u8, apic_id: u8) -> Result<(), PciError> {
+        let capability = self
+            .find_capability(CAPABILITY_MSI)
+            .ok_or(PciError::NoCapability(CAPABILITY_MSI))?;
+        let control = self.read_u16(capability + 2);
+        let (address, data) = msi_message(vector, apic_id);
+        self.write_u32(capability + 4, address);
+        if control & MSI_64BIT != 0 {
+            self.write_u32(capability + 8, 0);
+            self.write_u16(capability + 12, data);
+        } else {
+            self.write_u16(capability + 8, data);
+        }
+        // ベクタは1つだけ使う
+        let control = control & !MSI_MULTIPLE_MESSAGE_ENABLE_MASK | MSI_ENABLE;
+        self.write_u16(capability + 2, control);
+        self.set_command(self.command() | COMMAND_INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    /// MSI-Xのテーブルの場所を読む
+    pub fn msix(&self) -> Result<Msix, PciError> {
+        let capability = self
+            .find_capability(CAPABILITY_MSIX)
+            .ok_or(PciError::NoCapability(CAPABILITY_MSIX))?;
+        let control = self.read_u16(capability + 2);
+        let table = self.read_u32(capability + 4);
+        let pending = self.read_u32(capability + 8);
+        Ok(Msix {
+            device: *self,
+            capability,
+            table_size: (control & MSIX_TABLE_SIZE_MASK) + 1,
+            table_bar: (table & 0b111) as usize,
+            table_offset: table & !0b111,
+            pending_bar: (pending & 0b111) as usize,
+            pending_offset: pending & !0b111,
+            table: None,
+        })
+    }
+}
+
+/// MSIのメッセージのアドレスとデータ (エッジトリガ、固定配送)
+fn msi_message(vector: u8, apic_id: u8) -> (u32, u16) {
+    (
+        MSI_ADDRESS_BASE | u32::from(apic_id) << 12,
+        u16::from(vector),
+    )
+}
+
+/// ケーパビリティのリストが壊れていてもループしないための上限
+const MAX_CAPABILITIES: usize = 48;
+
+/// `PciDevice::capabilities` のイテレータ
+#[derive(Debug, Clone)]
+pub struct Capabilities {
+    address: PciAddress,
+    offset: u8,
+    remaining: usize,
+}
+
+impl Iterator for Capabilities {
+    type Item = (u8, u16);
+
+    fn next(&mut self) -> Option<(u8, u16)> {
+        if self.offset == 0 || self.remaining == 0 {
+            return None;
+        }
+        self.remaining -= 1;
+        let offset = u16::from(self.offset);
+        let id = read_config_u8(self.address, offset);
+        self.offset = read_config_u8(self.address, offset + 1) & !0b11;
+        Some((id, offset))
+    }
+}
+
+/// MSI-Xの設定
+#[derive(Debug, Clone, Copy)]
+pub struct Msix {
+    device: PciDevice,
+    capability: u16,
+    /// テーブルの項目の数
+    pub table_size: u16,
+    pub table_bar: usize,
+    pub table_offset: u32,
+    pub pending_bar: usize,
+    pub pending_offset: u32,
+    table: Option<VirtAddr>,
+}
+
+impl Msix {
+    /// テーブルをマップし、全項目をマスクしてからMSI-Xを有効にする
+    pub fn enable(&mut self) -> Result<(), PciError> {
+        let table = self.device.map_bar(self.table_bar)? + u64::from(self.table_offset);
+        self.table = Some(table);
+        for index in 0..self.table_size {
+            self.write_entry(index, 3, MSIX_ENTRY_MASKED);
+        }
+        let control = self.device.read_u16(self.capability + 2);
+        self.device.write_u16(
+            self.capability + 2,
+            control & !MSIX_FUNCTION_MASK | MSIX_ENABLE,
+        );
+        self.device
+            .set_command(self.device.command() | COMMAND_INTERRUPT_DISABLE);
+        Ok(())
+    }
+
+    /// 項目 `index` の割り込みを `apic_id` のLocal APICの `vector` に送らせる
+    pub fn set_vector(&mut self, index: u16, vector: u8, apic_id: u8) -> Result<(), PciError> {
+        if index >= self.table_size {
+            return Err(PciError::NoSuchVector(index));
+        }
+        if self.table.is_none() {
+            return Err(PciError::NotInitialized);
+        }
+        let (address, data) = msi_message(vector, apic_id);
+        self.write_entry(index, 3, MSIX_ENTRY_MASKED);
+        self.write_entry(index, 0, address);
+        self.write_entry(index, 1, 0);
+        self.write_entry(index, 2, data.into());
+        self.write_entry(index, 3, 0);
+        Ok(())
+    }
+
+    /// 項目 `index` の `word` 番目の4バイトを書く
+    fn write_entry(&self, index: u16, word: u64, value: u32) {
+        if let Some(table) = self.table {
+            let ptr = table + u64::from(index) * MSIX_ENTRY_SIZE + word * 4;
+            unsafe { ptr.as_mut_ptr::<u32>().write_volatile(value) };
+        }
+    }
+}
+
+/// ドライバが扱えるデバイスの条件。`None` の項目は何にでも合う
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PciMatch {
+    pub vendor_id: Option<u16>,
+    pub device_id: Option<u16>,
+    pub class: Option<u8>,
+    pub subclass: Option<u8>,
+}
+
+impl PciMatch {
+    pub const fn device(vendor_id: u16, device_id: u16) -> PciMatch {
+        PciMatch {
+            vendor_id: Some(vendor_id),
+            device_id: Some(device_id),
+            class: None,
+            subclass: None,
+        }
+    }
+
+    pub const fn class(class: u8, subclass: u8) -> PciMatch {
+        PciMatch {
+            vendor_id: None,
+            device_id: None,
+            class: Some(class),
+            subclass: Some(subclass),
+        }
+    }
+
+    pub fn matches(&self, device: &PciDevice) -> bool {
+        self.vendor_id.is_none_or(|id| id == device.vendor_id)
+            && self.device_id.is_none_or(|id| id == device.device_id)
+            && self.class.is_none_or(|class| class == device.class)
+            && self
+                .subclass
+                .is_none_or(|subclass| subclass == device.subclass)
+    }
+}
+
+/// PCIデバイスのドライバ
+pub struct PciDriver {
+    pub name: &'static str,
+    pub matches: &'static [PciMatch],
+    /// デバイスを初期化する。失敗したら次に合うドライバを試す
+    pub probe: fn(&PciDevice) -> Result<(), PciError>,
+}
+
+static DRIVERS: Mutex<Vec<&'static PciDriver>> = Mutex::new(Vec::new());
+
+/// 見つかったデバイスと、結びついたドライバの名前
+static DEVICES: Mutex<Vec<(PciDevice, Option<&'static str>)>> = Mutex::new(Vec::new());
+
+/// ドライバを登録する。`probe_drivers` の前に呼ぶこと
+pub fn register_driver(driver: &'static PciDriver) {
+    DRIVERS.lock().push(driver);
+}
+
+/// コンフィギュレーション空間の読み方を決めて全バスを走査する
+///
+/// `acpi::init` と `memory::MAPPER` の準備の後に呼ぶこと。見つかったデバイスの数を返す
+pub fn init() -> usize {
+    if let Ok(mcfg) = acpi::mcfg() {
+        let regions: Vec<EcamRegion> = mcfg
+            .entries()
+            .filter(|entry| entry.start_bus <= entry.end_bus)
+            .map(|entry| EcamRegion {
+                entry,
+                buses: alloc::vec![None; usize::from(entry.end_bus - entry.start_bus) + 1],
+            })
+            .collect();
+        if !regions.is_empty() {
+            *CONFIG_SPACE.lock() = ConfigSpace::Ecam(regions);
+        }
+    }
+
+    let roots: Vec<(u16, u8)> = match &*CONFIG_SPACE.lock() {
+        ConfigSpace::PortIo => alloc::vec![(0, 0)],
+        ConfigSpace::Ecam(regions) => regions
+            .iter()
+            .map(|region| (region.entry.segment, region.entry.start_bus))
+            .collect(),
+    };
+    let mut devices = Vec::new();
+    for (segment, bus) in roots {
+        // ホストブリッジが複数のファンクションを持つなら、それぞれが別のバスを受け持つ
+        let host = PciAddress::new(segment, bus, 0, 0);
+        match PciDevice::probe(host) {
+            Some(device) if device.header_type & HEADER_MULTIFUNCTION != 0 => {
+                for function in 0..MAX_FUNCTIONS {
+                    let address = PciAddress { function, ..host };
+                    if PciDevice::probe(address).is_some() {
+                        scan_bus(segment, bus + function, &mut devices);
+                    }
+                }
+            }
+            _ => scan_bus(segment, bus, &mut devices),
+        }
+    }
+    let count = devices.len();
+    *DEVICES.lock() = devices;
+    count
+}
+
+/// バスの上のデバイスを集め、PCI-to-PCIブリッジの先のバスもたどる
+fn scan_bus(segment: u16, bus: u8, devices: &mut Vec<(PciDevice, Option<&'static str>)>) {
+    for device in 0..MAX_DEVICES {
+        let Some(first) = PciDevice::probe(PciAddress::new(segment, bus, device, 0)) else {
+            continue;
+        };
+        let functions = if first.header_type & HEADER_MULTIFUNCTION != 0 {
+            MAX_FUNCTIONS
+        } else {
+            1
+        };
+        for function in 0..functions {
+            let Some(found) = PciDevice::probe(PciAddress::new(segment, bus, device, function))
+            else {
+                continue;
+            };
+            devices.push((found, None));
+            if found.header_type & HEADER_TYPE_MASK == HEADER_TYPE_BRIDGE {
+                let secondary = found.read_u8(REGISTER_SECONDARY_BUS);
+                // ファームウェアが番号を振っていないブリッジや、戻ってくる番号はたどらない
+                if secondary > bus {
+                    scan_bus(segment, secondary, devices);
+                }
+            }
+        }
+    }
+}
+
+/// 登録されたドライバをまだドライバのないデバイスに結びつける
+pub fn probe_drivers() {
+    let drivers = DRIVERS.lock().clone();
+    let unbound: Vec<PciDevice> = DEVICES
+        .lock()
+        .iter()
+        .filter(|(_, driver)| driver.is_none())
+        .map(|&(device, _)| device)
+        .collect();
+
+    for device in unbound {
+        let candidates = drivers
+            .iter()
+            .filter(|driver| driver.matches.iter().any(|m| m.matches(&device)));
+        for driver in candidates {
+            match (driver.probe)(&device) {
+                Ok(()) => {
+                    let mut devices = DEVICES.lock();
+                    if let Some(entry) = devices
+                        .iter_mut()
+                        .find(|(d, _)| d.address == device.address)
+                    {
+                        entry.1 = Some(driver.name);
+                    }
+                    break;
+                }
+                Err(err) => log!("pci: {} {}: {}", device.address, driver.name, err),
+            }
+        }
+    }
+}
+
+/// 見つかったデバイスと、結びついたドライバの名前の一覧
+pub fn devices() -> Vec<(PciDevice, Option<&'static str>)> {
+    DEVICES.lock().clone()
+}
+
+/// ECAMを使っているか
+pub fn uses_ecam() -> bool {
+    matches!(&*CONFIG_SPACE.lock(), ConfigSpace::Ecam(_))
+}
+
+/// クラスコードのおおまかな名前
+pub fn class_name(class: u8, subclass: u8) -> &'static str {
+    match (class, subclass) {
+        (0x01, 0x01) => "IDE controller",
+        (0x01, 0x06) => "SATA controller",
+        (0x01, 0x08) => "NVM controller",
+        (0x01, _) => "storage controller",
+        (0x02, 0x00) => "ethernet controller",
+        (0x02, _) => "network controller",
+        (0x03, 0x00) => "VGA controller",
+        (0x03, _) => "display controller",
+        (0x04, _) => "multimedia controller",
+        (0x05, _) => "memory controller",
+        (0x06, 0x00) => "host bridge",
+        (0x06, 0x01) => "ISA bridge",
+        (0x06, 0x04) => "PCI bridge",
+        (0x06, _) => "bridge",
+        (0x07, _) => "communication controller",
+        (0x08, _) => "system peripheral",
+        (0x0c, 0x03) => "USB controller",
+        (0x0c, 0x05) => "SMBus controller",
+        (0x0c, _) => "serial bus controller",
+        _ => "device",
+    }
+}
+
+#[test_case]
+fn test_bar_size_from_mask() {
+    // 32ビットのメモリBARで4KiB
+    assert_eq!(Bar::size_from_mask(0xffff_ffff_ffff_f000), 0x1000);
+    // 64ビットのメモリBARで4GiB
+    assert_eq!(Bar::size_from_mask(0xffff_ffff_0000_0000), 1 << 32);
+    // I/O BARで32バイト
+    assert_eq!(Bar::size_from_mask(0xffff_ffff_ffff_ffe0) as u32, 32);
+}
+
+#[test_case]
+fn test_driver_match() {
+    let device = PciDevice {
+        address: PciAddress::new(0, 0, 3, 0),
+        vendor_id: 0x8086,
+        device_id: 0x100e,
+        subsystem_vendor_id: 0,
+        subsystem_id: 0,
+        class: 0x02,
+        subclass: 0x00,
+        prog_if: 0,
+        revision: 3,
+        header_type: 0,
+        interrupt_line: 11,
+        interrupt_pin: 1,
+        bars: [None; 6],
+    };
+    assert!(PciMatch::device(0x8086, 0x100e).matches(&device));
+    assert!(!PciMatch::device(0x8086, 0x10d3).matches(&device));
+    assert!(PciMatch::class(0x02, 0x00).matches(&device));
+    assert!(!PciMatch::class(0x01, 0x01).matches(&device));
+}
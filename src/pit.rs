@@ -0,0 +1,78 @@
+use core::time::Duration;
+use x86_64::instructions::port::Port;
+
+/// PITの入力クロック (Hz)
+pub const BASE_FREQUENCY: u32 = 1_193_182;
+
+/// タイマー割り込みの周波数 (Hz)
+pub const TIMER_FREQUENCY: u32 = 100;
+
+const CHANNEL0_DATA: u16 = 0x40;
+const COMMAND: u16 = 0x43;
+
+/// チャンネル0, lobyte/hibyte アクセス, モード3 (方形波)
+const CHANNEL0_SQUARE_WAVE: u8 = 0b0011_0110;
+
+/// チャンネル0を `TIMER_FREQUENCY` で割り込みを出すように設定する
+pub fn init() {
+    let divisor = (BASE_FREQUENCY / TIMER_FREQUENCY) as u16;
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL0_DATA);
+    unsafe {
+        command.write(CHANNEL0_SQUARE_WAVE);
+        data.write((divisor & 0xff) as u8);
+        data.write((divisor >> 8) as u8);
+    }
+}
+
+const CHANNEL2_DATA: u16 = 0x42;
+/// キーボードコントローラのポートB。チャンネル2のゲートと出力がここにある
+const PORT_B: u16 = 0x61;
+
+/// チャンネル2, lobyte/hibyte アクセス, モード0 (カウント終了で出力が立つ)
+const CHANNEL2_ONE_SHOT: u8 = 0b1011_0000;
+
+/// ポートB: チャンネル2のゲート
+const PORT_B_GATE: u8 = 1 << 0;
+/// ポートB: スピーカーへの出力
+const PORT_B_SPEAKER: u8 = 1 << 1;
+/// ポートB: チャンネル2の出力
+const PORT_B_OUT2: u8 = 1 << 5;
+
+/// チャンネル2で `count` 回 (1/`BASE_FREQUENCY` 秒単位) 数え終わるまでビジーウェイトする
+///
+/// 割り込みを使わないので、割り込みを有効にする前の較正にも使える。
+/// `start` はカウントが始まる直前に呼ばれる
+pub fn wait_channel2(count: u16, start: impl FnOnce()) {
+    let mut port_b: Port<u8> = Port::new(PORT_B);
+    let mut command: Port<u8> = Port::new(COMMAND);
+    let mut data: Port<u8> = Port::new(CHANNEL2_DATA);
+    unsafe {
+        // ゲートを下げ、スピーカーは鳴らさない
+        let saved = port_b.read();
+        port_b.write(saved & !(PORT_B_GATE | PORT_B_SPEAKER));
+
+        command.write(CHANNEL2_ONE_SHOT);
+        data.write((count & 0xff) as u8);
+        data.write((count >> 8) as u8);
+
+        start();
+        port_b.write((saved & !PORT_B_SPEAKER) | PORT_B_GATE);
+        while port_b.read() & PORT_B_OUT2 == 0 {
+            core::hint::spin_loop();
+        }
+        port_b.write(saved);
+    }
+}
+
+/// チャンネル2で `duration` だけビジーウェイトする
+///
+/// タイマー割り込みにもTSCの較正にも頼らないので、割り込みが止まったパニックの中でも使える
+pub fn busy_wait(duration: Duration) {
+    let mut remaining = duration.as_micros() * u128::from(BASE_FREQUENCY) / 1_000_000;
+    while remaining > 0 {
+        let count = remaining.min(u128::from(u16::MAX)) as u16;
+        wait_channel2(count, || {});
+        remaining -= u128::from(count);
+    }
+}
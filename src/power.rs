@@ -0,0 +1,279 @@
+//! 電源の操作 (電源断と再起動)
+//!
+//! ACPIが使えればFADTのPM1制御ブロックとDSDTの `\_S5` で電源を切り、
+//! FADTのリセットレジスタで再起動する。必要な値は `init` で先に読んでおくので、
+//! パニックハンドラからもロックを取らずに呼べる
+
+use crate::acpi::{self, AcpiError, GenericAddress};
+use crate::memory;
+use spin::Once;
+use x86_64::PhysAddr;
+use x86_64::instructions::interrupts;
+use x86_64::instructions::port::Port;
+
+/// PM1制御レジスタ: SCIが有効 (ACPIモード)
+const PM1_SCI_ENABLE: u16 = 1 << 0;
+/// PM1制御レジスタ: スリープの種類
+const PM1_SLEEP_TYPE_SHIFT: u16 = 10;
+/// PM1制御レジスタ: 書くとスリープに入る
+const PM1_SLEEP_ENABLE: u16 = 1 << 13;
+
+/// ACPIモードに切り替わるのを待つ回数
+const ACPI_ENABLE_POLL_LIMIT: usize = 1_000_000;
+
+/// AMLのオペコード
+const AML_ZERO_OP: u8 = 0x00;
+const AML_ONE_OP: u8 = 0x01;
+const AML_NAME_OP: u8 = 0x08;
+const AML_BYTE_PREFIX: u8 = 0x0a;
+const AML_WORD_PREFIX: u8 = 0x0b;
+const AML_DWORD_PREFIX: u8 = 0x0c;
+const AML_PACKAGE_OP: u8 = 0x12;
+const AML_ROOT_CHAR: u8 = b'\\';
+
+/// `init` でACPIから読んでおいた値
+#[derive(Debug, Clone, Copy)]
+struct AcpiPower {
+    pm1a_control: u16,
+    pm1b_control: u16,
+    /// `\_S5` の SLP_TYPa と SLP_TYPb。見つからなければ `None`
+    sleep_types: Option<(u8, u8)>,
+    smi_command: u16,
+    acpi_enable: u8,
+    /// リセットレジスタとそこに書く値
+    reset: Option<(GenericAddress, u8)>,
+}
+
+static ACPI_POWER: Once<AcpiPower> = Once::new();
+
+/// FADTとDSDTから電源の操作に必要な値を読む。`acpi::init` の後に呼ぶこと
+pub fn init() -> Result<(), AcpiError> {
+    let fadt = acpi::fadt()?;
+    let dsdt = acpi::table_bytes(fadt.dsdt_address())?;
+    let aml = &dsdt[core::mem::size_of::<acpi::SdtHeader>()..];
+
+    let pm1a_control = fadt.pm1a_control_port()?;
+    let pm1b_control = fadt.pm1b_control_port()?;
+    let smi_command = fadt.smi_command_port()?;
+    // リセットレジスタはACPI 2.0のFADTから
+    let reset = (fadt.header.revision >= 2 && fadt.reset_register_supported())
+        .then_some((fadt.reset_register, fadt.reset_value));
+    ACPI_POWER.call_once(|| AcpiPower {
+        pm1a_control: pm1a_control.unwrap_or(0),
+        pm1b_control: pm1b_control.unwrap_or(0),
+        sleep_types: find_s5(aml),
+        smi_command: smi_command.unwrap_or(0),
+        acpi_enable: fadt.acpi_enable,
+        reset,
+    });
+    Ok(())
+}
+
+/// ACPIで電源を切れるか
+pub fn shutdown_supported() -> bool {
+    ACPI_POWER
+        .r#try()
+        .is_some_and(|power| power.sleep_types.is_some() && power.pm1a_control != 0)
+}
+
+/// 電源を切る。切れなければ割り込みを止めたまま停止する
+pub fn shutdown() -> ! {
+    interrupts::disable();
+    if let Some(power) = ACPI_POWER.r#try() {
+        acpi_power_off(power);
+    }
+    crate::hlt_loop();
+}
+
+/// マシンを再起動する
+///
+/// ACPIのリセットレジスタ、8042キーボードコントローラのリセット線、トリプルフォルトの順に試す
+pub fn reboot() -> ! {
+    interrupts::disable();
+    if let Some(power) = ACPI_POWER.r#try() {
+        acpi_reset(power);
+    }
+    pulse_reset_line();
+    triple_fault();
+}
+
+/// S5 (ソフトオフ) に入る。戻ってきたら失敗
+fn acpi_power_off(power: &AcpiPower) {
+    let Some((sleep_type_a, sleep_type_b)) = power.sleep_types else {
+        return;
+    };
+    if power.pm1a_control == 0 {
+        return;
+    }
+    enable_acpi_mode(power);
+
+    let mut pm1a: Port<u16> = Port::new(power.pm1a_control);
+    unsafe {
+        let value = pm1a.read() & !(0b111 << PM1_SLEEP_TYPE_SHIFT);
+        pm1a.write(value | u16::from(sleep_type_a) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+    }
+    if power.pm1b_control != 0 {
+        let mut pm1b: Port<u16> = Port::new(power.pm1b_control);
+        unsafe {
+            let value = pm1b.read() & !(0b111 << PM1_SLEEP_TYPE_SHIFT);
+            pm1b.write(value | u16::from(sleep_type_b) << PM1_SLEEP_TYPE_SHIFT | PM1_SLEEP_ENABLE);
+        }
+    }
+    // 電源が落ちるまで少し待つ
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// まだレガシーモードならSMIコマンドポートでACPIモードに切り替える
+fn enable_acpi_mode(power: &AcpiPower) {
+    let mut pm1a: Port<u16> = Port::new(power.pm1a_control);
+    if unsafe { pm1a.read() } & PM1_SCI_ENABLE != 0 {
+        return;
+    }
+    if power.smi_command == 0 || power.acpi_enable == 0 {
+        // 切り替えの手段がない。ハードウェアが最初からACPIモードのこともある
+        return;
+    }
+    let mut smi: Port<u8> = Port::new(power.smi_command);
+    unsafe { smi.write(power.acpi_enable) };
+    for _ in 0..ACPI_ENABLE_POLL_LIMIT {
+        if unsafe { pm1a.read() } & PM1_SCI_ENABLE != 0 {
+            return;
+        }
+    }
+}
+
+/// FADTのリセットレジスタに書く。戻ってきたら失敗
+fn acpi_reset(power: &AcpiPower) {
+    let Some((register, value)) = power.reset else {
+        return;
+    };
+    let address = register.address;
+    match register.address_space {
+        // 壊れたFADTで関係のないポートに書いたり、パニックしたりしないよう、範囲外なら諦める
+        GenericAddress::SYSTEM_IO => {
+            let Ok(address) = u16::try_from(address) else {
+                return;
+            };
+            let mut port: Port<u8> = Port::new(address);
+            unsafe { port.write(value) };
+        }
+        GenericAddress::SYSTEM_MEMORY => {
+            let Ok(address) = PhysAddr::try_new(address) else {
+                return;
+            };
+            let ptr = memory::phys_to_virt(address).as_mut_ptr::<u8>();
+            unsafe { ptr.write_volatile(value) };
+        }
+        // PCIのコンフィギュレーション空間などは使わない
+        _ => return,
+    }
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// DSDTのAMLから `Name(_S5, Package() {...})` を探し、最初の2つの要素を返す
+///
+/// AMLを解釈するのではなく、バイト列のパターンで探す
+fn find_s5(aml: &[u8]) -> Option<(u8, u8)> {
+    aml.windows(4)
+        .enumerate()
+        .filter(|&(_, name)| name == b"_S5_")
+        .map(|(i, _)| i)
+        .filter(|&i| {
+            let before = &aml[..i];
+            before.ends_with(&[AML_NAME_OP]) || before.ends_with(&[AML_NAME_OP, AML_ROOT_CHAR])
+        })
+        .find_map(|i| parse_s5_package(&aml[i + 4..]))
+}
+
+fn parse_s5_package(aml: &[u8]) -> Option<(u8, u8)> {
+    let (&op, rest) = aml.split_first()?;
+    if op != AML_PACKAGE_OP {
+        return None;
+    }
+    // PkgLength は先頭バイトの上位2ビットが後に続くバイト数
+    let (&lead, rest) = rest.split_first()?;
+    let rest = rest.get(usize::from(lead >> 6)..)?;
+    // NumElements
+    let rest = rest.get(1..)?;
+    let (sleep_type_a, rest) = parse_integer(rest)?;
+    let (sleep_type_b, _) = parse_integer(rest).unwrap_or((0, rest));
+    Some((sleep_type_a as u8, sleep_type_b as u8))
+}
+
+/// AMLの整数を1つ読み、残りを返す
+fn parse_integer(aml: &[u8]) -> Option<(u32, &[u8])> {
+    let (&op, rest) = aml.split_first()?;
+    match op {
+        AML_ZERO_OP => Some((0, rest)),
+        AML_ONE_OP => Some((1, rest)),
+        AML_BYTE_PREFIX => Some((u32::from(*rest.first()?), rest.get(1..)?)),
+        AML_WORD_PREFIX => {
+            let bytes = rest.get(..2)?;
+            Some((
+                u32::from(u16::from_le_bytes([bytes[0], bytes[1]])),
+                &rest[2..],
+            ))
+        }
+        AML_DWORD_PREFIX => {
+            let bytes = rest.get(..4)?;
+            let value = u32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]);
+            Some((value, &rest[4..]))
+        }
+        _ => None,
+    }
+}
+
+/// 8042のコマンド 0xFE でCPUのリセット線をパルスする
+fn pulse_reset_line() {
+    let mut status: Port<u8> = Port::new(0x64);
+    let mut command: Port<u8> = Port::new(0x64);
+    unsafe {
+        // 入力バッファが空くのを待つ
+        for _ in 0..100_000 {
+            if status.read() & 0b10 == 0 {
+                break;
+            }
+        }
+        command.write(0xfe);
+    }
+    // リセットが効くまで少し待つ
+    for _ in 0..1_000_000 {
+        core::hint::spin_loop();
+    }
+}
+
+/// 空のIDTを読み込んで例外を起こし、トリプルフォルトでリセットさせる
+fn triple_fault() -> ! {
+    use x86_64::VirtAddr;
+    use x86_64::instructions::tables::{DescriptorTablePointer, lidt};
+
+    let empty = DescriptorTablePointer {
+        limit: 0,
+        base: VirtAddr::new(0),
+    };
+    unsafe {
+        lidt(&empty);
+    }
+    x86_64::instructions::interrupts::int3();
+    crate::hlt_loop();
+}
+
+#[test_case]
+fn test_find_s5() {
+    // Name (\_S5, Package (0x04) { 0x05, Zero, Zero, Zero })
+    #[rustfmt::skip]
+    let aml = [
+        0x10, 0x08, AML_NAME_OP, AML_ROOT_CHAR, b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x07,
+        0x04, AML_BYTE_PREFIX, 0x05, AML_ZERO_OP, AML_ZERO_OP, AML_ZERO_OP,
+    ];
+    assert_eq!(find_s5(&aml), Some((5, 0)));
+
+    // 名前として定義されていない `_S5_` は無視する
+    #[rustfmt::skip]
+    let aml = [b'_', b'S', b'5', b'_', AML_PACKAGE_OP, 0x04, 0x02, AML_ONE_OP, AML_ONE_OP];
+    assert_eq!(find_s5(&aml), None);
+}
@@ -0,0 +1,358 @@
+//! カーネルの状態を見せる合成ファイルシステム
+//!
+//! ファイルの中身は読むたびにその時点の状態から作る。`/proc` にマウントされるので、
+//! シェルの `cat /proc/meminfo` などで読める (シェルの出力はシリアルにも出る)
+
+use crate::vfs::{self, DirEntry, FileSystem, FileType, FsError, Metadata};
+use crate::{allocator, interrupts, memory, rtc, time};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt::{self, Write};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::registers::control::Cr3;
+use x86_64::structures::paging::{PageTable, PageTableFlags};
+use x86_64::{PhysAddr, VirtAddr};
+
+/// マウントする場所
+pub const MOUNT_POINT: &str = "/proc";
+
+const ROOT: u64 = 1;
+
+const FRAME_SIZE: usize = 4096;
+
+struct ProcFile {
+    name: &'static str,
+    generate: fn(&mut String) -> fmt::Result,
+}
+
+/// ルートにあるファイル。inode番号は添字 + 2
+const FILES: [ProcFile; 6] = [
+    ProcFile {
+        name: "meminfo",
+        generate: meminfo,
+    },
+    ProcFile {
+        name: "interrupts",
+        generate: interrupts,
+    },
+    ProcFile {
+        name: "uptime",
+        generate: uptime,
+    },
+    ProcFile {
+        name: "tasks",
+        generate: tasks,
+    },
+    ProcFile {
+        name: "memmap",
+        generate: memmap,
+    },
+    ProcFile {
+        name: "pagetables",
+        generate: pagetables,
+    },
+];
+
+/// `FILES` の中の `name` のinode番号
+fn file_inode(name: &str) -> Option<u64> {
+    FILES
+        .iter()
+        .position(|file| file.name == name)
+        .map(|index| index as u64 + 2)
+}
+
+#[derive(Default)]
+pub struct ProcFs {
+    /// 最後に先頭から読まれたときの中身
+    ///
+    /// 何回かに分けて読まれても途中で中身が変わらないよう、オフセット0の読み込みでだけ作り直す
+    snapshots: Mutex<BTreeMap<u64, String>>,
+}
+
+impl ProcFs {
+    fn file(&self, inode: u64) -> Result<&'static ProcFile, FsError> {
+        match inode {
+            ROOT => Err(FsError::IsADirectory),
+            _ => inode
+                .checked_sub(2)
+                .and_then(|index| FILES.get(index as usize))
+                .ok_or(FsError::NotFound),
+        }
+    }
+}
+
+impl FileSystem for ProcFs {
+    fn name(&self) -> &'static str {
+        "proc"
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        if dir != ROOT {
+            self.file(dir)?;
+            return Err(FsError::NotADirectory);
+        }
+        file_inode(name).ok_or(FsError::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError> {
+        if inode == ROOT {
+            return Ok(Metadata {
+                inode,
+                file_type: FileType::Directory,
+                size: FILES.len() as u64,
+                mode: 0o555,
+                links: 2,
+                modified: rtc::now().unix_timestamp(),
+            });
+        }
+        self.file(inode)?;
+        // 中身は読むまで決まらないので、大きさは0にしておく
+        Ok(Metadata {
+            inode,
+            file_type: FileType::Regular,
+            size: 0,
+            mode: 0o444,
+            links: 1,
+            modified: rtc::now().unix_timestamp(),
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let file = self.file(inode)?;
+        let mut snapshots = self.snapshots.lock();
+        if offset == 0 || !snapshots.contains_key(&inode) {
+            let mut contents = String::new();
+            (file.generate)(&mut contents).map_err(|_| FsError::InvalidArgument)?;
+            snapshots.insert(inode, contents);
+        }
+        let data = snapshots[&inode].as_bytes();
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let count = buffer.len().min(data.len() - start);
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        if dir != ROOT {
+            self.file(dir)?;
+            return Err(FsError::NotADirectory);
+        }
+        Ok(FILES
+            .iter()
+            .enumerate()
+            .map(|(index, file)| DirEntry {
+                name: file.name.to_string(),
+                inode: index as u64 + 2,
+                file_type: FileType::Regular,
+            })
+            .collect())
+    }
+}
+
+/// `MOUNT_POINT` に作ってマウントする。ルートをマウントした後に呼ぶ
+pub fn init() -> Result<(), FsError> {
+    vfs::mount_at(MOUNT_POINT, Arc::new(ProcFs::default()))
+}
+
+/// 物理フレームとヒープの使用量
+fn meminfo(out: &mut String) -> fmt::Result {
+    let frames = without_interrupts(|| {
+        memory::FRAME_ALLOCATOR.lock().as_ref().map(|allocator| {
+            (
+                allocator.usable_frame_count(),
+                allocator.allocated_frame_count(),
+            )
+        })
+    });
+    if let Some((usable, allocated)) = frames {
+        writeln!(out, "FramesTotal:    {:>10} kB", usable * FRAME_SIZE / 1024)?;
+        writeln!(
+            out,
+            "FramesUsed:     {:>10} kB",
+            allocated * FRAME_SIZE / 1024
+        )?;
+        writeln!(
+            out,
+            "FramesFree:     {:>10} kB",
+            (usable - allocated) * FRAME_SIZE / 1024
+        )?;
+    }
+    let heap = without_interrupts(allocator::heap_stats);
+    writeln!(out, "HeapTotal:      {:>10} kB", heap.size / 1024)?;
+    writeln!(out, "HeapUsed:       {:>10} kB", heap.used / 1024)?;
+    writeln!(out, "HeapFree:       {:>10} kB", heap.free / 1024)
+}
+
+/// IRQ線ごとの割り込みの回数
+///
+/// 数えているのはPICの16本の線だけで、例外は入らない
+fn interrupts(out: &mut String) -> fmt::Result {
+    writeln!(out, "vector irq {:<14} count", "device")?;
+    for (irq, name) in interrupts::IRQ_NAMES.iter().enumerate() {
+        // PCIのデバイスは線を共有するので、決まった名前の代わりに数を出す
+        let mut device = String::from(*name);
+        let pci = interrupts::pci_handler_count(irq);
+        if pci > 0 {
+            if !device.is_empty() {
+                device.push('+');
+            }
+            let _ = write!(device, "pci({})", pci);
+        }
+        writeln!(
+            out,
+            "{:>6} {:>3} {:<14} {}",
+            usize::from(interrupts::PIC_1_OFFSET) + irq,
+            irq,
+            device,
+            interrupts::irq_count(irq)
+        )?;
+    }
+    writeln!(
+        out,
+        "# only the 16 PIC lines are counted; exceptions are not"
+    )
+}
+
+/// 起動してからの秒数
+fn uptime(out: &mut String) -> fmt::Result {
+    let uptime = time::uptime();
+    writeln!(
+        out,
+        "{}.{:02}",
+        uptime.as_secs(),
+        uptime.subsec_millis() / 10
+    )
+}
+
+/// 実行の流れの一覧
+///
+/// スケジューラはまだないので、シェルを動かしているカーネルの流れが1つだけある
+fn tasks(out: &mut String) -> fmt::Result {
+    writeln!(out, "id state   ticks      name")?;
+    writeln!(out, "{:>2} running {:<10} kernel", 0, interrupts::ticks())?;
+    writeln!(out, "# no scheduler yet; the kernel is the only task")
+}
+
+/// bootloaderから渡された物理メモリのマップ
+fn memmap(out: &mut String) -> fmt::Result {
+    let memory_map = without_interrupts(|| {
+        memory::FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map(|allocator| allocator.memory_map())
+    });
+    let Some(memory_map) = memory_map else {
+        return writeln!(out, "frame allocator not initialized");
+    };
+    for region in memory_map.iter() {
+        writeln!(
+            out,
+            "{:#018x}-{:#018x} {:?}",
+            region.range.start_addr(),
+            region.range.end_addr() - 1,
+            region.region_type
+        )?;
+    }
+    Ok(())
+}
+
+/// 1回の読み出しでたどるページテーブルの数の上限
+const MAX_PAGE_TABLES: usize = 1024;
+
+/// レベル4の項目ごとに、下にマップされているページの数を大きさ別に数える
+///
+/// 割り込みを止めて `MAPPER` を握るのはレベル4の項目1つ分ずつで、たどるテーブルの数にも
+/// 上限がある。上限に達したらそこで止め、数えきれなかったことを書く
+fn pagetables(out: &mut String) -> fmt::Result {
+    let (level_4_frame, _) = Cr3::read();
+    writeln!(out, "CR3: {:#x}", level_4_frame.start_address().as_u64())?;
+    writeln!(
+        out,
+        "l4  {:<37} {:>8} {:>6} {:>4}",
+        "virtual range", "4k", "2m", "1g"
+    )?;
+
+    let mut budget = MAX_PAGE_TABLES;
+    for index in 0..512 {
+        // 数えているあいだにマップが変わらないよう、`MAPPER` のロックを取っておく
+        let row = without_interrupts(|| {
+            let _mapper = memory::MAPPER.lock();
+            let entry = &table_at(level_4_frame.start_address())[index];
+            if !entry.flags().contains(PageTableFlags::PRESENT) {
+                return None;
+            }
+            let mut counts = [0u64; 3];
+            let complete = count_pages(table_at(entry.addr()), 3, &mut counts, &mut budget);
+            Some((counts, complete))
+        });
+        let Some(([small, large, huge], complete)) = row else {
+            continue;
+        };
+        let start = VirtAddr::new_truncate((index as u64) << 39);
+        writeln!(
+            out,
+            "{:>3} {:#018x}-{:#018x} {:>8} {:>6} {:>4}",
+            index,
+            start.as_u64(),
+            start.as_u64() + (1 << 39) - 1,
+            small,
+            large,
+            huge
+        )?;
+        if !complete {
+            return writeln!(out, "# stopped after {} page tables", MAX_PAGE_TABLES);
+        }
+    }
+    Ok(())
+}
+
+/// レベル `level` のページテーブルの下にあるページを数える。`counts` は4KiB、2MiB、1GiBの順
+///
+/// たどるテーブルごとに `budget` を1つ減らし、0になったら数えきれずに `false` を返す
+fn count_pages(table: &PageTable, level: usize, counts: &mut [u64; 3], budget: &mut usize) -> bool {
+    if *budget == 0 {
+        return false;
+    }
+    *budget -= 1;
+    for entry in table.iter() {
+        let flags = entry.flags();
+        if !flags.contains(PageTableFlags::PRESENT) {
+            continue;
+        }
+        if level == 1 || flags.contains(PageTableFlags::HUGE_PAGE) {
+            counts[level - 1] += 1;
+        } else if !count_pages(table_at(entry.addr()), level - 1, counts, budget) {
+            return false;
+        }
+    }
+    true
+}
+
+fn table_at(addr: PhysAddr) -> &'static PageTable {
+    // ページテーブルは物理メモリ全体のマッピングから読む
+    unsafe { &*memory::phys_to_virt(addr).as_ptr() }
+}
+
+#[test_case]
+fn test_file_inodes() {
+    assert_eq!(file_inode("meminfo"), Some(2));
+    assert_eq!(file_inode("pagetables"), Some(FILES.len() as u64 + 1));
+    assert_eq!(file_inode("missing"), None);
+    let fs = ProcFs::default();
+    assert_eq!(fs.lookup(ROOT, "uptime"), Ok(4));
+    assert_eq!(fs.lookup(4, "x"), Err(FsError::NotADirectory));
+    assert_eq!(fs.metadata(99).err(), Some(FsError::NotFound));
+}
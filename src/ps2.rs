@@ -0,0 +1,293 @@
+//! 8042 PS/2コントローラのドライバ
+//!
+//! キーボード (1番目のポート) とマウス (2番目のポート) のドライバが共有する。
+//! ファームウェアの設定に頼らず、起動時に自己診断とポートの検出をやり直す
+
+use core::fmt;
+use spin::Mutex;
+use x86_64::instructions::port::Port;
+
+const DATA_PORT: u16 = 0x60;
+const STATUS_PORT: u16 = 0x64;
+
+/// ステータスレジスタ: 出力バッファ (8042 -> CPU) にデータがある
+const STATUS_OUTPUT_FULL: u8 = 1 << 0;
+/// ステータスレジスタ: 入力バッファ (CPU -> 8042) にデータが残っている
+const STATUS_INPUT_FULL: u8 = 1 << 1;
+
+pub const RESPONSE_ACK: u8 = 0xfa;
+pub const RESPONSE_RESEND: u8 = 0xfe;
+
+const CONTROLLER_READ_CONFIG: u8 = 0x20;
+const CONTROLLER_WRITE_CONFIG: u8 = 0x60;
+const CONTROLLER_DISABLE_PORT2: u8 = 0xa7;
+const CONTROLLER_ENABLE_PORT2: u8 = 0xa8;
+const CONTROLLER_TEST_PORT2: u8 = 0xa9;
+const CONTROLLER_SELF_TEST: u8 = 0xaa;
+const CONTROLLER_TEST_PORT1: u8 = 0xab;
+const CONTROLLER_DISABLE_PORT1: u8 = 0xad;
+const CONTROLLER_ENABLE_PORT1: u8 = 0xae;
+/// 次にデータポートに書くバイトを2番目のポートに送る
+const CONTROLLER_WRITE_PORT2: u8 = 0xd4;
+
+const SELF_TEST_PASSED: u8 = 0x55;
+const PORT_TEST_PASSED: u8 = 0x00;
+
+/// 設定バイト: 1番目のポートの割り込み (IRQ1) を有効にする
+const CONFIG_PORT1_INTERRUPT: u8 = 1 << 0;
+/// 設定バイト: 2番目のポートの割り込み (IRQ12) を有効にする
+const CONFIG_PORT2_INTERRUPT: u8 = 1 << 1;
+/// 設定バイト: 1番目のポートのクロックを止める
+const CONFIG_PORT1_CLOCK_DISABLED: u8 = 1 << 4;
+/// 設定バイト: 2番目のポートのクロックを止める
+const CONFIG_PORT2_CLOCK_DISABLED: u8 = 1 << 5;
+/// 設定バイト: 1番目のポートのスキャンコードをセット1に変換する
+const CONFIG_TRANSLATION: u8 = 1 << 6;
+
+/// ステータスレジスタのポーリングを諦めるまでの回数
+const POLL_LIMIT: usize = 100_000;
+
+/// デバイスが再送を求めてきたときに送り直す回数
+const RESEND_LIMIT: usize = 3;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Port {
+    /// キーボードがつながる
+    First,
+    /// マウスがつながる
+    Second,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Ps2Error {
+    /// ステータスレジスタを待っている間に時間切れになった
+    Timeout,
+    /// コントローラの自己診断が失敗した
+    SelfTestFailed(u8),
+    /// ポートのインターフェーステストが失敗した
+    PortTestFailed(Ps2Port, u8),
+    /// ポートが存在しないか、テストに通らなかったので使えない
+    PortUnavailable(Ps2Port),
+    /// デバイスがACKの代わりに返してきた値
+    UnexpectedResponse(u8),
+}
+
+impl fmt::Display for Ps2Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Ps2Error::Timeout => write!(f, "ps2: timed out waiting for the controller"),
+            Ps2Error::SelfTestFailed(result) => {
+                write!(f, "ps2: controller self-test failed ({:#04x})", result)
+            }
+            Ps2Error::PortTestFailed(port, result) => {
+                write!(f, "ps2: {:?} port test failed ({:#04x})", port, result)
+            }
+            Ps2Error::PortUnavailable(port) => write!(f, "ps2: {:?} port is not available", port),
+            Ps2Error::UnexpectedResponse(response) => {
+                write!(f, "ps2: device responded with {:#04x} instead of ACK", response)
+            }
+        }
+    }
+}
+
+/// `init` で分かったコントローラの構成
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Ps2Controller {
+    /// 1番目のポートがテストに通った
+    pub port1: bool,
+    /// 2番目のポートが存在し、テストに通った
+    pub port2: bool,
+}
+
+static CONTROLLER: Mutex<Option<Ps2Controller>> = Mutex::new(None);
+
+/// コントローラを初期化する。割り込みを有効にする前に呼ぶこと
+///
+/// 自己診断、2番目のポートの有無の確認、各ポートのテストを行い、
+/// 使えるポートだけを割り込み付きで有効にする。スキャンコードの変換は有効のままにする
+pub fn init() -> Result<Ps2Controller, Ps2Error> {
+    // 初期化中にデバイスがデータを送ってこないように両方のポートを止める
+    write_command(CONTROLLER_DISABLE_PORT1)?;
+    write_command(CONTROLLER_DISABLE_PORT2)?;
+    flush_output();
+
+    let mut config = read_config()?;
+    config &= !(CONFIG_PORT1_INTERRUPT | CONFIG_PORT2_INTERRUPT);
+    write_config(config)?;
+
+    write_command(CONTROLLER_SELF_TEST)?;
+    match read_data()? {
+        SELF_TEST_PASSED => {}
+        result => return Err(Ps2Error::SelfTestFailed(result)),
+    }
+    // 自己診断でコントローラがリセットされるものがあるので書き直す
+    write_config(config)?;
+
+    // 2番目のポートを有効にしてクロックが動き出せば、2チャンネルのコントローラ
+    write_command(CONTROLLER_ENABLE_PORT2)?;
+    let dual_channel = read_config()? & CONFIG_PORT2_CLOCK_DISABLED == 0;
+    write_command(CONTROLLER_DISABLE_PORT2)?;
+
+    let port1 = test_port(Ps2Port::First).is_ok();
+    let port2 = dual_channel && test_port(Ps2Port::Second).is_ok();
+
+    let mut config = read_config()?;
+    if port1 {
+        write_command(CONTROLLER_ENABLE_PORT1)?;
+        config |= CONFIG_PORT1_INTERRUPT;
+        config &= !CONFIG_PORT1_CLOCK_DISABLED;
+    }
+    if port2 {
+        write_command(CONTROLLER_ENABLE_PORT2)?;
+        config |= CONFIG_PORT2_INTERRUPT;
+        config &= !CONFIG_PORT2_CLOCK_DISABLED;
+    }
+    config |= CONFIG_TRANSLATION;
+    write_config(config)?;
+
+    let controller = Ps2Controller { port1, port2 };
+    *CONTROLLER.lock() = Some(controller);
+    Ok(controller)
+}
+
+fn test_port(port: Ps2Port) -> Result<(), Ps2Error> {
+    let command = match port {
+        Ps2Port::First => CONTROLLER_TEST_PORT1,
+        Ps2Port::Second => CONTROLLER_TEST_PORT2,
+    };
+    write_command(command)?;
+    match read_data()? {
+        PORT_TEST_PASSED => Ok(()),
+        result => Err(Ps2Error::PortTestFailed(port, result)),
+    }
+}
+
+/// `init` で分かったコントローラの構成。初期化前や失敗したときは `None`
+pub fn controller() -> Option<Ps2Controller> {
+    *CONTROLLER.lock()
+}
+
+/// `port` が初期化済みで使えるか確かめる
+pub fn ensure_port(port: Ps2Port) -> Result<(), Ps2Error> {
+    let available = controller().is_some_and(|controller| match port {
+        Ps2Port::First => controller.port1,
+        Ps2Port::Second => controller.port2,
+    });
+    if available {
+        Ok(())
+    } else {
+        Err(Ps2Error::PortUnavailable(port))
+    }
+}
+
+/// 1番目のポートのスキャンコードをセット1に変換するかを切り替える
+pub fn set_translation(enabled: bool) -> Result<(), Ps2Error> {
+    let config = read_config()?;
+    let config = if enabled {
+        config | CONFIG_TRANSLATION
+    } else {
+        config & !CONFIG_TRANSLATION
+    };
+    write_config(config)
+}
+
+fn status() -> u8 {
+    let mut status: Port<u8> = Port::new(STATUS_PORT);
+    unsafe { status.read() }
+}
+
+fn wait_for_input_empty() -> Result<(), Ps2Error> {
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_INPUT_FULL == 0 {
+            return Ok(());
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// 出力バッファに残っている古いデータを捨てる
+fn flush_output() {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_OUTPUT_FULL == 0 {
+            return;
+        }
+        unsafe { data.read() };
+    }
+}
+
+/// データポートに書く。1番目のポートのデバイスに届く
+pub fn write_data(byte: u8) -> Result<(), Ps2Error> {
+    wait_for_input_empty()?;
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    unsafe { data.write(byte) };
+    Ok(())
+}
+
+/// コントローラ自身へのコマンドを書く
+fn write_command(byte: u8) -> Result<(), Ps2Error> {
+    wait_for_input_empty()?;
+    let mut command: Port<u8> = Port::new(STATUS_PORT);
+    unsafe { command.write(byte) };
+    Ok(())
+}
+
+/// `port` につながったデバイスにバイトを送る
+pub fn write_port(port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+    if port == Ps2Port::Second {
+        write_command(CONTROLLER_WRITE_PORT2)?;
+    }
+    write_data(byte)
+}
+
+/// 出力バッファにデータが来るのを待って読む
+pub fn read_data() -> Result<u8, Ps2Error> {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    for _ in 0..POLL_LIMIT {
+        if status() & STATUS_OUTPUT_FULL != 0 {
+            return Ok(unsafe { data.read() });
+        }
+    }
+    Err(Ps2Error::Timeout)
+}
+
+/// 出力バッファにデータがあれば読む。割り込みハンドラから使う
+///
+/// 割り込みが来た時点で既に読まれていた場合、データポートには古い値が残っているので読まない
+pub fn read_pending() -> Option<u8> {
+    let mut data: Port<u8> = Port::new(DATA_PORT);
+    (status() & STATUS_OUTPUT_FULL != 0).then(|| unsafe { data.read() })
+}
+
+/// デバイスにコマンドを送ってACKを待つ。再送を求められたら送り直す
+///
+/// 応答をポーリングで読むので、そのポートの割り込みを止めた状態で呼ぶこと
+pub fn device_command(port: Ps2Port, byte: u8) -> Result<(), Ps2Error> {
+    for _ in 0..RESEND_LIMIT {
+        write_port(port, byte)?;
+        match read_data()? {
+            RESPONSE_ACK => return Ok(()),
+            RESPONSE_RESEND => continue,
+            response => return Err(Ps2Error::UnexpectedResponse(response)),
+        }
+    }
+    Err(Ps2Error::UnexpectedResponse(RESPONSE_RESEND))
+}
+
+fn read_config() -> Result<u8, Ps2Error> {
+    write_command(CONTROLLER_READ_CONFIG)?;
+    read_data()
+}
+
+fn write_config(config: u8) -> Result<(), Ps2Error> {
+    write_command(CONTROLLER_WRITE_CONFIG)?;
+    write_data(config)
+}
+
+#[test_case]
+fn test_controller_initialized() {
+    // `crate::init` で初期化済み。QEMUの8042にはキーボードが必ずつながっている
+    let controller = controller().expect("ps2 controller was not initialized");
+    assert!(controller.port1);
+    assert_eq!(ensure_port(Ps2Port::First), Ok(()));
+}
@@ -0,0 +1,74 @@
+/// 割り込みハンドラから値を受け渡すための固定長のリングバッファ
+///
+/// ヒープを使わないので、ヒープの初期化前に割り込みが来ても使える
+pub struct Queue<T, const N: usize> {
+    items: [Option<T>; N],
+    head: usize,
+    len: usize,
+}
+
+impl<T, const N: usize> Queue<T, N> {
+    pub const fn new() -> Self {
+        Queue {
+            items: [const { None }; N],
+            head: 0,
+            len: 0,
+        }
+    }
+
+    /// 末尾に値を追加する。いっぱいのときは値をそのまま返す
+    pub fn push(&mut self, value: T) -> Result<(), T> {
+        if self.len == N {
+            return Err(value);
+        }
+        self.items[(self.head + self.len) % N] = Some(value);
+        self.len += 1;
+        Ok(())
+    }
+
+    /// 先頭の値を取り出す
+    pub fn pop(&mut self) -> Option<T> {
+        if self.len == 0 {
+            return None;
+        }
+        let value = self.items[self.head].take();
+        self.head = (self.head + 1) % N;
+        self.len -= 1;
+        value
+    }
+
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+}
+
+impl<T, const N: usize> Default for Queue<T, N> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+#[test_case]
+fn test_queue_wraps_around() {
+    let mut queue: Queue<u8, 3> = Queue::new();
+    for round in 0..4 {
+        assert_eq!(queue.push(round), Ok(()));
+        assert_eq!(queue.push(round + 10), Ok(()));
+        assert_eq!(queue.pop(), Some(round));
+        assert_eq!(queue.pop(), Some(round + 10));
+    }
+    assert!(queue.is_empty());
+}
+
+#[test_case]
+fn test_queue_rejects_when_full() {
+    let mut queue: Queue<u8, 2> = Queue::new();
+    assert_eq!(queue.push(1), Ok(()));
+    assert_eq!(queue.push(2), Ok(()));
+    assert_eq!(queue.push(3), Err(3));
+    assert_eq!(queue.len(), 2);
+}
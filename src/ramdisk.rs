@@ -0,0 +1,97 @@
+//! メモリ上のブロックデバイス
+//!
+//! 中身が全部0のブロックは持たないので、ほとんど空の大きなディスクでもヒープに収まる。
+//! ファイルシステムのテストや、ディスクのないときの作業場所に使う
+
+use crate::block::{self, BlockDevice, BlockError};
+use alloc::boxed::Box;
+use alloc::collections::BTreeMap;
+use alloc::vec;
+use alloc::vec::Vec;
+
+pub struct RamDisk {
+    block_size: usize,
+    block_count: u64,
+    /// 0ではないブロックだけを持つ
+    blocks: BTreeMap<u64, Box<[u8]>>,
+}
+
+impl RamDisk {
+    /// 全部0の `block_count` ブロックのディスクを作る
+    pub fn new(block_size: usize, block_count: u64) -> RamDisk {
+        RamDisk {
+            block_size,
+            block_count,
+            blocks: BTreeMap::new(),
+        }
+    }
+
+    /// `image` を中身にしたディスクを作る。最後のブロックの足りない分は0で埋める
+    pub fn from_image(block_size: usize, image: &[u8]) -> Result<RamDisk, BlockError> {
+        let mut disk = RamDisk::new(block_size, image.len().div_ceil(block_size) as u64);
+        let mut data = vec![0u8; block_size];
+        for (number, chunk) in image.chunks(block_size).enumerate() {
+            data.fill(0);
+            data[..chunk.len()].copy_from_slice(chunk);
+            disk.store(number as u64, &data)?;
+        }
+        Ok(disk)
+    }
+
+    /// 実際にヒープに置いているバイト数
+    pub fn usage(&self) -> usize {
+        self.blocks.len() * self.block_size
+    }
+
+    fn store(&mut self, number: u64, data: &[u8]) -> Result<(), BlockError> {
+        if data.iter().all(|&byte| byte == 0) {
+            self.blocks.remove(&number);
+            return Ok(());
+        }
+        if let Some(block) = self.blocks.get_mut(&number) {
+            block.copy_from_slice(data);
+            return Ok(());
+        }
+        // ヒープが足りなくてもパニックせず、デバイスのエラーにする
+        let mut block = Vec::new();
+        block
+            .try_reserve_exact(data.len())
+            .map_err(|_| BlockError::Device("ramdisk: out of memory"))?;
+        block.extend_from_slice(data);
+        self.blocks.insert(number, block.into_boxed_slice());
+        Ok(())
+    }
+}
+
+impl BlockDevice for RamDisk {
+    fn block_size(&self) -> usize {
+        self.block_size
+    }
+
+    fn block_count(&self) -> u64 {
+        self.block_count
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_range(self, start, buffer.len())?;
+        for (i, chunk) in buffer.chunks_exact_mut(self.block_size).enumerate() {
+            match self.blocks.get(&(start + i as u64)) {
+                Some(data) => chunk.copy_from_slice(data),
+                None => chunk.fill(0),
+            }
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        block::check_range(self, start, buffer.len())?;
+        for (i, chunk) in buffer.chunks_exact(self.block_size).enumerate() {
+            self.store(start + i as u64, chunk)?;
+        }
+        Ok(())
+    }
+
+    fn description(&self) -> &str {
+        "ramdisk"
+    }
+}
@@ -0,0 +1,45 @@
+//! 名前でデバイスを引く登録簿
+//!
+//! ブロックデバイス、キャラクタデバイス、ネットワークデバイスがそれぞれ1つずつ持つ
+
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+
+pub struct Registry<T> {
+    entries: Mutex<Vec<(String, T)>>,
+}
+
+impl<T: Clone> Registry<T> {
+    pub const fn new() -> Self {
+        Registry {
+            entries: Mutex::new(Vec::new()),
+        }
+    }
+
+    /// `value` を `name` で登録する。同じ名前があれば置き換える
+    pub fn register(&self, name: &str, value: T) {
+        let mut entries = self.entries.lock();
+        entries.retain(|(existing, _)| existing != name);
+        entries.push((name.to_string(), value));
+    }
+
+    pub fn get(&self, name: &str) -> Option<T> {
+        self.entries
+            .lock()
+            .iter()
+            .find(|(existing, _)| existing == name)
+            .map(|(_, value)| value.clone())
+    }
+
+    /// 登録されている名前と本体。登録した順に並ぶ
+    pub fn entries(&self) -> Vec<(String, T)> {
+        self.entries.lock().clone()
+    }
+}
+
+impl<T: Clone> Default for Registry<T> {
+    fn default() -> Self {
+        Self::new()
+    }
+}
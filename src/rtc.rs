@@ -0,0 +1,355 @@
+//! CMOSのリアルタイムクロック (RTC)
+//!
+//! 起動時に一度だけ日時を読み、以降はタイマー割り込みの回数を足して現在時刻を求める。
+//! 周期割り込み (IRQ8) も設定できる
+
+use crate::{acpi, hpet, interrupts, pit};
+use core::fmt;
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+use x86_64::instructions::port::Port;
+
+const CMOS_ADDRESS: u16 = 0x70;
+const CMOS_DATA: u16 = 0x71;
+
+const REGISTER_SECONDS: u8 = 0x00;
+const REGISTER_MINUTES: u8 = 0x02;
+const REGISTER_HOURS: u8 = 0x04;
+const REGISTER_DAY: u8 = 0x07;
+const REGISTER_MONTH: u8 = 0x08;
+const REGISTER_YEAR: u8 = 0x09;
+const REGISTER_STATUS_A: u8 = 0x0a;
+const REGISTER_STATUS_B: u8 = 0x0b;
+const REGISTER_STATUS_C: u8 = 0x0c;
+
+/// Status A: 更新中で値が読めない
+const STATUS_A_UPDATE_IN_PROGRESS: u8 = 1 << 7;
+/// Status B: 24時間制
+const STATUS_B_24_HOUR: u8 = 1 << 1;
+/// Status B: BCDではなく2進数
+const STATUS_B_BINARY: u8 = 1 << 2;
+/// Status B: 周期割り込みを出す
+const STATUS_B_PERIODIC_INTERRUPT: u8 = 1 << 6;
+/// 12時間制のときに時の最上位ビットが午後を表す
+const HOUR_PM: u8 = 1 << 7;
+
+const RTC_IRQ: u8 = 8;
+const CASCADE_IRQ: u8 = 2;
+
+/// 年のレジスタは下2桁しか持たない。FADTが世紀のレジスタを教えてくれなければこの世紀として扱う
+const DEFAULT_CENTURY: u16 = 2000;
+
+/// FADTにあった世紀のレジスタの番号。0ならない
+static CENTURY_REGISTER: AtomicU8 = AtomicU8::new(0);
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct DateTime {
+    pub year: u16,
+    pub month: u8,
+    pub day: u8,
+    pub hour: u8,
+    pub minute: u8,
+    pub second: u8,
+}
+
+impl DateTime {
+    /// 1970-01-01T00:00:00 (UTC) からの秒数。それより前の日時は0にする
+    pub fn unix_timestamp(&self) -> u64 {
+        let days = days_from_civil(i64::from(self.year), self.month.into(), self.day.into());
+        let Ok(days) = u64::try_from(days) else {
+            return 0;
+        };
+        let seconds =
+            u64::from(self.hour) * 3600 + u64::from(self.minute) * 60 + u64::from(self.second);
+        days * 86400 + seconds
+    }
+
+    pub fn from_unix_timestamp(timestamp: u64) -> DateTime {
+        let days = (timestamp / 86400) as i64;
+        let seconds = timestamp % 86400;
+        let (year, month, day) = civil_from_days(days);
+        DateTime {
+            year: year as u16,
+            month: month as u8,
+            day: day as u8,
+            hour: (seconds / 3600) as u8,
+            minute: (seconds / 60 % 60) as u8,
+            second: (seconds % 60) as u8,
+        }
+    }
+}
+
+impl fmt::Display for DateTime {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04}-{:02}-{:02} {:02}:{:02}:{:02}",
+            self.year, self.month, self.day, self.hour, self.minute, self.second
+        )
+    }
+}
+
+// 日付と1970-01-01からの日数の変換 (Howard Hinnantのアルゴリズム)
+
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * (month + if month > 2 { -3 } else { 9 }) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146097 + day_of_era - 719468
+}
+
+fn civil_from_days(days: i64) -> (i64, i64, i64) {
+    let days = days + 719468;
+    let era = days.div_euclid(146097);
+    let day_of_era = days - era * 146097;
+    let year_of_era =
+        (day_of_era - day_of_era / 1460 + day_of_era / 36524 - day_of_era / 146096) / 365;
+    let day_of_year = day_of_era - (365 * year_of_era + year_of_era / 4 - year_of_era / 100);
+    let mp = (5 * day_of_year + 2) / 153;
+    let day = day_of_year - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = year_of_era + era * 400 + if month <= 2 { 1 } else { 0 };
+    (year, month, day)
+}
+
+fn read_register(register: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(register);
+        data.read()
+    }
+}
+
+fn write_register(register: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CMOS_ADDRESS);
+    let mut data: Port<u8> = Port::new(CMOS_DATA);
+    unsafe {
+        address.write(register);
+        data.write(value);
+    }
+}
+
+fn update_in_progress() -> bool {
+    read_register(REGISTER_STATUS_A) & STATUS_A_UPDATE_IN_PROGRESS != 0
+}
+
+/// 時刻レジスタの生の値 (秒, 分, 時, 日, 月, 年)
+type RawTime = [u8; 6];
+
+fn read_raw() -> RawTime {
+    while update_in_progress() {
+        core::hint::spin_loop();
+    }
+    [
+        read_register(REGISTER_SECONDS),
+        read_register(REGISTER_MINUTES),
+        read_register(REGISTER_HOURS),
+        read_register(REGISTER_DAY),
+        read_register(REGISTER_MONTH),
+        read_register(REGISTER_YEAR),
+    ]
+}
+
+fn bcd_to_binary(value: u8) -> u8 {
+    (value >> 4) * 10 + (value & 0x0f)
+}
+
+/// Status B の形式に従って生の値を日時に直す。`century` は世紀のレジスタの生の値
+fn decode(raw: RawTime, century: Option<u8>, status_b: u8) -> DateTime {
+    let [second, minute, hour, day, month, year] = raw;
+    let binary = status_b & STATUS_B_BINARY != 0;
+    let convert = |value: u8| if binary { value } else { bcd_to_binary(value) };
+
+    let pm = hour & HOUR_PM != 0;
+    let mut hour = convert(hour & !HOUR_PM);
+    if status_b & STATUS_B_24_HOUR == 0 {
+        // 12時間制では 12 AM が0時、12 PM が12時
+        hour %= 12;
+        if pm {
+            hour += 12;
+        }
+    }
+
+    DateTime {
+        year: century.map_or(DEFAULT_CENTURY, |century| u16::from(convert(century)) * 100)
+            + u16::from(convert(year)),
+        month: convert(month),
+        day: convert(day),
+        hour,
+        minute: convert(minute),
+        second: convert(second),
+    }
+}
+
+/// RTCから日時を読む
+///
+/// 更新の途中の値を掴まないよう、同じ値が2回続けて読めるまで読み直す
+pub fn read() -> DateTime {
+    let century_register = match CENTURY_REGISTER.load(Ordering::Relaxed) {
+        0 => None,
+        register => Some(register),
+    };
+    let read_all = || (read_raw(), century_register.map(read_register));
+    without_interrupts(|| {
+        let mut raw = read_all();
+        loop {
+            let again = read_all();
+            if again == raw {
+                break;
+            }
+            raw = again;
+        }
+        let (raw, century) = raw;
+        decode(raw, century, read_register(REGISTER_STATUS_B))
+    })
+}
+
+/// 起動時に読んだ日時と、そのときのタイマー割り込みの回数
+static BOOT_TIME: Mutex<Option<(u64, u64)>> = Mutex::new(None);
+
+/// 起動時の日時を読んでおく
+///
+/// `acpi::init` の後にもう一度呼ぶと、FADTの世紀のレジスタを使って読み直す
+pub fn init() {
+    let century_register = acpi::fadt().map_or(0, |fadt| fadt.century);
+    CENTURY_REGISTER.store(century_register, Ordering::Relaxed);
+    let boot = (read().unix_timestamp(), interrupts::ticks());
+    *BOOT_TIME.lock() = Some(boot);
+}
+
+/// 現在の日時。`init` の前はRTCを直接読む
+pub fn now() -> DateTime {
+    let Some((boot_timestamp, boot_ticks)) = *BOOT_TIME.lock() else {
+        return read();
+    };
+    let elapsed = (interrupts::ticks() - boot_ticks) / u64::from(pit::TIMER_FREQUENCY);
+    DateTime::from_unix_timestamp(boot_timestamp + elapsed)
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum RtcError {
+    /// HPETのレガシー置き換えモードがIRQ8を使っている
+    IrqInUse,
+}
+
+impl fmt::Display for RtcError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            RtcError::IrqInUse => write!(f, "rtc: IRQ8 is routed to the HPET"),
+        }
+    }
+}
+
+/// 周期割り込みの回数
+static PERIODIC_TICKS: AtomicU64 = AtomicU64::new(0);
+static PERIODIC_ENABLED: AtomicBool = AtomicBool::new(false);
+
+/// 周期割り込みを有効にする。周波数は `32768 >> (rate - 1)` Hz で、`rate` は 3..=15
+///
+/// HPETがシステムタイマーになっているとIRQ8はHPETのものなので使えない
+pub fn enable_periodic_interrupt(rate: u8) -> Result<(), RtcError> {
+    assert!((3..=15).contains(&rate), "invalid RTC rate: {}", rate);
+    if hpet::legacy_replacement_enabled() {
+        return Err(RtcError::IrqInUse);
+    }
+    PERIODIC_ENABLED.store(true, Ordering::Relaxed);
+    without_interrupts(|| {
+        let status_a = read_register(REGISTER_STATUS_A);
+        write_register(REGISTER_STATUS_A, (status_a & 0xf0) | rate);
+        let status_b = read_register(REGISTER_STATUS_B);
+        write_register(REGISTER_STATUS_B, status_b | STATUS_B_PERIODIC_INTERRUPT);
+        // 前の割り込みが残っているとIRQ8が二度と来ないので読んで落としておく
+        read_register(REGISTER_STATUS_C);
+    });
+    interrupts::unmask_irq(CASCADE_IRQ);
+    interrupts::unmask_irq(RTC_IRQ);
+    Ok(())
+}
+
+pub fn disable_periodic_interrupt() {
+    without_interrupts(|| {
+        let status_b = read_register(REGISTER_STATUS_B);
+        write_register(REGISTER_STATUS_B, status_b & !STATUS_B_PERIODIC_INTERRUPT);
+    });
+    PERIODIC_ENABLED.store(false, Ordering::Relaxed);
+}
+
+/// 周期割り込みを有効にしているか
+pub fn periodic_interrupt_enabled() -> bool {
+    PERIODIC_ENABLED.load(Ordering::Relaxed)
+}
+
+/// 周期割り込みが来た回数
+pub fn periodic_ticks() -> u64 {
+    PERIODIC_TICKS.load(Ordering::Relaxed)
+}
+
+/// RTC割り込みから呼ばれる
+pub(crate) fn handle_interrupt() {
+    // Status C を読まないと次の割り込みが来ない
+    read_register(REGISTER_STATUS_C);
+    PERIODIC_TICKS.fetch_add(1, Ordering::Relaxed);
+}
+
+#[test_case]
+fn test_decode_bcd_12_hour() {
+    // 2024-02-29 12:05:09 AM (BCD, 12時間制)
+    let time = decode([0x09, 0x05, 0x12, 0x29, 0x02, 0x24], None, 0);
+    assert_eq!((time.year, time.month, time.day), (2024, 2, 29));
+    assert_eq!((time.hour, time.minute, time.second), (0, 5, 9));
+
+    // 11 PM
+    let time = decode([0, 0, 0x11 | HOUR_PM, 1, 1, 0x24], None, 0);
+    assert_eq!(time.hour, 23);
+}
+
+#[test_case]
+fn test_decode_binary_24_hour() {
+    let time = decode(
+        [59, 30, 17, 31, 12, 99],
+        None,
+        STATUS_B_BINARY | STATUS_B_24_HOUR,
+    );
+    assert_eq!((time.year, time.month, time.day), (2099, 12, 31));
+    assert_eq!((time.hour, time.minute, time.second), (17, 30, 59));
+}
+
+#[test_case]
+fn test_decode_century_register() {
+    let time = decode([0, 0, 0, 1, 1, 0x99], Some(0x19), 0);
+    assert_eq!(time.year, 1999);
+    let time = decode([0, 0, 0, 1, 1, 5], Some(21), STATUS_B_BINARY);
+    assert_eq!(time.year, 2105);
+}
+
+#[test_case]
+fn test_unix_timestamp_round_trip() {
+    let time = DateTime {
+        year: 2024,
+        month: 2,
+        day: 29,
+        hour: 23,
+        minute: 59,
+        second: 58,
+    };
+    assert_eq!(time.unix_timestamp(), 1_709_251_198);
+    assert_eq!(DateTime::from_unix_timestamp(1_709_251_198), time);
+    assert_eq!(DateTime::from_unix_timestamp(0).year, 1970);
+}
+
+#[test_case]
+fn test_unix_timestamp_before_1970() {
+    let time = DateTime {
+        year: 1950,
+        month: 6,
+        day: 1,
+        hour: 12,
+        minute: 0,
+        second: 0,
+    };
+    assert_eq!(time.unix_timestamp(), 0);
+}
@@ -10,6 +10,142 @@ lazy_static! {
     };
 }
 
+/// COM1〜COM4のI/Oポートの先頭
+pub const PORTS: [u16; 4] = [0x3f8, 0x2f8, 0x3e8, 0x2e8];
+
+lazy_static! {
+    /// COM2〜COM4。受信割り込みは使わず、`receive` で直接読む
+    static ref OTHER_PORTS: [Mutex<SerialPort>; PORTS.len() - 1] = core::array::from_fn(|i| {
+        let base = PORTS[i + 1];
+        let mut serial_port = unsafe { SerialPort::new(base) };
+        serial_port.init();
+        // COM3はCOM1とIRQ線を共有するので、割り込みは止めておく
+        unsafe { Port::<u8>::new(base + INTERRUPT_ENABLE).write(0) };
+        Mutex::new(serial_port)
+    });
+}
+
+/// COM1で受信したバイト列
+static INPUT: Mutex<Queue<u8, 256>> = Mutex::new(Queue::new());
+
+use crate::queue::Queue;
+use x86_64::instructions::port::Port;
+
+const COM1_DATA: u16 = 0x3f8;
+const COM1_LINE_STATUS: u16 = 0x3fd;
+/// Line Status Register の Data Ready ビット
+const DATA_READY: u8 = 1;
+
+/// ポートの先頭からのレジスタの位置
+const INTERRUPT_ENABLE: u16 = 1;
+const LINE_CONTROL: u16 = 3;
+const LINE_STATUS: u16 = 5;
+/// Line Control Register の、分周比のレジスタに切り替えるビット
+const DIVISOR_LATCH: u8 = 0x80;
+/// 分周比が1のときのボーレート
+const BAUD_BASE: u32 = 115200;
+
+/// COM1の受信割り込みから呼ばれ、届いているバイトを全て受信キューに移す
+pub(crate) fn receive_interrupt() {
+    let _serial = SERIAL1.lock();
+    let mut line_status: Port<u8> = Port::new(COM1_LINE_STATUS);
+    let mut data: Port<u8> = Port::new(COM1_DATA);
+    let mut input = INPUT.lock();
+    while unsafe { line_status.read() } & DATA_READY != 0 {
+        let byte = unsafe { data.read() };
+        // 溢れた分は捨てる
+        let _ = input.push(byte);
+    }
+}
+
+/// 受信キューにバイトが残っているか
+pub fn input_pending() -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| !INPUT.lock().is_empty())
+}
+
+/// 受信したバイトを1つ取り出す
+pub fn read_byte() -> Option<u8> {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| INPUT.lock().pop())
+}
+
+/// `index` 番 (0がCOM1) のポート
+pub fn port(index: usize) -> &'static Mutex<SerialPort> {
+    match index {
+        0 => &SERIAL1,
+        i => &OTHER_PORTS[i - 1],
+    }
+}
+
+fn line_status(index: usize) -> u8 {
+    unsafe { Port::<u8>::new(PORTS[index] + LINE_STATUS).read() }
+}
+
+/// ポートがつながっているか。ないポートのレジスタを読むと0xffになる
+pub fn present(index: usize) -> bool {
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let _port = port(index).lock();
+        line_status(index) != 0xff
+    })
+}
+
+/// `index` 番のポートで受信したバイトを1つ取り出す
+pub fn receive(index: usize) -> Option<u8> {
+    use x86_64::instructions::interrupts;
+
+    if index == 0 {
+        return read_byte();
+    }
+    interrupts::without_interrupts(|| {
+        let _port = port(index).lock();
+        let status = line_status(index);
+        (status != 0xff && status & DATA_READY != 0)
+            .then(|| unsafe { Port::<u8>::new(PORTS[index]).read() })
+    })
+}
+
+/// `index` 番のポートに受信したバイトが残っているか
+pub fn receive_pending(index: usize) -> bool {
+    use x86_64::instructions::interrupts;
+
+    if index == 0 {
+        return input_pending();
+    }
+    interrupts::without_interrupts(|| {
+        let _port = port(index).lock();
+        let status = line_status(index);
+        status != 0xff && status & DATA_READY != 0
+    })
+}
+
+/// ボーレートを設定する。115200を割り切れない速さなら `false` を返す
+pub fn set_baud_rate(index: usize, baud: u32) -> bool {
+    use x86_64::instructions::interrupts;
+
+    if baud == 0 || !BAUD_BASE.is_multiple_of(baud) {
+        return false;
+    }
+    let divisor = BAUD_BASE / baud;
+    let base = PORTS[index];
+    interrupts::without_interrupts(|| {
+        let _port = port(index).lock();
+        let mut line_control: Port<u8> = Port::new(base + LINE_CONTROL);
+        unsafe {
+            let line = line_control.read();
+            line_control.write(line | DIVISOR_LATCH);
+            Port::<u8>::new(base).write(divisor as u8);
+            Port::<u8>::new(base + INTERRUPT_ENABLE).write((divisor >> 8) as u8);
+            line_control.write(line & !DIVISOR_LATCH);
+        }
+    });
+    true
+}
+
 #[doc(hidden)]
 pub fn _print(args: ::core::fmt::Arguments) {
     use core::fmt::Write;
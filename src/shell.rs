@@ -0,0 +1,1018 @@
+use crate::{acpi, allocator, block, block_cache, ext2, fat, interrupts, keyboard, memory, net, pci, power, rtc, serial, time, vfs, vga_buffer};
+use alloc::{collections::VecDeque, format, string::String, sync::Arc, vec::Vec};
+use core::fmt::{self, Write};
+use keyboard::{DecodedKey, KeyCode, KeyState};
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// シェルを表示するコンソール (Alt+F2)
+pub const SHELL_CONSOLE: usize = 1;
+
+const PROMPT: &str = "> ";
+
+/// 1行に入力できる文字数。VGAで折り返さないように画面幅に収める
+const MAX_LINE: usize = vga_buffer::BUFFER_WIDTH - PROMPT.len() - 1;
+
+/// 覚えておくコマンド履歴の数
+const HISTORY_SIZE: usize = 32;
+
+/// シェルを起動する。ヒープと `memory::MAPPER` の準備が済んでから呼ぶこと
+pub fn run() -> ! {
+    use x86_64::instructions::interrupts::{disable, enable, enable_and_hlt};
+
+    vga_buffer::switch_console(SHELL_CONSOLE);
+    let mut shell = Shell::new();
+    let _ = writeln!(Output, "blog_os shell. type `help` for a list of commands.");
+    shell.prompt();
+
+    loop {
+        if let Some(event) = keyboard::read_event() {
+            if let Some(key) = key_from_keyboard(&event) {
+                shell.handle_key(key);
+            }
+        } else if let Some(byte) = serial::read_byte() {
+            if let Some(key) = shell.serial.decode(byte) {
+                shell.handle_key(key);
+            }
+        } else {
+            block_cache::sync_expired();
+            // キューを確認してからhltするまでの間に割り込みを取りこぼさないようにする
+            disable();
+            if keyboard::events_pending() || serial::input_pending() {
+                enable();
+            } else {
+                enable_and_hlt();
+            }
+        }
+    }
+}
+
+/// 行編集の操作
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Key {
+    Char(char),
+    Enter,
+    Backspace,
+    Delete,
+    Left,
+    Right,
+    Up,
+    Down,
+    Home,
+    End,
+    Tab,
+    Cancel,
+}
+
+fn key_from_keyboard(event: &keyboard::KeyEvent) -> Option<Key> {
+    if event.state == KeyState::Up {
+        return None;
+    }
+    let key = match event.key? {
+        DecodedKey::Unicode('\n') => Key::Enter,
+        DecodedKey::Unicode('\x08') => Key::Backspace,
+        DecodedKey::Unicode('\x7f') => Key::Delete,
+        DecodedKey::Unicode('\t') => Key::Tab,
+        DecodedKey::Unicode(c) if c == ' ' || c.is_ascii_graphic() => Key::Char(c),
+        DecodedKey::RawKey(KeyCode::ArrowLeft) => Key::Left,
+        DecodedKey::RawKey(KeyCode::ArrowRight) => Key::Right,
+        DecodedKey::RawKey(KeyCode::ArrowUp) => Key::Up,
+        DecodedKey::RawKey(KeyCode::ArrowDown) => Key::Down,
+        DecodedKey::RawKey(KeyCode::Home) => Key::Home,
+        DecodedKey::RawKey(KeyCode::End) => Key::End,
+        _ => return None,
+    };
+    Some(key)
+}
+
+/// シリアル端末から届くバイト列 (VT100のエスケープシーケンスを含む) を行編集の操作に直す
+#[derive(Default)]
+struct SerialDecoder {
+    state: EscapeState,
+    /// 直前のバイトがCRだったか。CRLFを1回のEnterとして扱うために使う
+    last_was_cr: bool,
+}
+
+#[derive(Default)]
+enum EscapeState {
+    #[default]
+    Ground,
+    Escape,
+    /// `ESC [` の後。数値パラメータを読んでいる
+    Csi(u32),
+    /// `ESC O` の後
+    Ss3,
+}
+
+impl SerialDecoder {
+    fn decode(&mut self, byte: u8) -> Option<Key> {
+        let last_was_cr = core::mem::replace(&mut self.last_was_cr, byte == b'\r');
+        match self.state {
+            EscapeState::Ground => match byte {
+                0x1b => {
+                    self.state = EscapeState::Escape;
+                    None
+                }
+                b'\r' => Some(Key::Enter),
+                b'\n' if last_was_cr => None,
+                b'\n' => Some(Key::Enter),
+                0x08 | 0x7f => Some(Key::Backspace),
+                b'\t' => Some(Key::Tab),
+                0x03 => Some(Key::Cancel),
+                0x20..=0x7e => Some(Key::Char(char::from(byte))),
+                _ => None,
+            },
+            EscapeState::Escape => {
+                self.state = match byte {
+                    b'[' => EscapeState::Csi(0),
+                    b'O' => EscapeState::Ss3,
+                    _ => EscapeState::Ground,
+                };
+                None
+            }
+            EscapeState::Csi(param) => {
+                if byte.is_ascii_digit() {
+                    let param = param.saturating_mul(10).saturating_add(u32::from(byte - b'0'));
+                    self.state = EscapeState::Csi(param);
+                    return None;
+                }
+                self.state = EscapeState::Ground;
+                match (byte, param) {
+                    (b'~', 1 | 7) => Some(Key::Home),
+                    (b'~', 4 | 8) => Some(Key::End),
+                    (b'~', 3) => Some(Key::Delete),
+                    (b'~', _) => None,
+                    (byte, _) => Self::cursor_key(byte),
+                }
+            }
+            EscapeState::Ss3 => {
+                self.state = EscapeState::Ground;
+                Self::cursor_key(byte)
+            }
+        }
+    }
+
+    fn cursor_key(byte: u8) -> Option<Key> {
+        match byte {
+            b'A' => Some(Key::Up),
+            b'B' => Some(Key::Down),
+            b'C' => Some(Key::Right),
+            b'D' => Some(Key::Left),
+            b'H' => Some(Key::Home),
+            b'F' => Some(Key::End),
+            _ => None,
+        }
+    }
+}
+
+/// シェルのコンソールとシリアルの両方に書き出す
+struct Output;
+
+impl fmt::Write for Output {
+    fn write_str(&mut self, s: &str) -> fmt::Result {
+        without_interrupts(|| {
+            vga_buffer::console(SHELL_CONSOLE).lock().write_string(s);
+            let mut serial = serial::SERIAL1.lock();
+            for byte in s.bytes() {
+                if byte == b'\n' {
+                    serial.send(b'\r');
+                }
+                serial.send(byte);
+            }
+        });
+        Ok(())
+    }
+}
+
+struct Shell {
+    line: String,
+    /// 行の中のカーソル位置。入力はASCIIに限っているのでバイト位置と同じ
+    cursor: usize,
+    history: VecDeque<String>,
+    /// 履歴をたどっている位置。`None` なら新しい行を編集している
+    history_index: Option<usize>,
+    /// 履歴をたどり始める前に編集していた行
+    draft: String,
+    serial: SerialDecoder,
+}
+
+impl Shell {
+    fn new() -> Self {
+        Shell {
+            line: String::new(),
+            cursor: 0,
+            history: VecDeque::new(),
+            history_index: None,
+            draft: String::new(),
+            serial: SerialDecoder::default(),
+        }
+    }
+
+    fn prompt(&mut self) {
+        let _ = Output.write_str(PROMPT);
+    }
+
+    fn handle_key(&mut self, key: Key) {
+        match key {
+            Key::Char(c) => {
+                if self.line.len() < MAX_LINE {
+                    self.line.insert(self.cursor, c);
+                    self.cursor += 1;
+                }
+            }
+            Key::Backspace => {
+                if self.cursor > 0 {
+                    self.cursor -= 1;
+                    self.line.remove(self.cursor);
+                }
+            }
+            Key::Delete => {
+                if self.cursor < self.line.len() {
+                    self.line.remove(self.cursor);
+                }
+            }
+            Key::Left => self.cursor = self.cursor.saturating_sub(1),
+            Key::Right => self.cursor = (self.cursor + 1).min(self.line.len()),
+            Key::Home => self.cursor = 0,
+            Key::End => self.cursor = self.line.len(),
+            Key::Up => self.history_previous(),
+            Key::Down => self.history_next(),
+            Key::Tab => self.complete(),
+            Key::Enter => {
+                let _ = Output.write_str("\n");
+                let line = core::mem::take(&mut self.line);
+                self.cursor = 0;
+                self.history_index = None;
+                self.add_history(&line);
+                execute(&line);
+                self.prompt();
+                return;
+            }
+            Key::Cancel => {
+                let _ = Output.write_str("^C\n");
+                self.line.clear();
+                self.cursor = 0;
+                self.history_index = None;
+                self.prompt();
+                return;
+            }
+        }
+        self.redraw();
+    }
+
+    fn add_history(&mut self, line: &str) {
+        let line = line.trim();
+        if line.is_empty() || self.history.back().is_some_and(|last| last == line) {
+            return;
+        }
+        if self.history.len() == HISTORY_SIZE {
+            self.history.pop_front();
+        }
+        self.history.push_back(String::from(line));
+    }
+
+    fn history_previous(&mut self) {
+        let index = match self.history_index {
+            None if self.history.is_empty() => return,
+            None => {
+                self.draft = self.line.clone();
+                self.history.len() - 1
+            }
+            Some(0) => return,
+            Some(index) => index - 1,
+        };
+        self.history_index = Some(index);
+        self.set_line(self.history[index].clone());
+    }
+
+    fn history_next(&mut self) {
+        let Some(index) = self.history_index else {
+            return;
+        };
+        if index + 1 < self.history.len() {
+            self.history_index = Some(index + 1);
+            self.set_line(self.history[index + 1].clone());
+        } else {
+            self.history_index = None;
+            let draft = core::mem::take(&mut self.draft);
+            self.set_line(draft);
+        }
+    }
+
+    fn set_line(&mut self, line: String) {
+        self.line = line;
+        self.cursor = self.line.len();
+    }
+
+    /// カーソルより前の単語をコマンド名で補完する
+    fn complete(&mut self) {
+        let prefix = &self.line[..self.cursor];
+        if prefix.contains(' ') {
+            return;
+        }
+        let candidates: Vec<&str> = COMMANDS
+            .iter()
+            .map(|command| command.name)
+            .filter(|name| name.starts_with(prefix))
+            .collect();
+
+        match candidates.as_slice() {
+            [] => {}
+            [name] => {
+                let rest = &self.line[self.cursor..];
+                let line = alloc::format!("{} {}", name, rest.trim_start());
+                self.cursor = name.len() + 1;
+                self.line = line;
+            }
+            [first, others @ ..] => {
+                let common = others.iter().fold(first.len(), |len, name| {
+                    first
+                        .bytes()
+                        .zip(name.bytes())
+                        .take(len)
+                        .take_while(|(a, b)| a == b)
+                        .count()
+                });
+                if common > prefix.len() {
+                    self.line.insert_str(self.cursor, &first[prefix.len()..common]);
+                    self.cursor = common;
+                } else {
+                    // これ以上補完できないときは候補を並べる
+                    let _ = writeln!(Output);
+                    for name in &candidates {
+                        let _ = write!(Output, "{}  ", name);
+                    }
+                    let _ = writeln!(Output);
+                    self.prompt();
+                }
+            }
+        }
+    }
+
+    /// 入力中の行を描き直す
+    fn redraw(&self) {
+        without_interrupts(|| {
+            let mut console = vga_buffer::console(SHELL_CONSOLE).lock();
+            let (row, _) = console.cursor_position();
+            console.set_cursor(row, 0);
+            console.write_string(PROMPT);
+            console.write_string(&self.line);
+            for _ in PROMPT.len() + self.line.len()..vga_buffer::BUFFER_WIDTH - 1 {
+                console.write_byte(b' ');
+            }
+            console.set_cursor(row, PROMPT.len() + self.cursor);
+
+            let mut serial = serial::SERIAL1.lock();
+            let _ = write!(serial, "\r{}{}\x1b[K", PROMPT, self.line);
+            let back = self.line.len() - self.cursor;
+            if back > 0 {
+                let _ = write!(serial, "\x1b[{}D", back);
+            }
+        });
+    }
+}
+
+//
+// built-in commands
+//
+
+struct Command {
+    name: &'static str,
+    usage: &'static str,
+    help: &'static str,
+    run: fn(&[&str]),
+}
+
+const COMMANDS: &[Command] = &[
+    Command {
+        name: "help",
+        usage: "help",
+        help: "list the built-in commands",
+        run: help,
+    },
+    Command {
+        name: "clear",
+        usage: "clear",
+        help: "clear the screen",
+        run: clear,
+    },
+    Command {
+        name: "meminfo",
+        usage: "meminfo",
+        help: "show frame allocator and heap usage",
+        run: meminfo,
+    },
+    Command {
+        name: "pt",
+        usage: "pt <addr>",
+        help: "translate a virtual address through the page tables",
+        run: pt,
+    },
+    Command {
+        name: "acpi",
+        usage: "acpi",
+        help: "list the ACPI tables and the interrupt controllers",
+        run: acpi_tables,
+    },
+    Command {
+        name: "lspci",
+        usage: "lspci [-v]",
+        help: "list PCI devices (-v shows BARs and capabilities)",
+        run: lspci,
+    },
+    Command {
+        name: "lsblk",
+        usage: "lsblk",
+        help: "list block devices",
+        run: lsblk,
+    },
+    Command {
+        name: "ifconfig",
+        usage: "ifconfig",
+        help: "list network interfaces",
+        run: ifconfig,
+    },
+    Command {
+        name: "ls",
+        usage: "ls [path]",
+        help: "list a directory",
+        run: ls,
+    },
+    Command {
+        name: "cat",
+        usage: "cat <path>...",
+        help: "print files",
+        run: cat,
+    },
+    Command {
+        name: "write",
+        usage: "write <path> <text>...",
+        help: "write the text to a file",
+        run: write,
+    },
+    Command {
+        name: "mkdir",
+        usage: "mkdir <path>",
+        help: "create a directory",
+        run: mkdir,
+    },
+    Command {
+        name: "rm",
+        usage: "rm <path>",
+        help: "remove a file or an empty directory",
+        run: rm,
+    },
+    Command {
+        name: "mv",
+        usage: "mv <from> <to>",
+        help: "rename a file or directory",
+        run: mv,
+    },
+    Command {
+        name: "mount",
+        usage: "mount [<device> <path>]",
+        help: "list mounted file systems or mount an ext2 or FAT disk",
+        run: mount,
+    },
+    Command {
+        name: "umount",
+        usage: "umount <path>",
+        help: "unmount a file system",
+        run: umount,
+    },
+    Command {
+        name: "sync",
+        usage: "sync",
+        help: "write cached blocks back to the disks",
+        run: sync,
+    },
+    Command {
+        name: "irqstat",
+        usage: "irqstat",
+        help: "show interrupt counts per IRQ line",
+        run: irqstat,
+    },
+    Command {
+        name: "uptime",
+        usage: "uptime",
+        help: "show the time since boot",
+        run: uptime,
+    },
+    Command {
+        name: "date",
+        usage: "date",
+        help: "show the current date and time (UTC)",
+        run: date,
+    },
+    Command {
+        name: "layout",
+        usage: "layout [name]",
+        help: "show or change the keyboard layout",
+        run: layout,
+    },
+    Command {
+        name: "reboot",
+        usage: "reboot",
+        help: "restart the machine",
+        run: reboot,
+    },
+    Command {
+        name: "shutdown",
+        usage: "shutdown",
+        help: "power off the machine through ACPI",
+        run: shutdown,
+    },
+];
+
+fn execute(line: &str) {
+    let args: Vec<&str> = line.split_whitespace().collect();
+    let Some(&name) = args.first() else {
+        return;
+    };
+    match COMMANDS.iter().find(|command| command.name == name) {
+        Some(command) => (command.run)(&args[1..]),
+        None => {
+            let _ = writeln!(Output, "{}: command not found", name);
+        }
+    }
+}
+
+fn help(_args: &[&str]) {
+    for command in COMMANDS {
+        let _ = writeln!(Output, "  {:<12} {}", command.usage, command.help);
+    }
+}
+
+fn clear(_args: &[&str]) {
+    without_interrupts(|| {
+        vga_buffer::console(SHELL_CONSOLE).lock().clear_screen();
+        let _ = serial::SERIAL1.lock().write_str("\x1b[2J\x1b[H");
+    });
+}
+
+fn meminfo(_args: &[&str]) {
+    const FRAME_SIZE: usize = 4096;
+
+    let frames = without_interrupts(|| {
+        memory::FRAME_ALLOCATOR
+            .lock()
+            .as_ref()
+            .map(|allocator| (allocator.usable_frame_count(), allocator.allocated_frame_count()))
+    });
+    match frames {
+        Some((usable, allocated)) => {
+            let _ = writeln!(
+                Output,
+                "frames: {} usable, {} allocated, {} free ({} KiB free)",
+                usable,
+                allocated,
+                usable - allocated,
+                (usable - allocated) * FRAME_SIZE / 1024
+            );
+        }
+        None => {
+            let _ = writeln!(Output, "frames: frame allocator not initialized");
+        }
+    }
+
+    let heap = without_interrupts(allocator::heap_stats);
+    let _ = writeln!(
+        Output,
+        "heap:   {} bytes, {} used, {} free",
+        heap.size, heap.used, heap.free
+    );
+}
+
+fn pt(args: &[&str]) {
+    use x86_64::{VirtAddr, structures::paging::Translate};
+
+    let [addr] = args else {
+        let _ = writeln!(Output, "usage: pt <addr>");
+        return;
+    };
+    let Some(addr) = parse_number(addr) else {
+        let _ = writeln!(Output, "pt: invalid address: {}", addr);
+        return;
+    };
+    let Ok(virt) = VirtAddr::try_new(addr) else {
+        let _ = writeln!(Output, "pt: non-canonical address: {:#x}", addr);
+        return;
+    };
+
+    let phys = without_interrupts(|| {
+        memory::MAPPER
+            .lock()
+            .as_ref()
+            .map(|mapper| mapper.translate_addr(virt))
+    });
+    let _ = match phys {
+        Some(Some(phys)) => writeln!(Output, "{:#x} -> {:#x}", virt.as_u64(), phys.as_u64()),
+        Some(None) => writeln!(Output, "{:#x} -> not mapped", virt.as_u64()),
+        None => writeln!(Output, "pt: page tables not initialized"),
+    };
+}
+
+/// `0x` で始まれば16進数、それ以外は10進数として読む。桁区切りの `_` は読み飛ばす
+fn parse_number(s: &str) -> Option<u64> {
+    let (digits, radix) = match s.strip_prefix("0x").or_else(|| s.strip_prefix("0X")) {
+        Some(hex) => (hex, 16),
+        None => (s, 10),
+    };
+    if digits.is_empty() {
+        return None;
+    }
+    digits.chars().filter(|&c| c != '_').try_fold(0u64, |value, c| {
+        let digit = c.to_digit(radix)?;
+        value.checked_mul(u64::from(radix))?.checked_add(u64::from(digit))
+    })
+}
+
+fn acpi_tables(_args: &[&str]) {
+    let tables = match acpi::tables() {
+        Ok(tables) => tables,
+        Err(err) => {
+            let _ = writeln!(Output, "{}", err);
+            return;
+        }
+    };
+    for table in tables {
+        let header = table.header;
+        let _ = writeln!(
+            Output,
+            "{} at {:#010x}, {} bytes, rev {}{}",
+            acpi::signature_str(&header.signature),
+            table.address.as_u64(),
+            { header.length },
+            header.revision,
+            if table.valid { "" } else { " (bad checksum)" }
+        );
+    }
+
+    if let Ok(madt) = acpi::madt() {
+        let mut cpus = 0;
+        for entry in madt.entries() {
+            match entry {
+                acpi::MadtEntry::LocalApic { flags, .. }
+                | acpi::MadtEntry::LocalX2Apic { flags, .. }
+                    if flags & acpi::MadtEntry::PROCESSOR_ENABLED != 0 =>
+                {
+                    cpus += 1;
+                }
+                acpi::MadtEntry::IoApic { id, address, gsi_base } => {
+                    let _ = writeln!(
+                        Output,
+                        "ioapic {} at {:#010x}, gsi base {}",
+                        id, address, gsi_base
+                    );
+                }
+                acpi::MadtEntry::InterruptSourceOverride { source, gsi, .. } => {
+                    let _ = writeln!(Output, "irq {} -> gsi {}", source, gsi);
+                }
+                _ => {}
+            }
+        }
+        let _ = writeln!(
+            Output,
+            "{} cpus, local apic at {:#010x}",
+            cpus,
+            madt.local_apic_address().as_u64()
+        );
+    }
+}
+
+fn lsblk(_args: &[&str]) {
+    for (name, device) in block::DEVICES.entries() {
+        let device = device.lock();
+        let bytes = device.block_count() * device.block_size() as u64;
+        let _ = writeln!(
+            Output,
+            "{:<8} {:>8} MiB  {}",
+            name,
+            bytes / (1024 * 1024),
+            device.description()
+        );
+    }
+}
+
+fn ifconfig(_args: &[&str]) {
+    for (name, device) in net::DEVICES.entries() {
+        let device = device.lock();
+        let stats = device.stats();
+        let _ = writeln!(
+            Output,
+            "{:<8} {}  link {}  {}",
+            name,
+            device.mac_address(),
+            if device.link_up() { "up" } else { "down" },
+            device.description()
+        );
+        let _ = writeln!(
+            Output,
+            "         rx {} packets {} bytes {} errors  tx {} packets {} bytes",
+            stats.rx_packets, stats.rx_bytes, stats.rx_errors, stats.tx_packets, stats.tx_bytes
+        );
+    }
+}
+
+fn ls(args: &[&str]) {
+    let path = args.first().copied().unwrap_or("/");
+    let entries = match vfs::readdir(path) {
+        Ok(entries) => entries,
+        Err(err) => {
+            let _ = writeln!(Output, "ls: {}: {}", path, err);
+            return;
+        }
+    };
+    for entry in entries {
+        let full_path = format!("{}/{}", path.trim_end_matches('/'), entry.name);
+        let size = vfs::lstat(&full_path).map_or(0, |metadata| metadata.size);
+        let kind = match entry.file_type {
+            vfs::FileType::Regular => '-',
+            vfs::FileType::Directory => 'd',
+            vfs::FileType::Symlink => 'l',
+            vfs::FileType::CharDevice => 'c',
+            vfs::FileType::BlockDevice => 'b',
+        };
+        match vfs::readlink(&full_path) {
+            Ok(target) => {
+                let _ = writeln!(Output, "{} {:>8} {} -> {}", kind, size, entry.name, target);
+            }
+            Err(_) => {
+                let _ = writeln!(Output, "{} {:>8} {}", kind, size, entry.name);
+            }
+        }
+    }
+}
+
+fn cat(args: &[&str]) {
+    if args.is_empty() {
+        let _ = writeln!(Output, "usage: cat <path>...");
+        return;
+    }
+    for path in args {
+        match vfs::read_file(path) {
+            Ok(contents) => {
+                let _ = write!(Output, "{}", String::from_utf8_lossy(&contents));
+            }
+            Err(err) => {
+                let _ = writeln!(Output, "cat: {}: {}", path, err);
+            }
+        }
+    }
+}
+
+fn write(args: &[&str]) {
+    let [path, words @ ..] = args else {
+        let _ = writeln!(Output, "usage: write <path> <text>...");
+        return;
+    };
+    let mut text = words.join(" ");
+    text.push('\n');
+    if let Err(err) = vfs::write_file(path, text.as_bytes()) {
+        let _ = writeln!(Output, "write: {}: {}", path, err);
+    }
+}
+
+fn mkdir(args: &[&str]) {
+    let [path] = args else {
+        let _ = writeln!(Output, "usage: mkdir <path>");
+        return;
+    };
+    if let Err(err) = vfs::mkdir(path) {
+        let _ = writeln!(Output, "mkdir: {}: {}", path, err);
+    }
+}
+
+fn rm(args: &[&str]) {
+    let [path] = args else {
+        let _ = writeln!(Output, "usage: rm <path>");
+        return;
+    };
+    let result = match vfs::lstat(path) {
+        Ok(metadata) if metadata.file_type == vfs::FileType::Directory => vfs::rmdir(path),
+        Ok(_) => vfs::unlink(path),
+        Err(err) => Err(err),
+    };
+    if let Err(err) = result {
+        let _ = writeln!(Output, "rm: {}: {}", path, err);
+    }
+}
+
+fn mv(args: &[&str]) {
+    let [from, to] = args else {
+        let _ = writeln!(Output, "usage: mv <from> <to>");
+        return;
+    };
+    if let Err(err) = vfs::rename(from, to) {
+        let _ = writeln!(Output, "mv: {}", err);
+    }
+}
+
+fn mount(args: &[&str]) {
+    match args {
+        [] => {
+            for (path, fs) in vfs::mounts() {
+                let _ = writeln!(Output, "{} on {}", fs, path);
+            }
+        }
+        [device, path] => {
+            let Some(cache) = block_cache::open(device) else {
+                let _ = writeln!(Output, "mount: {}: no such device", device);
+                return;
+            };
+            let fs: Result<Arc<dyn vfs::FileSystem>, vfs::FsError> = if ext2::detect(&cache) {
+                ext2::Ext2Fs::new(cache).map(|fs| Arc::new(fs) as _)
+            } else {
+                fat::FatFs::new(cache).map(|fs| Arc::new(fs) as _)
+            };
+            let result = fs.and_then(|fs| vfs::mount(path, fs));
+            if let Err(err) = result {
+                let _ = writeln!(Output, "mount: {}: {}", device, err);
+            }
+        }
+        _ => {
+            let _ = writeln!(Output, "usage: mount [<device> <path>]");
+        }
+    }
+}
+
+fn umount(args: &[&str]) {
+    let [path] = args else {
+        let _ = writeln!(Output, "usage: umount <path>");
+        return;
+    };
+    if let Err(err) = vfs::unmount(path) {
+        let _ = writeln!(Output, "umount: {}: {}", path, err);
+    }
+}
+
+fn sync(_args: &[&str]) {
+    if let Err(err) = vfs::sync() {
+        let _ = writeln!(Output, "{}", err);
+    }
+    if let Err(err) = block_cache::sync_all() {
+        let _ = writeln!(Output, "{}", err);
+    }
+    for (name, stats) in block_cache::stats() {
+        let _ = writeln!(
+            Output,
+            "{:<8} {} cached, {} dirty, {} hits, {} misses, {} read ahead, {} written",
+            name,
+            stats.cached,
+            stats.dirty,
+            stats.hits,
+            stats.misses,
+            stats.read_ahead,
+            stats.written_back
+        );
+    }
+}
+
+fn lspci(args: &[&str]) {
+    let verbose = match args {
+        [] => false,
+        ["-v"] => true,
+        _ => {
+            let _ = writeln!(Output, "usage: lspci [-v]");
+            return;
+        }
+    };
+    for (device, driver) in pci::devices() {
+        let _ = writeln!(
+            Output,
+            "{} {:04x}:{:04x} {}{}{}",
+            device.address,
+            device.vendor_id,
+            device.device_id,
+            pci::class_name(device.class, device.subclass),
+            if driver.is_some() { " -> " } else { "" },
+            driver.unwrap_or("")
+        );
+        if !verbose {
+            continue;
+        }
+        for (index, bar) in device.bars.iter().enumerate() {
+            match bar {
+                Some(pci::Bar::Memory { address, size, .. }) => {
+                    let _ = writeln!(Output, "  BAR{}: memory at {:#x} ({:#x})", index, address, size);
+                }
+                Some(pci::Bar::Io { port, size }) => {
+                    let _ = writeln!(Output, "  BAR{}: io at {:#x} ({:#x})", index, port, size);
+                }
+                None => {}
+            }
+        }
+        if device.interrupt_pin != 0 {
+            let _ = writeln!(
+                Output,
+                "  interrupt: pin {} irq {}",
+                (b'A' + device.interrupt_pin - 1) as char,
+                device.interrupt_line
+            );
+        }
+        for (id, offset) in device.capabilities() {
+            let _ = writeln!(Output, "  capability {:#04x} at {:#04x}", id, offset);
+        }
+    }
+}
+
+fn irqstat(_args: &[&str]) {
+    for (irq, name) in interrupts::IRQ_NAMES.iter().enumerate() {
+        let count = interrupts::irq_count(irq);
+        if count > 0 {
+            let _ = writeln!(Output, "  irq {:>2} {:<14} {}", irq, name, count);
+        }
+    }
+}
+
+fn uptime(_args: &[&str]) {
+    let uptime = time::uptime();
+    let seconds = uptime.as_secs();
+    let _ = writeln!(
+        Output,
+        "up {}:{:02}:{:02}.{:06} ({} ticks)",
+        seconds / 3600,
+        seconds / 60 % 60,
+        seconds % 60,
+        uptime.subsec_micros(),
+        interrupts::ticks()
+    );
+    match time::tsc_frequency() {
+        Some(frequency) => {
+            let invariant = if time::invariant_tsc() { "invariant" } else { "variant" };
+            let source = time::calibration_source().map_or("?", |source| source.name());
+            let _ = writeln!(
+                Output,
+                "tsc {} kHz ({}, from {})",
+                frequency / 1000,
+                invariant,
+                source
+            );
+        }
+        None => {
+            let _ = writeln!(Output, "tsc not calibrated");
+        }
+    }
+}
+
+fn date(_args: &[&str]) {
+    let _ = writeln!(Output, "{} UTC", rtc::now());
+}
+
+fn layout(args: &[&str]) {
+    match args {
+        [] => {
+            let _ = write!(Output, "current: {}, available:", keyboard::layout().name());
+            for layout in keyboard::Layout::ALL {
+                let _ = write!(Output, " {}", layout.name());
+            }
+            let _ = writeln!(Output);
+        }
+        [name] => match keyboard::Layout::from_name(name) {
+            Some(layout) => keyboard::set_layout(layout),
+            None => {
+                let _ = writeln!(Output, "layout: unknown layout: {}", name);
+            }
+        },
+        _ => {
+            let _ = writeln!(Output, "usage: layout [name]");
+        }
+    }
+}
+
+fn reboot(_args: &[&str]) {
+    let _ = writeln!(Output, "rebooting...");
+    power::reboot();
+}
+
+fn shutdown(_args: &[&str]) {
+    if !power::shutdown_supported() {
+        let _ = writeln!(Output, "shutdown: ACPI power-off is not available");
+        return;
+    }
+    let _ = writeln!(Output, "shutting down...");
+    power::shutdown();
+}
+
+#[test_case]
+fn test_parse_number() {
+    assert_eq!(parse_number("0xb8000"), Some(0xb8000));
+    assert_eq!(parse_number("4096"), Some(4096));
+    assert_eq!(parse_number("0x_4444_4444_0000"), Some(0x4444_4444_0000));
+    assert_eq!(parse_number("zz"), None);
+}
+
+#[test_case]
+fn test_serial_decoder_escape_sequences() {
+    let mut decoder = SerialDecoder::default();
+    let mut keys = b"\x1b[A\x1b[D\x1b[3~\x1bOHls\r\n"
+        .iter()
+        .filter_map(|&byte| decoder.decode(byte));
+    assert_eq!(keys.next(), Some(Key::Up));
+    assert_eq!(keys.next(), Some(Key::Left));
+    assert_eq!(keys.next(), Some(Key::Delete));
+    assert_eq!(keys.next(), Some(Key::Home));
+    assert_eq!(keys.next(), Some(Key::Char('l')));
+    assert_eq!(keys.next(), Some(Key::Char('s')));
+    assert_eq!(keys.next(), Some(Key::Enter));
+    assert_eq!(keys.next(), None);
+}
@@ -0,0 +1,264 @@
+//! TSCを使った高分解能の時刻
+//!
+//! 起動時にTSCの周波数をPITのチャンネル2で較正し、ナノ秒単位の `Instant` を提供する。
+//! HPETが見つかれば `calibrate_with_hpet` でより正確に較正し直す。
+//! 較正できなかったときはタイマー割り込みの回数 (10ms単位) に落とす
+
+use crate::{hpet, interrupts, pit};
+use core::arch::x86_64::{__cpuid, _rdtsc};
+use core::fmt;
+use core::ops::{Add, Sub};
+use core::sync::atomic::{AtomicBool, AtomicU8, AtomicU64, Ordering};
+pub use core::time::Duration;
+use x86_64::instructions::interrupts::without_interrupts;
+
+const NANOS_PER_SEC: u64 = 1_000_000_000;
+
+/// 較正に使うPITのカウント数 (約10ms)
+const CALIBRATION_COUNT: u16 = (pit::BASE_FREQUENCY / 100) as u16;
+/// 較正を繰り返す回数。割り込みやSMIで伸びた回を除くため最小値を取る
+const CALIBRATION_ROUNDS: usize = 3;
+/// HPETで較正するときに測る時間。両方のカウンタを同時に読むので1回で足りる
+const HPET_CALIBRATION_TIME: Duration = Duration::from_millis(50);
+
+/// TSCの周波数をどこから求めたか
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ClockSource {
+    Cpuid = 1,
+    Pit = 2,
+    Hpet = 3,
+}
+
+impl ClockSource {
+    pub fn name(self) -> &'static str {
+        match self {
+            ClockSource::Cpuid => "cpuid",
+            ClockSource::Pit => "pit",
+            ClockSource::Hpet => "hpet",
+        }
+    }
+}
+
+/// TSCの周波数 (Hz)。0なら較正前
+static TSC_FREQUENCY: AtomicU64 = AtomicU64::new(0);
+/// 最後に較正したときのTSCの値と、そのときの `Instant`。
+/// 較正し直しても `Instant` が戻らないよう、続きから数える
+static TSC_BASE: AtomicU64 = AtomicU64::new(0);
+static NANOS_BASE: AtomicU64 = AtomicU64::new(0);
+static INVARIANT_TSC: AtomicBool = AtomicBool::new(false);
+/// `ClockSource` の値。0なら較正前
+static SOURCE: AtomicU8 = AtomicU8::new(0);
+
+/// TSCの周波数を求める。`pit::init` の後に呼ぶこと
+pub fn init() {
+    INVARIANT_TSC.store(detect_invariant_tsc(), Ordering::Relaxed);
+    let (frequency, source) = match cpuid_tsc_frequency() {
+        Some(frequency) => (frequency, ClockSource::Cpuid),
+        None => (calibrate_with_pit(), ClockSource::Pit),
+    };
+    set_frequency(frequency, source);
+}
+
+/// HPETのメインカウンタでTSCを較正し直す。`hpet::init` の後に呼ぶこと
+///
+/// PITの10msより長く測れるので誤差が小さい。CPUIDで周波数が分かっていればそちらを使い続ける
+pub fn calibrate_with_hpet() -> Result<u64, hpet::HpetError> {
+    if let Some(frequency) = tsc_frequency()
+        && calibration_source() == Some(ClockSource::Cpuid)
+    {
+        return Ok(frequency);
+    }
+    let cycles = without_interrupts(|| {
+        let start = rdtsc();
+        hpet::busy_wait(HPET_CALIBRATION_TIME).map(|()| rdtsc() - start)
+    })?;
+    let frequency =
+        (u128::from(cycles) * u128::from(NANOS_PER_SEC) / HPET_CALIBRATION_TIME.as_nanos()) as u64;
+    set_frequency(frequency, ClockSource::Hpet);
+    Ok(frequency)
+}
+
+/// 今の `Instant` を原点に周波数を入れ替える
+fn set_frequency(frequency: u64, source: ClockSource) {
+    without_interrupts(|| {
+        let now = match TSC_FREQUENCY.load(Ordering::Relaxed) {
+            0 => 0,
+            _ => Instant::now().as_nanos(),
+        };
+        NANOS_BASE.store(now, Ordering::Relaxed);
+        TSC_BASE.store(rdtsc(), Ordering::Relaxed);
+        TSC_FREQUENCY.store(frequency, Ordering::Relaxed);
+        SOURCE.store(source as u8, Ordering::Relaxed);
+    });
+}
+
+pub fn rdtsc() -> u64 {
+    unsafe { _rdtsc() }
+}
+
+/// CPUID 0x80000007 の EDX bit 8。省電力状態やクロックの変更があってもTSCが一定の速さで進む
+fn detect_invariant_tsc() -> bool {
+    let max_extended = __cpuid(0x8000_0000).eax;
+    max_extended >= 0x8000_0007 && __cpuid(0x8000_0007).edx & (1 << 8) != 0
+}
+
+/// CPUID 0x15 がコアクリスタルの周波数とTSCとの比を教えてくれれば、それを使う
+fn cpuid_tsc_frequency() -> Option<u64> {
+    if __cpuid(0).eax < 0x15 {
+        return None;
+    }
+    let leaf = __cpuid(0x15);
+    let (denominator, numerator, crystal) = (leaf.eax, leaf.ebx, leaf.ecx);
+    if denominator == 0 || numerator == 0 || crystal == 0 {
+        return None;
+    }
+    Some(u64::from(crystal) * u64::from(numerator) / u64::from(denominator))
+}
+
+fn calibrate_with_pit() -> u64 {
+    let cycles = without_interrupts(|| {
+        (0..CALIBRATION_ROUNDS)
+            .map(|_| {
+                let mut start = 0;
+                pit::wait_channel2(CALIBRATION_COUNT, || start = rdtsc());
+                rdtsc() - start
+            })
+            .min()
+            .unwrap_or(0)
+    });
+    cycles * u64::from(pit::BASE_FREQUENCY) / u64::from(CALIBRATION_COUNT)
+}
+
+/// 較正したTSCの周波数 (Hz)。較正前は `None`
+pub fn tsc_frequency() -> Option<u64> {
+    match TSC_FREQUENCY.load(Ordering::Relaxed) {
+        0 => None,
+        frequency => Some(frequency),
+    }
+}
+
+/// TSCの周波数をどこから求めたか。較正前は `None`
+pub fn calibration_source() -> Option<ClockSource> {
+    match SOURCE.load(Ordering::Relaxed) {
+        1 => Some(ClockSource::Cpuid),
+        2 => Some(ClockSource::Pit),
+        3 => Some(ClockSource::Hpet),
+        _ => None,
+    }
+}
+
+/// TSCが一定の速さで進むか。そうでなければ `Instant` は省電力状態でずれることがある
+pub fn invariant_tsc() -> bool {
+    INVARIANT_TSC.load(Ordering::Relaxed)
+}
+
+/// 起動後のある時点。ナノ秒単位で、単調に増える
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
+pub struct Instant(u64);
+
+impl Instant {
+    pub fn now() -> Instant {
+        let frequency = TSC_FREQUENCY.load(Ordering::Relaxed);
+        if frequency == 0 {
+            let ticks = interrupts::ticks();
+            return Instant(ticks * (NANOS_PER_SEC / u64::from(pit::TIMER_FREQUENCY)));
+        }
+        let cycles = rdtsc().wrapping_sub(TSC_BASE.load(Ordering::Relaxed));
+        Instant(NANOS_BASE.load(Ordering::Relaxed) + cycles_to_nanos(cycles, frequency))
+    }
+
+    /// 原点 (最初にTSCを較正したとき) からのナノ秒
+    pub fn as_nanos(&self) -> u64 {
+        self.0
+    }
+
+    pub fn duration_since(&self, earlier: Instant) -> Duration {
+        Duration::from_nanos(self.0.saturating_sub(earlier.0))
+    }
+
+    pub fn elapsed(&self) -> Duration {
+        Instant::now().duration_since(*self)
+    }
+
+    pub fn checked_add(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_add(nanos).map(Instant)
+    }
+
+    pub fn checked_sub(&self, duration: Duration) -> Option<Instant> {
+        let nanos = u64::try_from(duration.as_nanos()).ok()?;
+        self.0.checked_sub(nanos).map(Instant)
+    }
+}
+
+impl Add<Duration> for Instant {
+    type Output = Instant;
+
+    fn add(self, duration: Duration) -> Instant {
+        self.checked_add(duration)
+            .expect("overflow when adding duration to instant")
+    }
+}
+
+impl Sub<Duration> for Instant {
+    type Output = Instant;
+
+    fn sub(self, duration: Duration) -> Instant {
+        self.checked_sub(duration)
+            .expect("overflow when subtracting duration from instant")
+    }
+}
+
+impl Sub<Instant> for Instant {
+    type Output = Duration;
+
+    fn sub(self, earlier: Instant) -> Duration {
+        self.duration_since(earlier)
+    }
+}
+
+/// ログ用に `[   秒.マイクロ秒]` の形で表示する
+impl fmt::Display for Instant {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "[{:5}.{:06}]",
+            self.0 / NANOS_PER_SEC,
+            self.0 % NANOS_PER_SEC / 1000
+        )
+    }
+}
+
+fn cycles_to_nanos(cycles: u64, frequency: u64) -> u64 {
+    (u128::from(cycles) * u128::from(NANOS_PER_SEC) / u128::from(frequency)) as u64
+}
+
+/// 起動 (最初のTSCの較正) からの経過時間
+pub fn uptime() -> Duration {
+    Duration::from_nanos(Instant::now().as_nanos())
+}
+
+/// `f` を1回実行して、かかった時間を返す
+pub fn measure<R>(f: impl FnOnce() -> R) -> (R, Duration) {
+    let start = Instant::now();
+    let result = f();
+    (result, start.elapsed())
+}
+
+#[test_case]
+fn test_cycles_to_nanos() {
+    assert_eq!(cycles_to_nanos(3_000_000_000, 3_000_000_000), NANOS_PER_SEC);
+    assert_eq!(cycles_to_nanos(1, 1_000_000), 1000);
+    // 途中で u64 に収まらなくなる値
+    assert_eq!(cycles_to_nanos(u64::MAX / 2, u64::MAX / 2), NANOS_PER_SEC);
+}
+
+#[test_case]
+fn test_instant_is_monotonic() {
+    assert!(tsc_frequency().is_some());
+    let start = Instant::now();
+    let (_, elapsed) = measure(|| pit::wait_channel2(1193, || {}));
+    // 約1ms待ったはず
+    assert!(elapsed >= Duration::from_micros(500));
+    assert!(Instant::now() >= start + elapsed);
+}
@@ -0,0 +1,419 @@
+//! ヒープに置くファイルシステム
+//!
+//! ディスクがなくても使える書き込み用の場所。中身は再起動すると消える。
+//! 使える量は作るときに決めた上限と、ヒープの空きの両方で制限する
+
+use crate::allocator;
+use crate::rtc;
+use crate::vfs::{DirEntry, FileSystem, FileType, FsError, Metadata};
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::vec::Vec;
+use spin::Mutex;
+use x86_64::instructions::interrupts::without_interrupts;
+
+/// 上限を指定しないときに使う、ヒープに対する割合
+pub const DEFAULT_LIMIT: usize = allocator::HEAP_SIZE / 2;
+
+/// カーネルのほかの部分のために残しておくヒープの空き
+const HEAP_RESERVE: usize = 256 * 1024;
+
+/// ノード1つを置くのにかかるおおよそのバイト数
+const NODE_OVERHEAD: usize = 64;
+
+const ROOT: u64 = 1;
+
+enum NodeKind {
+    File(Vec<u8>),
+    /// 名前からinode番号
+    Directory(BTreeMap<String, u64>),
+    Symlink(String),
+}
+
+struct Node {
+    kind: NodeKind,
+    mode: u16,
+    links: u32,
+    modified: u64,
+}
+
+impl Node {
+    fn file_type(&self) -> FileType {
+        match self.kind {
+            NodeKind::File(_) => FileType::Regular,
+            NodeKind::Directory(_) => FileType::Directory,
+            NodeKind::Symlink(_) => FileType::Symlink,
+        }
+    }
+
+    /// ファイルを `length` バイト以上に伸ばして中身を返す。ヒープが足りなければ `NoSpace`
+    fn grow_file(&mut self, length: usize) -> Result<&mut Vec<u8>, FsError> {
+        let NodeKind::File(contents) = &mut self.kind else {
+            return Err(FsError::IsADirectory);
+        };
+        if length > contents.len() {
+            contents
+                .try_reserve(length - contents.len())
+                .map_err(|_| FsError::NoSpace)?;
+            contents.resize(length, 0);
+        }
+        Ok(contents)
+    }
+
+    /// 上限に数えるバイト数
+    fn usage(&self) -> usize {
+        NODE_OVERHEAD
+            + match &self.kind {
+                NodeKind::File(data) => data.len(),
+                NodeKind::Directory(_) => 0,
+                NodeKind::Symlink(target) => target.len(),
+            }
+    }
+}
+
+struct Inner {
+    nodes: BTreeMap<u64, Node>,
+    next_inode: u64,
+    /// 使っているバイト数
+    used: usize,
+    limit: usize,
+}
+
+impl Inner {
+    fn node(&self, inode: u64) -> Result<&Node, FsError> {
+        self.nodes.get(&inode).ok_or(FsError::NotFound)
+    }
+
+    fn node_mut(&mut self, inode: u64) -> Result<&mut Node, FsError> {
+        self.nodes.get_mut(&inode).ok_or(FsError::NotFound)
+    }
+
+    fn entries(&self, dir: u64) -> Result<&BTreeMap<String, u64>, FsError> {
+        match &self.node(dir)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    fn entries_mut(&mut self, dir: u64) -> Result<&mut BTreeMap<String, u64>, FsError> {
+        match &mut self.node_mut(dir)?.kind {
+            NodeKind::Directory(entries) => Ok(entries),
+            _ => Err(FsError::NotADirectory),
+        }
+    }
+
+    /// `additional` バイト増やしてよいか確かめて数える
+    fn reserve(&mut self, additional: usize) -> Result<(), FsError> {
+        let free = without_interrupts(allocator::heap_stats).free;
+        if self.used + additional > self.limit || free < additional + HEAP_RESERVE {
+            return Err(FsError::NoSpace);
+        }
+        self.used += additional;
+        Ok(())
+    }
+
+    fn release(&mut self, amount: usize) {
+        self.used = self.used.saturating_sub(amount);
+    }
+
+    /// `dir` に新しいノードを `name` で入れる
+    fn insert(&mut self, dir: u64, name: &str, kind: NodeKind) -> Result<u64, FsError> {
+        if self.entries(dir)?.contains_key(name) {
+            return Err(FsError::AlreadyExists);
+        }
+        let is_directory = matches!(kind, NodeKind::Directory(_));
+        let node = Node {
+            kind,
+            mode: if is_directory { 0o755 } else { 0o644 },
+            links: if is_directory { 2 } else { 1 },
+            modified: now(),
+        };
+        self.reserve(node.usage() + name.len())?;
+        let inode = self.next_inode;
+        self.next_inode += 1;
+        self.nodes.insert(inode, node);
+        self.entries_mut(dir)?.insert(name.to_string(), inode);
+        let parent = self.node_mut(dir)?;
+        parent.modified = now();
+        if is_directory {
+            parent.links += 1;
+        }
+        Ok(inode)
+    }
+
+    /// `dir` から `name` を外し、リンクがなくなればノードも消す
+    fn remove(&mut self, dir: u64, name: &str) -> Result<(), FsError> {
+        let inode = self
+            .entries_mut(dir)?
+            .remove(name)
+            .ok_or(FsError::NotFound)?;
+        self.release(name.len());
+        let node = self.node_mut(inode)?;
+        let is_directory = node.file_type() == FileType::Directory;
+        node.links = node.links.saturating_sub(if is_directory { 2 } else { 1 });
+        if node.links == 0 {
+            // 開いたままのファイルも読めなくなる
+            let node = self
+                .nodes
+                .remove(&inode)
+                .expect("the node was just looked up");
+            self.release(node.usage());
+        }
+        let parent = self.node_mut(dir)?;
+        parent.modified = now();
+        if is_directory {
+            parent.links -= 1;
+        }
+        Ok(())
+    }
+}
+
+fn now() -> u64 {
+    rtc::now().unix_timestamp()
+}
+
+pub struct TmpFs {
+    inner: Mutex<Inner>,
+}
+
+impl TmpFs {
+    /// 中身の合計が `limit` バイトまでのtmpfsを作る
+    pub fn new(limit: usize) -> TmpFs {
+        let mut nodes = BTreeMap::new();
+        nodes.insert(
+            ROOT,
+            Node {
+                kind: NodeKind::Directory(BTreeMap::new()),
+                mode: 0o755,
+                links: 2,
+                modified: now(),
+            },
+        );
+        TmpFs {
+            inner: Mutex::new(Inner {
+                nodes,
+                next_inode: ROOT + 1,
+                used: NODE_OVERHEAD,
+                limit,
+            }),
+        }
+    }
+
+    /// 使っているバイト数と上限
+    pub fn usage(&self) -> (usize, usize) {
+        let inner = self.inner.lock();
+        (inner.used, inner.limit)
+    }
+}
+
+impl Default for TmpFs {
+    fn default() -> TmpFs {
+        TmpFs::new(DEFAULT_LIMIT)
+    }
+}
+
+impl FileSystem for TmpFs {
+    fn name(&self) -> &'static str {
+        "tmpfs"
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        let inner = self.inner.lock();
+        inner
+            .entries(dir)?
+            .get(name)
+            .copied()
+            .ok_or(FsError::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError> {
+        let inner = self.inner.lock();
+        let node = inner.node(inode)?;
+        let size = match &node.kind {
+            NodeKind::File(data) => data.len(),
+            NodeKind::Directory(entries) => entries.len(),
+            NodeKind::Symlink(target) => target.len(),
+        };
+        Ok(Metadata {
+            inode,
+            file_type: node.file_type(),
+            size: size as u64,
+            mode: node.mode,
+            links: node.links,
+            modified: node.modified,
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let inner = self.inner.lock();
+        let NodeKind::File(data) = &inner.node(inode)?.kind else {
+            return Err(FsError::IsADirectory);
+        };
+        let start = usize::try_from(offset)
+            .unwrap_or(usize::MAX)
+            .min(data.len());
+        let count = buffer.len().min(data.len() - start);
+        buffer[..count].copy_from_slice(&data[start..start + count]);
+        Ok(count)
+    }
+
+    fn write(&self, inode: u64, offset: u64, data: &[u8]) -> Result<usize, FsError> {
+        let mut inner = self.inner.lock();
+        let offset = usize::try_from(offset).map_err(|_| FsError::NoSpace)?;
+        let end = offset.checked_add(data.len()).ok_or(FsError::NoSpace)?;
+        let NodeKind::File(contents) = &inner.node(inode)?.kind else {
+            return Err(FsError::IsADirectory);
+        };
+        let growth = end.saturating_sub(contents.len());
+        inner.reserve(growth)?;
+        let node = inner.node_mut(inode)?;
+        match node.grow_file(end) {
+            Ok(contents) => {
+                contents[offset..end].copy_from_slice(data);
+                node.modified = now();
+                Ok(data.len())
+            }
+            Err(err) => {
+                inner.release(growth);
+                Err(err)
+            }
+        }
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        let inner = self.inner.lock();
+        inner
+            .entries(dir)?
+            .iter()
+            .map(|(name, &inode)| {
+                Ok(DirEntry {
+                    name: name.clone(),
+                    inode,
+                    file_type: inner.node(inode)?.file_type(),
+                })
+            })
+            .collect()
+    }
+
+    fn readlink(&self, inode: u64) -> Result<String, FsError> {
+        match &self.inner.lock().node(inode)?.kind {
+            NodeKind::Symlink(target) => Ok(target.clone()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+
+    fn create(&self, dir: u64, name: &str, file_type: FileType) -> Result<u64, FsError> {
+        let kind = match file_type {
+            FileType::Regular => NodeKind::File(Vec::new()),
+            FileType::Directory => NodeKind::Directory(BTreeMap::new()),
+            _ => return Err(FsError::NotSupported),
+        };
+        self.inner.lock().insert(dir, name, kind)
+    }
+
+    fn symlink(&self, dir: u64, name: &str, target: &str) -> Result<u64, FsError> {
+        self.inner
+            .lock()
+            .insert(dir, name, NodeKind::Symlink(target.to_string()))
+    }
+
+    fn unlink(&self, dir: u64, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let inode = *inner.entries(dir)?.get(name).ok_or(FsError::NotFound)?;
+        if inner.node(inode)?.file_type() == FileType::Directory {
+            return Err(FsError::IsADirectory);
+        }
+        inner.remove(dir, name)
+    }
+
+    fn rmdir(&self, dir: u64, name: &str) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let inode = *inner.entries(dir)?.get(name).ok_or(FsError::NotFound)?;
+        if !inner.entries(inode)?.is_empty() {
+            return Err(FsError::NotEmpty);
+        }
+        inner.remove(dir, name)
+    }
+
+    fn rename(
+        &self,
+        old_dir: u64,
+        old_name: &str,
+        new_dir: u64,
+        new_name: &str,
+    ) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let inode = *inner
+            .entries(old_dir)?
+            .get(old_name)
+            .ok_or(FsError::NotFound)?;
+        let is_directory = inner.node(inode)?.file_type() == FileType::Directory;
+        let existing = inner.entries(new_dir)?.get(new_name).copied();
+        if let Some(existing) = existing {
+            if existing == inode {
+                return Ok(());
+            }
+            match (is_directory, inner.node(existing)?.file_type()) {
+                (true, FileType::Directory) => {
+                    if !inner.entries(existing)?.is_empty() {
+                        return Err(FsError::NotEmpty);
+                    }
+                }
+                (true, _) => return Err(FsError::NotADirectory),
+                (false, FileType::Directory) => return Err(FsError::IsADirectory),
+                (false, _) => {}
+            }
+        }
+
+        // 置き換える先は、新しい名前の分が確保できてから消す
+        inner.reserve(new_name.len())?;
+        if existing.is_some()
+            && let Err(err) = inner.remove(new_dir, new_name)
+        {
+            inner.release(new_name.len());
+            return Err(err);
+        }
+        inner.entries_mut(old_dir)?.remove(old_name);
+        inner.release(old_name.len());
+        inner
+            .entries_mut(new_dir)?
+            .insert(new_name.to_string(), inode);
+        let modified = now();
+        if is_directory {
+            inner.node_mut(old_dir)?.links -= 1;
+            inner.node_mut(new_dir)?.links += 1;
+        }
+        inner.node_mut(old_dir)?.modified = modified;
+        inner.node_mut(new_dir)?.modified = modified;
+        Ok(())
+    }
+
+    fn truncate(&self, inode: u64, size: u64) -> Result<(), FsError> {
+        let mut inner = self.inner.lock();
+        let size = usize::try_from(size).map_err(|_| FsError::NoSpace)?;
+        let NodeKind::File(contents) = &inner.node(inode)?.kind else {
+            return Err(FsError::IsADirectory);
+        };
+        let length = contents.len();
+        let growth = size.saturating_sub(length);
+        inner.reserve(growth)?;
+        inner.release(length.saturating_sub(size));
+        let node = inner.node_mut(inode)?;
+        match node.grow_file(size) {
+            Ok(contents) => {
+                contents.truncate(size);
+                contents.shrink_to_fit();
+                node.modified = now();
+                Ok(())
+            }
+            Err(err) => {
+                inner.release(growth);
+                Err(err)
+            }
+        }
+    }
+}
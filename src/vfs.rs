@@ -0,0 +1,830 @@
+//! 仮想ファイルシステム
+//!
+//! 具体的なファイルシステムは `FileSystem` を実装してパスに `mount` する。
+//! カーネルのコードはパスとファイル記述子で `open` / `read` / `write` / `seek` /
+//! `close` / `readdir` / `stat` などを呼び、どのファイルシステムかを意識しない
+
+use crate::block::BlockError;
+use alloc::collections::BTreeMap;
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use core::fmt;
+use core::ops::BitOr;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// パスの解決で追うシンボリックリンクの数の上限
+const MAX_SYMLINKS: usize = 8;
+
+/// ディレクトリエントリのキャッシュの大きさ。超えたら捨てる
+const DENTRY_CACHE_SIZE: usize = 1024;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FsError {
+    NotFound,
+    NotADirectory,
+    IsADirectory,
+    AlreadyExists,
+    /// 空ではないディレクトリを消そうとした
+    NotEmpty,
+    /// 空の名前、`.` や `..` を作ろうとした、絶対パスではないなど
+    InvalidPath,
+    TooManySymlinks,
+    ReadOnly,
+    NoSpace,
+    BadFileDescriptor,
+    InvalidArgument,
+    /// 別のファイルシステムをまたいだ名前の変更
+    CrossDevice,
+    /// マウントポイントや使用中のファイルシステム
+    Busy,
+    /// ファイルシステムがその操作を持たない
+    NotSupported,
+    /// 待たない設定のデバイスで、今は読めるものがない
+    WouldBlock,
+    Io(BlockError),
+    /// ディスク上の構造が壊れている
+    Corrupted(&'static str),
+}
+
+impl fmt::Display for FsError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            FsError::NotFound => write!(f, "vfs: no such file or directory"),
+            FsError::NotADirectory => write!(f, "vfs: not a directory"),
+            FsError::IsADirectory => write!(f, "vfs: is a directory"),
+            FsError::AlreadyExists => write!(f, "vfs: file exists"),
+            FsError::NotEmpty => write!(f, "vfs: directory not empty"),
+            FsError::InvalidPath => write!(f, "vfs: invalid path"),
+            FsError::TooManySymlinks => write!(f, "vfs: too many levels of symbolic links"),
+            FsError::ReadOnly => write!(f, "vfs: read-only file system"),
+            FsError::NoSpace => write!(f, "vfs: no space left on device"),
+            FsError::BadFileDescriptor => write!(f, "vfs: bad file descriptor"),
+            FsError::InvalidArgument => write!(f, "vfs: invalid argument"),
+            FsError::CrossDevice => write!(f, "vfs: cross-device link"),
+            FsError::Busy => write!(f, "vfs: device or resource busy"),
+            FsError::NotSupported => write!(f, "vfs: operation not supported"),
+            FsError::WouldBlock => write!(f, "vfs: resource temporarily unavailable"),
+            FsError::Io(err) => write!(f, "{}", err),
+            FsError::Corrupted(reason) => write!(f, "vfs: corrupted file system ({})", reason),
+        }
+    }
+}
+
+impl From<BlockError> for FsError {
+    fn from(err: BlockError) -> FsError {
+        FsError::Io(err)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FileType {
+    Regular,
+    Directory,
+    Symlink,
+    CharDevice,
+    BlockDevice,
+}
+
+/// `stat` で返すファイルの情報
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Metadata {
+    pub inode: u64,
+    pub file_type: FileType,
+    pub size: u64,
+    /// パーミッションのビット (0o755 など)。今は検査しない
+    pub mode: u16,
+    pub links: u32,
+    /// 最終更新時刻 (UNIX時間の秒)
+    pub modified: u64,
+}
+
+/// `readdir` で返すディレクトリの中身1つ
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DirEntry {
+    pub name: String,
+    pub inode: u64,
+    pub file_type: FileType,
+}
+
+/// 具体的なファイルシステム
+///
+/// ファイルやディレクトリはinode番号で指す。名前からinodeを引くのはVFSが `lookup` で行い、
+/// パスの解釈やシンボリックリンク、マウントポイントの扱いはファイルシステムの側では要らない。
+/// 複数の場所から同時に呼ばれるので、中身は自分でロックすること。
+/// 書き込みの操作は既定では `ReadOnly` を返す
+pub trait FileSystem: Send + Sync {
+    /// `mount` の一覧に表示する種類の名前
+    fn name(&self) -> &'static str;
+
+    /// 書き込めないファイルシステムか。`true` なら書き込み用の `open` をVFSが断る
+    fn read_only(&self) -> bool {
+        false
+    }
+
+    fn root(&self) -> u64;
+
+    /// ディレクトリ `dir` の中の `name` のinode番号。`.` と `..` は渡されない
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError>;
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError>;
+
+    /// `offset` から読み、読んだバイト数を返す。ファイルの終わりでは0
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError>;
+
+    /// `offset` に書き、書いたバイト数を返す。ファイルの終わりを越えれば伸ばす
+    fn write(&self, _inode: u64, _offset: u64, _data: &[u8]) -> Result<usize, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// ディレクトリの中身。`.` と `..` は含めない
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError>;
+
+    fn readlink(&self, _inode: u64) -> Result<String, FsError> {
+        Err(FsError::InvalidArgument)
+    }
+
+    /// `dir` に空のファイルかディレクトリを作る
+    fn create(&self, _dir: u64, _name: &str, _file_type: FileType) -> Result<u64, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn symlink(&self, _dir: u64, _name: &str, _target: &str) -> Result<u64, FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// ディレクトリ以外を消す
+    fn unlink(&self, _dir: u64, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// 空のディレクトリを消す
+    fn rmdir(&self, _dir: u64, _name: &str) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// 名前を変える。`new_name` があれば置き換える
+    ///
+    /// ディレクトリを自分の下に動かそうとしていないことはVFSが確かめてから呼ぶ
+    fn rename(
+        &self,
+        _old_dir: u64,
+        _old_name: &str,
+        _new_dir: u64,
+        _new_name: &str,
+    ) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    fn truncate(&self, _inode: u64, _size: u64) -> Result<(), FsError> {
+        Err(FsError::ReadOnly)
+    }
+
+    /// 書き込みをデバイスに書き出す
+    fn sync(&self) -> Result<(), FsError> {
+        Ok(())
+    }
+
+    /// デバイス固有の操作。`request` と `arg` の意味はファイルによる
+    fn ioctl(&self, _inode: u64, _request: u32, _arg: u64) -> Result<u64, FsError> {
+        Err(FsError::NotSupported)
+    }
+}
+
+/// マウントされたファイルシステム
+pub struct Mount {
+    id: usize,
+    path: String,
+    fs: Arc<dyn FileSystem>,
+    /// 覆っているディレクトリ (マウントのIDとinode番号)。ルートは `None`
+    covered: Option<(usize, u64)>,
+}
+
+/// あるマウントの中のファイルやディレクトリ
+#[derive(Clone)]
+pub struct Inode {
+    mount: Arc<Mount>,
+    number: u64,
+}
+
+impl Inode {
+    pub fn number(&self) -> u64 {
+        self.number
+    }
+
+    pub fn fs(&self) -> &dyn FileSystem {
+        &*self.mount.fs
+    }
+
+    pub fn metadata(&self) -> Result<Metadata, FsError> {
+        self.fs().metadata(self.number)
+    }
+
+    fn is_directory(&self) -> Result<bool, FsError> {
+        Ok(self.metadata()?.file_type == FileType::Directory)
+    }
+}
+
+static MOUNTS: Mutex<Vec<Arc<Mount>>> = Mutex::new(Vec::new());
+static NEXT_MOUNT_ID: AtomicUsize = AtomicUsize::new(1);
+
+/// (マウントのID, ディレクトリのinode番号, 名前) から中身のinode番号を引くキャッシュ
+static DENTRIES: Mutex<BTreeMap<(usize, u64, String), u64>> = Mutex::new(BTreeMap::new());
+
+fn root() -> Result<Inode, FsError> {
+    let mounts = MOUNTS.lock();
+    let mount = mounts
+        .iter()
+        .find(|mount| mount.covered.is_none())
+        .ok_or(FsError::NotFound)?;
+    Ok(Inode {
+        mount: mount.clone(),
+        number: mount.fs.root(),
+    })
+}
+
+/// `dir` の中の `name` を引く。マウントポイントならマウントされた側のルートを返す
+fn lookup(dir: &Inode, name: &str) -> Result<Inode, FsError> {
+    let key = (dir.mount.id, dir.number, name.to_string());
+    let cached = DENTRIES.lock().get(&key).copied();
+    let number = match cached {
+        Some(number) => number,
+        None => {
+            let number = dir.fs().lookup(dir.number, name)?;
+            let mut dentries = DENTRIES.lock();
+            if dentries.len() >= DENTRY_CACHE_SIZE {
+                dentries.clear();
+            }
+            dentries.insert(key, number);
+            number
+        }
+    };
+    let covering = MOUNTS
+        .lock()
+        .iter()
+        .find(|mount| mount.covered == Some((dir.mount.id, number)))
+        .cloned();
+    Ok(match covering {
+        Some(mount) => Inode {
+            number: mount.fs.root(),
+            mount,
+        },
+        None => Inode {
+            mount: dir.mount.clone(),
+            number,
+        },
+    })
+}
+
+/// `dir` の中身が変わったので、`dir` のキャッシュをすべて捨てる
+///
+/// 大文字と小文字を区別しないファイルシステムでは同じファイルが別の綴りで覚えられているので、
+/// 変えた名前だけを捨てたのでは古いinodeが残る
+fn forget(dir: &Inode) {
+    DENTRIES
+        .lock()
+        .retain(|(id, parent, _), _| (*id, *parent) != (dir.mount.id, dir.number));
+}
+
+/// パスを空と `.` を除いた名前に分ける
+fn components(path: &str) -> impl Iterator<Item = &str> {
+    path.split('/')
+        .filter(|component| !component.is_empty() && *component != ".")
+}
+
+/// ルートからたどってきたディレクトリの列 `stack` に `path` をたどって積む
+///
+/// `..` は列を1つ戻るので、シンボリックリンクやマウントポイントを越えた後でも
+/// 実際にたどってきた親に戻る
+fn walk(
+    stack: &mut Vec<Inode>,
+    path: &str,
+    follow_last: bool,
+    links: &mut usize,
+) -> Result<(), FsError> {
+    if path.starts_with('/') {
+        stack.truncate(1);
+    }
+    let mut components = components(path).peekable();
+    while let Some(name) = components.next() {
+        let last = components.peek().is_none();
+        if name == ".." {
+            if stack.len() > 1 {
+                stack.pop();
+            }
+            continue;
+        }
+        let dir = stack.last().expect("the stack always holds the root");
+        if !dir.is_directory()? {
+            return Err(FsError::NotADirectory);
+        }
+        let child = lookup(dir, name)?;
+        if (!last || follow_last) && child.metadata()?.file_type == FileType::Symlink {
+            *links += 1;
+            if *links > MAX_SYMLINKS {
+                return Err(FsError::TooManySymlinks);
+            }
+            // 相対パスのリンクはリンクがあるディレクトリから解決する
+            let target = child.fs().readlink(child.number)?;
+            walk(stack, &target, true, links)?;
+        } else {
+            stack.push(child);
+        }
+    }
+    Ok(())
+}
+
+/// 絶対パスを解決する。`follow_last` なら最後の名前がシンボリックリンクでもたどる
+fn resolve(path: &str, follow_last: bool) -> Result<Inode, FsError> {
+    let mut stack = resolve_stack(path, follow_last)?;
+    Ok(stack.pop().expect("the stack always holds the root"))
+}
+
+/// 絶対パスを解決し、ルートからたどったディレクトリと最後のinodeの列を返す
+fn resolve_stack(path: &str, follow_last: bool) -> Result<Vec<Inode>, FsError> {
+    if !path.starts_with('/') {
+        return Err(FsError::InvalidPath);
+    }
+    let mut stack = alloc::vec![root()?];
+    walk(&mut stack, path, follow_last, &mut 0)?;
+    Ok(stack)
+}
+
+/// パスを親ディレクトリと最後の名前に分け、親を解決する
+fn resolve_parent(path: &str) -> Result<(Inode, &str), FsError> {
+    let (mut stack, name) = resolve_parent_stack(path)?;
+    Ok((stack.pop().expect("the stack always holds the root"), name))
+}
+
+/// `resolve_parent` と同じだが、ルートから親までのディレクトリの列を返す
+fn resolve_parent_stack(path: &str) -> Result<(Vec<Inode>, &str), FsError> {
+    let trimmed = path.trim_end_matches('/');
+    let (parent, name) = trimmed.rsplit_once('/').ok_or(FsError::InvalidPath)?;
+    if name.is_empty() || name == "." || name == ".." {
+        return Err(FsError::InvalidPath);
+    }
+    let stack = resolve_stack(if parent.is_empty() { "/" } else { parent }, true)?;
+    let parent = stack.last().expect("the stack always holds the root");
+    if !parent.is_directory()? {
+        return Err(FsError::NotADirectory);
+    }
+    Ok((stack, name))
+}
+
+/// `.` と `..` を取り除いた形に直す。シンボリックリンクは見ない
+pub fn normalize(path: &str) -> String {
+    let mut names: Vec<&str> = Vec::new();
+    for name in components(path) {
+        if name == ".." {
+            names.pop();
+        } else {
+            names.push(name);
+        }
+    }
+    let mut normalized = String::new();
+    for name in &names {
+        normalized.push('/');
+        normalized.push_str(name);
+    }
+    if normalized.is_empty() {
+        normalized.push('/');
+    }
+    normalized
+}
+
+/// `fs` を `path` にマウントする。最初のマウントは `/` でなければならない
+pub fn mount(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    let path = normalize(path);
+    let covered = if path == "/" {
+        if root().is_ok() {
+            return Err(FsError::Busy);
+        }
+        None
+    } else {
+        let dir = resolve(&path, true)?;
+        if !dir.is_directory()? {
+            return Err(FsError::NotADirectory);
+        }
+        // すでに何かがマウントされていれば、そのルートが返ってくる
+        if dir.number == dir.mount.fs.root() && dir.mount.path == path {
+            return Err(FsError::Busy);
+        }
+        Some((dir.mount.id, dir.number))
+    };
+    let id = NEXT_MOUNT_ID.fetch_add(1, Ordering::Relaxed);
+    MOUNTS.lock().push(Arc::new(Mount {
+        id,
+        path,
+        fs,
+        covered,
+    }));
+    Ok(())
+}
+
+/// `path` のディレクトリがなければ作ってから `fs` をマウントする
+pub fn mount_at(path: &str, fs: Arc<dyn FileSystem>) -> Result<(), FsError> {
+    match mkdir(path) {
+        Ok(()) | Err(FsError::AlreadyExists) => {}
+        Err(err) => return Err(err),
+    }
+    mount(path, fs)
+}
+
+/// `path` にマウントされたファイルシステムを書き出して外す
+///
+/// 開いているファイルや、下にマウントされたものがあれば `Busy`
+pub fn unmount(path: &str) -> Result<(), FsError> {
+    let path = normalize(path);
+    let mount = MOUNTS
+        .lock()
+        .iter()
+        .find(|mount| mount.path == path)
+        .cloned()
+        .ok_or(FsError::InvalidArgument)?;
+    let busy = MOUNTS
+        .lock()
+        .iter()
+        .any(|other| other.covered.is_some_and(|(id, _)| id == mount.id))
+        || FILES
+            .lock()
+            .iter()
+            .flatten()
+            .any(|file| file.inode.mount.id == mount.id);
+    if busy {
+        return Err(FsError::Busy);
+    }
+    mount.fs.sync()?;
+    MOUNTS.lock().retain(|other| other.id != mount.id);
+    DENTRIES.lock().retain(|(id, _, _), _| *id != mount.id);
+    Ok(())
+}
+
+/// マウントの一覧 (パス, ファイルシステムの種類)
+pub fn mounts() -> Vec<(String, &'static str)> {
+    MOUNTS
+        .lock()
+        .iter()
+        .map(|mount| (mount.path.clone(), mount.fs.name()))
+        .collect()
+}
+
+/// すべてのファイルシステムを書き出す
+pub fn sync() -> Result<(), FsError> {
+    let mounts: Vec<_> = MOUNTS.lock().clone();
+    let mut result = Ok(());
+    for mount in mounts {
+        result = result.and(mount.fs.sync());
+    }
+    result
+}
+
+/// `open` の指定
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct OpenFlags(u32);
+
+impl OpenFlags {
+    pub const READ: OpenFlags = OpenFlags(1 << 0);
+    pub const WRITE: OpenFlags = OpenFlags(1 << 1);
+    /// なければ作る
+    pub const CREATE: OpenFlags = OpenFlags(1 << 2);
+    /// `CREATE` と一緒に使い、すでにあれば失敗する
+    pub const EXCLUSIVE: OpenFlags = OpenFlags(1 << 3);
+    /// 長さを0にする
+    pub const TRUNCATE: OpenFlags = OpenFlags(1 << 4);
+    /// 書き込みは常にファイルの終わりに足す
+    pub const APPEND: OpenFlags = OpenFlags(1 << 5);
+    /// ディレクトリでなければ失敗する
+    pub const DIRECTORY: OpenFlags = OpenFlags(1 << 6);
+    /// 最後の名前がシンボリックリンクでもたどらない
+    pub const NO_FOLLOW: OpenFlags = OpenFlags(1 << 7);
+
+    pub const READ_WRITE: OpenFlags = OpenFlags(Self::READ.0 | Self::WRITE.0);
+
+    pub fn contains(self, other: OpenFlags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl BitOr for OpenFlags {
+    type Output = OpenFlags;
+
+    fn bitor(self, other: OpenFlags) -> OpenFlags {
+        OpenFlags(self.0 | other.0)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum SeekFrom {
+    Start(u64),
+    Current(i64),
+    End(i64),
+}
+
+/// 開いているファイル。オフセットは同じファイル記述子を使う全員で共有する
+pub struct OpenFile {
+    inode: Inode,
+    flags: OpenFlags,
+    offset: Mutex<u64>,
+}
+
+/// ファイル記述子
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub struct Fd(pub usize);
+
+impl fmt::Display for Fd {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+static FILES: Mutex<Vec<Option<Arc<OpenFile>>>> = Mutex::new(Vec::new());
+
+fn file(fd: Fd) -> Result<Arc<OpenFile>, FsError> {
+    FILES
+        .lock()
+        .get(fd.0)
+        .cloned()
+        .flatten()
+        .ok_or(FsError::BadFileDescriptor)
+}
+
+/// `path` を開いてファイル記述子を返す
+pub fn open(path: &str, flags: OpenFlags) -> Result<Fd, FsError> {
+    let follow = !flags.contains(OpenFlags::NO_FOLLOW);
+    let inode = match resolve(path, follow) {
+        Ok(_) if flags.contains(OpenFlags::CREATE | OpenFlags::EXCLUSIVE) => {
+            return Err(FsError::AlreadyExists);
+        }
+        Ok(inode) => inode,
+        Err(FsError::NotFound) if flags.contains(OpenFlags::CREATE) => {
+            let (parent, name) = resolve_parent(path)?;
+            let number = parent.fs().create(parent.number, name, FileType::Regular)?;
+            forget(&parent);
+            Inode {
+                mount: parent.mount.clone(),
+                number,
+            }
+        }
+        Err(err) => return Err(err),
+    };
+    let metadata = inode.metadata()?;
+    let writable = flags.contains(OpenFlags::WRITE) || flags.contains(OpenFlags::APPEND);
+    match metadata.file_type {
+        FileType::Directory if writable => return Err(FsError::IsADirectory),
+        FileType::Directory => {}
+        _ if flags.contains(OpenFlags::DIRECTORY) => return Err(FsError::NotADirectory),
+        _ => {}
+    }
+    // 最初の `write` まで待たず、開くときに断る
+    if (writable || flags.contains(OpenFlags::TRUNCATE)) && inode.fs().read_only() {
+        return Err(FsError::ReadOnly);
+    }
+    if flags.contains(OpenFlags::TRUNCATE) && writable && metadata.size != 0 {
+        inode.fs().truncate(inode.number, 0)?;
+    }
+
+    let file = Arc::new(OpenFile {
+        inode,
+        flags,
+        offset: Mutex::new(0),
+    });
+    let mut files = FILES.lock();
+    let fd = match files.iter().position(Option::is_none) {
+        Some(index) => {
+            files[index] = Some(file);
+            index
+        }
+        None => {
+            files.push(Some(file));
+            files.len() - 1
+        }
+    };
+    Ok(Fd(fd))
+}
+
+pub fn close(fd: Fd) -> Result<(), FsError> {
+    let mut files = FILES.lock();
+    match files.get_mut(fd.0) {
+        Some(slot @ Some(_)) => {
+            *slot = None;
+            Ok(())
+        }
+        _ => Err(FsError::BadFileDescriptor),
+    }
+}
+
+/// 今のオフセットから読み、オフセットを進める
+pub fn read(fd: Fd, buffer: &mut [u8]) -> Result<usize, FsError> {
+    let file = file(fd)?;
+    if !file.flags.contains(OpenFlags::READ) {
+        return Err(FsError::BadFileDescriptor);
+    }
+    if file.inode.is_directory()? {
+        return Err(FsError::IsADirectory);
+    }
+    let mut offset = file.offset.lock();
+    let read = file.inode.fs().read(file.inode.number, *offset, buffer)?;
+    *offset += read as u64;
+    Ok(read)
+}
+
+/// 今のオフセット (`APPEND` ならファイルの終わり) に書き、オフセットを進める
+pub fn write(fd: Fd, data: &[u8]) -> Result<usize, FsError> {
+    let file = file(fd)?;
+    if !file.flags.contains(OpenFlags::WRITE) && !file.flags.contains(OpenFlags::APPEND) {
+        return Err(FsError::BadFileDescriptor);
+    }
+    let mut offset = file.offset.lock();
+    if file.flags.contains(OpenFlags::APPEND) {
+        *offset = file.inode.metadata()?.size;
+    }
+    let written = file.inode.fs().write(file.inode.number, *offset, data)?;
+    *offset += written as u64;
+    Ok(written)
+}
+
+/// オフセットを動かし、新しいオフセットを返す
+pub fn seek(fd: Fd, position: SeekFrom) -> Result<u64, FsError> {
+    let file = file(fd)?;
+    let mut offset = file.offset.lock();
+    let (base, delta) = match position {
+        SeekFrom::Start(position) => (position, 0),
+        SeekFrom::Current(delta) => (*offset, delta),
+        SeekFrom::End(delta) => (file.inode.metadata()?.size, delta),
+    };
+    *offset = base
+        .checked_add_signed(delta)
+        .ok_or(FsError::InvalidArgument)?;
+    Ok(*offset)
+}
+
+/// 開いているデバイスに固有の操作をする
+pub fn ioctl(fd: Fd, request: u32, arg: u64) -> Result<u64, FsError> {
+    let file = file(fd)?;
+    file.inode.fs().ioctl(file.inode.number, request, arg)
+}
+
+pub fn fstat(fd: Fd) -> Result<Metadata, FsError> {
+    file(fd)?.inode.metadata()
+}
+
+/// シンボリックリンクをたどった先の情報
+pub fn stat(path: &str) -> Result<Metadata, FsError> {
+    resolve(path, true)?.metadata()
+}
+
+/// シンボリックリンクならリンク自身の情報
+pub fn lstat(path: &str) -> Result<Metadata, FsError> {
+    resolve(path, false)?.metadata()
+}
+
+/// ディレクトリの中身を名前の順に返す
+pub fn readdir(path: &str) -> Result<Vec<DirEntry>, FsError> {
+    let dir = resolve(path, true)?;
+    if !dir.is_directory()? {
+        return Err(FsError::NotADirectory);
+    }
+    let mut entries = dir.fs().readdir(dir.number)?;
+    entries.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(entries)
+}
+
+/// `dir` に `name` がないことを確かめる。引けなかった理由が `NotFound` 以外ならそれを返す
+fn ensure_absent(dir: &Inode, name: &str) -> Result<(), FsError> {
+    match lookup(dir, name) {
+        Ok(_) => Err(FsError::AlreadyExists),
+        Err(FsError::NotFound) => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+pub fn mkdir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    ensure_absent(&parent, name)?;
+    parent
+        .fs()
+        .create(parent.number, name, FileType::Directory)?;
+    forget(&parent);
+    Ok(())
+}
+
+pub fn rmdir(path: &str) -> Result<(), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    let dir = lookup(&parent, name)?;
+    if dir.mount.id != parent.mount.id {
+        return Err(FsError::Busy);
+    }
+    parent.fs().rmdir(parent.number, name)?;
+    forget(&parent);
+    Ok(())
+}
+
+pub fn unlink(path: &str) -> Result<(), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    if lookup(&parent, name)?.is_directory()? {
+        return Err(FsError::IsADirectory);
+    }
+    parent.fs().unlink(parent.number, name)?;
+    forget(&parent);
+    Ok(())
+}
+
+/// 名前を変える。同じファイルシステムの中でしか動かせない
+pub fn rename(old_path: &str, new_path: &str) -> Result<(), FsError> {
+    let (old_parent, old_name) = resolve_parent(old_path)?;
+    let (new_ancestors, new_name) = resolve_parent_stack(new_path)?;
+    let new_parent = new_ancestors
+        .last()
+        .expect("the stack always holds the root");
+    if old_parent.mount.id != new_parent.mount.id {
+        return Err(FsError::CrossDevice);
+    }
+    let source = lookup(&old_parent, old_name)?;
+    if source.mount.id != old_parent.mount.id {
+        return Err(FsError::Busy);
+    }
+    // ディレクトリを自分の下に動かすと木から切り離されてしまうので、どのファイルシステムでも断る
+    let source_key = (source.mount.id, source.number);
+    if new_ancestors
+        .iter()
+        .any(|dir| (dir.mount.id, dir.number) == source_key)
+    {
+        return Err(FsError::InvalidArgument);
+    }
+    old_parent
+        .fs()
+        .rename(old_parent.number, old_name, new_parent.number, new_name)?;
+    forget(&old_parent);
+    forget(new_parent);
+    Ok(())
+}
+
+/// `target` を指すシンボリックリンクを `path` に作る
+pub fn symlink(target: &str, path: &str) -> Result<(), FsError> {
+    let (parent, name) = resolve_parent(path)?;
+    ensure_absent(&parent, name)?;
+    parent.fs().symlink(parent.number, name, target)?;
+    forget(&parent);
+    Ok(())
+}
+
+pub fn readlink(path: &str) -> Result<String, FsError> {
+    let link = resolve(path, false)?;
+    link.fs().readlink(link.number)
+}
+
+pub fn truncate(path: &str, size: u64) -> Result<(), FsError> {
+    let inode = resolve(path, true)?;
+    if inode.is_directory()? {
+        return Err(FsError::IsADirectory);
+    }
+    inode.fs().truncate(inode.number, size)
+}
+
+/// ファイルの中身をすべて読む
+pub fn read_file(path: &str) -> Result<Vec<u8>, FsError> {
+    let fd = open(path, OpenFlags::READ)?;
+    let mut contents = Vec::new();
+    let mut buffer = [0u8; 512];
+    let result = loop {
+        match read(fd, &mut buffer) {
+            Ok(0) => break Ok(contents),
+            Ok(read) => contents.extend_from_slice(&buffer[..read]),
+            Err(err) => break Err(err),
+        }
+    };
+    close(fd)?;
+    result
+}
+
+/// ファイルを作るか空にして `data` を書く
+pub fn write_file(path: &str, data: &[u8]) -> Result<(), FsError> {
+    let fd = open(
+        path,
+        OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE,
+    )?;
+    let mut written = 0;
+    let result = loop {
+        if written == data.len() {
+            break Ok(());
+        }
+        match write(fd, &data[written..]) {
+            Ok(0) => break Err(FsError::NoSpace),
+            Ok(count) => written += count,
+            Err(err) => break Err(err),
+        }
+    };
+    close(fd)?;
+    result
+}
+
+#[test_case]
+fn test_components() {
+    let mut names = components("//usr/./lib/../bin/");
+    assert_eq!(names.next(), Some("usr"));
+    assert_eq!(names.next(), Some("lib"));
+    assert_eq!(names.next(), Some(".."));
+    assert_eq!(names.next(), Some("bin"));
+    assert_eq!(names.next(), None);
+}
@@ -38,8 +38,13 @@ struct ScreenChar {
     color_code: ColorCode,
 }
 
-const BUFFER_HEIGHT: usize = 25;
-const BUFFER_WIDTH: usize = 80;
+const BLANK: ScreenChar = ScreenChar {
+    ascii_character: b' ',
+    color_code: ColorCode(0x0f),
+};
+
+pub const BUFFER_HEIGHT: usize = 25;
+pub const BUFFER_WIDTH: usize = 80;
 
 use volatile::Volatile;
 
@@ -49,25 +54,168 @@ struct Buffer {
 }
 
 pub struct Writer {
+    row_position: usize,
     column_position: usize,
     color_code: ColorCode,
     buffer: &'static mut Buffer,
+    scrollback: Option<Scrollback>,
+    /// VGAバッファに表示されているか。裏のコンソールは自分の画面バッファに書く
+    active: bool,
+}
+
+const TAB_WIDTH: usize = 8;
+
+/// スクロールバックに保持する最大行数
+pub const SCROLLBACK_LINES: usize = 2500;
+
+use alloc::{boxed::Box, collections::VecDeque};
+
+type Row = [ScreenChar; BUFFER_WIDTH];
+
+/// 画面上端から流れ出た行の履歴
+struct Scrollback {
+    lines: VecDeque<Row>,
+    /// 履歴を表示している間の本来の画面内容
+    live: Option<Box<[Row; BUFFER_HEIGHT]>>,
+    /// 最新の画面から何行さかのぼって表示しているか
+    offset: usize,
 }
 
 impl Writer {
+    fn new(buffer: &'static mut Buffer, active: bool) -> Self {
+        let mut writer = Writer {
+            row_position: 0,
+            column_position: 0,
+            color_code: ColorCode::new(Color::White, Color::Black),
+            buffer,
+            scrollback: None,
+            active,
+        };
+        writer.clear_screen();
+        writer
+    }
+
     pub fn set_color(&mut self, color: ColorCode) {
         self.color_code = color;
     }
 
+    /// 現在のカーソル位置を (row, col) で返す
+    pub fn cursor_position(&self) -> (usize, usize) {
+        (self.row_position, self.column_position)
+    }
+
+    /// カーソルを移動する。画面外の位置は端に丸められる
+    pub fn set_cursor(&mut self, row: usize, col: usize) {
+        self.scroll_to_bottom();
+        self.row_position = row.min(BUFFER_HEIGHT - 1);
+        self.column_position = col.min(BUFFER_WIDTH - 1);
+        self.update_cursor();
+    }
+
     pub fn write_byte(&mut self, byte: u8) {
+        self.scroll_to_bottom();
+        self.put_byte(byte);
+        self.update_cursor();
+    }
+
+    /// 画面の文字を読む
+    pub fn read_char(&self, row: usize, col: usize) -> u8 {
+        self.buffer.chars[row][col].read().ascii_character
+    }
+
+    /// スクロールバックを有効にする。履歴はヒープに置かれるのでヒープの初期化後に呼ぶこと
+    pub fn enable_scrollback(&mut self) {
+        if self.scrollback.is_none() {
+            self.scrollback = Some(Scrollback {
+                lines: VecDeque::new(),
+                live: None,
+                offset: 0,
+            });
+        }
+    }
+
+    /// 表示を最新の画面から何行さかのぼっているか
+    pub fn scroll_offset(&self) -> usize {
+        self.scrollback.as_ref().map_or(0, |sb| sb.offset)
+    }
+
+    /// 表示を履歴の方向に `lines` 行ずらす
+    pub fn scroll_view_up(&mut self, lines: usize) {
+        let offset = self.scroll_offset().saturating_add(lines);
+        self.set_scroll_offset(offset);
+    }
+
+    /// 表示を最新の画面の方向に `lines` 行ずらす
+    pub fn scroll_view_down(&mut self, lines: usize) {
+        let offset = self.scroll_offset().saturating_sub(lines);
+        self.set_scroll_offset(offset);
+    }
+
+    /// 最新の画面の表示に戻る
+    pub fn scroll_to_bottom(&mut self) {
+        if self.scroll_offset() != 0 {
+            self.set_scroll_offset(0);
+        }
+    }
+
+    fn set_scroll_offset(&mut self, offset: usize) {
+        let Some(scrollback) = self.scrollback.as_mut() else {
+            return;
+        };
+        let offset = offset.min(scrollback.lines.len());
+        if offset == scrollback.offset {
+            return;
+        }
+
+        // 履歴を見始めるときに本来の画面を退避しておく
+        let live = scrollback.live.get_or_insert_with(|| {
+            let mut live = Box::new([[BLANK; BUFFER_WIDTH]; BUFFER_HEIGHT]);
+            for (row, line) in live.iter_mut().enumerate() {
+                for (col, c) in line.iter_mut().enumerate() {
+                    *c = self.buffer.chars[row][col].read();
+                }
+            }
+            live
+        });
+
+        let history = scrollback.lines.len();
+        let top = history - offset;
+        for row in 0..BUFFER_HEIGHT {
+            let index = top + row;
+            let line = if index < history {
+                &scrollback.lines[index]
+            } else {
+                &live[index - history]
+            };
+            for (col, &c) in line.iter().enumerate() {
+                self.buffer.chars[row][col].write(c);
+            }
+        }
+
+        scrollback.offset = offset;
+        if offset == 0 {
+            scrollback.live = None;
+        }
+        self.update_cursor();
+    }
+
+    fn put_byte(&mut self, byte: u8) {
         match byte {
             b'\n' => self.new_line(),
+            b'\r' => self.column_position = 0,
+            b'\t' => {
+                let next_stop = (self.column_position / TAB_WIDTH + 1) * TAB_WIDTH;
+                while self.column_position < next_stop.min(BUFFER_WIDTH) {
+                    self.put_byte(b' ');
+                }
+            }
+            0x08 => self.backspace(),
             byte => {
                 if self.column_position >= BUFFER_WIDTH {
                     self.new_line();
                 }
 
-                let row = BUFFER_HEIGHT - 1;
+                let row = self.row_position;
                 let col = self.column_position;
 
                 let color_code = self.color_code;
@@ -80,14 +228,45 @@ impl Writer {
         }
     }
 
+    /// 1文字戻って消す。行頭なら前の行の末尾に戻る
+    fn backspace(&mut self) {
+        if self.column_position > 0 {
+            self.column_position -= 1;
+        } else if self.row_position > 0 {
+            self.row_position -= 1;
+            self.column_position = BUFFER_WIDTH - 1;
+        } else {
+            return;
+        }
+        let blank = ScreenChar {
+            ascii_character: b' ',
+            color_code: self.color_code,
+        };
+        self.buffer.chars[self.row_position][self.column_position].write(blank);
+    }
+
     fn new_line(&mut self) {
-        for row in 1..BUFFER_HEIGHT {
-            for col in 0..BUFFER_WIDTH {
-                let character = self.buffer.chars[row][col].read();
-                self.buffer.chars[row - 1][col].write(character);
+        if self.row_position < BUFFER_HEIGHT - 1 {
+            self.row_position += 1;
+        } else {
+            if let Some(scrollback) = self.scrollback.as_mut() {
+                if scrollback.lines.len() >= SCROLLBACK_LINES {
+                    scrollback.lines.pop_front();
+                }
+                let mut line = [BLANK; BUFFER_WIDTH];
+                for (col, c) in line.iter_mut().enumerate() {
+                    *c = self.buffer.chars[0][col].read();
+                }
+                scrollback.lines.push_back(line);
             }
+            for row in 1..BUFFER_HEIGHT {
+                for col in 0..BUFFER_WIDTH {
+                    let character = self.buffer.chars[row][col].read();
+                    self.buffer.chars[row - 1][col].write(character);
+                }
+            }
+            self.clear_row(BUFFER_HEIGHT - 1);
         }
-        self.clear_row(BUFFER_HEIGHT - 1);
         self.column_position = 0;
     }
 
@@ -101,15 +280,101 @@ impl Writer {
         }
     }
 
+    /// 画面全体を消去してカーソルを左上に戻す
+    pub fn clear_screen(&mut self) {
+        self.scroll_to_bottom();
+        for row in 0..BUFFER_HEIGHT {
+            self.clear_row(row);
+        }
+        self.set_cursor(0, 0);
+    }
+
     pub fn write_string(&mut self, s: &str) {
-        for byte in s.bytes() {
+        self.write_bytes(s.as_bytes());
+    }
+
+    /// バイト列を書く。表示できないバイトは■にする
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.scroll_to_bottom();
+        for &byte in bytes {
             match byte {
-                // printable ASCII byte or newline
-                0x20..=0x7e | b'\n' => self.write_byte(byte),
+                // printable ASCII byte or control characters handled by the writer
+                0x20..=0x7e | b'\n' | b'\r' | b'\t' | 0x08 => self.put_byte(byte),
                 // not part of printable ASCII range
-                _ => self.write_byte(0xfe),
+                _ => self.put_byte(0xfe),
             }
         }
+        self.update_cursor();
+    }
+
+    /// ハードウェアカーソルを現在の位置に合わせる
+    fn update_cursor(&mut self) {
+        if !self.active {
+            return;
+        }
+        let position = if self.scroll_offset() != 0 {
+            // 履歴の表示中は画面外に追い出して隠す
+            BUFFER_HEIGHT * BUFFER_WIDTH
+        } else {
+            // 行末まで書いた直後は次の行に送られるまで最後の列に置いておく
+            let col = self.column_position.min(BUFFER_WIDTH - 1);
+            self.row_position * BUFFER_WIDTH + col
+        };
+        crtc_write(CRTC_CURSOR_LOCATION_LOW, (position & 0xff) as u8);
+        crtc_write(CRTC_CURSOR_LOCATION_HIGH, ((position >> 8) & 0xff) as u8);
+    }
+
+    /// カーソルの形を走査線の範囲 (0..=15) で指定する
+    pub fn set_cursor_shape(&mut self, start: u8, end: u8) {
+        let start_reg = crtc_read(CRTC_CURSOR_START);
+        crtc_write(CRTC_CURSOR_START, (start_reg & 0xe0) | (start & 0x1f));
+        let end_reg = crtc_read(CRTC_CURSOR_END);
+        crtc_write(CRTC_CURSOR_END, (end_reg & 0xe0) | (end & 0x1f));
+    }
+
+    pub fn set_cursor_visible(&mut self, visible: bool) {
+        let start_reg = crtc_read(CRTC_CURSOR_START);
+        let start_reg = if visible {
+            start_reg & !CURSOR_DISABLE
+        } else {
+            start_reg | CURSOR_DISABLE
+        };
+        crtc_write(CRTC_CURSOR_START, start_reg);
+    }
+}
+
+//
+// CRT controller registers (hardware cursor)
+//
+
+use x86_64::instructions::port::Port;
+
+const CRTC_ADDRESS_PORT: u16 = 0x3d4;
+const CRTC_DATA_PORT: u16 = 0x3d5;
+
+const CRTC_CURSOR_START: u8 = 0x0a;
+const CRTC_CURSOR_END: u8 = 0x0b;
+const CRTC_CURSOR_LOCATION_HIGH: u8 = 0x0e;
+const CRTC_CURSOR_LOCATION_LOW: u8 = 0x0f;
+
+/// Cursor Start Register のビット5が立っているとカーソルが表示されない
+const CURSOR_DISABLE: u8 = 1 << 5;
+
+fn crtc_write(index: u8, value: u8) {
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(index);
+        data.write(value);
+    }
+}
+
+fn crtc_read(index: u8) -> u8 {
+    let mut address: Port<u8> = Port::new(CRTC_ADDRESS_PORT);
+    let mut data: Port<u8> = Port::new(CRTC_DATA_PORT);
+    unsafe {
+        address.write(index);
+        data.read()
     }
 }
 
@@ -128,13 +393,116 @@ use lazy_static::lazy_static;
 use spin::Mutex;
 
 lazy_static! {
-    pub static ref WRITER: Mutex<Writer> = Mutex::new(Writer {
-        column_position: 0,
-        color_code: ColorCode::new(Color::White, Color::Black),
-        buffer: unsafe { &mut *(0xb8000 as *mut Buffer) },
+    /// カーネルのログを出すコンソール (Alt+F1)
+    ///
+    /// 起動時に表示されるのはこのコンソールで、bootloaderが残した文字は消してから書き始める
+    pub static ref WRITER: Mutex<Writer> =
+        Mutex::new(Writer::new(unsafe { &mut *(0xb8000 as *mut Buffer) }, true));
+
+    static ref OTHER_CONSOLES: [Mutex<Writer>; NUM_CONSOLES - 1] =
+        core::array::from_fn(|i| {
+            let buffer = unsafe { &mut *(&raw mut OFFSCREEN_BUFFERS[i + 1] as *mut Buffer) };
+            Mutex::new(Writer::new(buffer, false))
+        });
+}
+
+//
+// virtual consoles
+//
+
+/// 仮想コンソールの数 (Alt+F1..Alt+F6)
+pub const NUM_CONSOLES: usize = 6;
+
+/// 裏に回ったコンソールが書き込む画面バッファ
+///
+/// `Volatile::new` がconstでないので `Buffer` と同じレイアウトの生の配列として確保する
+#[repr(C, align(2))]
+struct OffscreenBuffer([u8; 2 * BUFFER_WIDTH * BUFFER_HEIGHT]);
+
+static mut OFFSCREEN_BUFFERS: [OffscreenBuffer; NUM_CONSOLES] =
+    [const { OffscreenBuffer([0; 2 * BUFFER_WIDTH * BUFFER_HEIGHT]) }; NUM_CONSOLES];
+
+static ACTIVE_CONSOLE: AtomicUsize = AtomicUsize::new(0);
+
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+/// `index` 番目のコンソールを返す。0番は `WRITER` と同じもの
+pub fn console(index: usize) -> &'static Mutex<Writer> {
+    match index {
+        0 => &WRITER,
+        i => &OTHER_CONSOLES[i - 1],
+    }
+}
+
+/// 表示中のコンソールの番号
+pub fn active_console() -> usize {
+    ACTIVE_CONSOLE.load(Ordering::Relaxed)
+}
+
+/// 表示するコンソールを切り替える
+pub fn switch_console(index: usize) {
+    use x86_64::instructions::interrupts;
+
+    assert!(index < NUM_CONSOLES, "no such console: {}", index);
+    interrupts::without_interrupts(|| {
+        let current = active_console();
+        if current == index {
+            return;
+        }
+        // ロックの順序を番号順に固定してデッドロックを防ぐ
+        let (mut outgoing, mut incoming) = if current < index {
+            let outgoing = console(current).lock();
+            (outgoing, console(index).lock())
+        } else {
+            let incoming = console(index).lock();
+            (console(current).lock(), incoming)
+        };
+
+        outgoing.scroll_to_bottom();
+        let screen = unsafe { &mut *(&raw mut OFFSCREEN_BUFFERS[current] as *mut Buffer) };
+        let vga = core::mem::replace(&mut outgoing.buffer, screen);
+        copy_buffer(vga, outgoing.buffer);
+        outgoing.active = false;
+
+        let screen = core::mem::replace(&mut incoming.buffer, vga);
+        copy_buffer(screen, incoming.buffer);
+        incoming.active = true;
+        incoming.update_cursor();
+
+        ACTIVE_CONSOLE.store(index, Ordering::Relaxed);
     });
 }
 
+/// パニックハンドラが表示中のコンソールに書くためにロックを取る
+///
+/// パニックしたのがロックを持っている最中かもしれないので、取れなければ外してから取る。
+/// 割り込みを止めてから呼ぶこと
+pub fn lock_active_for_panic() -> spin::MutexGuard<'static, Writer> {
+    let console = console(active_console());
+    if let Some(writer) = console.try_lock() {
+        return writer;
+    }
+    unsafe { console.force_unlock() };
+    console.lock()
+}
+
+/// 全てのコンソールのスクロールバックを有効にする。ヒープの初期化後に呼ぶこと
+pub fn enable_scrollback() {
+    use x86_64::instructions::interrupts;
+
+    for index in 0..NUM_CONSOLES {
+        interrupts::without_interrupts(|| console(index).lock().enable_scrollback());
+    }
+}
+
+fn copy_buffer(from: &Buffer, to: &mut Buffer) {
+    for row in 0..BUFFER_HEIGHT {
+        for col in 0..BUFFER_WIDTH {
+            to.chars[row][col].write(from.chars[row][col].read());
+        }
+    }
+}
+
 // define printing macros from here
 
 // we use it in order to hide from generated documentation
@@ -159,6 +527,12 @@ macro_rules! println {
     ($($arg:tt)*) => ($crate::print!("{}\n", format_args!($($arg)*)));
 }
 
+/// 起動からの時刻を付けて1行表示する。ドライバや初期化のメッセージに使う
+#[macro_export]
+macro_rules! log {
+    ($($arg:tt)*) => ($crate::println!("{} {}", $crate::time::Instant::now(), format_args!($($arg)*)));
+}
+
 #[test_case]
 fn test_println_simple() {
     println!("test_println_simple output");
@@ -180,9 +554,53 @@ fn test_println_output() {
         let mut writer = WRITER.lock();
 
         writeln!(writer, "\n{}", s).expect("writeln failed");
+        let (row, _) = writer.cursor_position();
         for (i, c) in s.chars().enumerate() {
-            let screen_char = writer.buffer.chars[BUFFER_HEIGHT - 2][i].read();
+            let screen_char = writer.buffer.chars[row - 1][i].read();
             assert_eq!(char::from(screen_char.ascii_character), c);
         }
     });
 }
+
+#[test_case]
+fn test_cursor_position() {
+    use x86_64::instructions::interrupts;
+    interrupts::without_interrupts(|| {
+        use core::fmt::Write;
+        let mut writer = WRITER.lock();
+
+        writer.set_cursor(3, 10);
+        write!(writer, "ab\tc").expect("write failed");
+        assert_eq!(writer.cursor_position(), (3, 17));
+        assert_eq!(char::from(writer.buffer.chars[3][16].read().ascii_character), 'c');
+
+        write!(writer, "\x08\rd").expect("write failed");
+        assert_eq!(writer.cursor_position(), (3, 1));
+        assert_eq!(char::from(writer.buffer.chars[3][0].read().ascii_character), 'd');
+        assert_eq!(char::from(writer.buffer.chars[3][16].read().ascii_character), ' ');
+    });
+}
+
+#[test_case]
+fn test_switch_console() {
+    use core::fmt::Write;
+    use x86_64::instructions::interrupts;
+
+    interrupts::without_interrupts(|| {
+        let mut writer = console(1).lock();
+        writer.clear_screen();
+        write!(writer, "tty2").expect("write failed");
+    });
+
+    switch_console(1);
+    interrupts::without_interrupts(|| {
+        let vga = unsafe { &*(0xb8000 as *const Buffer) };
+        assert_eq!(vga.chars[0][3].read().ascii_character, b'2');
+    });
+
+    switch_console(0);
+    assert_eq!(active_console(), 0);
+    interrupts::without_interrupts(|| {
+        assert_eq!(console(1).lock().read_char(0, 3), b'2');
+    });
+}
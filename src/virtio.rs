@@ -0,0 +1,575 @@
+//! virtio-pci (modern) のトランスポートとsplit virtqueue
+//!
+//! デバイスごとのドライバ (`virtio_blk` など) は `Transport` で機能の交渉と
+//! キューの準備をして、`Virtqueue` にバッファを積んでデバイスに渡す
+
+use crate::interrupts;
+use crate::memory::{self, DmaRegion};
+use crate::pci::{CAPABILITY_VENDOR, PciDevice, PciError};
+use crate::time::{Duration, Instant};
+use core::fmt;
+use core::sync::atomic::{AtomicU64, Ordering, fence};
+use x86_64::instructions::interrupts as cpu_interrupts;
+use x86_64::{PhysAddr, VirtAddr};
+
+pub const VENDOR_ID: u16 = 0x1af4;
+
+/// virtio 1.0 に従う (modern) デバイスであることを示す機能ビット
+pub const FEATURE_VERSION_1: u64 = 1 << 32;
+
+/// ベンダー固有ケーパビリティの `cfg_type`
+const CONFIG_COMMON: u8 = 1;
+const CONFIG_NOTIFY: u8 = 2;
+const CONFIG_ISR: u8 = 3;
+const CONFIG_DEVICE: u8 = 4;
+
+// 共通設定の構造体のオフセット
+const COMMON_DEVICE_FEATURE_SELECT: u64 = 0;
+const COMMON_DEVICE_FEATURE: u64 = 4;
+const COMMON_DRIVER_FEATURE_SELECT: u64 = 8;
+const COMMON_DRIVER_FEATURE: u64 = 12;
+const COMMON_NUM_QUEUES: u64 = 18;
+const COMMON_DEVICE_STATUS: u64 = 20;
+const COMMON_CONFIG_GENERATION: u64 = 21;
+const COMMON_QUEUE_SELECT: u64 = 22;
+const COMMON_QUEUE_SIZE: u64 = 24;
+const COMMON_QUEUE_ENABLE: u64 = 28;
+const COMMON_QUEUE_NOTIFY_OFF: u64 = 30;
+const COMMON_QUEUE_DESC: u64 = 32;
+const COMMON_QUEUE_DRIVER: u64 = 40;
+const COMMON_QUEUE_DEVICE: u64 = 48;
+
+const STATUS_ACKNOWLEDGE: u8 = 1;
+const STATUS_DRIVER: u8 = 2;
+const STATUS_DRIVER_OK: u8 = 4;
+const STATUS_FEATURES_OK: u8 = 8;
+const STATUS_FAILED: u8 = 128;
+
+/// ISRステータス: キューの割り込み
+const ISR_QUEUE: u8 = 1 << 0;
+
+const DESC_F_NEXT: u16 = 1;
+const DESC_F_WRITE: u16 = 2;
+
+/// キューの大きさの上限。デバイスがもっと大きなキューを持っていても小さくして使う
+const MAX_QUEUE_SIZE: u16 = 256;
+
+const PAGE_SIZE: usize = 4096;
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum VirtioError {
+    Pci(PciError),
+    /// 必要なベンダー固有ケーパビリティがない (legacyのみのデバイス)
+    MissingCapability(u8),
+    /// デバイスが `FEATURES_OK` を受け付けなかった
+    FeaturesRejected,
+    NoSuchQueue(u16),
+    OutOfMemory,
+    /// ディスクリプタが足りない
+    QueueFull,
+    /// 割り込みを受けられないIRQ線につながっている
+    UnsupportedIrq(u8),
+    Timeout,
+}
+
+impl VirtioError {
+    /// `PciError::Driver` に渡せる説明
+    pub fn message(&self) -> &'static str {
+        match self {
+            VirtioError::Pci(_) => "virtio: PCI error",
+            VirtioError::MissingCapability(_) => "virtio: device has no modern interface",
+            VirtioError::FeaturesRejected => "virtio: device rejected the features",
+            VirtioError::NoSuchQueue(_) => "virtio: no such queue",
+            VirtioError::OutOfMemory => "virtio: out of DMA memory",
+            VirtioError::QueueFull => "virtio: queue is full",
+            VirtioError::UnsupportedIrq(_) => "virtio: unsupported IRQ line",
+            VirtioError::Timeout => "virtio: device timed out",
+        }
+    }
+}
+
+impl fmt::Display for VirtioError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            VirtioError::Pci(err) => write!(f, "{}", err),
+            VirtioError::MissingCapability(cfg_type) => {
+                write!(f, "{} (cfg_type {})", self.message(), cfg_type)
+            }
+            VirtioError::NoSuchQueue(index) => write!(f, "{} ({})", self.message(), index),
+            VirtioError::UnsupportedIrq(irq) => write!(f, "{} ({})", self.message(), irq),
+            _ => write!(f, "{}", self.message()),
+        }
+    }
+}
+
+impl From<PciError> for VirtioError {
+    fn from(err: PciError) -> VirtioError {
+        VirtioError::Pci(err)
+    }
+}
+
+impl From<VirtioError> for PciError {
+    fn from(err: VirtioError) -> PciError {
+        match err {
+            VirtioError::Pci(err) => err,
+            err => PciError::Driver(err.message()),
+        }
+    }
+}
+
+/// 割り込みを受けるデバイスのISRステータスレジスタ (0は空き)
+static ISR_REGISTERS: [AtomicU64; 8] = [const { AtomicU64::new(0) }; 8];
+
+/// PCIの割り込みハンドラ。ISRステータスを読んでデバイスの割り込みを下げる
+///
+/// 完了したかどうかは使用済みリングで確かめるので、ここでは読むだけでよい
+fn handle_interrupt() {
+    for register in &ISR_REGISTERS {
+        let address = register.load(Ordering::Relaxed);
+        if address != 0 {
+            unsafe { (address as *const u8).read_volatile() };
+        }
+    }
+}
+
+/// ケーパビリティが指す、BARの中の構造体
+#[derive(Debug, Clone, Copy)]
+struct Region {
+    address: VirtAddr,
+    length: u32,
+}
+
+/// virtio-pciのデバイスのレジスタ
+pub struct Transport {
+    device: PciDevice,
+    common: VirtAddr,
+    notify: VirtAddr,
+    notify_multiplier: u32,
+    isr: VirtAddr,
+    device_config: Option<Region>,
+}
+
+impl Transport {
+    /// ベンダー固有ケーパビリティから共通設定、通知、ISR、デバイス設定の場所を探してマップする
+    pub fn new(device: &PciDevice) -> Result<Transport, VirtioError> {
+        device.enable();
+        let mut mapped: [Option<VirtAddr>; 6] = [None; 6];
+        let mut regions: [Option<Region>; 5] = [None; 5];
+        let mut notify_multiplier = 0;
+        for (id, offset) in device.capabilities() {
+            if id != CAPABILITY_VENDOR {
+                continue;
+            }
+            let cfg_type = device.read_u8(offset + 3);
+            let bar = usize::from(device.read_u8(offset + 4));
+            if !(CONFIG_COMMON..=CONFIG_DEVICE).contains(&cfg_type)
+                || regions[usize::from(cfg_type)].is_some()
+                || bar >= mapped.len()
+            {
+                continue;
+            }
+            let base = match mapped[bar] {
+                Some(base) => base,
+                None => {
+                    let base = device.map_bar(bar)?;
+                    mapped[bar] = Some(base);
+                    base
+                }
+            };
+            regions[usize::from(cfg_type)] = Some(Region {
+                address: base + u64::from(device.read_u32(offset + 8)),
+                length: device.read_u32(offset + 12),
+            });
+            if cfg_type == CONFIG_NOTIFY {
+                notify_multiplier = device.read_u32(offset + 16);
+            }
+        }
+        let region = |cfg_type: u8| {
+            regions[usize::from(cfg_type)].ok_or(VirtioError::MissingCapability(cfg_type))
+        };
+        Ok(Transport {
+            device: *device,
+            common: region(CONFIG_COMMON)?.address,
+            notify: region(CONFIG_NOTIFY)?.address,
+            notify_multiplier,
+            isr: region(CONFIG_ISR)?.address,
+            device_config: regions[usize::from(CONFIG_DEVICE)],
+        })
+    }
+
+    pub fn pci_device(&self) -> &PciDevice {
+        &self.device
+    }
+
+    fn read_common<T: Copy>(&self, offset: u64) -> T {
+        unsafe { (self.common + offset).as_ptr::<T>().read_volatile() }
+    }
+
+    fn write_common<T: Copy>(&self, offset: u64, value: T) {
+        unsafe {
+            (self.common + offset)
+                .as_mut_ptr::<T>()
+                .write_volatile(value)
+        }
+    }
+
+    fn status(&self) -> u8 {
+        self.read_common(COMMON_DEVICE_STATUS)
+    }
+
+    fn set_status(&self, status: u8) {
+        self.write_common(COMMON_DEVICE_STATUS, status)
+    }
+
+    /// デバイスをリセットし、`supported` のうちデバイスも持つ機能を交渉して返す
+    ///
+    /// `FEATURE_VERSION_1` は常に要求する。この後キューを準備して `finish_init` を呼ぶ
+    pub fn begin_init(&self, supported: u64) -> Result<u64, VirtioError> {
+        self.set_status(0);
+        let start = Instant::now();
+        while self.status() != 0 {
+            if start.elapsed() > Duration::from_secs(1) {
+                return Err(VirtioError::Timeout);
+            }
+            core::hint::spin_loop();
+        }
+        self.set_status(STATUS_ACKNOWLEDGE);
+        self.set_status(STATUS_ACKNOWLEDGE | STATUS_DRIVER);
+
+        let mut device_features = 0;
+        for select in 0..2u32 {
+            self.write_common(COMMON_DEVICE_FEATURE_SELECT, select);
+            let features: u32 = self.read_common(COMMON_DEVICE_FEATURE);
+            device_features |= u64::from(features) << (32 * select);
+        }
+        let features = device_features & (supported | FEATURE_VERSION_1);
+        if features & FEATURE_VERSION_1 == 0 {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+        for select in 0..2u32 {
+            self.write_common(COMMON_DRIVER_FEATURE_SELECT, select);
+            self.write_common(COMMON_DRIVER_FEATURE, (features >> (32 * select)) as u32);
+        }
+        self.set_status(self.status() | STATUS_FEATURES_OK);
+        if self.status() & STATUS_FEATURES_OK == 0 {
+            self.fail();
+            return Err(VirtioError::FeaturesRejected);
+        }
+        Ok(features)
+    }
+
+    /// 準備が終わったことをデバイスに伝える。これ以降デバイスがキューを処理する
+    pub fn finish_init(&self) {
+        self.set_status(self.status() | STATUS_DRIVER_OK);
+    }
+
+    /// 初期化をあきらめたことをデバイスに伝える
+    pub fn fail(&self) {
+        self.set_status(self.status() | STATUS_FAILED);
+    }
+
+    pub fn queue_count(&self) -> u16 {
+        self.read_common(COMMON_NUM_QUEUES)
+    }
+
+    /// キュー `index` のメモリを割り当ててデバイスに教え、有効にする
+    pub fn setup_queue(&self, index: u16) -> Result<Virtqueue, VirtioError> {
+        if index >= self.queue_count() {
+            return Err(VirtioError::NoSuchQueue(index));
+        }
+        self.write_common(COMMON_QUEUE_SELECT, index);
+        let max_size: u16 = self.read_common(COMMON_QUEUE_SIZE);
+        if max_size == 0 {
+            return Err(VirtioError::NoSuchQueue(index));
+        }
+        // キューの大きさは2のべき乗
+        let size = max_size.min(MAX_QUEUE_SIZE);
+        let size: u16 = 1 << (15 - size.leading_zeros());
+        let layout = QueueLayout::new(size);
+        let memory = memory::allocate_dma(layout.pages).ok_or(VirtioError::OutOfMemory)?;
+
+        self.write_common(COMMON_QUEUE_SIZE, size);
+        self.write_common(COMMON_QUEUE_DESC, memory.phys.as_u64());
+        self.write_common(
+            COMMON_QUEUE_DRIVER,
+            memory.phys.as_u64() + layout.avail as u64,
+        );
+        self.write_common(
+            COMMON_QUEUE_DEVICE,
+            memory.phys.as_u64() + layout.used as u64,
+        );
+        let notify_offset: u16 = self.read_common(COMMON_QUEUE_NOTIFY_OFF);
+        self.write_common(COMMON_QUEUE_ENABLE, 1u16);
+
+        let mut queue = Virtqueue {
+            index,
+            size,
+            layout,
+            memory,
+            notify: self.notify + u64::from(notify_offset) * u64::from(self.notify_multiplier),
+            free_head: 0,
+            free_count: size,
+            last_used: 0,
+            chain_lengths: [0; MAX_QUEUE_SIZE as usize],
+        };
+        for i in 0..size {
+            queue.descriptor(i).next = (i + 1) % size;
+        }
+        Ok(queue)
+    }
+
+    /// デバイス固有の設定の `offset` バイト目を読む
+    ///
+    /// 読んでいる間に設定が変わったら読み直す
+    pub fn read_config<T: Copy>(&self, offset: u64) -> Option<T> {
+        let config = self.device_config?;
+        if offset + core::mem::size_of::<T>() as u64 > u64::from(config.length) {
+            return None;
+        }
+        loop {
+            let generation: u8 = self.read_common(COMMON_CONFIG_GENERATION);
+            let value = unsafe { (config.address + offset).as_ptr::<T>().read_volatile() };
+            if self.read_common::<u8>(COMMON_CONFIG_GENERATION) == generation {
+                return Some(value);
+            }
+        }
+    }
+
+    /// PCIのINTxでキューの割り込みを受ける
+    pub fn enable_interrupts(&self) -> Result<(), VirtioError> {
+        let irq = self.device.interrupt_line;
+        let slot = ISR_REGISTERS
+            .iter()
+            .find(|register| {
+                register
+                    .compare_exchange(0, self.isr.as_u64(), Ordering::Relaxed, Ordering::Relaxed)
+                    .is_ok()
+            })
+            .ok_or(VirtioError::UnsupportedIrq(irq))?;
+        if !interrupts::register_pci_handler(irq, handle_interrupt) {
+            slot.store(0, Ordering::Relaxed);
+            return Err(VirtioError::UnsupportedIrq(irq));
+        }
+        Ok(())
+    }
+
+    /// ISRステータスを読み、キューの割り込みが来ていたか返す。読むと割り込みが下がる
+    pub fn acknowledge_interrupt(&self) -> bool {
+        let status = unsafe { self.isr.as_ptr::<u8>().read_volatile() };
+        status & ISR_QUEUE != 0
+    }
+}
+
+/// split virtqueueのディスクリプタ
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct Descriptor {
+    address: u64,
+    length: u32,
+    flags: u16,
+    next: u16,
+}
+
+/// キューのメモリの中の、ディスクリプタテーブル・使用可能リング・使用済みリングの位置
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct QueueLayout {
+    avail: usize,
+    used: usize,
+    pages: usize,
+}
+
+impl QueueLayout {
+    fn new(size: u16) -> QueueLayout {
+        let size = usize::from(size);
+        // ディスクリプタは16バイト、使用可能リングは flags, idx, ring[size], used_event
+        let avail = 16 * size;
+        let avail_end = avail + 2 * (3 + size);
+        // 使用済みリング (flags, idx, ring[size] (8バイト), avail_event) はページ境界から置く
+        let used = avail_end.div_ceil(PAGE_SIZE) * PAGE_SIZE;
+        let used_end = used + 2 * 3 + 8 * size;
+        QueueLayout {
+            avail,
+            used,
+            pages: used_end.div_ceil(PAGE_SIZE),
+        }
+    }
+}
+
+/// デバイスに渡すバッファ1つ
+#[derive(Debug, Clone, Copy)]
+pub struct Buffer {
+    pub address: PhysAddr,
+    pub length: u32,
+    /// デバイスが書き込むバッファか
+    pub device_writable: bool,
+}
+
+/// split virtqueue
+pub struct Virtqueue {
+    index: u16,
+    size: u16,
+    layout: QueueLayout,
+    memory: DmaRegion,
+    notify: VirtAddr,
+    /// 空いているディスクリプタのリストの先頭
+    free_head: u16,
+    free_count: u16,
+    /// 次に読む使用済みリングの位置
+    last_used: u16,
+    /// 先頭のディスクリプタごとのチェーンの長さ
+    chain_lengths: [u16; MAX_QUEUE_SIZE as usize],
+}
+
+impl Virtqueue {
+    pub fn index(&self) -> u16 {
+        self.index
+    }
+
+    pub fn size(&self) -> u16 {
+        self.size
+    }
+
+    fn descriptor(&mut self, index: u16) -> &mut Descriptor {
+        unsafe {
+            &mut *self
+                .memory
+                .as_mut_ptr::<Descriptor>()
+                .add(usize::from(index))
+        }
+    }
+
+    /// リングの `offset` バイト目の `u16`
+    fn ring_u16(&self, offset: usize) -> *mut u16 {
+        (self.memory.virt + offset as u64).as_mut_ptr()
+    }
+
+    /// `buffers` を1つのチェーンにして使用可能リングに積み、先頭のディスクリプタの番号を返す
+    ///
+    /// デバイスに知らせるには続けて `notify` を呼ぶ
+    pub fn push(&mut self, buffers: &[Buffer]) -> Result<u16, VirtioError> {
+        if buffers.is_empty() || buffers.len() > usize::from(self.free_count) {
+            return Err(VirtioError::QueueFull);
+        }
+        let head = self.free_head;
+        let mut index = head;
+        for (i, buffer) in buffers.iter().enumerate() {
+            let last = i + 1 == buffers.len();
+            let descriptor = self.descriptor(index);
+            descriptor.address = buffer.address.as_u64();
+            descriptor.length = buffer.length;
+            descriptor.flags = if buffer.device_writable {
+                DESC_F_WRITE
+            } else {
+                0
+            } | if last { 0 } else { DESC_F_NEXT };
+            if !last {
+                index = descriptor.next;
+            } else {
+                self.free_head = descriptor.next;
+            }
+        }
+        self.free_count -= buffers.len() as u16;
+        self.chain_lengths[usize::from(head)] = buffers.len() as u16;
+
+        // 使用可能リングに積んでから idx を進める
+        let avail_idx = unsafe { self.ring_u16(self.layout.avail + 2).read_volatile() };
+        let slot = self.layout.avail + 4 + 2 * usize::from(avail_idx % self.size);
+        unsafe { self.ring_u16(slot).write_volatile(head) };
+        fence(Ordering::SeqCst);
+        unsafe {
+            self.ring_u16(self.layout.avail + 2)
+                .write_volatile(avail_idx.wrapping_add(1))
+        };
+        Ok(head)
+    }
+
+    /// 積んだバッファがあることをデバイスに知らせる
+    pub fn notify(&self) {
+        fence(Ordering::SeqCst);
+        unsafe { self.notify.as_mut_ptr::<u16>().write_volatile(self.index) };
+    }
+
+    /// デバイスが返したバッファがあるか
+    pub fn has_used(&self) -> bool {
+        fence(Ordering::SeqCst);
+        let used_idx = unsafe { self.ring_u16(self.layout.used + 2).read_volatile() };
+        used_idx != self.last_used
+    }
+
+    /// 使用済みリングから1つ取り出し、チェーンの先頭の番号とデバイスが書いた長さを返す
+    pub fn pop_used(&mut self) -> Option<(u16, u32)> {
+        if !self.has_used() {
+            return None;
+        }
+        let slot = self.layout.used + 4 + 8 * usize::from(self.last_used % self.size);
+        let element = (self.memory.virt + slot as u64).as_ptr::<[u32; 2]>();
+        let [id, length] = unsafe { element.read_volatile() };
+        self.last_used = self.last_used.wrapping_add(1);
+
+        // チェーンを空きリストに戻す
+        let head = id as u16;
+        let chain_length = self.chain_lengths[usize::from(head)];
+        let mut tail = head;
+        for _ in 1..chain_length {
+            tail = self.descriptor(tail).next;
+        }
+        let free_head = self.free_head;
+        self.descriptor(tail).next = free_head;
+        self.free_head = head;
+        self.free_count += chain_length;
+        Some((head, length))
+    }
+
+    /// デバイスがバッファを返すまで待って取り出す
+    ///
+    /// 割り込みが有効なら割り込みが来るまでhltで眠る。無効なら使用済みリングをポーリングする
+    pub fn wait_used(&mut self, timeout: Duration) -> Result<(u16, u32), VirtioError> {
+        let sleep = cpu_interrupts::are_enabled();
+        let start = Instant::now();
+        loop {
+            if sleep {
+                cpu_interrupts::disable();
+            }
+            if let Some(used) = self.pop_used() {
+                if sleep {
+                    cpu_interrupts::enable();
+                }
+                return Ok(used);
+            }
+            if start.elapsed() > timeout {
+                if sleep {
+                    cpu_interrupts::enable();
+                }
+                return Err(VirtioError::Timeout);
+            }
+            if sleep {
+                // 割り込みが来なくてもタイマー割り込みで起きる
+                cpu_interrupts::enable_and_hlt();
+            } else {
+                core::hint::spin_loop();
+            }
+        }
+    }
+}
+
+#[test_case]
+fn test_queue_layout() {
+    // 256個: ディスクリプタ4096バイト、使用可能リング518バイト、使用済みリング2054バイト
+    assert_eq!(
+        QueueLayout::new(256),
+        QueueLayout {
+            avail: 4096,
+            used: 8192,
+            pages: 3,
+        }
+    );
+    assert_eq!(
+        QueueLayout::new(16),
+        QueueLayout {
+            avail: 256,
+            used: 4096,
+            pages: 2,
+        }
+    );
+}
@@ -0,0 +1,234 @@
+//! virtio-blk のドライバ
+//!
+//! 要求はヘッダ、データ、ステータスの3つのディスクリプタのチェーンで送る。
+//! データは呼び出し側のバッファではなく、物理的に連続したバウンスバッファを通す
+
+use crate::block::{self, BlockDevice, BlockError};
+use crate::log;
+use crate::memory::{self, DmaRegion};
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+use crate::time::Duration;
+use crate::virtio::{self, Buffer, Transport, VirtioError, Virtqueue};
+use alloc::format;
+use alloc::sync::Arc;
+use core::sync::atomic::{AtomicUsize, Ordering};
+use spin::Mutex;
+
+/// virtioのセクタの大きさ。デバイスの論理ブロックの大きさに関係なく512バイト
+pub const SECTOR_SIZE: usize = 512;
+
+/// 書き込めないデバイス
+const FEATURE_RO: u64 = 1 << 5;
+/// キャッシュの書き出し (`REQUEST_FLUSH`) ができる
+const FEATURE_FLUSH: u64 = 1 << 9;
+
+const REQUEST_IN: u32 = 0;
+const REQUEST_OUT: u32 = 1;
+const REQUEST_FLUSH: u32 = 4;
+
+const STATUS_OK: u8 = 0;
+const STATUS_IOERR: u8 = 1;
+const STATUS_UNSUPPORTED: u8 = 2;
+
+/// デバイス設定: 容量 (セクタ数)
+const CONFIG_CAPACITY: u64 = 0;
+
+/// バウンスバッファのページ数。1回の要求で読み書きする量の上限になる
+const BOUNCE_PAGES: usize = 16;
+
+/// 要求の完了を待つ時間
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(5);
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-blk",
+    // 移行用 (transitional) のデバイスIDと、modern専用のデバイスID
+    matches: &[
+        PciMatch::device(virtio::VENDOR_ID, 0x1001),
+        PciMatch::device(virtio::VENDOR_ID, 0x1042),
+    ],
+    probe,
+};
+
+/// 次に付ける名前の番号 (`vda`, `vdb`, ...)
+static NEXT_INDEX: AtomicUsize = AtomicUsize::new(0);
+
+fn probe(device: &PciDevice) -> Result<(), PciError> {
+    let disk = VirtioBlk::new(device)?;
+    let index = NEXT_INDEX.fetch_add(1, Ordering::Relaxed);
+    let name = format!("vd{}", char::from(b'a' + (index % 26) as u8));
+    log!(
+        "virtio-blk: {} {} MiB{}",
+        name,
+        disk.capacity * SECTOR_SIZE as u64 / (1024 * 1024),
+        if disk.read_only { " (read-only)" } else { "" }
+    );
+    block::DEVICES.register(&name, Arc::new(Mutex::new(disk)));
+    Ok(())
+}
+
+/// 要求のヘッダ
+#[derive(Debug, Clone, Copy)]
+#[repr(C)]
+struct RequestHeader {
+    kind: u32,
+    reserved: u32,
+    sector: u64,
+}
+
+pub struct VirtioBlk {
+    transport: Transport,
+    queue: Virtqueue,
+    capacity: u64,
+    read_only: bool,
+    flush_supported: bool,
+    /// ヘッダとステータスを置くページ
+    request: DmaRegion,
+    bounce: DmaRegion,
+}
+
+/// `request` のページの中のステータスバイトの位置
+const STATUS_OFFSET: usize = core::mem::size_of::<RequestHeader>();
+
+impl VirtioBlk {
+    pub fn new(device: &PciDevice) -> Result<VirtioBlk, VirtioError> {
+        let transport = Transport::new(device)?;
+        let features = transport.begin_init(FEATURE_RO | FEATURE_FLUSH)?;
+        let result = (|| {
+            let queue = transport.setup_queue(0)?;
+            let request = memory::allocate_dma(1).ok_or(VirtioError::OutOfMemory)?;
+            let bounce = memory::allocate_dma(BOUNCE_PAGES).ok_or(VirtioError::OutOfMemory)?;
+            transport.enable_interrupts()?;
+            Ok((queue, request, bounce))
+        })();
+        let (queue, request, bounce) = match result {
+            Ok(parts) => parts,
+            Err(err) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        transport.finish_init();
+        let capacity = transport.read_config::<u64>(CONFIG_CAPACITY).unwrap_or(0);
+        Ok(VirtioBlk {
+            transport,
+            queue,
+            capacity,
+            read_only: features & FEATURE_RO != 0,
+            flush_supported: features & FEATURE_FLUSH != 0,
+            request,
+            bounce,
+        })
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    /// 要求を1つ送り、完了を待つ。データはバウンスバッファの先頭 `length` バイト
+    fn submit(&mut self, kind: u32, sector: u64, length: usize) -> Result<(), BlockError> {
+        let header = RequestHeader {
+            kind,
+            reserved: 0,
+            sector,
+        };
+        unsafe {
+            self.request
+                .as_mut_ptr::<RequestHeader>()
+                .write_volatile(header);
+            self.request
+                .as_mut_ptr::<u8>()
+                .add(STATUS_OFFSET)
+                .write_volatile(0xff);
+        }
+        let header = Buffer {
+            address: self.request.phys,
+            length: STATUS_OFFSET as u32,
+            device_writable: false,
+        };
+        let data = Buffer {
+            address: self.bounce.phys,
+            length: length as u32,
+            device_writable: kind == REQUEST_IN,
+        };
+        let status = Buffer {
+            address: self.request.phys + STATUS_OFFSET as u64,
+            length: 1,
+            device_writable: true,
+        };
+        let result = if length == 0 {
+            self.queue.push(&[header, status])
+        } else {
+            self.queue.push(&[header, data, status])
+        };
+        result.map_err(|err| BlockError::Device(err.message()))?;
+        self.queue.notify();
+        self.queue
+            .wait_used(REQUEST_TIMEOUT)
+            .map_err(|err| match err {
+                VirtioError::Timeout => BlockError::Timeout,
+                err => BlockError::Device(err.message()),
+            })?;
+
+        let status = unsafe {
+            self.request
+                .as_mut_ptr::<u8>()
+                .add(STATUS_OFFSET)
+                .read_volatile()
+        };
+        match status {
+            STATUS_OK => Ok(()),
+            STATUS_IOERR => Err(BlockError::Device("virtio-blk: I/O error")),
+            STATUS_UNSUPPORTED => Err(BlockError::Device("virtio-blk: unsupported request")),
+            _ => Err(BlockError::Device("virtio-blk: invalid status")),
+        }
+    }
+}
+
+impl BlockDevice for VirtioBlk {
+    fn block_size(&self) -> usize {
+        SECTOR_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        self.capacity
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        block::check_range(self, start, buffer.len())?;
+        let chunk_size = self.bounce.size;
+        for (i, chunk) in buffer.chunks_mut(chunk_size).enumerate() {
+            let sector = start + (i * chunk_size / SECTOR_SIZE) as u64;
+            self.submit(REQUEST_IN, sector, chunk.len())?;
+            let bounce = self.bounce.as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(bounce, chunk.as_mut_ptr(), chunk.len()) };
+        }
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        if self.read_only {
+            return Err(BlockError::ReadOnly);
+        }
+        block::check_range(self, start, buffer.len())?;
+        let chunk_size = self.bounce.size;
+        for (i, chunk) in buffer.chunks(chunk_size).enumerate() {
+            let sector = start + (i * chunk_size / SECTOR_SIZE) as u64;
+            let bounce = self.bounce.as_mut_ptr::<u8>();
+            unsafe { core::ptr::copy_nonoverlapping(chunk.as_ptr(), bounce, chunk.len()) };
+            self.submit(REQUEST_OUT, sector, chunk.len())?;
+        }
+        Ok(())
+    }
+
+    fn flush(&mut self) -> Result<(), BlockError> {
+        // FLUSHを持たないデバイスは書き込みをキャッシュしない
+        if !self.flush_supported {
+            return Ok(());
+        }
+        self.submit(REQUEST_FLUSH, 0, 0)
+    }
+
+    fn description(&self) -> &str {
+        "virtio-blk"
+    }
+}
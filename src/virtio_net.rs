@@ -0,0 +1,414 @@
+//! virtio-net のドライバ
+//!
+//! キュー0が受信、1が送信。受信バッファはDMA用のフレームに用意して受信キューに積んでおき、
+//! 送信はヘッダとフレームを送信バッファにコピーしてから積む。
+//! `FEATURE_MRG_RXBUF` を交渉したときは、大きなフレームが複数の受信バッファにまたがって届く
+
+use crate::log;
+use crate::memory::{self, DmaRegion};
+use crate::net::{self, ChecksumOffload, MacAddress, NetDevice, NetError, NetStats};
+use crate::pci::{PciDevice, PciDriver, PciError, PciMatch};
+use crate::time::{self, Duration};
+use crate::virtio::{self, Buffer, Transport, VirtioError, Virtqueue};
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use spin::Mutex;
+
+/// 送るフレームのチェックサムをデバイスが計算できる
+const FEATURE_CSUM: u64 = 1 << 0;
+/// チェックサムが計算されていないフレームを受け取れる
+const FEATURE_GUEST_CSUM: u64 = 1 << 1;
+/// デバイス設定にMACアドレスがある
+const FEATURE_MAC: u64 = 1 << 5;
+/// 受信したフレームを複数のバッファに分けて書ける
+const FEATURE_MRG_RXBUF: u64 = 1 << 15;
+/// デバイス設定にリンクの状態がある
+const FEATURE_STATUS: u64 = 1 << 16;
+
+/// ヘッダのフラグ: `csum_start` から後ろのチェックサムがまだ計算されていない
+const HEADER_NEEDS_CSUM: u8 = 1;
+const GSO_NONE: u8 = 0;
+
+// デバイス設定のオフセット
+const CONFIG_MAC: u64 = 0;
+const CONFIG_STATUS: u64 = 6;
+
+const STATUS_LINK_UP: u16 = 1;
+
+const RX_QUEUE: u16 = 0;
+const TX_QUEUE: u16 = 1;
+
+/// 積んでおく受信バッファの数
+const RX_BUFFERS: usize = 64;
+/// 分けて受け取れるときの受信バッファの大きさ。最大のフレームは2つにまたがる
+const MERGEABLE_RX_BUFFER_SIZE: usize = 1024;
+/// 送信バッファと、分けて受け取れないときの受信バッファの大きさ。ヘッダと最大のフレームが入る
+const BUFFER_SIZE: usize = 2048;
+const TX_BUFFERS: usize = 16;
+
+/// 送信キューが空くのを待つ時間
+const TRANSMIT_TIMEOUT: Duration = Duration::from_millis(100);
+
+const PAGE_SIZE: usize = 4096;
+
+pub static DRIVER: PciDriver = PciDriver {
+    name: "virtio-net",
+    // 移行用 (transitional) のデバイスIDと、modern専用のデバイスID
+    matches: &[
+        PciMatch::device(virtio::VENDOR_ID, 0x1000),
+        PciMatch::device(virtio::VENDOR_ID, 0x1041),
+    ],
+    probe,
+};
+
+fn probe(device: &PciDevice) -> Result<(), PciError> {
+    let nic = VirtioNet::new(device)?;
+    let name = net::next_name();
+    log!(
+        "virtio-net: {} {} link {}{}",
+        name,
+        nic.mac,
+        if nic.link_up() { "up" } else { "down" },
+        if nic.mergeable_rx_buffers() {
+            " (mergeable rx buffers)"
+        } else {
+            ""
+        }
+    );
+    net::DEVICES.register(&name, Arc::new(Mutex::new(nic)));
+    Ok(())
+}
+
+/// フレームの前に付くヘッダ。virtio 1.0 では `num_buffers` まで常に付く
+#[derive(Debug, Clone, Copy, Default)]
+#[repr(C)]
+struct Header {
+    flags: u8,
+    gso_type: u8,
+    header_length: u16,
+    gso_size: u16,
+    csum_start: u16,
+    csum_offset: u16,
+    /// 受信したフレームが使ったバッファの数 (`FEATURE_MRG_RXBUF` のときだけ意味がある)
+    num_buffers: u16,
+}
+
+const HEADER_SIZE: usize = core::mem::size_of::<Header>();
+
+pub struct VirtioNet {
+    transport: Transport,
+    rx_queue: Virtqueue,
+    tx_queue: Virtqueue,
+    mac: MacAddress,
+    features: u64,
+    rx_buffers: DmaRegion,
+    rx_buffer_size: usize,
+    /// 受信キューのディスクリプタの番号ごとの、そこに積んだ受信バッファの番号
+    rx_slots: Vec<usize>,
+    tx_buffers: DmaRegion,
+    /// 送信キューのディスクリプタの番号ごとの、そこに積んだ送信バッファの番号
+    tx_slots: Vec<usize>,
+    /// デバイスに渡していない送信バッファの番号
+    tx_free: Vec<usize>,
+    stats: NetStats,
+}
+
+impl VirtioNet {
+    pub fn new(device: &PciDevice) -> Result<VirtioNet, VirtioError> {
+        let transport = Transport::new(device)?;
+        let features = transport.begin_init(
+            FEATURE_CSUM | FEATURE_GUEST_CSUM | FEATURE_MAC | FEATURE_MRG_RXBUF | FEATURE_STATUS,
+        )?;
+        let rx_buffer_size = if features & FEATURE_MRG_RXBUF != 0 {
+            MERGEABLE_RX_BUFFER_SIZE
+        } else {
+            BUFFER_SIZE
+        };
+        let result = (|| {
+            let rx_queue = transport.setup_queue(RX_QUEUE)?;
+            let tx_queue = transport.setup_queue(TX_QUEUE)?;
+            let rx_buffers = memory::allocate_dma(RX_BUFFERS * rx_buffer_size / PAGE_SIZE)
+                .ok_or(VirtioError::OutOfMemory)?;
+            let tx_buffers = memory::allocate_dma(TX_BUFFERS * BUFFER_SIZE / PAGE_SIZE)
+                .ok_or(VirtioError::OutOfMemory)?;
+            transport.enable_interrupts()?;
+            Ok((rx_queue, tx_queue, rx_buffers, tx_buffers))
+        })();
+        let (rx_queue, tx_queue, rx_buffers, tx_buffers) = match result {
+            Ok(parts) => parts,
+            Err(err) => {
+                transport.fail();
+                return Err(err);
+            }
+        };
+        let mac = match transport.read_config::<[u8; 6]>(CONFIG_MAC) {
+            Some(mac) if features & FEATURE_MAC != 0 => MacAddress(mac),
+            _ => random_mac_address(),
+        };
+        let mut nic = VirtioNet {
+            rx_slots: vec![0; usize::from(rx_queue.size())],
+            tx_slots: vec![0; usize::from(tx_queue.size())],
+            tx_free: (0..TX_BUFFERS).collect(),
+            transport,
+            rx_queue,
+            tx_queue,
+            mac,
+            features,
+            rx_buffers,
+            rx_buffer_size,
+            tx_buffers,
+            stats: NetStats::default(),
+        };
+        // 受信バッファは `DRIVER_OK` の前に積み、通知は後でする
+        let rx_count = RX_BUFFERS.min(usize::from(nic.rx_queue.size()));
+        for slot in 0..rx_count {
+            if let Err(err) = nic.post_rx_buffer(slot) {
+                nic.transport.fail();
+                return Err(err);
+            }
+        }
+        nic.transport.finish_init();
+        nic.rx_queue.notify();
+        Ok(nic)
+    }
+
+    pub fn transport(&self) -> &Transport {
+        &self.transport
+    }
+
+    pub fn mergeable_rx_buffers(&self) -> bool {
+        self.features & FEATURE_MRG_RXBUF != 0
+    }
+
+    /// 受信バッファ `slot` を受信キューに積む
+    fn post_rx_buffer(&mut self, slot: usize) -> Result<(), VirtioError> {
+        let buffer = Buffer {
+            address: self.rx_buffers.phys + (slot * self.rx_buffer_size) as u64,
+            length: self.rx_buffer_size as u32,
+            device_writable: true,
+        };
+        let head = self.rx_queue.push(&[buffer])?;
+        self.rx_slots[usize::from(head)] = slot;
+        Ok(())
+    }
+
+    /// ヘッダを付けてフレームを送信キューに積む
+    fn send(&mut self, frame: &[u8], header: Header) -> Result<(), NetError> {
+        net::check_frame(frame)?;
+        if !self.link_up() {
+            return Err(NetError::LinkDown);
+        }
+        // 送り終わったバッファを取り戻す
+        while let Some((head, _)) = self.tx_queue.pop_used() {
+            self.tx_free.push(self.tx_slots[usize::from(head)]);
+        }
+        let slot = match self.tx_free.pop() {
+            Some(slot) => slot,
+            None => {
+                let (head, _) =
+                    self.tx_queue
+                        .wait_used(TRANSMIT_TIMEOUT)
+                        .map_err(|err| match err {
+                            VirtioError::Timeout => NetError::Timeout,
+                            err => NetError::Device(err.message()),
+                        })?;
+                self.tx_slots[usize::from(head)]
+            }
+        };
+
+        let offset = slot * BUFFER_SIZE;
+        unsafe {
+            let buffer = self.tx_buffers.as_mut_ptr::<u8>().add(offset);
+            buffer.cast::<Header>().write_volatile(header);
+            core::ptr::copy_nonoverlapping(frame.as_ptr(), buffer.add(HEADER_SIZE), frame.len());
+        }
+        let buffer = Buffer {
+            address: self.tx_buffers.phys + offset as u64,
+            length: (HEADER_SIZE + frame.len()) as u32,
+            device_writable: false,
+        };
+        let head = match self.tx_queue.push(&[buffer]) {
+            Ok(head) => head,
+            Err(err) => {
+                self.tx_free.push(slot);
+                return Err(NetError::Device(err.message()));
+            }
+        };
+        self.tx_slots[usize::from(head)] = slot;
+        self.tx_queue.notify();
+
+        self.stats.tx_packets += 1;
+        self.stats.tx_bytes += frame.len() as u64;
+        Ok(())
+    }
+
+    /// 使用済みリングから1つのフレームを `buffer` に集め、使った受信バッファを積み直す
+    ///
+    /// 入りきらなかったときも長さは数えて返す。取り出すものがなければ `None`、
+    /// ヘッダの数だけバッファがそろわなかったら `InvalidFrame`
+    fn collect_frame(&mut self, buffer: &mut [u8]) -> Option<Result<(Header, usize), NetError>> {
+        let (head, length) = self.rx_queue.pop_used()?;
+        let mut slot = self.rx_slots[usize::from(head)];
+        let mut length = length as usize;
+        let header = unsafe {
+            self.rx_buffers
+                .as_mut_ptr::<u8>()
+                .add(slot * self.rx_buffer_size)
+                .cast::<Header>()
+                .read_volatile()
+        };
+        let count = if self.mergeable_rx_buffers() {
+            header.num_buffers.max(1)
+        } else {
+            1
+        };
+
+        let mut total = 0;
+        // ヘッダは最初のバッファの先頭にだけある
+        let mut start = HEADER_SIZE;
+        for i in 0..count {
+            if i > 0 {
+                // フレームの残りのバッファは使用済みリングにまとめて置かれる
+                let Some((head, next_length)) = self.rx_queue.pop_used() else {
+                    self.rx_queue.notify();
+                    return Some(Err(NetError::InvalidFrame));
+                };
+                slot = self.rx_slots[usize::from(head)];
+                length = next_length as usize;
+                start = 0;
+            }
+            let length = length.clamp(start, self.rx_buffer_size);
+            let data_length = length - start;
+            if let Some(destination) = buffer.get_mut(total..total + data_length) {
+                let source = unsafe {
+                    self.rx_buffers
+                        .as_mut_ptr::<u8>()
+                        .add(slot * self.rx_buffer_size + start)
+                };
+                unsafe {
+                    core::ptr::copy_nonoverlapping(source, destination.as_mut_ptr(), data_length)
+                };
+            }
+            total += data_length;
+            // 受信キューには積んだ数だけ空きがあるので失敗しない
+            let posted = self.post_rx_buffer(slot);
+            debug_assert!(
+                posted.is_ok(),
+                "virtio-net: lost an rx buffer: {:?}",
+                posted
+            );
+        }
+        self.rx_queue.notify();
+        Some(Ok((header, total)))
+    }
+}
+
+/// デバイスがMACアドレスを持たないときに使う、ローカルに管理されたユニキャストのアドレス
+fn random_mac_address() -> MacAddress {
+    let [a, b, c, d, e, f, _, _] = time::rdtsc().to_le_bytes();
+    MacAddress([(a & 0xfe) | 0x02, b, c, d, e, f])
+}
+
+impl NetDevice for VirtioNet {
+    fn mac_address(&self) -> MacAddress {
+        self.mac
+    }
+
+    fn link_up(&self) -> bool {
+        // 状態を持たないデバイスのリンクは常につながっている
+        if self.features & FEATURE_STATUS == 0 {
+            return true;
+        }
+        self.transport
+            .read_config::<u16>(CONFIG_STATUS)
+            .is_none_or(|status| status & STATUS_LINK_UP != 0)
+    }
+
+    fn transmit(&mut self, frame: &[u8]) -> Result<(), NetError> {
+        self.send(frame, Header::default())
+    }
+
+    /// `FEATURE_CSUM` があればチェックサムの位置をヘッダでデバイスに伝える
+    fn transmit_with_checksum(
+        &mut self,
+        frame: &mut [u8],
+        checksum: ChecksumOffload,
+    ) -> Result<(), NetError> {
+        if self.features & FEATURE_CSUM == 0 {
+            net::complete_checksum(frame, checksum)?;
+            return self.transmit(frame);
+        }
+        if checksum.start + checksum.offset + 2 > frame.len() {
+            return Err(NetError::InvalidFrame);
+        }
+        let header = Header {
+            flags: HEADER_NEEDS_CSUM,
+            gso_type: GSO_NONE,
+            csum_start: checksum.start as u16,
+            csum_offset: checksum.offset as u16,
+            ..Header::default()
+        };
+        self.send(frame, header)
+    }
+
+    fn receive(&mut self, buffer: &mut [u8]) -> Result<Option<usize>, NetError> {
+        loop {
+            let (header, length) = match self.collect_frame(buffer) {
+                Some(Ok(frame)) => frame,
+                // 途中で切れたフレームは捨てて次を見る
+                Some(Err(_)) => {
+                    self.stats.rx_errors += 1;
+                    continue;
+                }
+                None => return Ok(None),
+            };
+            if length > buffer.len() {
+                self.stats.rx_errors += 1;
+                return Err(NetError::BufferTooSmall);
+            }
+            let frame = &mut buffer[..length];
+            // `FEATURE_GUEST_CSUM` のとき、同じホストの中から来たフレームは
+            // チェックサムが計算されていないことがあるので、ここで埋めて渡す
+            let valid = net::check_frame(frame).is_ok()
+                && (header.flags & HEADER_NEEDS_CSUM == 0
+                    || net::complete_checksum(
+                        frame,
+                        ChecksumOffload {
+                            start: usize::from(header.csum_start),
+                            offset: usize::from(header.csum_offset),
+                        },
+                    )
+                    .is_ok());
+            if !valid {
+                // 壊れたフレームは捨てて次を見る
+                self.stats.rx_errors += 1;
+                continue;
+            }
+            self.stats.rx_packets += 1;
+            self.stats.rx_bytes += length as u64;
+            return Ok(Some(length));
+        }
+    }
+
+    fn stats(&self) -> NetStats {
+        self.stats
+    }
+
+    fn description(&self) -> &str {
+        "virtio-net"
+    }
+}
+
+#[test_case]
+fn test_header_layout() {
+    assert_eq!(HEADER_SIZE, 12);
+    assert_eq!(core::mem::offset_of!(Header, csum_start), 6);
+    assert_eq!(core::mem::offset_of!(Header, num_buffers), 10);
+    // 最大のフレームは、分けて受け取るときは2つのバッファに、そうでなければ1つに入る
+    assert_eq!(
+        (HEADER_SIZE + net::MAX_FRAME_SIZE).div_ceil(MERGEABLE_RX_BUFFER_SIZE),
+        2
+    );
+    assert_eq!((HEADER_SIZE + net::MAX_FRAME_SIZE).div_ceil(BUFFER_SIZE), 1);
+}
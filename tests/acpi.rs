@@ -0,0 +1,67 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    acpi::init().expect("acpi initialization failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::acpi::{self, AcpiError, MadtEntry};
+
+#[test_case]
+fn all_tables_have_valid_checksums() {
+    let mut count = 0;
+    for table in acpi::tables().unwrap() {
+        assert!(table.valid, "{}", acpi::signature_str(&{ table.header.signature }));
+        count += 1;
+    }
+    assert!(count > 0);
+}
+
+#[test_case]
+fn madt_lists_the_boot_processor_and_ioapic() {
+    let madt = acpi::madt().unwrap();
+    assert!(madt.has_8259());
+    assert!(
+        madt.entries()
+            .any(|entry| matches!(entry, MadtEntry::LocalApic { .. }))
+    );
+    assert!(
+        madt.entries()
+            .any(|entry| matches!(entry, MadtEntry::IoApic { .. }))
+    );
+    assert_eq!(madt.local_apic_address().as_u64(), 0xfee0_0000);
+}
+
+#[test_case]
+fn fadt_points_to_the_dsdt() {
+    let fadt = acpi::fadt().unwrap();
+    let dsdt = acpi::table_bytes(fadt.dsdt_address()).unwrap();
+    assert_eq!(&dsdt[..4], b"DSDT");
+    // QEMUのSCIはIRQ9
+    assert_eq!({ fadt.sci_interrupt }, 9);
+}
+
+#[test_case]
+fn missing_table_is_reported() {
+    assert_eq!(
+        acpi::find_table(b"NONE").unwrap_err(),
+        AcpiError::TableNotFound(*b"NONE")
+    );
+}
@@ -0,0 +1,78 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    blog_os::ata::init();
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::ata::SECTOR_SIZE;
+use blog_os::block::{self, BlockError};
+
+#[test_case]
+fn boot_disk_has_a_boot_signature() {
+    // QEMUはブートイメージをプライマリのマスターにつなぐ
+    let disk = block::DEVICES.get("hda").expect("no boot disk");
+    let mut sector = [0u8; SECTOR_SIZE];
+    disk.lock().read_blocks(0, &mut sector).unwrap();
+    assert_eq!(&sector[510..], &[0x55, 0xaa]);
+}
+
+#[test_case]
+fn write_and_read_back_the_last_sector() {
+    let disk = block::DEVICES.get("hda").expect("no boot disk");
+    let mut disk = disk.lock();
+    let last = disk.block_count() - 1;
+
+    let mut original = [0u8; SECTOR_SIZE];
+    disk.read_blocks(last, &mut original).unwrap();
+    let mut pattern = [0u8; SECTOR_SIZE];
+    for (i, byte) in pattern.iter_mut().enumerate() {
+        *byte = i as u8 ^ 0x5a;
+    }
+    let mut read_back = [0u8; SECTOR_SIZE];
+    let written = disk
+        .write_blocks(last, &pattern)
+        .and_then(|()| disk.flush())
+        .and_then(|()| disk.read_blocks(last, &mut read_back));
+
+    // 確かめる前に元に戻し、失敗してもブートイメージを壊したままにしない
+    disk.write_blocks(last, &original).unwrap();
+    disk.flush().unwrap();
+    assert_eq!(written, Ok(()));
+    assert_eq!(read_back, pattern);
+}
+
+#[test_case]
+fn access_past_the_end_is_rejected() {
+    let disk = block::DEVICES.get("hda").expect("no boot disk");
+    let mut disk = disk.lock();
+    let count = disk.block_count();
+    let mut sectors = [0u8; SECTOR_SIZE * 2];
+    assert_eq!(
+        disk.read_blocks(count - 1, &mut sectors),
+        Err(BlockError::OutOfRange)
+    );
+    assert_eq!(
+        disk.read_blocks(0, &mut sectors[..100]),
+        Err(BlockError::InvalidBuffer)
+    );
+}
@@ -0,0 +1,160 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec;
+use alloc::vec::Vec;
+use blog_os::block::{BlockDevice, BlockError};
+use blog_os::block_cache::BlockCache;
+use spin::Mutex;
+
+const BLOCK_SIZE: usize = 512;
+
+/// 読み書きの回数を数えるメモリ上のデバイス
+struct CountingDevice {
+    data: Vec<u8>,
+    blocks_read: u64,
+    writes: usize,
+}
+
+impl CountingDevice {
+    fn new(blocks: usize) -> Arc<Mutex<CountingDevice>> {
+        Arc::new(Mutex::new(CountingDevice {
+            data: vec![0; blocks * BLOCK_SIZE],
+            blocks_read: 0,
+            writes: 0,
+        }))
+    }
+}
+
+impl BlockDevice for CountingDevice {
+    fn block_size(&self) -> usize {
+        BLOCK_SIZE
+    }
+
+    fn block_count(&self) -> u64 {
+        (self.data.len() / BLOCK_SIZE) as u64
+    }
+
+    fn read_blocks(&mut self, start: u64, buffer: &mut [u8]) -> Result<(), BlockError> {
+        blog_os::block::check_range(self, start, buffer.len())?;
+        let offset = start as usize * BLOCK_SIZE;
+        buffer.copy_from_slice(&self.data[offset..offset + buffer.len()]);
+        self.blocks_read += (buffer.len() / BLOCK_SIZE) as u64;
+        Ok(())
+    }
+
+    fn write_blocks(&mut self, start: u64, buffer: &[u8]) -> Result<(), BlockError> {
+        blog_os::block::check_range(self, start, buffer.len())?;
+        let offset = start as usize * BLOCK_SIZE;
+        self.data[offset..offset + buffer.len()].copy_from_slice(buffer);
+        self.writes += 1;
+        Ok(())
+    }
+}
+
+#[test_case]
+fn repeated_reads_hit_the_cache() {
+    let device = CountingDevice::new(64);
+    device.lock().data[BLOCK_SIZE] = 7;
+    let mut cache = BlockCache::new(device.clone(), 16 * BLOCK_SIZE);
+    let mut block = [0u8; BLOCK_SIZE];
+    cache.read_blocks(1, &mut block).unwrap();
+    cache.read_blocks(1, &mut block).unwrap();
+    assert_eq!(block[0], 7);
+    assert_eq!(device.lock().blocks_read, 1);
+    assert_eq!(cache.stats().hits, 1);
+}
+
+#[test_case]
+fn writes_stay_in_the_cache_until_sync() {
+    let device = CountingDevice::new(64);
+    let mut cache = BlockCache::new(device.clone(), 16 * BLOCK_SIZE);
+    cache.write_blocks(3, &[0xaa; BLOCK_SIZE * 2]).unwrap();
+    assert_eq!(device.lock().writes, 0);
+    assert_eq!(cache.stats().dirty, 2);
+
+    let mut block = [0u8; BLOCK_SIZE];
+    cache.read_blocks(4, &mut block).unwrap();
+    assert_eq!(block, [0xaa; BLOCK_SIZE]);
+
+    // 連続した汚れたブロックは1回で書き出す
+    cache.sync().unwrap();
+    assert_eq!(device.lock().writes, 1);
+    assert_eq!(device.lock().data[3 * BLOCK_SIZE], 0xaa);
+    assert_eq!(cache.stats().dirty, 0);
+}
+
+#[test_case]
+fn eviction_writes_back_dirty_blocks() {
+    let device = CountingDevice::new(64);
+    let mut cache = BlockCache::new(device.clone(), 4 * BLOCK_SIZE);
+    for number in 0..4 {
+        cache.write_blocks(number, &[number as u8 + 1; BLOCK_SIZE]).unwrap();
+    }
+    // 5つ目で一番古いブロック0が追い出される
+    cache.write_blocks(10, &[0xff; BLOCK_SIZE]).unwrap();
+    let stats = cache.stats();
+    assert_eq!(stats.cached, 4);
+    assert_eq!(stats.evicted, 1);
+    assert_eq!(device.lock().data[0], 1);
+    assert_eq!(device.lock().data[BLOCK_SIZE], 0);
+}
+
+#[test_case]
+fn sequential_reads_trigger_read_ahead() {
+    let device = CountingDevice::new(64);
+    let mut cache = BlockCache::new(device.clone(), 32 * BLOCK_SIZE);
+    let mut block = [0u8; BLOCK_SIZE];
+    cache.read_blocks(0, &mut block).unwrap();
+    cache.read_blocks(1, &mut block).unwrap();
+    assert!(cache.stats().read_ahead > 0);
+
+    // 先読みしたブロックはキャッシュから返る
+    let misses = cache.stats().misses;
+    cache.read_blocks(2, &mut block).unwrap();
+    assert_eq!(cache.stats().misses, misses);
+
+    // 飛び飛びに読むと先読みしない
+    let blocks_read = device.lock().blocks_read;
+    cache.read_blocks(40, &mut block).unwrap();
+    assert_eq!(device.lock().blocks_read, blocks_read + 1);
+}
+
+#[test_case]
+fn large_reads_stay_within_budget() {
+    let device = CountingDevice::new(64);
+    let mut cache = BlockCache::new(device.clone(), 4 * BLOCK_SIZE);
+    let mut buffer = [0u8; 16 * BLOCK_SIZE];
+    cache.read_blocks(0, &mut buffer).unwrap();
+    // 入りきらない分はキャッシュせず、読み直しもしない
+    assert_eq!(cache.stats().cached, 4);
+    assert_eq!(device.lock().blocks_read, 16);
+
+    // 後ろの方がキャッシュに残る
+    let mut block = [0u8; BLOCK_SIZE];
+    cache.read_blocks(15, &mut block).unwrap();
+    assert_eq!(device.lock().blocks_read, 16);
+}
@@ -0,0 +1,154 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(TmpFs::default())).expect("mounting the root failed");
+    chardev::init();
+    blog_os::devfs::init().expect("mounting /dev failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use blog_os::chardev::{self, CharDevice};
+use blog_os::tmpfs::TmpFs;
+use blog_os::vfs::{self, FileType, FsError, OpenFlags};
+
+#[test_case]
+fn devices_are_listed() {
+    let names: Vec<_> = vfs::readdir("/dev")
+        .unwrap()
+        .into_iter()
+        .map(|entry| {
+            assert_eq!(entry.file_type, FileType::CharDevice);
+            entry.name
+        })
+        .collect();
+    for name in ["console", "ttyS0", "ttyS1", "ttyS2", "ttyS3", "kbd", "null", "zero", "random"] {
+        assert!(names.iter().any(|existing| existing == name), "{}", name);
+    }
+    let metadata = vfs::stat("/dev/null").unwrap();
+    assert_eq!(metadata.file_type, FileType::CharDevice);
+    assert_eq!(vfs::stat("/dev/missing").err(), Some(FsError::NotFound));
+    assert_eq!(vfs::mkdir("/dev/dir"), Err(FsError::ReadOnly));
+}
+
+#[test_case]
+fn null_zero_and_random() {
+    vfs::write_file("/dev/null", b"discarded").unwrap();
+    assert_eq!(vfs::read_file("/dev/null").unwrap(), b"");
+
+    let mut buffer = [0xffu8; 100];
+    let fd = vfs::open("/dev/zero", OpenFlags::READ).unwrap();
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(100));
+    assert!(buffer.iter().all(|&byte| byte == 0));
+    vfs::close(fd).unwrap();
+
+    let fd = vfs::open("/dev/random", OpenFlags::READ_WRITE).unwrap();
+    let (mut first, mut second) = ([0u8; 13], [0u8; 13]);
+    assert_eq!(vfs::read(fd, &mut first), Ok(13));
+    assert_eq!(vfs::write(fd, b"seed"), Ok(4));
+    assert_eq!(vfs::read(fd, &mut second), Ok(13));
+    assert_ne!(first, second);
+    assert_ne!(first, [0; 13]);
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn serial_ports() {
+    let fd = vfs::open("/dev/ttyS0", OpenFlags::READ_WRITE).unwrap();
+    assert_eq!(vfs::write(fd, b"hello from /dev/ttyS0\n"), Ok(22));
+    assert_eq!(vfs::ioctl(fd, chardev::SET_NONBLOCKING, 1), Ok(0));
+    let mut buffer = [0u8; 16];
+    if vfs::ioctl(fd, chardev::INPUT_READY, 0) == Ok(0) {
+        assert_eq!(vfs::read(fd, &mut buffer), Err(FsError::WouldBlock));
+    }
+    // 115200を割り切れない速さは設定できない
+    assert_eq!(
+        vfs::ioctl(fd, chardev::TTY_SET_BAUD_RATE, 7),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(vfs::ioctl(fd, chardev::TTY_SET_BAUD_RATE, 38400), Ok(0));
+    assert_eq!(vfs::ioctl(fd, 0xdead, 0), Err(FsError::NotSupported));
+    vfs::close(fd).unwrap();
+
+    // QEMUはCOM1しか持たないので、COM4はすぐに終わる
+    let fd = vfs::open("/dev/ttyS3", OpenFlags::READ).unwrap();
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(0));
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn keyboard_and_console() {
+    let fd = vfs::open("/dev/kbd", OpenFlags::READ_WRITE).unwrap();
+    assert_eq!(vfs::ioctl(fd, chardev::SET_NONBLOCKING, 1), Ok(0));
+    let mut buffer = [0u8; chardev::KEY_RECORD_SIZE * 4];
+    assert_eq!(vfs::read(fd, &mut buffer), Err(FsError::WouldBlock));
+    assert_eq!(vfs::read(fd, &mut buffer[..3]), Err(FsError::InvalidArgument));
+    assert_eq!(vfs::write(fd, b"x"), Err(FsError::NotSupported));
+    // Caps LockとNum Lockを点ける
+    assert_eq!(vfs::ioctl(fd, chardev::KBD_SET_LEDS, 0b110), Ok(0));
+    assert_eq!(vfs::ioctl(fd, chardev::KBD_GET_MODIFIERS, 0), Ok(0b1100_0000));
+    assert_eq!(vfs::ioctl(fd, chardev::KBD_SET_LEDS, 0b010), Ok(0));
+    vfs::close(fd).unwrap();
+
+    let fd = vfs::open("/dev/console", OpenFlags::READ_WRITE).unwrap();
+    assert_eq!(vfs::write(fd, b"hello from /dev/console\n"), Ok(24));
+    assert_eq!(vfs::ioctl(fd, chardev::SET_NONBLOCKING, 1), Ok(0));
+    assert_eq!(vfs::ioctl(fd, chardev::INPUT_READY, 0), Ok(0));
+    assert_eq!(vfs::read(fd, &mut buffer), Err(FsError::WouldBlock));
+    assert_eq!(
+        vfs::ioctl(fd, chardev::CONSOLE_SWITCH, 99),
+        Err(FsError::InvalidArgument)
+    );
+    vfs::close(fd).unwrap();
+}
+
+/// 名前で登録したデバイスがそのまま `/dev` に現れる
+struct Loopback(spin::Mutex<Vec<u8>>);
+
+impl CharDevice for Loopback {
+    fn read(&self, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let mut data = self.0.lock();
+        let count = buffer.len().min(data.len());
+        buffer[..count].copy_from_slice(&data[..count]);
+        data.drain(..count);
+        Ok(count)
+    }
+
+    fn write(&self, data: &[u8]) -> Result<usize, FsError> {
+        self.0.lock().extend_from_slice(data);
+        Ok(data.len())
+    }
+}
+
+#[test_case]
+fn registered_devices_appear() {
+    chardev::DEVICES.register("loop", Arc::new(Loopback(spin::Mutex::new(Vec::new()))));
+    vfs::write_file("/dev/loop", b"round trip").unwrap();
+    assert_eq!(vfs::read_file("/dev/loop").unwrap(), b"round trip");
+
+    // デバイスではないファイルはioctlを持たない
+    vfs::write_file("/plain", b"").unwrap();
+    let fd = vfs::open("/plain", OpenFlags::READ).unwrap();
+    assert_eq!(vfs::ioctl(fd, chardev::INPUT_READY, 0), Err(FsError::NotSupported));
+    vfs::close(fd).unwrap();
+}
@@ -0,0 +1,115 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    blog_os::acpi::init().expect("acpi initialization failed");
+    pci::init();
+    pci::register_driver(&blog_os::e1000::DRIVER);
+    pci::probe_drivers();
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::interrupts;
+use blog_os::net::{self, MacAddress, NetError, SharedNetDevice};
+use blog_os::pci;
+use blog_os::time::Duration;
+
+/// QEMUのユーザーモードネットワークでのこのマシンとゲートウェイのアドレス
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+fn nic() -> SharedNetDevice {
+    net::DEVICES.entries()
+        .into_iter()
+        .find(|(_, device)| device.lock().description() == "e1000")
+        .map(|(_, device)| device)
+        .expect("no e1000 device")
+}
+
+/// `target` のMACアドレスを尋ねるARP要求
+fn arp_request(source: MacAddress, target: [u8; 4]) -> [u8; 42] {
+    let mut frame = [0u8; 42];
+    frame[0..6].copy_from_slice(&MacAddress::BROADCAST.0);
+    frame[6..12].copy_from_slice(&source.0);
+    frame[12..14].copy_from_slice(&[0x08, 0x06]);
+    // Ethernet / IPv4、アドレス長6と4、要求
+    frame[14..22].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    frame[22..28].copy_from_slice(&source.0);
+    frame[28..32].copy_from_slice(&GUEST_IP);
+    frame[38..42].copy_from_slice(&target);
+    frame
+}
+
+#[test_case]
+fn mac_address_and_link() {
+    let nic = nic();
+    let nic = nic.lock();
+    // QEMUが最初のNICに付ける既定のアドレス
+    assert_eq!(nic.mac_address(), MacAddress([0x52, 0x54, 0x00, 0x12, 0x34, 0x56]));
+    assert!(nic.link_up());
+}
+
+#[test_case]
+fn gateway_answers_arp() {
+    let nic = nic();
+    let irq = pci::devices()
+        .into_iter()
+        .find(|(_, driver)| *driver == Some("e1000"))
+        .map(|(device, _)| usize::from(device.interrupt_line))
+        .unwrap();
+    let interrupts_before = interrupts::irq_count(irq);
+
+    let mac = nic.lock().mac_address();
+    nic.lock().transmit(&arp_request(mac, GATEWAY_IP)).unwrap();
+
+    let mut frame = [0u8; net::MAX_FRAME_SIZE];
+    let reply = loop {
+        let length = net::receive_timeout(&nic, &mut frame, Duration::from_secs(2))
+            .unwrap()
+            .expect("no ARP reply from the gateway");
+        let frame = &frame[..length];
+        // ARPの応答 (operation 2) で、送信元がゲートウェイのもの
+        if frame[12..14] == [0x08, 0x06] && frame[20..22] == [0, 2] && frame[28..32] == GATEWAY_IP
+        {
+            break length;
+        }
+    };
+    assert!(reply >= 42);
+    assert_eq!(frame[0..6], mac.0);
+    assert_eq!(frame[32..38], mac.0);
+
+    let stats = nic.lock().stats();
+    assert!(stats.tx_packets >= 1);
+    assert!(stats.rx_packets >= 1);
+    // 受信は割り込みで知らされる
+    assert!(interrupts::irq_count(irq) > interrupts_before);
+}
+
+#[test_case]
+fn invalid_frames_are_rejected() {
+    let nic = nic();
+    let mut nic = nic.lock();
+    assert_eq!(nic.transmit(&[0; 10]), Err(NetError::InvalidFrame));
+    assert_eq!(
+        nic.transmit(&[0; net::MAX_FRAME_SIZE + 1]),
+        Err(NetError::InvalidFrame)
+    );
+}
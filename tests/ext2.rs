@@ -0,0 +1,127 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(TmpFs::default())).expect("mounting the root failed");
+    if IMAGE.is_empty() {
+        blog_os::serial_println!("ext2: no image (mke2fs was not found at build time), skipping");
+        blog_os::exit_qemu(blog_os::QemuExitCode::Success);
+    } else {
+        let device: SharedBlockDevice = Arc::new(Mutex::new(RamDisk::from_image(512, IMAGE).unwrap()));
+        vfs::mount_at("/ext2", Arc::new(Ext2Fs::new(device).unwrap())).unwrap();
+        test_main();
+    }
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use blog_os::block::SharedBlockDevice;
+use blog_os::ext2::{self, Ext2Fs};
+use blog_os::ramdisk::RamDisk;
+use blog_os::tmpfs::TmpFs;
+use blog_os::vfs::{self, FileType, FsError};
+use spin::Mutex;
+
+/// `build.rs` が `mke2fs` で作ったイメージ
+static IMAGE: &[u8] = include_bytes!(concat!(env!("OUT_DIR"), "/ext2.img"));
+
+fn names(path: &str) -> Vec<String> {
+    vfs::readdir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn superblock_is_parsed() {
+    let device: SharedBlockDevice = Arc::new(Mutex::new(RamDisk::from_image(512, IMAGE).unwrap()));
+    assert!(ext2::detect(&device));
+    let fs = Ext2Fs::new(device).unwrap();
+    assert_eq!(fs.label(), "blog_os");
+    assert_eq!(fs.block_size(), 1024);
+}
+
+#[test_case]
+fn directories_are_walked() {
+    assert_eq!(
+        names("/ext2"),
+        [
+            "big.bin",
+            "dir",
+            "dir_link",
+            "hello.txt",
+            "link",
+            "long_link",
+            "lost+found",
+            "many",
+            "sparse.bin"
+        ]
+    );
+    let many = names("/ext2/many");
+    assert_eq!(many.len(), 100);
+    assert_eq!(many[42], "file042.txt");
+    assert_eq!(vfs::read_file("/ext2/many/file099.txt").unwrap(), b"99\n");
+    assert_eq!(
+        vfs::stat("/ext2/dir/nested").unwrap().file_type,
+        FileType::Directory
+    );
+    assert_eq!(vfs::read_file("/ext2/dir/nested/deep.txt").unwrap(), b"deep\n");
+}
+
+#[test_case]
+fn files_are_read_through_indirect_blocks() {
+    assert_eq!(vfs::read_file("/ext2/hello.txt").unwrap(), b"Hello from ext2!\n");
+    // 1KiBのブロックで300,000バイトなら二重間接ブロックまで使う
+    let big = vfs::read_file("/ext2/big.bin").unwrap();
+    assert_eq!(big.len(), 300_000);
+    assert!(
+        big.iter()
+            .enumerate()
+            .all(|(i, &byte)| byte == ((i * 7 + i / 1024) % 251) as u8)
+    );
+    // 穴は0として読める
+    let sparse = vfs::read_file("/ext2/sparse.bin").unwrap();
+    assert_eq!(sparse.len(), 200_003);
+    assert_eq!(&sparse[..5], b"start");
+    assert!(sparse[5..200_000].iter().all(|&byte| byte == 0));
+    assert_eq!(&sparse[200_000..], b"end");
+}
+
+#[test_case]
+fn symlinks_are_followed() {
+    assert_eq!(vfs::readlink("/ext2/link").unwrap(), "hello.txt");
+    assert_eq!(vfs::lstat("/ext2/link").unwrap().file_type, FileType::Symlink);
+    assert_eq!(vfs::read_file("/ext2/link").unwrap(), b"Hello from ext2!\n");
+    assert_eq!(names("/ext2/dir_link"), ["deep.txt"]);
+    // 60バイトを超えるリンクの先はブロックに置かれる
+    let target = vfs::readlink("/ext2/long_link").unwrap();
+    assert!(target.len() > 60);
+    assert_eq!(vfs::read_file("/ext2/long_link").unwrap(), b"deep\n");
+}
+
+#[test_case]
+fn writes_are_rejected() {
+    assert_eq!(vfs::write_file("/ext2/new", b"x"), Err(FsError::ReadOnly));
+    assert_eq!(vfs::mkdir("/ext2/new"), Err(FsError::ReadOnly));
+    assert_eq!(vfs::unlink("/ext2/hello.txt"), Err(FsError::ReadOnly));
+    assert_eq!(vfs::truncate("/ext2/hello.txt", 0), Err(FsError::ReadOnly));
+}
@@ -0,0 +1,206 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(TmpFs::default())).expect("mounting the root failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::format;
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use blog_os::block::SharedBlockDevice;
+use blog_os::fat::{self, FatFs, FatType};
+use blog_os::ramdisk::RamDisk;
+use blog_os::tmpfs::TmpFs;
+use blog_os::vfs::{self, FileType, FsError, OpenFlags, SeekFrom};
+use spin::Mutex;
+
+/// `blocks` ブロックのRAMディスクをフォーマットして `path` にマウントする
+fn mount_new(path: &str, blocks: u64, fat_type: Option<FatType>) -> SharedBlockDevice {
+    let device: SharedBlockDevice = Arc::new(Mutex::new(RamDisk::new(512, blocks)));
+    fat::format(&device, fat_type).unwrap();
+    vfs::mount_at(path, Arc::new(FatFs::new(device.clone()).unwrap())).unwrap();
+    device
+}
+
+/// 外してから読み直し、ディスクに書かれた内容だけが残ることを確かめる
+fn remount(path: &str, device: &SharedBlockDevice) {
+    vfs::unmount(path).unwrap();
+    vfs::mount(path, Arc::new(FatFs::new(device.clone()).unwrap())).unwrap();
+}
+
+fn names(path: &str) -> Vec<String> {
+    vfs::readdir(path)
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect()
+}
+
+#[test_case]
+fn fat12_keeps_files_and_long_names() {
+    let device = mount_new("/fat12", 2048, None);
+    vfs::write_file("/fat12/hello.txt", b"hello").unwrap();
+    vfs::write_file("/fat12/A long file name.text", b"long").unwrap();
+    vfs::write_file("/fat12/MixedCase.Txt", b"mixed").unwrap();
+    vfs::mkdir("/fat12/docs").unwrap();
+    vfs::write_file("/fat12/docs/日本語.txt", b"unicode").unwrap();
+    assert_eq!(
+        vfs::write_file("/fat12/bad?name", b""),
+        Err(FsError::InvalidPath)
+    );
+
+    remount("/fat12", &device);
+    assert_eq!(
+        names("/fat12"),
+        ["A long file name.text", "MixedCase.Txt", "docs", "hello.txt"]
+    );
+    assert_eq!(vfs::read_file("/fat12/hello.txt").unwrap(), b"hello");
+    // 名前は大文字小文字を区別せず、短い名前でも引ける
+    assert_eq!(vfs::read_file("/fat12/HELLO.TXT").unwrap(), b"hello");
+    assert_eq!(vfs::read_file("/fat12/ALONGF~1.TEX").unwrap(), b"long");
+    assert_eq!(vfs::read_file("/fat12/docs/日本語.txt").unwrap(), b"unicode");
+    assert_eq!(
+        vfs::stat("/fat12/docs").unwrap().file_type,
+        FileType::Directory
+    );
+    assert_eq!(
+        vfs::symlink("hello.txt", "/fat12/link"),
+        Err(FsError::NotSupported)
+    );
+
+    // 別の綴りで覚えたキャッシュも、消して作り直せば新しいファイルを指す
+    vfs::stat("/fat12/HELLO.TXT").unwrap();
+    vfs::unlink("/fat12/hello.txt").unwrap();
+    assert_eq!(vfs::stat("/fat12/HELLO.TXT").err(), Some(FsError::NotFound));
+    vfs::write_file("/fat12/hello.txt", b"again").unwrap();
+    assert_eq!(vfs::read_file("/fat12/HELLO.TXT").unwrap(), b"again");
+    vfs::unmount("/fat12").unwrap();
+}
+
+#[test_case]
+fn fat16_files_grow_and_shrink() {
+    let device = mount_new("/fat16", 32 * 2048, Some(FatType::Fat16));
+    let contents: Vec<u8> = (0..100_000u32).map(|i| (i % 251) as u8).collect();
+    vfs::write_file("/fat16/data.bin", &contents).unwrap();
+    assert_eq!(vfs::read_file("/fat16/data.bin").unwrap(), contents);
+
+    let fs = FatFs::new(device.clone()).unwrap();
+    assert_eq!(fs.fat_type(), FatType::Fat16);
+    let (_, cluster_size) = fs.clusters();
+    let free_before = fs.free_clusters().unwrap();
+    vfs::truncate("/fat16/data.bin", 1000).unwrap();
+    let free_after = FatFs::new(device.clone()).unwrap().free_clusters().unwrap();
+    assert_eq!(
+        u64::from(free_after - free_before),
+        100_000u64.div_ceil(cluster_size) - 1000u64.div_ceil(cluster_size)
+    );
+
+    // 終わりを越えて書くと間は0で埋まる
+    let fd = vfs::open("/fat16/data.bin", OpenFlags::READ_WRITE).unwrap();
+    vfs::seek(fd, SeekFrom::Start(5000)).unwrap();
+    vfs::write(fd, b"end").unwrap();
+    vfs::close(fd).unwrap();
+    remount("/fat16", &device);
+    let data = vfs::read_file("/fat16/data.bin").unwrap();
+    assert_eq!(data.len(), 5003);
+    assert_eq!(data[..1000], contents[..1000]);
+    assert!(data[1000..5000].iter().all(|&byte| byte == 0));
+    assert_eq!(&data[5000..], b"end");
+    vfs::unmount("/fat16").unwrap();
+}
+
+#[test_case]
+fn fat32_directories_grow_and_move() {
+    let device = mount_new("/fat32", 64 * 2048, Some(FatType::Fat32));
+    vfs::mkdir("/fat32/many").unwrap();
+    for i in 0..100 {
+        let path = format!("/fat32/many/file with a long name {}", i);
+        vfs::write_file(&path, path.as_bytes()).unwrap();
+    }
+    vfs::mkdir("/fat32/a").unwrap();
+    vfs::mkdir("/fat32/a/b").unwrap();
+    vfs::write_file("/fat32/a/b/inner", b"inner").unwrap();
+    vfs::rename("/fat32/a/b", "/fat32/b").unwrap();
+    vfs::rename("/fat32/many/file with a long name 7", "/fat32/b/seven").unwrap();
+    assert_eq!(
+        vfs::rename("/fat32/b", "/fat32/b/c"),
+        Err(FsError::InvalidArgument)
+    );
+    assert_eq!(vfs::rmdir("/fat32/b"), Err(FsError::NotEmpty));
+
+    remount("/fat32", &device);
+    assert_eq!(vfs::readdir("/fat32/many").unwrap().len(), 99);
+    assert_eq!(
+        vfs::read_file("/fat32/many/file with a long name 42").unwrap(),
+        b"/fat32/many/file with a long name 42"
+    );
+    assert_eq!(names("/fat32/b"), ["inner", "seven"]);
+    assert!(names("/fat32/a").is_empty());
+    vfs::rmdir("/fat32/a").unwrap();
+    vfs::unlink("/fat32/b/inner").unwrap();
+    vfs::unlink("/fat32/b/seven").unwrap();
+    vfs::rmdir("/fat32/b").unwrap();
+    assert_eq!(names("/fat32"), ["many"]);
+    vfs::unmount("/fat32").unwrap();
+}
+
+#[test_case]
+fn format_rejects_impossible_types() {
+    let device: SharedBlockDevice = Arc::new(Mutex::new(RamDisk::new(512, 2048)));
+    assert_eq!(
+        fat::format(&device, Some(FatType::Fat32)),
+        Err(FsError::InvalidArgument)
+    );
+    assert!(FatFs::new(device).is_err());
+}
+
+#[test_case]
+fn invalid_first_clusters_are_corrupted() {
+    let device = mount_new("/fatbad", 2048, None);
+    vfs::write_file("/fatbad/a.txt", b"data").unwrap();
+    vfs::unmount("/fatbad").unwrap();
+
+    // ディレクトリエントリの最初のクラスタを1に書き換える
+    let mut disk = device.lock();
+    let mut block = [0u8; 512];
+    let entry = (0..disk.block_count())
+        .find_map(|number| {
+            disk.read_blocks(number, &mut block).unwrap();
+            let offset = block
+                .chunks(32)
+                .position(|entry| &entry[..11] == b"A       TXT")?;
+            Some((number, offset * 32))
+        })
+        .expect("no directory entry");
+    block[entry.1 + 26..entry.1 + 28].copy_from_slice(&1u16.to_le_bytes());
+    disk.write_blocks(entry.0, &block).unwrap();
+    drop(disk);
+
+    vfs::mount("/fatbad", Arc::new(FatFs::new(device.clone()).unwrap())).unwrap();
+    assert!(matches!(
+        vfs::read_file("/fatbad/a.txt"),
+        Err(FsError::Corrupted(_))
+    ));
+    vfs::unmount("/fatbad").unwrap();
+}
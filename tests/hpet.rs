@@ -0,0 +1,84 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+
+    blog_os::acpi::init().expect("acpi initialization failed");
+    hpet::init().expect("hpet initialization failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::time::{self, ClockSource, Duration, Instant};
+use blog_os::{hpet, interrupts, rtc};
+
+#[test_case]
+fn counter_runs() {
+    let info = hpet::info().unwrap();
+    // QEMUのHPETは100MHz
+    assert!(info.frequency() >= 10_000_000);
+    let before = hpet::counter().unwrap();
+    hpet::busy_wait(Duration::from_micros(100)).unwrap();
+    assert!(hpet::counter().unwrap() > before);
+}
+
+#[test_case]
+fn busy_wait_agrees_with_tsc() {
+    let start = Instant::now();
+    hpet::busy_wait(Duration::from_millis(20)).unwrap();
+    let elapsed = start.elapsed();
+    assert!(elapsed >= Duration::from_millis(18), "{:?}", elapsed);
+    assert!(elapsed < Duration::from_millis(40), "{:?}", elapsed);
+}
+
+#[test_case]
+fn calibrates_the_tsc() {
+    let before = Instant::now();
+    let frequency = time::calibrate_with_hpet().unwrap();
+    assert_eq!(time::tsc_frequency(), Some(frequency));
+    assert_ne!(time::calibration_source(), Some(ClockSource::Pit));
+    // 較正し直しても時刻は戻らない
+    assert!(Instant::now() >= before);
+}
+
+#[test_case]
+fn drives_the_timer_interrupt() {
+    hpet::use_as_system_timer().unwrap();
+    let before = interrupts::ticks();
+    hpet::busy_wait(Duration::from_millis(100)).unwrap();
+    // 100Hzで100ms待てば10回前後のはず
+    let ticks = interrupts::ticks() - before;
+    assert!((5..=15).contains(&ticks), "{} ticks", ticks);
+    // IRQ8もHPETのものになったので、RTCの周期割り込みは断られる
+    assert!(hpet::legacy_replacement_enabled());
+    assert_eq!(
+        rtc::enable_periodic_interrupt(6),
+        Err(rtc::RtcError::IrqInUse)
+    );
+}
+
+#[test_case]
+fn rejects_missing_timer() {
+    let timers = hpet::info().unwrap().timers;
+    assert_eq!(
+        hpet::stop_timer(timers),
+        Err(hpet::HpetError::NoSuchTimer(timers))
+    );
+}
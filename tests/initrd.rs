@@ -0,0 +1,180 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(TmpFs::default())).expect("mounting the root failed");
+    initrd::init().expect("mounting the initrd failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use blog_os::initrd::{self, InitrdFs};
+use blog_os::tmpfs::TmpFs;
+use blog_os::vfs::{self, FileType, FsError, OpenFlags};
+
+/// USTARのヘッダ1つとデータを `archive` に足す
+fn push_tar(archive: &mut Vec<u8>, name: &str, kind: u8, link: &str, data: &[u8]) {
+    let mut header = [0u8; 512];
+    header[..name.len()].copy_from_slice(name.as_bytes());
+    header[100..107].copy_from_slice(b"0000644");
+    header[124..135].copy_from_slice(alloc::format!("{:011o}", data.len()).as_bytes());
+    header[136..147].copy_from_slice(b"00000000000");
+    header[156] = kind;
+    header[157..157 + link.len()].copy_from_slice(link.as_bytes());
+    header[257..263].copy_from_slice(b"ustar\0");
+    header[263..265].copy_from_slice(b"00");
+    header[148..156].fill(b' ');
+    let sum: u32 = header.iter().map(|&byte| u32::from(byte)).sum();
+    header[148..155].copy_from_slice(alloc::format!("{:06o}\0", sum).as_bytes());
+    archive.extend_from_slice(&header);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(512), 0);
+}
+
+/// cpio (newc) のエントリ1つを `archive` に足す
+fn push_cpio(archive: &mut Vec<u8>, name: &str, mode: u32, data: &[u8]) {
+    let fields = [
+        0,
+        mode,
+        0,
+        0,
+        1,
+        0,
+        data.len() as u32,
+        0,
+        0,
+        0,
+        0,
+        name.len() as u32 + 1,
+        0,
+    ];
+    archive.extend_from_slice(b"070701");
+    for field in fields {
+        archive.extend_from_slice(alloc::format!("{:08x}", field).as_bytes());
+    }
+    archive.extend_from_slice(name.as_bytes());
+    archive.push(0);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+    archive.extend_from_slice(data);
+    archive.resize(archive.len().next_multiple_of(4), 0);
+}
+
+#[test_case]
+fn embedded_archive_is_mounted() {
+    assert_eq!(
+        vfs::stat("/initrd/etc").unwrap().file_type,
+        FileType::Directory
+    );
+    assert_eq!(
+        vfs::read_file("/initrd/etc/motd").unwrap(),
+        b"Welcome to blog_os!\n"
+    );
+    assert!(
+        vfs::mounts()
+            .iter()
+            .any(|(path, name)| path == "/initrd" && *name == "initrd")
+    );
+}
+
+#[test_case]
+fn tar_archives_are_parsed() {
+    let mut archive = Vec::new();
+    // 親のディレクトリはアーカイブになくても作られる
+    push_tar(&mut archive, "usr/share/doc/readme", b'0', "", b"read me");
+    push_tar(&mut archive, "usr/share/", b'5', "", b"");
+    push_tar(&mut archive, "usr/readme", b'2', "share/doc/readme", b"");
+    archive.resize(archive.len() + 1024, 0);
+    let fs = InitrdFs::parse(archive.leak()).unwrap();
+
+    vfs::mount_at("/tar", Arc::new(fs)).unwrap();
+    assert_eq!(
+        vfs::read_file("/tar/usr/share/doc/readme").unwrap(),
+        b"read me"
+    );
+    assert_eq!(vfs::read_file("/tar/usr/readme").unwrap(), b"read me");
+    assert_eq!(
+        vfs::readlink("/tar/usr/readme").unwrap(),
+        "share/doc/readme"
+    );
+    let names: Vec<_> = vfs::readdir("/tar/usr")
+        .unwrap()
+        .into_iter()
+        .map(|e| e.name)
+        .collect();
+    assert_eq!(names, ["readme", "share"]);
+
+    // 読み出し専用
+    assert_eq!(
+        vfs::write_file("/tar/usr/new", b"x"),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(vfs::unlink("/tar/usr/readme"), Err(FsError::ReadOnly));
+    // 書き込み用には開けないので、ファイルが開いたままアンマウントできなくなることもない
+    assert_eq!(
+        vfs::open("/tar/usr/share/doc/readme", OpenFlags::WRITE),
+        Err(FsError::ReadOnly)
+    );
+    vfs::unmount("/tar").unwrap();
+}
+
+#[test_case]
+fn cpio_archives_are_parsed() {
+    let mut archive = Vec::new();
+    push_cpio(&mut archive, ".", 0o040755, b"");
+    push_cpio(&mut archive, "bin", 0o040755, b"");
+    push_cpio(&mut archive, "bin/init", 0o100755, b"#!init");
+    push_cpio(&mut archive, "sbin", 0o120777, b"bin");
+    push_cpio(&mut archive, "TRAILER!!!", 0, b"");
+    let fs = InitrdFs::parse(archive.leak()).unwrap();
+
+    vfs::mount_at("/cpio", Arc::new(fs)).unwrap();
+    assert_eq!(vfs::read_file("/cpio/sbin/init").unwrap(), b"#!init");
+    assert_eq!(vfs::stat("/cpio/bin/init").unwrap().mode, 0o755);
+    assert_eq!(
+        vfs::lstat("/cpio/sbin").unwrap().file_type,
+        FileType::Symlink
+    );
+    vfs::unmount("/cpio").unwrap();
+}
+
+#[test_case]
+fn damaged_archives_are_rejected() {
+    let mut archive = Vec::new();
+    push_tar(&mut archive, "file", b'0', "", b"data");
+    archive[0] = b'F';
+    assert!(matches!(
+        InitrdFs::parse(archive.leak()),
+        Err(FsError::Corrupted(_))
+    ));
+
+    let mut archive = Vec::new();
+    push_cpio(&mut archive, "file", 0o100644, b"data");
+    // TRAILER!!! がないまま終わる
+    assert!(matches!(
+        InitrdFs::parse(archive.leak()),
+        Err(FsError::Corrupted(_))
+    ));
+    assert!(matches!(
+        InitrdFs::parse(b"not an archive"),
+        Err(FsError::Corrupted(_))
+    ));
+}
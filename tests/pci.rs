@@ -0,0 +1,88 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    blog_os::acpi::init().expect("acpi initialization failed");
+    pci::init();
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::pci::{self, Bar, PciAddress, PciDevice, PciDriver, PciError, PciMatch};
+use core::sync::atomic::{AtomicUsize, Ordering};
+
+fn find(class: u8, subclass: u8) -> Option<PciDevice> {
+    pci::devices()
+        .into_iter()
+        .map(|(device, _)| device)
+        .find(|device| device.class == class && device.subclass == subclass)
+}
+
+#[test_case]
+fn host_bridge_is_at_the_root() {
+    let host = find(0x06, 0x00).expect("no host bridge");
+    assert_eq!(host.address, PciAddress::new(0, 0, 0, 0));
+    assert_eq!(host.vendor_id, 0x8086);
+}
+
+#[test_case]
+fn vga_has_a_framebuffer_bar() {
+    // QEMUの標準VGAはBAR0がフレームバッファ
+    let vga = find(0x03, 0x00).expect("no VGA controller");
+    match vga.bar(0) {
+        Ok(Bar::Memory { size, .. }) => assert!(size >= 0x10_0000),
+        other => panic!("unexpected BAR0: {:?}", other),
+    }
+    assert_eq!(vga.map_bar(5), Err(PciError::NoSuchBar(5)));
+}
+
+#[test_case]
+fn config_space_reads_match_the_scan() {
+    for (device, _) in pci::devices() {
+        assert_eq!(pci::read_config_u16(device.address, 0), device.vendor_id);
+    }
+}
+
+static PROBED: AtomicUsize = AtomicUsize::new(0);
+
+static BRIDGE_DRIVER: PciDriver = PciDriver {
+    name: "test-bridge",
+    matches: &[PciMatch::class(0x06, 0x00)],
+    probe: |_| {
+        PROBED.fetch_add(1, Ordering::Relaxed);
+        Ok(())
+    },
+};
+
+#[test_case]
+fn drivers_bind_to_matching_devices() {
+    pci::register_driver(&BRIDGE_DRIVER);
+    pci::probe_drivers();
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+    let (_, driver) = pci::devices()
+        .into_iter()
+        .find(|(device, _)| device.address == PciAddress::new(0, 0, 0, 0))
+        .unwrap();
+    assert_eq!(driver, Some("test-bridge"));
+
+    // 結びついたデバイスは二度と渡されない
+    pci::probe_drivers();
+    assert_eq!(PROBED.load(Ordering::Relaxed), 1);
+}
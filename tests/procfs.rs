@@ -0,0 +1,113 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(TmpFs::default())).expect("mounting the root failed");
+    blog_os::procfs::init().expect("mounting /proc failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::string::String;
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use blog_os::interrupts;
+use blog_os::tmpfs::TmpFs;
+use blog_os::vfs::{self, FileType, FsError, OpenFlags};
+
+fn read_text(path: &str) -> String {
+    String::from_utf8(vfs::read_file(path).unwrap()).unwrap()
+}
+
+#[test_case]
+fn files_are_listed() {
+    let mut names: Vec<_> = vfs::readdir("/proc")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    names.sort();
+    assert_eq!(
+        names,
+        ["interrupts", "memmap", "meminfo", "pagetables", "tasks", "uptime"]
+    );
+    let metadata = vfs::stat("/proc/meminfo").unwrap();
+    assert_eq!(metadata.file_type, FileType::Regular);
+    assert_eq!(metadata.mode, 0o444);
+    assert_eq!(vfs::stat("/proc/missing").err(), Some(FsError::NotFound));
+}
+
+#[test_case]
+fn meminfo_reports_frames_and_heap() {
+    let meminfo = read_text("/proc/meminfo");
+    let heap_total = meminfo
+        .lines()
+        .find_map(|line| line.strip_prefix("HeapTotal:"))
+        .unwrap();
+    assert_eq!(heap_total.trim(), "4096 kB");
+    assert!(meminfo.contains("FramesTotal:"));
+    assert!(meminfo.contains("FramesFree:"));
+}
+
+#[test_case]
+fn counters_are_current() {
+    let seconds = |text: String| text.trim().parse::<f64>().unwrap();
+    let before = seconds(read_text("/proc/uptime"));
+    let start = interrupts::ticks();
+    while interrupts::ticks() < start + 5 {
+        x86_64::instructions::hlt();
+    }
+    let after = seconds(read_text("/proc/uptime"));
+    assert!(after > before);
+
+    let interrupts = read_text("/proc/interrupts");
+    let timer = interrupts.lines().nth(1).unwrap();
+    assert!(timer.starts_with("    32   0 timer"));
+    let count: u64 = timer.split_whitespace().last().unwrap().parse().unwrap();
+    assert!(count > 0);
+    assert!(read_text("/proc/tasks").contains("running"));
+}
+
+#[test_case]
+fn memory_layout_is_described() {
+    let memmap = read_text("/proc/memmap");
+    assert!(memmap.lines().any(|line| line.ends_with("Usable")));
+    assert!(memmap.lines().any(|line| line.ends_with("Kernel")));
+
+    // ヒープはレベル4の136番目の項目の下にある
+    let pagetables = read_text("/proc/pagetables");
+    assert!(pagetables.starts_with("CR3: 0x"));
+    let heap_row = pagetables
+        .lines()
+        .find(|line| line.trim_start().starts_with("136 "))
+        .unwrap();
+    let heap_pages: u64 = heap_row.split_whitespace().nth(2).unwrap().parse().unwrap();
+    assert!(heap_pages >= (blog_os::allocator::HEAP_SIZE / 4096) as u64);
+}
+
+#[test_case]
+fn files_are_read_only() {
+    assert_eq!(vfs::write_file("/proc/uptime", b"0"), Err(FsError::ReadOnly));
+    assert_eq!(vfs::mkdir("/proc/dir"), Err(FsError::ReadOnly));
+    assert_eq!(
+        vfs::open("/proc/new", OpenFlags::WRITE | OpenFlags::CREATE).err(),
+        Some(FsError::ReadOnly)
+    );
+}
@@ -0,0 +1,119 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(TmpFs::default())).expect("mounting the root failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::sync::Arc;
+use alloc::vec;
+use blog_os::tmpfs::TmpFs;
+use blog_os::vfs::{self, FileType, FsError, OpenFlags, SeekFrom};
+
+#[test_case]
+fn files_can_be_written_and_read_back() {
+    vfs::write_file("/greeting", b"hello").unwrap();
+    assert_eq!(vfs::read_file("/greeting").unwrap(), b"hello");
+
+    let fd = vfs::open("/greeting", OpenFlags::READ_WRITE).unwrap();
+    // 終わりを越えて書くと間は0で埋まる
+    vfs::seek(fd, SeekFrom::Start(8)).unwrap();
+    vfs::write(fd, b"!").unwrap();
+    vfs::close(fd).unwrap();
+    assert_eq!(vfs::read_file("/greeting").unwrap(), b"hello\0\0\0!");
+
+    let fd = vfs::open("/greeting", OpenFlags::APPEND).unwrap();
+    vfs::write(fd, b"?").unwrap();
+    vfs::close(fd).unwrap();
+    assert_eq!(vfs::stat("/greeting").unwrap().size, 10);
+
+    vfs::truncate("/greeting", 2).unwrap();
+    assert_eq!(vfs::read_file("/greeting").unwrap(), b"he");
+    vfs::unlink("/greeting").unwrap();
+    assert_eq!(vfs::stat("/greeting"), Err(FsError::NotFound));
+}
+
+#[test_case]
+fn directories_track_their_contents() {
+    vfs::mkdir("/a").unwrap();
+    vfs::mkdir("/a/b").unwrap();
+    vfs::write_file("/a/b/file", b"x").unwrap();
+    assert_eq!(vfs::mkdir("/a/b"), Err(FsError::AlreadyExists));
+    assert_eq!(vfs::stat("/a").unwrap().links, 3);
+    assert_eq!(vfs::rmdir("/a/b"), Err(FsError::NotEmpty));
+    assert_eq!(vfs::unlink("/a/b"), Err(FsError::IsADirectory));
+    assert_eq!(vfs::rmdir("/a/b/file"), Err(FsError::NotADirectory));
+
+    vfs::unlink("/a/b/file").unwrap();
+    vfs::rmdir("/a/b").unwrap();
+    assert_eq!(vfs::stat("/a").unwrap().links, 2);
+    vfs::rmdir("/a").unwrap();
+}
+
+#[test_case]
+fn rename_moves_and_replaces() {
+    vfs::mkdir("/src").unwrap();
+    vfs::mkdir("/dst").unwrap();
+    vfs::write_file("/src/one", b"1").unwrap();
+    vfs::write_file("/dst/two", b"2").unwrap();
+
+    vfs::rename("/src/one", "/dst/two").unwrap();
+    assert_eq!(vfs::read_file("/dst/two").unwrap(), b"1");
+    assert_eq!(vfs::stat("/src/one"), Err(FsError::NotFound));
+
+    // ディレクトリを自分の下には動かせない
+    assert_eq!(vfs::rename("/src", "/src/inner"), Err(FsError::InvalidArgument));
+    vfs::rename("/src", "/dst/src").unwrap();
+    assert_eq!(vfs::stat("/dst/src/..").unwrap().inode, vfs::stat("/dst").unwrap().inode);
+    assert_eq!(vfs::stat("/dst").unwrap().links, 3);
+}
+
+#[test_case]
+fn symlinks_are_followed() {
+    vfs::mkdir("/links").unwrap();
+    vfs::write_file("/links/target", b"data").unwrap();
+    vfs::symlink("target", "/links/alias").unwrap();
+    assert_eq!(vfs::read_file("/links/alias").unwrap(), b"data");
+    assert_eq!(vfs::lstat("/links/alias").unwrap().file_type, FileType::Symlink);
+}
+
+#[test_case]
+fn size_limit_is_enforced() {
+    vfs::mkdir("/small").unwrap();
+    let fs = Arc::new(TmpFs::new(4096));
+    vfs::mount("/small", fs.clone()).unwrap();
+    assert_eq!(vfs::write_file("/small/big", &vec![0u8; 8192]), Err(FsError::NoSpace));
+    vfs::write_file("/small/fits", &[1; 1024]).unwrap();
+    assert_eq!(vfs::mounts().last().unwrap().1, "tmpfs");
+
+    // 新しい名前の分がなくて失敗した rename は、置き換える先を消さない
+    vfs::write_file("/small/long-destination", b"kept").unwrap();
+    vfs::write_file("/small/pad", b"").unwrap();
+    let (used, limit) = fs.usage();
+    vfs::truncate("/small/pad", (limit - used - 4) as u64).unwrap();
+    assert_eq!(
+        vfs::rename("/small/fits", "/small/long-destination"),
+        Err(FsError::NoSpace)
+    );
+    assert_eq!(vfs::read_file("/small/long-destination").unwrap(), b"kept");
+    assert_eq!(vfs::stat("/small/fits").unwrap().size, 1024);
+}
@@ -0,0 +1,201 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    vfs::mount("/", Arc::new(StaticFs)).expect("mounting the root failed");
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use alloc::string::{String, ToString};
+use alloc::sync::Arc;
+use alloc::vec::Vec;
+use blog_os::vfs::{
+    self, DirEntry, FileSystem, FileType, FsError, Metadata, OpenFlags, SeekFrom,
+};
+
+/// 固定の木を持つ読み出し専用のファイルシステム
+///
+/// ```text
+/// /hello          "hello world\n"
+/// /etc/motd       "welcome\n"
+/// /etc/up      -> ../hello
+/// /link        -> etc/motd
+/// /loop        -> loop
+/// /mnt/
+/// ```
+struct StaticFs;
+
+const ROOT: u64 = 1;
+const NODES: &[(u64, u64, &str, FileType, &str)] = &[
+    // (inode, 親, 名前, 種類, 中身かリンク先)
+    (2, ROOT, "hello", FileType::Regular, "hello world\n"),
+    (3, ROOT, "etc", FileType::Directory, ""),
+    (4, 3, "motd", FileType::Regular, "welcome\n"),
+    (5, 3, "up", FileType::Symlink, "../hello"),
+    (6, ROOT, "link", FileType::Symlink, "etc/motd"),
+    (7, ROOT, "loop", FileType::Symlink, "loop"),
+    (8, ROOT, "mnt", FileType::Directory, ""),
+];
+
+fn node(inode: u64) -> Result<(FileType, &'static str), FsError> {
+    if inode == ROOT {
+        return Ok((FileType::Directory, ""));
+    }
+    NODES
+        .iter()
+        .find(|node| node.0 == inode)
+        .map(|node| (node.3, node.4))
+        .ok_or(FsError::NotFound)
+}
+
+impl FileSystem for StaticFs {
+    fn name(&self) -> &'static str {
+        "static"
+    }
+
+    fn read_only(&self) -> bool {
+        true
+    }
+
+    fn root(&self) -> u64 {
+        ROOT
+    }
+
+    fn lookup(&self, dir: u64, name: &str) -> Result<u64, FsError> {
+        NODES
+            .iter()
+            .find(|node| node.1 == dir && node.2 == name)
+            .map(|node| node.0)
+            .ok_or(FsError::NotFound)
+    }
+
+    fn metadata(&self, inode: u64) -> Result<Metadata, FsError> {
+        let (file_type, contents) = node(inode)?;
+        Ok(Metadata {
+            inode,
+            file_type,
+            size: contents.len() as u64,
+            mode: 0o444,
+            links: 1,
+            modified: 0,
+        })
+    }
+
+    fn read(&self, inode: u64, offset: u64, buffer: &mut [u8]) -> Result<usize, FsError> {
+        let contents = node(inode)?.1.as_bytes();
+        let start = (offset as usize).min(contents.len());
+        let count = buffer.len().min(contents.len() - start);
+        buffer[..count].copy_from_slice(&contents[start..start + count]);
+        Ok(count)
+    }
+
+    fn readdir(&self, dir: u64) -> Result<Vec<DirEntry>, FsError> {
+        Ok(NODES
+            .iter()
+            .filter(|node| node.1 == dir)
+            .map(|node| DirEntry {
+                name: node.2.to_string(),
+                inode: node.0,
+                file_type: node.3,
+            })
+            .collect())
+    }
+
+    fn readlink(&self, inode: u64) -> Result<String, FsError> {
+        match node(inode)? {
+            (FileType::Symlink, target) => Ok(target.to_string()),
+            _ => Err(FsError::InvalidArgument),
+        }
+    }
+}
+
+#[test_case]
+fn read_and_seek_through_a_file_descriptor() {
+    let fd = vfs::open("/hello", OpenFlags::READ).unwrap();
+    let mut buffer = [0u8; 5];
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(5));
+    assert_eq!(&buffer, b"hello");
+    assert_eq!(vfs::seek(fd, SeekFrom::End(-6)), Ok(6));
+    assert_eq!(vfs::read(fd, &mut buffer), Ok(5));
+    assert_eq!(&buffer, b"world");
+    assert_eq!(vfs::seek(fd, SeekFrom::Current(-100)), Err(FsError::InvalidArgument));
+    vfs::close(fd).unwrap();
+    assert_eq!(vfs::read(fd, &mut buffer), Err(FsError::BadFileDescriptor));
+}
+
+#[test_case]
+fn paths_resolve_dot_dot_and_symlinks() {
+    assert_eq!(vfs::read_file("/etc/../hello").unwrap(), b"hello world\n");
+    assert_eq!(vfs::read_file("/link").unwrap(), b"welcome\n");
+    // 相対パスのリンクはリンクのあるディレクトリから解決する
+    assert_eq!(vfs::read_file("/etc/up").unwrap(), b"hello world\n");
+    // リンクをたどった後の `..` はリンク先の親に戻る
+    assert_eq!(vfs::stat("/link/..").unwrap().inode, 3);
+    assert_eq!(vfs::lstat("/link").unwrap().file_type, FileType::Symlink);
+    assert_eq!(vfs::readlink("/link").unwrap(), "etc/motd");
+    assert_eq!(vfs::stat("/loop"), Err(FsError::TooManySymlinks));
+    assert_eq!(vfs::stat("/hello/x"), Err(FsError::NotADirectory));
+    assert_eq!(vfs::stat("hello"), Err(FsError::InvalidPath));
+}
+
+#[test_case]
+fn readdir_is_sorted() {
+    let names: Vec<String> = vfs::readdir("/")
+        .unwrap()
+        .into_iter()
+        .map(|entry| entry.name)
+        .collect();
+    assert_eq!(names, ["etc", "hello", "link", "loop", "mnt"]);
+}
+
+#[test_case]
+fn read_only_file_systems_reject_writes() {
+    assert_eq!(
+        vfs::open("/new", OpenFlags::WRITE | OpenFlags::CREATE),
+        Err(FsError::ReadOnly)
+    );
+    assert_eq!(vfs::mkdir("/etc/x"), Err(FsError::ReadOnly));
+    assert_eq!(vfs::open("/etc", OpenFlags::WRITE), Err(FsError::IsADirectory));
+    assert_eq!(vfs::open("/hello", OpenFlags::WRITE), Err(FsError::ReadOnly));
+    assert_eq!(
+        vfs::open("/hello", OpenFlags::READ | OpenFlags::APPEND),
+        Err(FsError::ReadOnly)
+    );
+    let fd = vfs::open("/hello", OpenFlags::READ).unwrap();
+    assert_eq!(vfs::write(fd, b"x"), Err(FsError::BadFileDescriptor));
+    vfs::close(fd).unwrap();
+}
+
+#[test_case]
+fn mounts_cover_directories() {
+    vfs::mount("/mnt", Arc::new(StaticFs)).unwrap();
+    assert_eq!(vfs::mount("/mnt/", Arc::new(StaticFs)), Err(FsError::Busy));
+    assert_eq!(vfs::read_file("/mnt/etc/motd").unwrap(), b"welcome\n");
+    // マウントのルートの `..` は覆っているディレクトリの親
+    assert_eq!(vfs::read_file("/mnt/../hello").unwrap(), b"hello world\n");
+    assert!(vfs::mounts().contains(&("/mnt".to_string(), "static")));
+
+    let fd = vfs::open("/mnt/hello", OpenFlags::READ).unwrap();
+    assert_eq!(vfs::unmount("/mnt"), Err(FsError::Busy));
+    vfs::close(fd).unwrap();
+    vfs::unmount("/mnt").unwrap();
+    assert_eq!(vfs::stat("/mnt/hello"), Err(FsError::NotFound));
+}
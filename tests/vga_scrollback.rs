@@ -0,0 +1,82 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    WRITER.lock().enable_scrollback();
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::vga_buffer::WRITER;
+use core::fmt::Write;
+use x86_64::instructions::interrupts;
+
+#[test_case]
+fn scroll_back_and_snap_to_bottom() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..40 {
+            writeln!(writer, "line {:02}", i).unwrap();
+        }
+        // the last line written sits just above the cursor on the bottom row
+        assert_eq!(writer.read_char(23, 5), b'3');
+        assert_eq!(writer.read_char(23, 6), b'9');
+
+        writer.scroll_view_up(10);
+        assert_eq!(writer.scroll_offset(), 10);
+        assert_eq!(writer.read_char(23, 5), b'2');
+        assert_eq!(writer.read_char(23, 6), b'9');
+
+        writer.scroll_view_down(4);
+        assert_eq!(writer.scroll_offset(), 6);
+
+        write!(writer, "x").unwrap();
+        assert_eq!(writer.scroll_offset(), 0);
+        assert_eq!(writer.read_char(23, 6), b'9');
+        assert_eq!(writer.read_char(24, 0), b'x');
+    });
+}
+
+#[test_case]
+fn scroll_offset_is_bounded_by_history() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        writer.scroll_view_up(usize::MAX / 2);
+        assert!(writer.scroll_offset() <= blog_os::vga_buffer::SCROLLBACK_LINES);
+        writer.scroll_to_bottom();
+        assert_eq!(writer.scroll_offset(), 0);
+    });
+}
+
+#[test_case]
+fn clear_screen_returns_to_the_live_screen() {
+    interrupts::without_interrupts(|| {
+        let mut writer = WRITER.lock();
+        for i in 0..40 {
+            writeln!(writer, "line {:02}", i).unwrap();
+        }
+        writer.scroll_view_up(usize::MAX);
+        assert!(writer.scroll_offset() > 0);
+        writer.clear_screen();
+        assert_eq!(writer.scroll_offset(), 0);
+        assert_eq!(writer.read_char(0, 0), b' ');
+        assert_eq!(writer.cursor_position(), (0, 0));
+    });
+}
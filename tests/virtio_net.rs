@@ -0,0 +1,187 @@
+#![no_std]
+#![no_main]
+#![feature(custom_test_frameworks)]
+#![test_runner(blog_os::test_runner)]
+#![reexport_test_harness_main = "test_main"]
+
+extern crate alloc;
+
+use bootloader::{entry_point, BootInfo};
+use core::panic::PanicInfo;
+
+entry_point!(main);
+
+fn main(boot_info: &'static BootInfo) -> ! {
+    blog_os::test_init(boot_info);
+    blog_os::acpi::init().expect("acpi initialization failed");
+    pci::init();
+    pci::register_driver(&blog_os::virtio_net::DRIVER);
+    pci::probe_drivers();
+
+    test_main();
+    blog_os::hlt_loop();
+}
+
+#[panic_handler]
+fn panic(info: &PanicInfo) -> ! {
+    blog_os::test_panic_handler(info)
+}
+
+use blog_os::interrupts;
+use blog_os::net::{self, ChecksumOffload, MacAddress, NetError, SharedNetDevice};
+use blog_os::pci;
+use blog_os::time::Duration;
+
+/// QEMUのユーザーモードネットワークでのこのマシンとゲートウェイのアドレス
+const GUEST_IP: [u8; 4] = [10, 0, 2, 15];
+const GATEWAY_IP: [u8; 4] = [10, 0, 2, 2];
+
+/// pingで送るデータの大きさ。返ってくるフレームは受信バッファ1つに収まらない
+const PING_DATA_SIZE: usize = 1400;
+const PING_FRAME_SIZE: usize = 14 + 20 + 8 + PING_DATA_SIZE;
+
+fn nic() -> SharedNetDevice {
+    net::DEVICES.entries()
+        .into_iter()
+        .find(|(_, device)| device.lock().description() == "virtio-net")
+        .map(|(_, device)| device)
+        .expect("no virtio-net device")
+}
+
+/// `target` のMACアドレスを尋ねるARP要求
+fn arp_request(source: MacAddress, target: [u8; 4]) -> [u8; 42] {
+    let mut frame = [0u8; 42];
+    frame[0..6].copy_from_slice(&MacAddress::BROADCAST.0);
+    frame[6..12].copy_from_slice(&source.0);
+    frame[12..14].copy_from_slice(&[0x08, 0x06]);
+    // Ethernet / IPv4、アドレス長6と4、要求
+    frame[14..22].copy_from_slice(&[0, 1, 0x08, 0x00, 6, 4, 0, 1]);
+    frame[22..28].copy_from_slice(&source.0);
+    frame[28..32].copy_from_slice(&GUEST_IP);
+    frame[38..42].copy_from_slice(&target);
+    frame
+}
+
+/// ゲートウェイにARPで尋ね、返ってきたMACアドレス
+fn gateway_mac(nic: &SharedNetDevice) -> MacAddress {
+    let mac = nic.lock().mac_address();
+    nic.lock().transmit(&arp_request(mac, GATEWAY_IP)).unwrap();
+
+    let mut frame = [0u8; net::MAX_FRAME_SIZE];
+    loop {
+        let length = net::receive_timeout(nic, &mut frame, Duration::from_secs(2))
+            .unwrap()
+            .expect("no ARP reply from the gateway");
+        let frame = &frame[..length];
+        // ARPの応答 (operation 2) で、送信元がゲートウェイのもの
+        if frame[12..14] == [0x08, 0x06] && frame[20..22] == [0, 2] && frame[28..32] == GATEWAY_IP
+        {
+            assert!(length >= 42);
+            assert_eq!(frame[0..6], mac.0);
+            assert_eq!(frame[32..38], mac.0);
+            let mut gateway = [0u8; 6];
+            gateway.copy_from_slice(&frame[22..28]);
+            return MacAddress(gateway);
+        }
+    }
+}
+
+#[test_case]
+fn mac_address_and_link() {
+    let nic = nic();
+    let nic = nic.lock();
+    // QEMUが付ける既定のアドレス。最後のバイトはNICの順番で変わる
+    assert_eq!(nic.mac_address().0[..5], [0x52, 0x54, 0x00, 0x12, 0x34]);
+    assert!(nic.link_up());
+}
+
+#[test_case]
+fn gateway_answers_arp() {
+    let nic = nic();
+    let irq = pci::devices()
+        .into_iter()
+        .find(|(_, driver)| *driver == Some("virtio-net"))
+        .map(|(device, _)| usize::from(device.interrupt_line))
+        .unwrap();
+    let interrupts_before = interrupts::irq_count(irq);
+
+    let gateway = gateway_mac(&nic);
+    assert!(!gateway.is_multicast());
+
+    let stats = nic.lock().stats();
+    assert!(stats.tx_packets >= 1);
+    assert!(stats.rx_packets >= 1);
+    // 受信は割り込みで知らされる
+    assert!(interrupts::irq_count(irq) > interrupts_before);
+}
+
+#[test_case]
+fn large_ping_spans_rx_buffers() {
+    let nic = nic();
+    let mac = nic.lock().mac_address();
+    let gateway = gateway_mac(&nic);
+
+    let mut request = [0u8; PING_FRAME_SIZE];
+    request[0..6].copy_from_slice(&gateway.0);
+    request[6..12].copy_from_slice(&mac.0);
+    request[12..14].copy_from_slice(&[0x08, 0x00]);
+    // IPv4ヘッダ: 全体の長さ、DF、TTL 64、ICMP
+    let ip_length = (PING_FRAME_SIZE - 14) as u16;
+    request[14..16].copy_from_slice(&[0x45, 0]);
+    request[16..18].copy_from_slice(&ip_length.to_be_bytes());
+    request[18..24].copy_from_slice(&[0x12, 0x34, 0x40, 0, 64, 1]);
+    request[26..30].copy_from_slice(&GUEST_IP);
+    request[30..34].copy_from_slice(&GATEWAY_IP);
+    let ip_checksum = net::internet_checksum(&request[14..34]);
+    request[24..26].copy_from_slice(&ip_checksum.to_be_bytes());
+    // ICMPエコー要求。チェックサムはデバイスかドライバが入れる
+    request[34..42].copy_from_slice(&[8, 0, 0, 0, 0xbe, 0xef, 0, 1]);
+    for (i, byte) in request[42..].iter_mut().enumerate() {
+        *byte = i as u8;
+    }
+    let checksum = ChecksumOffload {
+        start: 34,
+        offset: 2,
+    };
+    nic.lock()
+        .transmit_with_checksum(&mut request, checksum)
+        .unwrap();
+
+    let mut frame = [0u8; net::MAX_FRAME_SIZE];
+    let length = loop {
+        let length = net::receive_timeout(&nic, &mut frame, Duration::from_secs(2))
+            .unwrap()
+            .expect("no echo reply from the gateway");
+        // ICMPのエコー応答で、こちらが送った識別子のもの
+        if frame[12..14] == [0x08, 0x00]
+            && frame[23] == 1
+            && frame[34] == 0
+            && frame[38..40] == [0xbe, 0xef]
+        {
+            break length;
+        }
+    };
+    assert_eq!(length, PING_FRAME_SIZE);
+    assert_eq!(frame[42..length], request[42..]);
+    assert_eq!(net::internet_checksum(&frame[14..34]), 0);
+    assert_eq!(net::internet_checksum(&frame[34..length]), 0);
+}
+
+#[test_case]
+fn invalid_frames_are_rejected() {
+    let nic = nic();
+    let mut nic = nic.lock();
+    assert_eq!(nic.transmit(&[0; 10]), Err(NetError::InvalidFrame));
+    assert_eq!(
+        nic.transmit(&[0; net::MAX_FRAME_SIZE + 1]),
+        Err(NetError::InvalidFrame)
+    );
+    let checksum = ChecksumOffload {
+        start: 34,
+        offset: 2,
+    };
+    assert_eq!(
+        nic.transmit_with_checksum(&mut [0; 35], checksum),
+        Err(NetError::InvalidFrame)
+    );
+}